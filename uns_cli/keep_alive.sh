@@ -1,16 +1,19 @@
 #!/bin/bash
 
 # Script to keep the UNS CLI running in the background
-# Usage: ./keep_alive.sh [tags_file] [mqtt_host] [mqtt_port]
-
-# Default values
-TAGS_FILE=${1:-"/usr/src/uns_cli/tags.json"}
-MQTT_HOST=${2:-"hivemq"}
-MQTT_PORT=${3:-1883}
+# Usage: ./keep_alive.sh
+#
+# Settings are read from the configuration file (/etc/uns_cli/config.toml, or the file named by
+# UNS_CONFIG) and can be overridden through UNS_* environment variables, for example:
+#   UNS_MQTT_HOST, UNS_MQTT_PORT                                  broker address
+#   UNS_TAGS_FILE                                                 tag file to load
+#   UNS_MQTT_CA_FILE, UNS_MQTT_CLIENT_CERT, UNS_MQTT_CLIENT_KEY   PEM files (any of them enables TLS)
+#   UNS_MQTT_TLS=1                                                TLS with the system CA certificates
+#   UNS_MQTT_USERNAME                                             broker username
+#   UNS_MQTT_PASSWORD_FILE or UNS_MQTT_PASSWORD                   broker password
 
 echo "Starting UNS CLI in background mode..."
-echo "Tags file: $TAGS_FILE"
-echo "MQTT Broker: $MQTT_HOST:$MQTT_PORT"
+echo "Configuration file: ${UNS_CONFIG:-/etc/uns_cli/config.toml}"
 echo "-----------------------------------"
 
 # Debug information
@@ -18,17 +21,23 @@ echo "Current directory: $(pwd)"
 echo "Listing files in current directory:"
 ls -la
 
-# Check if the tags file exists
-if [ ! -f "$TAGS_FILE" ]; then
-    echo "Error: Tags file not found: $TAGS_FILE"
+# Check the tag file early when it is given in the environment
+if [ -n "$UNS_TAGS_FILE" ] && [ ! -f "$UNS_TAGS_FILE" ]; then
+    echo "Error: Tags file not found: $UNS_TAGS_FILE"
     echo "Trying to find the file:"
     find / -name tags.json 2>/dev/null
     exit 1
 fi
 
+# Check the configuration and tag file before going to the background
+if ! uns_cli validate; then
+    echo "Error: invalid configuration or tag file"
+    exit 1
+fi
+
 # Run the UNS CLI in run mode
 echo "Running UNS CLI..."
-uns_cli run --tags-file $TAGS_FILE --mqtt-host $MQTT_HOST --mqtt-port $MQTT_PORT &
+uns_cli run &
 
 # Keep the container running
 echo "UNS CLI is running in the background. Container will stay alive."
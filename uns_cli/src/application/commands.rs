@@ -1,6 +1,13 @@
-use std::sync::Arc;
+use log::{error, info};
+use std::{sync::Arc, time::Duration};
 
-use crate::domain::TagService;
+use crate::application::{
+    DatabaseSnapshotter, ReconnectRepublisher, RetainedPurger, StatusAnnouncer, TagMirror, WriteRequestListener,
+};
+use crate::domain::instance_status::DEFAULT_INSTANCE_ID;
+use crate::domain::{DatabaseMode, ReplyTo, Tag, TagRepository, TagService, TopicMapping, WriteRequest, WriteResponse};
+use crate::infrastructure::mqtt::{MqttClient, MqttPublisher, MqttSubscriber};
+use crate::infrastructure::repositories::{JsonTagRepository, TagFileValidator};
 use crate::infrastructure::UnsError;
 
 /// Command handler trait
@@ -12,133 +19,589 @@ pub trait CommandHandler: Send + Sync {
 /// Run command handler
 pub struct RunCommandHandler {
     tag_service: Arc<dyn TagService>,
+    subscriber: Arc<dyn MqttSubscriber>,
+    publisher: Arc<dyn MqttPublisher>,
     tags_file: String,
+    republish_on_reconnect: bool,
+    writable_prefixes: Vec<String>,
+    instance_id: String,
+    mapping: TopicMapping,
+    database_mode: DatabaseMode,
+    snapshot_interval: Option<Duration>,
+    stale_purge: Option<(RetainedPurger, bool)>,
     #[cfg(test)]
     test_mode: bool,
 }
 
 impl RunCommandHandler {
     /// Creates a new RunCommandHandler
-    pub fn new(tag_service: Arc<dyn TagService>, tags_file: String) -> Self {
+    pub fn new(
+        tag_service: Arc<dyn TagService>,
+        subscriber: Arc<dyn MqttSubscriber>,
+        publisher: Arc<dyn MqttPublisher>,
+        tags_file: String,
+    ) -> Self {
         Self {
             tag_service,
+            subscriber,
+            publisher,
             tags_file,
+            republish_on_reconnect: false,
+            writable_prefixes: Vec::new(),
+            instance_id: DEFAULT_INSTANCE_ID.to_string(),
+            mapping: TopicMapping::default(),
+            database_mode: DatabaseMode::default(),
+            snapshot_interval: None,
+            stale_purge: None,
             #[cfg(test)]
             test_mode: false,
         }
     }
     
+    /// Publishes the retained tag state again after the broker connection is restored
+    pub fn with_republish_on_reconnect(mut self, enabled: bool) -> Self {
+        self.republish_on_reconnect = enabled;
+        self
+    }
+    
+    /// Restricts write requests to tags under these path prefixes (all tags when empty)
+    pub fn with_writable_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        self.writable_prefixes = prefixes;
+        self
+    }
+    
+    /// Sets the identifier used for the status topic `uns/status/<instance id>`
+    pub fn with_instance_id(mut self, instance_id: String) -> Self {
+        self.instance_id = instance_id;
+        self
+    }
+    
+    /// Sets the topics write requests are received on
+    pub fn with_topic_mapping(mut self, mapping: TopicMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+    
+    /// Serves database snapshots periodically and on request when the tag service publishes deltas
+    pub fn with_database_mode(mut self, mode: DatabaseMode, snapshot_interval: Option<Duration>) -> Self {
+        self.database_mode = mode;
+        self.snapshot_interval = snapshot_interval;
+        self
+    }
+    
+    /// Reports retained tag topics left behind by tags no longer loaded, and clears them if `apply`
+    pub fn with_stale_purge(mut self, purger: RetainedPurger, apply: bool) -> Self {
+        self.stale_purge = Some((purger, apply));
+        self
+    }
+    
     #[cfg(test)]
     /// Creates a new RunCommandHandler in test mode
-    pub fn new_test_mode(tag_service: Arc<dyn TagService>, tags_file: String) -> Self {
+    pub fn new_test_mode(
+        tag_service: Arc<dyn TagService>,
+        subscriber: Arc<dyn MqttSubscriber>,
+        publisher: Arc<dyn MqttPublisher>,
+        tags_file: String,
+    ) -> Self {
         Self {
             tag_service,
+            subscriber,
+            publisher,
             tags_file,
+            republish_on_reconnect: false,
+            writable_prefixes: Vec::new(),
+            instance_id: DEFAULT_INSTANCE_ID.to_string(),
+            mapping: TopicMapping::default(),
+            database_mode: DatabaseMode::default(),
+            snapshot_interval: None,
+            stale_purge: None,
             test_mode: true,
         }
     }
 }
 
 impl CommandHandler for RunCommandHandler {
-    fn execute(&self) -> impl std::future::Future<Output = Result<(), UnsError>> + Send {
-        async move {
-            println!("Starting UNS CLI...");
-            println!("Loading tags from: {}", self.tags_file);
-            
-            match self.tag_service.load_and_publish_tags(&self.tags_file).await {
-                Ok(_) => {
-                    println!("Tags loaded and published successfully.");
-                    println!("UNS CLI running. Waiting for updates or termination...");
-                    
-                    // In test mode, we don't wait for Ctrl+C
-                    #[cfg(test)]
-                    if self.test_mode {
-                        println!("Test mode: not waiting for Ctrl+C");
-                        return Ok(());
+    async fn execute(&self) -> Result<(), UnsError> {
+        info!("Starting UNS CLI...");
+        info!("Loading tags from: {}", self.tags_file);
+        
+        match self.tag_service.load_and_publish_tags(&self.tags_file).await {
+            Ok(_) => {
+                info!("Tags loaded and published successfully.");
+                
+                // Retained topics of renamed or deleted tags would otherwise stay forever
+                if let Some((purger, apply)) = &self.stale_purge {
+                    let stale = purger.find_stale(&self.tag_service.get_all_tags().await?).await?;
+                    RetainedPurger::report(&stale);
+                    if *apply {
+                        purger.purge(&stale).await?;
                     }
-                    
-                    // Keep the application running indefinitely
-                    // In a real application, you might have a loop listening for external updates or commands
-                    tokio::signal::ctrl_c().await?;
-                    println!("Shutting down...");
-                    Ok(())
                 }
-                Err(e) => {
-                    eprintln!("Error loading tags: {}", e);
-                    Err(e)
+                
+                // Accept write requests from other processes
+                let listener = WriteRequestListener::new(
+                    self.tag_service.clone(),
+                    self.subscriber.clone(),
+                    self.publisher.clone(),
+                )
+                .with_writable_prefixes(self.writable_prefixes.clone())
+                .with_topic_mapping(self.mapping.clone());
+                let listener_task = listener.start().await?;
+                
+                // Restore retained state lost by a broker restart
+                let republish_task = self
+                    .republish_on_reconnect
+                    .then(|| ReconnectRepublisher::new(self.tag_service.clone()).start());
+                
+                // Tell consumers the retained tags are maintained
+                let announcer = StatusAnnouncer::new(
+                    self.tag_service.clone(),
+                    self.publisher.clone(),
+                    self.instance_id.clone(),
+                );
+                let status_task = announcer.start();
+                
+                // Deltas need a snapshot to build on
+                let snapshot_task = match self.database_mode {
+                    DatabaseMode::Delta => Some(
+                        DatabaseSnapshotter::new(self.tag_service.clone(), self.subscriber.clone())
+                            .with_topic_mapping(self.mapping.clone())
+                            .with_interval(self.snapshot_interval)
+                            .start()
+                            .await?,
+                    ),
+                    DatabaseMode::Full => None,
+                };
+                
+                info!("UNS CLI running. Waiting for updates or termination...");
+                
+                // In test mode, we don't wait for Ctrl+C
+                #[cfg(test)]
+                if self.test_mode {
+                    info!("Test mode: not waiting for Ctrl+C");
+                    return Ok(());
+                }
+                
+                // Keep the application running until interrupted
+                tokio::signal::ctrl_c().await?;
+                info!("Shutting down...");
+                listener_task.abort();
+                status_task.abort();
+                if let Some(task) = republish_task {
+                    task.abort();
+                }
+                if let Some(task) = snapshot_task {
+                    task.abort();
+                }
+                
+                // Nobody updates the retained tags from now on
+                if let Err(e) = self.tag_service.mark_tags_stale().await {
+                    error!("Error marking tags stale: {}", e);
+                }
+                if let Err(e) = announcer.announce_offline().await {
+                    error!("Error publishing offline status: {}", e);
                 }
+                
+                // Give the event loop time to send the final messages
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error loading tags: {}", e);
+                Err(e)
             }
         }
     }
 }
 
+/// Default time the update command waits for the running instance to reply
+pub const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Update command handler
+///
+/// Sends a write request to the running instance instead of touching a
+/// local repository, which would be empty in this process, and waits for
+/// the instance to reply with the outcome.
 pub struct UpdateCommandHandler {
-    tag_service: Arc<dyn TagService>,
+    publisher: Arc<dyn MqttPublisher>,
+    subscriber: Arc<dyn MqttSubscriber>,
     path: String,
     value: String,
+    reply_timeout: Duration,
+    mapping: TopicMapping,
 }
 
 impl UpdateCommandHandler {
     /// Creates a new UpdateCommandHandler
-    pub fn new(tag_service: Arc<dyn TagService>, path: String, value: String) -> Self {
+    pub fn new(
+        publisher: Arc<dyn MqttPublisher>,
+        subscriber: Arc<dyn MqttSubscriber>,
+        path: String,
+        value: String,
+    ) -> Self {
         Self {
-            tag_service,
+            publisher,
+            subscriber,
             path,
             value,
+            reply_timeout: DEFAULT_REPLY_TIMEOUT,
+            mapping: TopicMapping::default(),
+        }
+    }
+    
+    /// Sets how long to wait for the running instance to reply
+    pub fn with_reply_timeout(mut self, timeout: Duration) -> Self {
+        self.reply_timeout = timeout;
+        self
+    }
+    
+    /// Sets the topics the reply is expected on
+    pub fn with_topic_mapping(mut self, mapping: TopicMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+    
+    /// Sends the request and waits for the matching reply
+    async fn request(&self) -> Result<WriteResponse, UnsError> {
+        let reply_to = ReplyTo::unique(&self.mapping);
+        
+        // Subscribe before sending so the reply cannot arrive first
+        let mut replies = self.subscriber.subscribe_stream(&reply_to.topic).await?;
+        
+        let request = WriteRequest::new(self.path.clone(), self.value.clone()).with_reply_to(reply_to.clone());
+        self.publisher.publish_write_request(&request).await?;
+        info!("Write request sent: {} = {}", self.path, self.value);
+        
+        let reply = tokio::time::timeout(self.reply_timeout, async {
+            while let Some(message) = replies.recv().await {
+                if message.properties.correlation_data == reply_to.correlation_data {
+                    return Some(message);
+                }
+            }
+            None
+        })
+        .await;
+        
+        match reply {
+            Ok(Some(message)) => WriteResponse::from_payload(&message.payload),
+            _ => Err(UnsError::Mqtt(format!(
+                "No reply within {:?}; is `uns_cli run` running and connected to the same broker?",
+                self.reply_timeout
+            ))),
         }
     }
 }
 
 impl CommandHandler for UpdateCommandHandler {
-    fn execute(&self) -> impl std::future::Future<Output = Result<(), UnsError>> + Send {
-        async move {
-            println!("Attempting to update tag: {} with value: {}", self.path, self.value);
+    async fn execute(&self) -> Result<(), UnsError> {
+        info!("Attempting to update tag: {} with value: {}", self.path, self.value);
+        
+        match self.request().await.and_then(WriteResponse::into_result) {
+            Ok(_) => {
+                println!("Tag updated successfully: {} = {}", self.path, self.value);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error updating tag: {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Validate command handler
+pub struct ValidateCommandHandler {
+    tags_file: String,
+    schema: bool,
+}
+
+impl ValidateCommandHandler {
+    /// Creates a new ValidateCommandHandler
+    pub fn new(tags_file: String, schema: bool) -> Self {
+        Self { tags_file, schema }
+    }
+}
+
+impl CommandHandler for ValidateCommandHandler {
+    async fn execute(&self) -> Result<(), UnsError> {
+        if self.schema {
+            let schema = serde_json::to_string_pretty(&TagFileValidator::json_schema())?;
+            println!("{}", schema);
+            return Ok(());
+        }
+        
+        let report = TagFileValidator::validate_file(&self.tags_file)?;
+        
+        if report.is_valid() {
+            println!("{}: OK", self.tags_file);
+            Ok(())
+        } else {
+            eprintln!("{}", report.render(&self.tags_file));
+            Err(UnsError::Validation(format!(
+                "{} problem(s) found in {}",
+                report.diagnostics.len(),
+                self.tags_file
+            )))
+        }
+    }
+}
+
+/// Mirror command handler
+///
+/// Keeps a local replica of the tags published on the broker, which the tag
+/// service reads from, and writes it to files for tools that want a snapshot.
+pub struct MirrorCommandHandler {
+    tag_service: Arc<dyn TagService>,
+    mirror: TagMirror,
+    output: Option<String>,
+    paths_output: Option<String>,
+    prefix: Option<String>,
+    settle_time: Option<Duration>,
+}
+
+impl MirrorCommandHandler {
+    /// Creates a new MirrorCommandHandler
+    pub fn new(tag_service: Arc<dyn TagService>, mirror: TagMirror) -> Self {
+        Self {
+            tag_service,
+            mirror,
+            output: None,
+            paths_output: None,
+            prefix: None,
+            settle_time: None,
+        }
+    }
+    
+    /// Writes the replica as a tag file whenever it changes
+    pub fn with_output(mut self, output: Option<String>) -> Self {
+        self.output = output;
+        self
+    }
+    
+    /// Writes the sorted tag paths, one per line, whenever the replica changes
+    pub fn with_paths_output(mut self, paths_output: Option<String>) -> Self {
+        self.paths_output = paths_output;
+        self
+    }
+    
+    /// Only writes tags under this path prefix to the output files
+    pub fn with_prefix(mut self, prefix: Option<String>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+    
+    /// Stops once nothing changed for this long (`None` = run until interrupted)
+    pub fn with_settle_time(mut self, settle_time: Option<Duration>) -> Self {
+        self.settle_time = settle_time;
+        self
+    }
+    
+    /// Writes the output files and returns the number of tags written
+    async fn write_outputs(&self) -> Result<usize, UnsError> {
+        let mut tags = self.tag_service.get_all_tags().await?;
+        if let Some(prefix) = &self.prefix {
+            tags.retain(|path, _| Tag::path_starts_with(path, prefix));
+        }
+        
+        // Same format as the tag file, so the output can seed another instance
+        if let Some(output) = &self.output {
+            JsonTagRepository::new().save_tags(&tags, output).await?;
+        }
+        
+        if let Some(paths_output) = &self.paths_output {
+            let mut paths: Vec<&String> = tags.keys().collect();
+            paths.sort();
+            let contents: String = paths.iter().map(|path| format!("{}\n", path)).collect();
+            std::fs::write(paths_output, contents)
+                .map_err(|e| UnsError::Repository(format!("Failed to write to file {}: {}", paths_output, e)))?;
+        }
+        
+        Ok(tags.len())
+    }
+}
+
+impl CommandHandler for MirrorCommandHandler {
+    async fn execute(&self) -> Result<(), UnsError> {
+        info!("Mirroring tags from the broker...");
+        let mut changes = self.mirror.changes();
+        let mirror_task = self.mirror.start().await?;
+        
+        loop {
+            let changed = match self.settle_time {
+                Some(settle_time) => matches!(tokio::time::timeout(settle_time, changes.changed()).await, Ok(Ok(()))),
+                None => tokio::select! {
+                    changed = changes.changed() => changed.is_ok(),
+                    _ = tokio::signal::ctrl_c() => false,
+                },
+            };
+            if !changed {
+                break;
+            }
             
-            match self.tag_service.update_and_publish_tag(&self.path, self.value.clone()).await {
-                Ok(_) => {
-                    println!("Tag updated successfully: {} = {}", self.path, self.value);
-                    
-                    // Wait a moment to ensure the update is published
-                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                    
-                    println!("Update command finished.");
-                    Ok(())
-                }
-                Err(e) => {
-                    eprintln!("Error updating tag: {}", e);
-                    Err(e)
+            // A one-off copy is only written once the broker has sent everything
+            if self.settle_time.is_none() {
+                if let Err(e) = self.write_outputs().await {
+                    error!("Error writing the replica: {}", e);
                 }
             }
         }
+        
+        mirror_task.abort();
+        let count = self.write_outputs().await?;
+        println!("Mirrored {} tags", count);
+        Ok(())
+    }
+}
+
+/// Purge command handler
+///
+/// Reports the retained tag topics whose tag is no longer in the tag file, and
+/// clears them when applied.
+pub struct PurgeCommandHandler {
+    repository: Arc<dyn TagRepository>,
+    purger: RetainedPurger,
+    tags_file: String,
+    apply: bool,
+}
+
+impl PurgeCommandHandler {
+    /// Creates a new PurgeCommandHandler that only reports
+    pub fn new(repository: Arc<dyn TagRepository>, purger: RetainedPurger, tags_file: String) -> Self {
+        Self {
+            repository,
+            purger,
+            tags_file,
+            apply: false,
+        }
+    }
+    
+    /// Clears the stale topics after reporting them
+    pub fn with_apply(mut self, apply: bool) -> Self {
+        self.apply = apply;
+        self
+    }
+}
+
+impl CommandHandler for PurgeCommandHandler {
+    async fn execute(&self) -> Result<(), UnsError> {
+        let catalog = self.repository.load_tags(&self.tags_file).await?;
+        info!("Comparing retained topics with {} tags from {}...", catalog.len(), self.tags_file);
+        
+        let stale = self.purger.find_stale(&catalog).await?;
+        RetainedPurger::report(&stale);
+        
+        if !self.apply {
+            if !stale.is_empty() {
+                println!("Dry run: nothing was cleared. Run again with --apply to clear them.");
+            }
+            return Ok(());
+        }
+        
+        self.purger.purge(&stale).await
     }
 }
 
 /// Command factory for creating command handlers
 pub struct CommandFactory {
     tag_service: Arc<dyn TagService>,
+    subscriber: Arc<dyn MqttSubscriber>,
+    publisher: Arc<dyn MqttPublisher>,
+    repository: Option<Arc<dyn TagRepository>>,
+    client: Option<Arc<dyn MqttClient>>,
 }
 
 impl CommandFactory {
     /// Creates a new CommandFactory
-    pub fn new(tag_service: Arc<dyn TagService>) -> Self {
-        Self { tag_service }
+    pub fn new(
+        tag_service: Arc<dyn TagService>,
+        subscriber: Arc<dyn MqttSubscriber>,
+        publisher: Arc<dyn MqttPublisher>,
+    ) -> Self {
+        Self {
+            tag_service,
+            subscriber,
+            publisher,
+            repository: None,
+            client: None,
+        }
+    }
+    
+    /// Sets the repository behind the tag service, which mirror commands fill and
+    /// purge commands load the catalog into
+    pub fn with_repository(mut self, repository: Arc<dyn TagRepository>) -> Self {
+        self.repository = Some(repository);
+        self
+    }
+    
+    /// Sets the client used for plain publishes: snapshot requests and clearing retained topics
+    pub fn with_client(mut self, client: Arc<dyn MqttClient>) -> Self {
+        self.client = Some(client);
+        self
     }
     
     /// Creates a RunCommandHandler
     pub fn create_run_command(&self, tags_file: String) -> RunCommandHandler {
-        RunCommandHandler::new(self.tag_service.clone(), tags_file)
+        RunCommandHandler::new(
+            self.tag_service.clone(),
+            self.subscriber.clone(),
+            self.publisher.clone(),
+            tags_file,
+        )
     }
     
     #[cfg(test)]
     /// Creates a RunCommandHandler in test mode
     pub fn create_run_command_test_mode(&self, tags_file: String) -> RunCommandHandler {
-        RunCommandHandler::new_test_mode(self.tag_service.clone(), tags_file)
+        RunCommandHandler::new_test_mode(
+            self.tag_service.clone(),
+            self.subscriber.clone(),
+            self.publisher.clone(),
+            tags_file,
+        )
     }
     
     /// Creates an UpdateCommandHandler
     pub fn create_update_command(&self, path: String, value: String) -> UpdateCommandHandler {
-        UpdateCommandHandler::new(self.tag_service.clone(), path, value)
+        UpdateCommandHandler::new(self.publisher.clone(), self.subscriber.clone(), path, value)
+    }
+    
+    /// Creates a ValidateCommandHandler
+    pub fn create_validate_command(&self, tags_file: String, schema: bool) -> ValidateCommandHandler {
+        ValidateCommandHandler::new(tags_file, schema)
+    }
+    
+    /// Creates a MirrorCommandHandler mirroring the topics of the given mapping
+    pub fn create_mirror_command(&self, mapping: TopicMapping) -> Result<MirrorCommandHandler, UnsError> {
+        let repository = self
+            .repository
+            .clone()
+            .ok_or_else(|| UnsError::Other("No replica repository configured for the mirror command".to_string()))?;
+        
+        let mut mirror = TagMirror::new(repository, self.subscriber.clone()).with_topic_mapping(mapping);
+        if let Some(client) = &self.client {
+            mirror = mirror.with_snapshot_requests(client.clone());
+        }
+        Ok(MirrorCommandHandler::new(self.tag_service.clone(), mirror))
+    }
+    
+    /// Creates a RetainedPurger looking for stale topics under the given mapping
+    pub fn create_purger(&self, mapping: TopicMapping) -> Result<RetainedPurger, UnsError> {
+        let client = self
+            .client
+            .clone()
+            .ok_or_else(|| UnsError::Other("No MQTT client configured for clearing retained topics".to_string()))?;
+        
+        Ok(RetainedPurger::new(self.subscriber.clone(), client).with_topic_mapping(mapping))
+    }
+    
+    /// Creates a PurgeCommandHandler comparing the broker with the tags in the given file
+    pub fn create_purge_command(&self, tags_file: String, purger: RetainedPurger) -> Result<PurgeCommandHandler, UnsError> {
+        let repository = self
+            .repository
+            .clone()
+            .ok_or_else(|| UnsError::Other("No tag repository configured for the purge command".to_string()))?;
+        
+        Ok(PurgeCommandHandler::new(repository, purger, tags_file))
     }
 }
 
@@ -146,10 +609,14 @@ impl CommandFactory {
 mod tests {
     use super::*;
     use crate::domain::Tag;
+    use crate::infrastructure::mqtt::subscriber::MockMqttSubscriber;
+    use crate::infrastructure::mqtt::{ConnectionState, IncomingMessage, MessageStream};
+    use crate::infrastructure::mqtt::publisher::MockMqttPublisher;
     use async_trait::async_trait;
     use mockall::predicate::*;
     use mockall::*;
     use std::collections::HashMap;
+    use tokio::sync::watch;
     
     // Mock the TagService
     mock! {
@@ -161,9 +628,26 @@ mod tests {
             async fn update_and_publish_tag(&self, path: &str, value: String) -> Result<(), UnsError>;
             async fn get_all_tags(&self) -> Result<HashMap<String, Tag>, UnsError>;
             async fn get_tag(&self, path: &str) -> Result<Option<Tag>, UnsError>;
+            async fn publish_database(&self) -> Result<(), UnsError>;
+            async fn republish_tags(&self) -> Result<(), UnsError>;
+            async fn mark_tags_stale(&self) -> Result<(), UnsError>;
+            fn connection_state(&self) -> tokio::sync::watch::Receiver<crate::infrastructure::mqtt::ConnectionState>;
         }
     }
     
+    // Creates a mock subscriber that accepts the write request subscription
+    fn mock_listener_subscriber() -> MockMqttSubscriber {
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        
+        mock_subscriber
+            .expect_subscribe_stream()
+            .with(eq("tags/set/#"))
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(MessageStream::from_messages(Vec::new())) }));
+        
+        mock_subscriber
+    }
+    
     #[tokio::test]
     async fn test_run_command() {
         // Create mock tag service
@@ -176,9 +660,18 @@ mod tests {
             .times(1)
             .returning(|_| Ok(()));
         
+        // The status announcer watches the connection, which is not up yet
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_connection_state()
+            .times(1)
+            .returning(|| watch::channel(ConnectionState::Connecting).1);
+        
         // Create the command handler in test mode
         let handler = RunCommandHandler::new_test_mode(
             Arc::new(mock_service),
+            Arc::new(mock_listener_subscriber()),
+            Arc::new(mock_publisher),
             "test.json".to_string(),
         );
         
@@ -189,24 +682,50 @@ mod tests {
         assert!(result.is_ok());
     }
     
-    #[tokio::test]
-    async fn test_update_command() {
-        // Create mock tag service
-        let mut mock_service = MockTagService::new();
+    // Creates a mock subscriber/publisher pair where the running instance replies with `response`
+    fn mock_replying_instance(response: WriteResponse) -> (MockMqttSubscriber, MockMqttPublisher) {
+        let (reply_sender, replies) = MessageStream::channel(10);
         
-        // Set up expectations
-        mock_service
-            .expect_update_and_publish_tag()
-            .with(
-                eq("US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE"),
-                eq("50.2".to_string())
-            )
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .withf(|filter| filter.starts_with("tags/reply/"))
+            .times(1)
+            .return_once(move |_| Box::pin(async move { Ok(replies) }));
+        
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_publish_write_request()
+            .withf(|request| {
+                request.path == "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE" && request.value == "50.2"
+            })
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(move |request| {
+                let reply_to = request.reply_to.clone().unwrap();
+                
+                // A reply to some other request comes first and must be ignored
+                let mut other = IncomingMessage::new(&reply_to.topic, br#"{"status":"ok"}"#);
+                other.properties.correlation_data = Some(b"other".to_vec());
+                reply_sender.try_send(other).unwrap();
+                
+                let mut reply = IncomingMessage::new(&reply_to.topic, &response.to_payload().unwrap());
+                reply.properties.correlation_data = reply_to.correlation_data;
+                reply_sender.try_send(reply).unwrap();
+                
+                Box::pin(async { Ok(()) })
+            });
+        
+        (mock_subscriber, mock_publisher)
+    }
+    
+    #[tokio::test]
+    async fn test_update_command() {
+        let (mock_subscriber, mock_publisher) = mock_replying_instance(WriteResponse::from_result(&Ok(())));
         
         // Create the command handler
         let handler = UpdateCommandHandler::new(
-            Arc::new(mock_service),
+            Arc::new(mock_publisher),
+            Arc::new(mock_subscriber),
             "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE".to_string(),
             "50.2".to_string(),
         );
@@ -218,13 +737,133 @@ mod tests {
         assert!(result.is_ok());
     }
     
+    #[tokio::test]
+    async fn test_update_command_reports_outcome() {
+        let response = WriteResponse::from_result(&Err(UnsError::PermissionDenied("read-only".to_string())));
+        let (mock_subscriber, mock_publisher) = mock_replying_instance(response);
+        
+        let handler = UpdateCommandHandler::new(
+            Arc::new(mock_publisher),
+            Arc::new(mock_subscriber),
+            "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE".to_string(),
+            "50.2".to_string(),
+        );
+        
+        let result = handler.execute().await;
+        assert!(matches!(result, Err(UnsError::PermissionDenied(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_update_command_times_out() {
+        // Nobody replies
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(MessageStream::from_messages(Vec::new())) }));
+        
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_publish_write_request()
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(()) }));
+        
+        let handler = UpdateCommandHandler::new(
+            Arc::new(mock_publisher),
+            Arc::new(mock_subscriber),
+            "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE".to_string(),
+            "50.2".to_string(),
+        )
+        .with_reply_timeout(Duration::from_millis(50));
+        
+        let result = handler.execute().await;
+        assert!(matches!(result, Err(UnsError::Mqtt(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_validate_command() {
+        // Create a tag file whose key does not match the embedded path
+        let mut temp_file = tempfile::NamedTempFile::new().unwrap();
+        let test_data = r#"{
+            "tags": {
+                "A/B": { "path": "A/C", "name": "n", "description": "d", "value": "1" }
+            }
+        }"#;
+        use std::io::Write;
+        write!(temp_file, "{}", test_data).unwrap();
+        
+        // Create the command handler
+        let handler = ValidateCommandHandler::new(
+            temp_file.path().to_str().unwrap().to_string(),
+            false,
+        );
+        
+        // Call the method
+        let result = handler.execute().await;
+        
+        // Verify the result
+        assert!(matches!(result, Err(UnsError::Validation(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_mirror_command_once() {
+        // The broker holds the retained database and a newer value of one tag
+        let database = br#"{"tags":{
+            "US/TX/PUMP1":{"path":"US/TX/PUMP1","name":"n","description":"d","value":"1"},
+            "US/CA/PUMP2":{"path":"US/CA/PUMP2","name":"n","description":"d","value":"2"}
+        }}"#;
+        let messages = vec![
+            IncomingMessage::new("tags/database", database),
+            IncomingMessage::new("tags/US.TX.PUMP1", br#"{"path":"US/TX/PUMP1","name":"n","description":"d","value":"3"}"#),
+        ];
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .with(eq("tags/#"))
+            .times(1)
+            .return_once(move |_| Box::pin(async move { Ok(MessageStream::from_messages(messages)) }));
+        
+        // The replica is read back through the regular tag service
+        let repository = Arc::new(JsonTagRepository::new());
+        let tag_service = Arc::new(crate::application::TagServiceImpl::new(
+            repository.clone(),
+            Arc::new(MockMqttPublisher::new()),
+        ));
+        let factory = CommandFactory::new(tag_service.clone(), Arc::new(mock_subscriber), Arc::new(MockMqttPublisher::new()))
+            .with_repository(repository);
+        
+        let output = tempfile::NamedTempFile::new().unwrap();
+        let paths_output = tempfile::NamedTempFile::new().unwrap();
+        let handler = factory
+            .create_mirror_command(TopicMapping::default())
+            .unwrap()
+            .with_output(Some(output.path().to_str().unwrap().to_string()))
+            .with_paths_output(Some(paths_output.path().to_str().unwrap().to_string()))
+            .with_prefix(Some("US/TX".to_string()))
+            .with_settle_time(Some(Duration::from_millis(100)));
+        
+        handler.execute().await.unwrap();
+        
+        assert_eq!(tag_service.get_tag("US/TX/PUMP1").await.unwrap().unwrap().value, "3");
+        assert_eq!(tag_service.get_all_tags().await.unwrap().len(), 2);
+        
+        let written = JsonTagRepository::new().load_tags(output.path().to_str().unwrap()).await.unwrap();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written["US/TX/PUMP1"].value, "3");
+        assert_eq!(std::fs::read_to_string(paths_output.path()).unwrap(), "US/TX/PUMP1\n");
+    }
+    
     #[test]
     fn test_command_factory() {
         // Create mock tag service
         let mock_service = MockTagService::new();
         
         // Create the factory
-        let factory = CommandFactory::new(Arc::new(mock_service));
+        let factory = CommandFactory::new(
+            Arc::new(mock_service),
+            Arc::new(MockMqttSubscriber::new()),
+            Arc::new(MockMqttPublisher::new()),
+        );
         
         // Create a run command
         let run_command = factory.create_run_command("test.json".to_string());
@@ -239,5 +878,8 @@ mod tests {
         assert_eq!(run_command.tags_file, "test.json");
         assert_eq!(update_command.path, "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE");
         assert_eq!(update_command.value, "50.2");
+        
+        // Mirroring needs a repository to mirror into
+        assert!(factory.create_mirror_command(TopicMapping::default()).is_err());
     }
 }
@@ -0,0 +1,144 @@
+use log::error;
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Instant};
+
+use crate::domain::{TagService, TopicMapping};
+use crate::infrastructure::mqtt::MqttSubscriber;
+use crate::infrastructure::UnsError;
+
+/// Default time between full database snapshots in delta mode
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Publishes full database snapshots periodically and when a consumer asks for one
+///
+/// In delta mode the database topic is not updated with every change, so
+/// consumers that join late or miss a delta request a snapshot by publishing
+/// anything on `<prefix>/database/get`.
+pub struct DatabaseSnapshotter {
+    tag_service: Arc<dyn TagService>,
+    subscriber: Arc<dyn MqttSubscriber>,
+    mapping: TopicMapping,
+    interval: Option<Duration>,
+}
+
+impl DatabaseSnapshotter {
+    /// Creates a snapshotter publishing every `DEFAULT_SNAPSHOT_INTERVAL`
+    pub fn new(tag_service: Arc<dyn TagService>, subscriber: Arc<dyn MqttSubscriber>) -> Self {
+        Self {
+            tag_service,
+            subscriber,
+            mapping: TopicMapping::default(),
+            interval: Some(DEFAULT_SNAPSHOT_INTERVAL),
+        }
+    }
+
+    /// Listens for requests on the topics of the given mapping
+    pub fn with_topic_mapping(mut self, mapping: TopicMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    /// Sets the time between periodic snapshots (`None` = only on request)
+    pub fn with_interval(mut self, interval: Option<Duration>) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Subscribes to snapshot requests and publishes snapshots in a background task
+    pub async fn start(&self) -> Result<JoinHandle<()>, UnsError> {
+        let mut requests = self.subscriber.subscribe_stream(&self.mapping.database_request_topic()).await?;
+
+        let tag_service = self.tag_service.clone();
+        let mut ticker = self.interval.map(|period| interval_at(Instant::now() + period, period));
+        Ok(tokio::spawn(async move {
+            loop {
+                let periodic = async {
+                    match ticker.as_mut() {
+                        Some(ticker) => ticker.tick().await,
+                        None => std::future::pending().await,
+                    }
+                };
+
+                tokio::select! {
+                    _ = periodic => {}
+                    request = requests.recv() => {
+                        if request.is_none() {
+                            break;
+                        }
+                    }
+                }
+
+                if let Err(e) = tag_service.publish_database().await {
+                    error!("Error publishing database snapshot: {}", e);
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::tag_service::MockTagService;
+    use crate::infrastructure::mqtt::subscriber::MockMqttSubscriber;
+    use crate::infrastructure::mqtt::{IncomingMessage, MessageStream};
+    use mockall::predicate::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_publishes_on_request() {
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .with(eq("tags/database/get"))
+            .times(1)
+            .returning(|_| {
+                let requests = vec![IncomingMessage::new("tags/database/get", b""); 2];
+                Box::pin(async move { Ok(MessageStream::from_messages(requests)) })
+            });
+
+        // One snapshot per request; the periodic one is far off
+        let mut mock_service = MockTagService::new();
+        mock_service
+            .expect_publish_database()
+            .times(2)
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let task = DatabaseSnapshotter::new(Arc::new(mock_service), Arc::new(mock_subscriber))
+            .start()
+            .await
+            .unwrap();
+
+        // The stream ends after the requests, which ends the task
+        tokio::time::timeout(Duration::from_secs(1), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_publishes_periodically() {
+        let (_requests, stream) = MessageStream::channel(1);
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .times(1)
+            .return_once(move |_| Box::pin(async move { Ok(stream) }));
+
+        let snapshots = Arc::new(AtomicUsize::new(0));
+        let counter = snapshots.clone();
+        let mut mock_service = MockTagService::new();
+        mock_service.expect_publish_database().returning(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        });
+
+        let task = DatabaseSnapshotter::new(Arc::new(mock_service), Arc::new(mock_subscriber))
+            .with_interval(Some(Duration::from_millis(20)))
+            .start()
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        task.abort();
+        assert!(snapshots.load(Ordering::SeqCst) >= 2);
+    }
+}
@@ -1,7 +1,19 @@
 // Application module exports
 pub mod tag_service_impl;
 pub mod commands;
+pub mod write_request_listener;
+pub mod reconnect_republisher;
+pub mod status_announcer;
+pub mod database_snapshotter;
+pub mod tag_mirror;
+pub mod retained_purger;
 
 // Re-export key types
 pub use tag_service_impl::TagServiceImpl;
 pub use commands::CommandHandler;
+pub use write_request_listener::WriteRequestListener;
+pub use reconnect_republisher::ReconnectRepublisher;
+pub use status_announcer::StatusAnnouncer;
+pub use database_snapshotter::DatabaseSnapshotter;
+pub use tag_mirror::TagMirror;
+pub use retained_purger::RetainedPurger;
@@ -0,0 +1,91 @@
+use log::{error, info};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use crate::domain::TagService;
+use crate::infrastructure::mqtt::ConnectionState;
+
+/// Publishes the retained tag state again whenever the broker connection is restored
+///
+/// A broker restarted without persistence comes back with no retained
+/// messages, so subscribers joining later would see no tags until the next
+/// update.
+pub struct ReconnectRepublisher {
+    tag_service: Arc<dyn TagService>,
+}
+
+impl ReconnectRepublisher {
+    /// Creates a new ReconnectRepublisher
+    pub fn new(tag_service: Arc<dyn TagService>) -> Self {
+        Self { tag_service }
+    }
+
+    /// Watches the connection state and republishes after each reconnect
+    pub fn start(&self) -> JoinHandle<()> {
+        let tag_service = self.tag_service.clone();
+        let mut state = tag_service.connection_state();
+
+        tokio::spawn(async move {
+            let mut interrupted = false;
+
+            while state.changed().await.is_ok() {
+                let current = state.borrow_and_update().clone();
+
+                match current {
+                    ConnectionState::Reconnecting { .. } => interrupted = true,
+                    ConnectionState::Connected if interrupted => {
+                        interrupted = false;
+                        info!("Republishing tags after reconnect...");
+                        if let Err(e) = tag_service.republish_tags().await {
+                            error!("Error republishing tags: {}", e);
+                        }
+                    }
+                    ConnectionState::Failed(_) => break,
+                    _ => {}
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::tag_service::MockTagService;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn test_republishes_only_after_reconnect() {
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Connecting);
+
+        // Create mock tag service expecting a single republish
+        let mut mock_service = MockTagService::new();
+        mock_service
+            .expect_connection_state()
+            .times(1)
+            .return_once(move || state_rx);
+        mock_service
+            .expect_republish_tags()
+            .times(1)
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let task = ReconnectRepublisher::new(Arc::new(mock_service)).start();
+
+        // The first connection is not a reconnect
+        state_tx.send_replace(ConnectionState::Connected);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        state_tx.send_replace(ConnectionState::Reconnecting {
+            attempt: 1,
+            delay: Duration::from_millis(500),
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        state_tx.send_replace(ConnectionState::Connected);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Giving up ends the task
+        state_tx.send_replace(ConnectionState::Failed("refused".to_string()));
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::domain::{PublishPolicy, Tag, TopicMapping};
+use crate::infrastructure::mqtt::{MqttClient, MqttSubscriber};
+use crate::infrastructure::UnsError;
+
+/// Default time without new retained messages after which all are considered received
+pub const DEFAULT_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// A retained tag topic whose tag is no longer in the catalog
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StaleTopic {
+    /// Topic holding the retained message
+    pub topic: String,
+
+    /// Tag path the topic belongs to
+    pub path: String,
+}
+
+/// Finds and clears retained tag topics left behind by renamed or deleted tags
+///
+/// The broker sends every retained message below the namespace right after
+/// subscribing; tag topics whose path is not in the catalog are stale. They are
+/// cleared with an empty retained message, which makes the broker drop them.
+pub struct RetainedPurger {
+    subscriber: Arc<dyn MqttSubscriber>,
+    client: Arc<dyn MqttClient>,
+    mapping: TopicMapping,
+    settle_time: Duration,
+}
+
+impl RetainedPurger {
+    /// Creates a purger for the default topic mapping
+    pub fn new(subscriber: Arc<dyn MqttSubscriber>, client: Arc<dyn MqttClient>) -> Self {
+        Self {
+            subscriber,
+            client,
+            mapping: TopicMapping::default(),
+            settle_time: DEFAULT_SETTLE_TIME,
+        }
+    }
+
+    /// Looks for stale topics under the given mapping
+    pub fn with_topic_mapping(mut self, mapping: TopicMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    /// Sets how long to wait for further retained messages before comparing
+    pub fn with_settle_time(mut self, settle_time: Duration) -> Self {
+        self.settle_time = settle_time;
+        self
+    }
+
+    /// Collects the retained tag topics whose tag is not in the catalog, sorted by topic
+    pub async fn find_stale(&self, catalog: &HashMap<String, Tag>) -> Result<Vec<StaleTopic>, UnsError> {
+        let mut messages = self.subscriber.subscribe_stream(&self.mapping.tag_filter()).await?;
+
+        let mut stale = Vec::new();
+        while let Ok(Some(message)) = tokio::time::timeout(self.settle_time, messages.recv()).await {
+            // Live publishes are not left behind, and an empty message is already cleared
+            if !message.retain || message.payload.is_empty() {
+                continue;
+            }
+
+            if let Some(path) = self.mapping.tag_path(&message.topic) {
+                if !catalog.contains_key(&path) {
+                    stale.push(StaleTopic { topic: message.topic, path });
+                }
+            }
+        }
+
+        stale.sort_by(|a, b| a.topic.cmp(&b.topic));
+        stale.dedup();
+        Ok(stale)
+    }
+
+    /// Clears the given topics with empty retained messages
+    pub async fn purge(&self, stale: &[StaleTopic]) -> Result<(), UnsError> {
+        for topic in stale {
+            self.client.publish(&topic.topic, Vec::new(), PublishPolicy::default()).await?;
+            println!("Cleared {}", topic.topic);
+        }
+        Ok(())
+    }
+
+    /// Prints the stale topics found
+    pub fn report(stale: &[StaleTopic]) {
+        for topic in stale {
+            println!("Stale retained topic: {} ({})", topic.topic, topic.path);
+        }
+        println!("Found {} stale retained topic(s)", stale.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::mqtt::client::MockMqttClient;
+    use crate::infrastructure::mqtt::subscriber::MockMqttSubscriber;
+    use crate::infrastructure::mqtt::{IncomingMessage, MessageStream};
+    use mockall::predicate::*;
+
+    fn tag(path: &str) -> Tag {
+        Tag::new(path.to_string(), "n".to_string(), "d".to_string(), "1".to_string())
+    }
+
+    fn retained(topic: &str, payload: &[u8]) -> IncomingMessage {
+        let mut message = IncomingMessage::new(topic, payload);
+        message.retain = true;
+        message
+    }
+
+    #[tokio::test]
+    async fn test_finds_and_clears_stale_tag_topics() {
+        let messages = vec![
+            retained("tags/US.TX.PUMP1", b"{}"),
+            retained("tags/US.TX.OLD_PUMP", b"{}"),
+            retained("tags/US.CA.RENAMED", b"{}"),
+            // Already cleared, not a tag, or published live
+            retained("tags/US.TX.CLEARED", b""),
+            retained("tags/database", b"{}"),
+            IncomingMessage::new("tags/US.TX.LIVE", b"{}"),
+        ];
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .with(eq("tags/#"))
+            .times(1)
+            .return_once(move |_| Box::pin(async move { Ok(MessageStream::from_messages(messages)) }));
+
+        let mut mock_client = MockMqttClient::new();
+        let mut sequence = mockall::Sequence::new();
+        for topic in ["tags/US.CA.RENAMED", "tags/US.TX.OLD_PUMP"] {
+            mock_client
+                .expect_publish()
+                .withf(move |t, payload, policy| t == topic && payload.is_empty() && policy.retain)
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        }
+
+        let purger = RetainedPurger::new(Arc::new(mock_subscriber), Arc::new(mock_client))
+            .with_settle_time(Duration::from_millis(50));
+        let catalog = HashMap::from([("US/TX/PUMP1".to_string(), tag("US/TX/PUMP1"))]);
+
+        let stale = purger.find_stale(&catalog).await.unwrap();
+        assert_eq!(
+            stale,
+            vec![
+                StaleTopic { topic: "tags/US.CA.RENAMED".to_string(), path: "US/CA/RENAMED".to_string() },
+                StaleTopic { topic: "tags/US.TX.OLD_PUMP".to_string(), path: "US/TX/OLD_PUMP".to_string() },
+            ]
+        );
+        purger.purge(&stale).await.unwrap();
+    }
+}
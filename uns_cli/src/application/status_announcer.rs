@@ -0,0 +1,151 @@
+use chrono::{DateTime, Utc};
+use log::error;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use crate::domain::{InstanceStatus, TagService};
+use crate::infrastructure::mqtt::{ConnectionState, MqttPublisher};
+use crate::infrastructure::UnsError;
+
+/// Publishes the birth message of an instance on every connect and its death message on shutdown
+///
+/// The broker only publishes the Last Will after an unexpected disconnect,
+/// and the will overwrites the retained status, so `online` must be sent
+/// again after each reconnect.
+pub struct StatusAnnouncer {
+    tag_service: Arc<dyn TagService>,
+    publisher: Arc<dyn MqttPublisher>,
+    instance_id: String,
+    started_at: DateTime<Utc>,
+}
+
+impl StatusAnnouncer {
+    /// Creates a new StatusAnnouncer
+    pub fn new(tag_service: Arc<dyn TagService>, publisher: Arc<dyn MqttPublisher>, instance_id: String) -> Self {
+        Self {
+            tag_service,
+            publisher,
+            instance_id,
+            started_at: Utc::now(),
+        }
+    }
+
+    /// Announces the instance now and after every reconnect
+    pub fn start(&self) -> JoinHandle<()> {
+        let tag_service = self.tag_service.clone();
+        let publisher = self.publisher.clone();
+        let instance_id = self.instance_id.clone();
+        let started_at = self.started_at;
+        let mut state = publisher.connection_state();
+
+        tokio::spawn(async move {
+            loop {
+                if state.borrow_and_update().is_connected() {
+                    let result = Self::announce_online(tag_service.as_ref(), publisher.as_ref(), &instance_id, started_at).await;
+                    if let Err(e) = result {
+                        error!("Error publishing instance status: {}", e);
+                    }
+                }
+
+                if state.changed().await.is_err() || matches!(*state.borrow(), ConnectionState::Failed(_)) {
+                    break;
+                }
+            }
+        })
+    }
+
+    /// Marks the instance offline after a graceful shutdown
+    pub async fn announce_offline(&self) -> Result<(), UnsError> {
+        self.publisher.publish_status(&InstanceStatus::offline(&self.instance_id)).await
+    }
+
+    async fn announce_online(
+        tag_service: &dyn TagService,
+        publisher: &dyn MqttPublisher,
+        instance_id: &str,
+        started_at: DateTime<Utc>,
+    ) -> Result<(), UnsError> {
+        let tag_count = tag_service.get_all_tags().await?.len();
+        publisher.publish_status(&InstanceStatus::online(instance_id, tag_count, started_at)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::tag_service::MockTagService;
+    use crate::domain::{InstanceState, Tag};
+    use crate::infrastructure::mqtt::publisher::MockMqttPublisher;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn test_announces_on_every_connect() {
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Connected);
+
+        // Create mock tag service holding one tag
+        let mut mock_service = MockTagService::new();
+        mock_service
+            .expect_get_all_tags()
+            .times(2)
+            .returning(|| {
+                Box::pin(async {
+                    let mut tags = HashMap::new();
+                    tags.insert(
+                        "A/B".to_string(),
+                        Tag::new("A/B".to_string(), "n".to_string(), "d".to_string(), "1".to_string()),
+                    );
+                    Ok(tags)
+                })
+            });
+
+        // Online is published for the initial connection and after the reconnect
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_connection_state()
+            .times(1)
+            .return_once(move || state_rx);
+        mock_publisher
+            .expect_publish_status()
+            .withf(|status| {
+                status.instance_id == "gateway-1" && status.state == InstanceState::Online && status.tag_count == Some(1)
+            })
+            .times(2)
+            .returning(|_| Box::pin(async { Ok(()) }));
+
+        let announcer = StatusAnnouncer::new(Arc::new(mock_service), Arc::new(mock_publisher), "gateway-1".to_string());
+        let task = announcer.start();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        state_tx.send_replace(ConnectionState::Reconnecting {
+            attempt: 1,
+            delay: Duration::from_millis(500),
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        state_tx.send_replace(ConnectionState::Connected);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // The task ends with the connection
+        drop(state_tx);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_announce_offline() {
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_publish_status()
+            .with(mockall::predicate::eq(InstanceStatus::offline("gateway-1")))
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(()) }));
+
+        let announcer = StatusAnnouncer::new(
+            Arc::new(MockTagService::new()),
+            Arc::new(mock_publisher),
+            "gateway-1".to_string(),
+        );
+
+        assert!(announcer.announce_offline().await.is_ok());
+    }
+}
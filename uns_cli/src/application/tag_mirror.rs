@@ -0,0 +1,379 @@
+use log::{debug, error, warn};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::domain::{PayloadCodec, PublishPolicy, Tag, TagDelta, TagRepository, TopicMapping};
+use crate::infrastructure::mqtt::{IncomingMessage, MqttClient, MqttSubscriber};
+use crate::infrastructure::UnsError;
+
+/// Full database or one part of it, as received from the broker
+#[derive(Deserialize, Debug)]
+struct Snapshot {
+    tags: HashMap<String, Tag>,
+
+    #[serde(default)]
+    seq: Option<u64>,
+}
+
+/// Index of a database split into parts; only the part topics matter here
+#[derive(Deserialize)]
+struct Index {
+    parts: Vec<IndexEntry>,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    topic: String,
+}
+
+/// What a received message did to the replica
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MirrorOutcome {
+    /// The message was not for the replica, or carried nothing new
+    Unchanged,
+
+    /// Tags were inserted or updated
+    Changed,
+
+    /// A delta was missed; known tags were updated but a snapshot is needed
+    Gap,
+}
+
+/// Delta numbering of the replica
+#[derive(Debug, Default)]
+pub struct ReplicaState {
+    /// Sequence number of the last delta included in the replica
+    seq: Option<u64>,
+
+    /// Whether a snapshot was requested and has not arrived yet
+    awaiting_snapshot: bool,
+
+    /// Part topics listed by the latest database index
+    listed_parts: HashSet<String>,
+
+    /// Parts received before an index listed them
+    unlisted_parts: HashMap<String, Snapshot>,
+}
+
+/// Mirrors the tags of a remote instance into a local repository
+///
+/// Subscribes to everything below the namespace: tag topics insert or replace
+/// single tags, the database and its parts insert every tag they hold, and
+/// deltas update the values of known tags. A part is only applied once the
+/// latest index lists it, so parts left behind by an older layout are ignored.
+/// When a delta is missed the mirror asks for a snapshot on
+/// `<prefix>/database/get`, if it has a client to ask with.
+pub struct TagMirror {
+    repository: Arc<dyn TagRepository>,
+    subscriber: Arc<dyn MqttSubscriber>,
+    requester: Option<Arc<dyn MqttClient>>,
+    mapping: TopicMapping,
+    changes: watch::Sender<u64>,
+}
+
+impl TagMirror {
+    /// Creates a mirror writing into the given repository
+    pub fn new(repository: Arc<dyn TagRepository>, subscriber: Arc<dyn MqttSubscriber>) -> Self {
+        Self {
+            repository,
+            subscriber,
+            requester: None,
+            mapping: TopicMapping::default(),
+            changes: watch::channel(0).0,
+        }
+    }
+
+    /// Mirrors the topics of the given mapping
+    pub fn with_topic_mapping(mut self, mapping: TopicMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    /// Requests a database snapshot over this client when a delta is missed
+    pub fn with_snapshot_requests(mut self, client: Arc<dyn MqttClient>) -> Self {
+        self.requester = Some(client);
+        self
+    }
+
+    /// Returns a receiver counting the messages that changed the replica
+    pub fn changes(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// Subscribes to the namespace and applies messages in a background task
+    pub async fn start(&self) -> Result<JoinHandle<()>, UnsError> {
+        let mut messages = self.subscriber.subscribe_stream(&self.mapping.tag_filter()).await?;
+
+        let repository = self.repository.clone();
+        let requester = self.requester.clone();
+        let mapping = self.mapping.clone();
+        let changes = self.changes.clone();
+        Ok(tokio::spawn(async move {
+            let mut state = ReplicaState::default();
+            while let Some(message) = messages.recv().await {
+                let outcome = match Self::apply(repository.as_ref(), &mapping, &mut state, &message).await {
+                    Ok(outcome) => outcome,
+                    Err(e) => {
+                        warn!("Ignoring message on {}: {}", message.topic, e);
+                        continue;
+                    }
+                };
+
+                if outcome != MirrorOutcome::Unchanged {
+                    changes.send_modify(|count| *count += 1);
+                }
+
+                if outcome == MirrorOutcome::Gap && !state.awaiting_snapshot {
+                    if let Some(client) = &requester {
+                        warn!("Missed a database delta, requesting a snapshot");
+                        let topic = mapping.database_request_topic();
+                        match client.publish(&topic, Vec::new(), PublishPolicy::not_retained()).await {
+                            Ok(()) => state.awaiting_snapshot = true,
+                            Err(e) => error!("Failed to request a snapshot: {}", e),
+                        }
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Applies a single received message to the replica
+    pub async fn apply(
+        repository: &dyn TagRepository,
+        mapping: &TopicMapping,
+        state: &mut ReplicaState,
+        message: &IncomingMessage,
+    ) -> Result<MirrorOutcome, UnsError> {
+        // An empty retained message clears a topic; there is nothing to mirror
+        if message.payload.is_empty() {
+            state.unlisted_parts.remove(&message.topic);
+            return Ok(MirrorOutcome::Unchanged);
+        }
+
+        let content_type = message.properties.content_type.as_deref();
+        let codec = PayloadCodec::from_content_type(content_type).ok_or_else(|| {
+            UnsError::Validation(format!("Unsupported content type: {}", content_type.unwrap_or_default()))
+        })?;
+
+        let topic = message.topic.as_str();
+        if let Some(path) = mapping.tag_path(topic) {
+            let tag: Tag = codec.decode(&message.payload)?;
+            if tag.path != path {
+                return Err(UnsError::Validation(format!("Tag {} published on the topic of {}", tag.path, path)));
+            }
+            debug!("Tag update: {} = {}", tag.path, tag.value);
+            repository.put_tag(tag).await?;
+            return Ok(MirrorOutcome::Changed);
+        }
+
+        let part_prefix = format!("{}/part/", mapping.database_topic());
+        if topic == mapping.database_topic() || topic.starts_with(&part_prefix) {
+            let snapshot: Snapshot = codec.decode(&message.payload)?;
+            debug!("Received {} with {} tags", topic, snapshot.tags.len());
+
+            // Parts are published before the index listing them, so a new part waits for it
+            if topic != mapping.database_topic() && !state.listed_parts.contains(topic) {
+                state.unlisted_parts.insert(topic.to_string(), snapshot);
+                return Ok(MirrorOutcome::Unchanged);
+            }
+            Self::apply_snapshot(repository, state, snapshot).await?;
+            return Ok(MirrorOutcome::Changed);
+        }
+
+        if topic == mapping.database_index_topic() {
+            let index: Index = codec.decode(&message.payload)?;
+            state.listed_parts = index.parts.into_iter().map(|part| part.topic).collect();
+
+            // Parts the index does not list belong to an older layout
+            let mut outcome = MirrorOutcome::Unchanged;
+            for (topic, snapshot) in std::mem::take(&mut state.unlisted_parts) {
+                if state.listed_parts.contains(&topic) {
+                    Self::apply_snapshot(repository, state, snapshot).await?;
+                    outcome = MirrorOutcome::Changed;
+                }
+            }
+            return Ok(outcome);
+        }
+
+        if topic == mapping.delta_topic() {
+            let delta: TagDelta = codec.decode(&message.payload)?;
+            if state.seq.is_some_and(|seq| delta.seq <= seq) {
+                // Already included in the snapshot
+                return Ok(MirrorOutcome::Unchanged);
+            }
+
+            // Newer values are worth having even across a gap; the snapshot settles the rest
+            for (path, value) in delta.changes {
+                if let Some(mut tag) = repository.get_tag(&path).await? {
+                    debug!("Tag update: {} = {}", path, value);
+                    tag.value = value;
+                    repository.put_tag(tag).await?;
+                }
+            }
+
+            if state.seq.is_some_and(|seq| delta.seq == seq + 1) {
+                state.seq = Some(delta.seq);
+                return Ok(MirrorOutcome::Changed);
+            }
+            return Ok(MirrorOutcome::Gap);
+        }
+
+        // Snapshot requests, write requests and replies hold no tag state
+        Ok(MirrorOutcome::Unchanged)
+    }
+
+    /// Inserts every tag of a database or part, taking over its delta number
+    async fn apply_snapshot(
+        repository: &dyn TagRepository,
+        state: &mut ReplicaState,
+        snapshot: Snapshot,
+    ) -> Result<(), UnsError> {
+        for tag in snapshot.tags.into_values() {
+            repository.put_tag(tag).await?;
+        }
+        if snapshot.seq.is_some() {
+            state.seq = snapshot.seq;
+            state.awaiting_snapshot = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::mqtt::client::MockMqttClient;
+    use crate::infrastructure::mqtt::subscriber::MockMqttSubscriber;
+    use crate::infrastructure::mqtt::{MessageProperties, MessageStream};
+    use crate::infrastructure::repositories::JsonTagRepository;
+    use mockall::predicate::*;
+
+    fn tag(path: &str, value: &str) -> Tag {
+        Tag::new(path.to_string(), "n".to_string(), "d".to_string(), value.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_builds_replica_from_database_tags_and_deltas() {
+        let repository = JsonTagRepository::new();
+        let mapping = TopicMapping::default();
+        let mut state = ReplicaState::default();
+
+        let database = br#"{"tags":{"US/TX/PUMP1":{"path":"US/TX/PUMP1","name":"n","description":"d","value":"1"}},"seq":4}"#;
+        let messages = vec![
+            IncomingMessage::new("tags/database", database),
+            IncomingMessage::new("tags/database/delta", br#"{"seq":4,"timestamp":"2024-01-01T00:00:00Z","changes":{"US/TX/PUMP1":"0"}}"#),
+            IncomingMessage::new("tags/database/delta", br#"{"seq":5,"timestamp":"2024-01-01T00:00:00Z","changes":{"US/TX/PUMP1":"2"}}"#),
+            IncomingMessage::new("tags/database/index", br#"{"layout":"single","parts":[]}"#),
+            IncomingMessage::new("tags/set/US.TX.PUMP1", br#"{"value":"9"}"#),
+        ];
+        let mut outcomes = Vec::new();
+        for message in &messages {
+            outcomes.push(TagMirror::apply(&repository, &mapping, &mut state, message).await.unwrap());
+        }
+        assert_eq!(
+            outcomes,
+            vec![
+                MirrorOutcome::Changed,
+                MirrorOutcome::Unchanged,
+                MirrorOutcome::Changed,
+                MirrorOutcome::Unchanged,
+                MirrorOutcome::Unchanged,
+            ]
+        );
+        assert_eq!(repository.get_tag("US/TX/PUMP1").await.unwrap().unwrap().value, "2");
+
+        // Tag topics carry the whole tag, in whatever codec the publisher chose
+        let mut cbor = IncomingMessage::new("tags/US.CA.PUMP2", &PayloadCodec::Cbor.encode(&tag("US/CA/PUMP2", "7")).unwrap());
+        cbor.properties = MessageProperties {
+            content_type: Some(PayloadCodec::Cbor.content_type().to_string()),
+            ..MessageProperties::default()
+        };
+        TagMirror::apply(&repository, &mapping, &mut state, &cbor).await.unwrap();
+        assert_eq!(repository.get_tag("US/CA/PUMP2").await.unwrap(), Some(tag("US/CA/PUMP2", "7")));
+
+        // Missing a delta still applies it, but asks for a snapshot
+        let skipped = IncomingMessage::new("tags/database/delta", br#"{"seq":7,"timestamp":"2024-01-01T00:00:00Z","changes":{"US/CA/PUMP2":"8"}}"#);
+        let outcome = TagMirror::apply(&repository, &mapping, &mut state, &skipped).await.unwrap();
+        assert_eq!(outcome, MirrorOutcome::Gap);
+        assert_eq!(repository.get_tag("US/CA/PUMP2").await.unwrap().unwrap().value, "8");
+
+        let invalid = IncomingMessage::new("tags/US.TX.PUMP3", b"not json");
+        assert!(TagMirror::apply(&repository, &mapping, &mut state, &invalid).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requests_one_snapshot_per_gap() {
+        let gap = |seq: u64| {
+            let payload = format!(r#"{{"seq":{},"timestamp":"2024-01-01T00:00:00Z","changes":{{}}}}"#, seq);
+            IncomingMessage::new("tags/database/delta", payload.as_bytes())
+        };
+        let messages = vec![
+            IncomingMessage::new("tags/database", br#"{"tags":{},"seq":1}"#),
+            gap(3),
+            gap(4),
+        ];
+
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .with(eq("tags/#"))
+            .times(1)
+            .return_once(move |_| Box::pin(async move { Ok(MessageStream::from_messages(messages)) }));
+
+        let mut mock_client = MockMqttClient::new();
+        mock_client
+            .expect_publish()
+            .withf(|topic, payload, policy| topic == "tags/database/get" && payload.is_empty() && !policy.retain)
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+
+        let mirror = TagMirror::new(Arc::new(JsonTagRepository::new()), Arc::new(mock_subscriber))
+            .with_snapshot_requests(Arc::new(mock_client));
+        let changes = mirror.changes();
+
+        mirror.start().await.unwrap().await.unwrap();
+        assert_eq!(*changes.borrow(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_applies_only_parts_of_the_latest_index() {
+        let repository = JsonTagRepository::new();
+        let mapping = TopicMapping::default();
+        let mut state = ReplicaState::default();
+
+        let part = |topic: &str, path: &str| {
+            let payload = format!(r#"{{"tags":{{"{0}":{{"path":"{0}","name":"n","description":"d","value":"1"}}}}}}"#, path);
+            IncomingMessage::new(topic, payload.as_bytes())
+        };
+        let index = br#"{"layout":"pages:1","parts":[{"id":"1","topic":"tags/database/part/1","tags":1}]}"#;
+
+        // Retained messages come in any order; a part left behind by an older layout is never listed
+        let messages = vec![
+            part("tags/database/part/2", "OLD/TAG"),
+            part("tags/database/part/1", "US/TX/PUMP1"),
+            IncomingMessage::new("tags/database/index", index),
+            part("tags/database/part/1", "US/TX/PUMP2"),
+            part("tags/database/part/2", "OLD/TAG"),
+        ];
+        let mut outcomes = Vec::new();
+        for message in &messages {
+            outcomes.push(TagMirror::apply(&repository, &mapping, &mut state, message).await.unwrap());
+        }
+        assert_eq!(
+            outcomes,
+            vec![
+                MirrorOutcome::Unchanged,
+                MirrorOutcome::Unchanged,
+                MirrorOutcome::Changed,
+                MirrorOutcome::Changed,
+                MirrorOutcome::Unchanged,
+            ]
+        );
+        assert!(repository.get_tag("US/TX/PUMP1").await.unwrap().is_some());
+        assert!(repository.get_tag("US/TX/PUMP2").await.unwrap().is_some());
+        assert!(repository.get_tag("OLD/TAG").await.unwrap().is_none());
+    }
+}
@@ -1,13 +1,21 @@
 use async_trait::async_trait;
 use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{watch, Mutex};
 
-use crate::domain::{Tag, TagRepository, TagService};
-use crate::infrastructure::{mqtt::MqttPublisher, UnsError};
+use crate::domain::{DatabaseMode, Tag, TagDelta, TagRepository, TagService};
+use crate::infrastructure::{
+    mqtt::{publisher::TagDatabase, ConnectionState, MqttPublisher},
+    UnsError,
+};
 
 /// Implementation of the TagService interface
 pub struct TagServiceImpl {
     repository: Arc<dyn TagRepository>,
     publisher: Arc<dyn MqttPublisher>,
+    database_mode: DatabaseMode,
+    /// Sequence number of the last published delta; held while publishing so
+    /// deltas and snapshots go out in numbering order
+    delta_seq: Mutex<u64>,
 }
 
 impl TagServiceImpl {
@@ -16,13 +24,36 @@ impl TagServiceImpl {
         Self {
             repository,
             publisher,
+            database_mode: DatabaseMode::default(),
+            delta_seq: Mutex::new(0),
         }
     }
+    
+    /// Sets how the database topic follows tag updates
+    pub fn with_database_mode(mut self, mode: DatabaseMode) -> Self {
+        self.database_mode = mode;
+        self
+    }
+    
+    /// Publishes a snapshot of the given tags, numbered with the last delta in delta mode
+    ///
+    /// The caller holds the `delta_seq` lock from before it read the tags, so
+    /// no delta can change them between the read and the numbering.
+    async fn publish_snapshot(&self, seq: u64, tags: HashMap<String, Tag>) -> Result<(), UnsError> {
+        let mut tag_data = TagDatabase::new(tags);
+        if self.database_mode == DatabaseMode::Delta {
+            tag_data.seq = Some(seq);
+        }
+        
+        self.publisher.publish_database(&tag_data).await
+    }
 }
 
 #[async_trait]
 impl TagService for TagServiceImpl {
     async fn load_and_publish_tags(&self, source: &str) -> Result<(), UnsError> {
+        let seq = self.delta_seq.lock().await;
+        
         // Load tags from the repository
         let tags = self.repository.load_tags(source).await?;
         
@@ -32,16 +63,31 @@ impl TagService for TagServiceImpl {
         // Publish individual tags
         self.publisher.publish_tags(&tags_vec).await?;
         
-        // Create a TagDatabase for publishing
-        let tag_data = crate::infrastructure::mqtt::publisher::TagDatabase { tags };
-        
         // Publish the full database
-        self.publisher.publish_database(&tag_data).await?;
+        self.publish_snapshot(*seq, tags).await?;
         
         Ok(())
     }
     
     async fn update_and_publish_tag(&self, path: &str, value: String) -> Result<(), UnsError> {
+        if self.database_mode == DatabaseMode::Delta {
+            // Keep snapshots out until the delta is numbered and sent
+            let mut seq = self.delta_seq.lock().await;
+            
+            let tag = self
+                .repository
+                .update_tag(path, value)
+                .await?
+                .ok_or_else(|| UnsError::NotFound(format!("Tag not found: {}", path)))?;
+            let published = self.publisher.publish_tag(&tag).await;
+            
+            // The repository changed either way, so the change is numbered and sent even if the
+            // tag topic was not updated; skipping it would hide the loss from delta consumers
+            *seq += 1;
+            let delta = self.publisher.publish_delta(&TagDelta::single(*seq, &tag.path, &tag.value)).await;
+            return published.and(delta);
+        }
+        
         // Update the tag in the repository
         let updated_tag = self.repository.update_tag(path, value).await?;
         
@@ -53,7 +99,7 @@ impl TagService for TagServiceImpl {
             let all_tags = self.repository.get_all_tags().await?;
             
             // Create a TagDatabase for publishing
-            let tag_data = crate::infrastructure::mqtt::publisher::TagDatabase { tags: all_tags };
+            let tag_data = TagDatabase::new(all_tags);
             
             // Publish the full database
             self.publisher.publish_database(&tag_data).await?;
@@ -71,6 +117,43 @@ impl TagService for TagServiceImpl {
     async fn get_tag(&self, path: &str) -> Result<Option<Tag>, UnsError> {
         self.repository.get_tag(path).await
     }
+    
+    async fn publish_database(&self) -> Result<(), UnsError> {
+        let seq = self.delta_seq.lock().await;
+        let tags = self.repository.get_all_tags().await?;
+        self.publish_snapshot(*seq, tags).await
+    }
+    
+    async fn republish_tags(&self) -> Result<(), UnsError> {
+        // Publish what the repository holds now, not what was loaded at startup
+        let seq = self.delta_seq.lock().await;
+        let tags = self.repository.get_all_tags().await?;
+        
+        // Nothing to restore; avoid wiping the database topic with an empty map
+        if tags.is_empty() {
+            return Ok(());
+        }
+        
+        let tags_vec: Vec<Tag> = tags.values().cloned().collect();
+        self.publisher.publish_tags(&tags_vec).await?;
+        
+        self.publish_snapshot(*seq, tags).await
+    }
+    
+    async fn mark_tags_stale(&self) -> Result<(), UnsError> {
+        let tags = self.repository.get_all_tags().await?;
+        
+        if tags.is_empty() {
+            return Ok(());
+        }
+        
+        let tag_data = TagDatabase::new(tags);
+        self.publisher.publish_stale(&tag_data).await
+    }
+    
+    fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.publisher.connection_state()
+    }
 }
 
 #[cfg(test)]
@@ -89,6 +172,7 @@ mod tests {
             async fn save_tags(&self, tags: &HashMap<String, Tag>, destination: &str) -> Result<(), UnsError>;
             async fn get_tag(&self, path: &str) -> Result<Option<Tag>, UnsError>;
             async fn update_tag(&self, path: &str, value: String) -> Result<Option<Tag>, UnsError>;
+            async fn put_tag(&self, tag: Tag) -> Result<(), UnsError>;
             async fn get_all_tags(&self) -> Result<HashMap<String, Tag>, UnsError>;
         }
     }
@@ -102,6 +186,16 @@ mod tests {
             async fn publish_tag(&self, tag: &Tag) -> Result<(), UnsError>;
             async fn publish_tags(&self, tags: &[Tag]) -> Result<(), UnsError>;
             async fn publish_database(&self, data: &crate::infrastructure::mqtt::publisher::TagDatabase) -> Result<(), UnsError>;
+            async fn publish_delta(&self, delta: &TagDelta) -> Result<(), UnsError>;
+            async fn publish_write_request(&self, request: &crate::domain::WriteRequest) -> Result<(), UnsError>;
+            async fn publish_write_response(
+                &self,
+                reply_to: &crate::domain::ReplyTo,
+                response: &crate::domain::WriteResponse,
+            ) -> Result<(), UnsError>;
+            async fn publish_status(&self, status: &crate::domain::InstanceStatus) -> Result<(), UnsError>;
+            async fn publish_stale(&self, data: &crate::infrastructure::mqtt::publisher::TagDatabase) -> Result<(), UnsError>;
+            fn connection_state(&self) -> watch::Receiver<ConnectionState>;
         }
     }
     
@@ -236,4 +330,220 @@ mod tests {
         // Verify the result
         assert!(result.is_ok());
     }
+    
+    #[tokio::test]
+    async fn test_update_publishes_numbered_deltas() {
+        let mut mock_repo = MockTagRepository::new();
+        mock_repo
+            .expect_update_tag()
+            .times(2)
+            .returning(|path, value| Ok(Some(Tag::new(path.to_string(), "n".to_string(), "d".to_string(), value))));
+        mock_repo
+            .expect_get_all_tags()
+            .times(1)
+            .returning(|| Ok(HashMap::new()));
+        
+        // Each update sends a delta instead of the whole database
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_publish_tag()
+            .times(2)
+            .returning(|_| Ok(()));
+        let mut seq = mockall::Sequence::new();
+        mock_publisher
+            .expect_publish_delta()
+            .withf(|delta| delta.seq == 1 && delta.changes["A/B"] == "1")
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        mock_publisher
+            .expect_publish_delta()
+            .withf(|delta| delta.seq == 2 && delta.changes["A/B"] == "2")
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        
+        // A snapshot names the last delta it includes
+        mock_publisher
+            .expect_publish_database()
+            .withf(|data| data.seq == Some(2))
+            .times(1)
+            .returning(|_| Ok(()));
+        
+        let service = TagServiceImpl::new(Arc::new(mock_repo), Arc::new(mock_publisher))
+            .with_database_mode(DatabaseMode::Delta);
+        
+        service.update_and_publish_tag("A/B", "1".to_string()).await.unwrap();
+        service.update_and_publish_tag("A/B", "2".to_string()).await.unwrap();
+        service.publish_database().await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_update_sends_delta_when_tag_publish_fails() {
+        let mut mock_repo = MockTagRepository::new();
+        mock_repo
+            .expect_update_tag()
+            .times(2)
+            .returning(|path, value| Ok(Some(Tag::new(path.to_string(), "n".to_string(), "d".to_string(), value))));
+        
+        let mut mock_publisher = MockMqttPublisher::new();
+        let mut tags = mockall::Sequence::new();
+        mock_publisher
+            .expect_publish_tag()
+            .times(1)
+            .in_sequence(&mut tags)
+            .returning(|_| Err(UnsError::Mqtt("broker unreachable".to_string())));
+        mock_publisher
+            .expect_publish_tag()
+            .times(1)
+            .in_sequence(&mut tags)
+            .returning(|_| Ok(()));
+        
+        // The failed update still uses up its number, so no gap is hidden
+        let mut deltas = mockall::Sequence::new();
+        mock_publisher
+            .expect_publish_delta()
+            .withf(|delta| delta.seq == 1 && delta.changes["A/B"] == "1")
+            .times(1)
+            .in_sequence(&mut deltas)
+            .returning(|_| Ok(()));
+        mock_publisher
+            .expect_publish_delta()
+            .withf(|delta| delta.seq == 2 && delta.changes["A/B"] == "2")
+            .times(1)
+            .in_sequence(&mut deltas)
+            .returning(|_| Ok(()));
+        
+        let service = TagServiceImpl::new(Arc::new(mock_repo), Arc::new(mock_publisher))
+            .with_database_mode(DatabaseMode::Delta);
+        
+        // The tag publish error is still reported
+        let result = service.update_and_publish_tag("A/B", "1".to_string()).await;
+        assert!(matches!(result, Err(UnsError::Mqtt(_))));
+        service.update_and_publish_tag("A/B", "2".to_string()).await.unwrap();
+    }
+    
+    /// Repository holding one counter tag that yields during every read
+    struct CounterRepository {
+        value: std::sync::Mutex<u64>,
+    }
+    
+    #[async_trait]
+    impl TagRepository for CounterRepository {
+        async fn load_tags(&self, _source: &str) -> Result<HashMap<String, Tag>, UnsError> {
+            self.get_all_tags().await
+        }
+        
+        async fn save_tags(&self, _tags: &HashMap<String, Tag>, _destination: &str) -> Result<(), UnsError> {
+            Ok(())
+        }
+        
+        async fn get_tag(&self, _path: &str) -> Result<Option<Tag>, UnsError> {
+            Ok(None)
+        }
+        
+        async fn update_tag(&self, path: &str, _value: String) -> Result<Option<Tag>, UnsError> {
+            let mut value = self.value.lock().unwrap();
+            *value += 1;
+            Ok(Some(Tag::new(path.to_string(), "n".to_string(), "d".to_string(), value.to_string())))
+        }
+        
+        async fn put_tag(&self, _tag: Tag) -> Result<(), UnsError> {
+            Ok(())
+        }
+        
+        async fn get_all_tags(&self) -> Result<HashMap<String, Tag>, UnsError> {
+            // Give a concurrent update the chance to run before the read returns
+            let value = self.value.lock().unwrap().to_string();
+            tokio::task::yield_now().await;
+            let tag = Tag::new("A/B".to_string(), "n".to_string(), "d".to_string(), value);
+            Ok(HashMap::from([("A/B".to_string(), tag)]))
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_snapshot_seq_matches_its_contents_during_updates() {
+        let repository = Arc::new(CounterRepository { value: std::sync::Mutex::new(0) });
+        
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher.expect_publish_tag().returning(|_| Ok(()));
+        mock_publisher.expect_publish_delta().returning(|_| Ok(()));
+        
+        // Every update up to the snapshot's number is in it, and none after
+        mock_publisher
+            .expect_publish_database()
+            .withf(|data| data.seq.map(|seq| seq.to_string()) == Some(data.tags["A/B"].value.clone()))
+            .times(20)
+            .returning(|_| Ok(()));
+        
+        let service = TagServiceImpl::new(repository, Arc::new(mock_publisher))
+            .with_database_mode(DatabaseMode::Delta);
+        
+        let updates = async {
+            for _ in 0..20 {
+                service.update_and_publish_tag("A/B", "x".to_string()).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        };
+        let snapshots = async {
+            for _ in 0..20 {
+                service.publish_database().await.unwrap();
+            }
+        };
+        tokio::join!(updates, snapshots);
+    }
+    
+    #[tokio::test]
+    async fn test_republish_tags() {
+        // Create mock repository holding one tag
+        let mut mock_repo = MockTagRepository::new();
+        mock_repo
+            .expect_get_all_tags()
+            .times(1)
+            .returning(|| {
+                let mut tags = HashMap::new();
+                tags.insert(
+                    "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE".to_string(),
+                    Tag::new(
+                        "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE".to_string(),
+                        "Pump 1 Pressure".to_string(),
+                        "Pressure sensor for Pump 1".to_string(),
+                        "50.2".to_string(),
+                    ),
+                );
+                Ok(tags)
+            });
+        
+        // Current values are published again
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_publish_tags()
+            .withf(|tags| tags.len() == 1 && tags[0].value == "50.2")
+            .times(1)
+            .returning(|_| Ok(()));
+        mock_publisher
+            .expect_publish_database()
+            .times(1)
+            .returning(|_| Ok(()));
+        
+        let service = TagServiceImpl::new(Arc::new(mock_repo), Arc::new(mock_publisher));
+        
+        assert!(service.republish_tags().await.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_republish_skips_empty_repository() {
+        let mut mock_repo = MockTagRepository::new();
+        mock_repo
+            .expect_get_all_tags()
+            .times(1)
+            .returning(|| Ok(HashMap::new()));
+        
+        // No publish is expected
+        let mock_publisher = MockMqttPublisher::new();
+        
+        let service = TagServiceImpl::new(Arc::new(mock_repo), Arc::new(mock_publisher));
+        
+        assert!(service.republish_tags().await.is_ok());
+    }
 }
@@ -0,0 +1,240 @@
+use log::{error, info, warn};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use crate::domain::{ReplyTo, Tag, TagService, TopicMapping, WriteRequest, WriteResponse};
+use crate::infrastructure::mqtt::{IncomingMessage, MqttPublisher, MqttSubscriber};
+use crate::infrastructure::UnsError;
+
+/// Listens for write requests on `<prefix>/set/#` and applies them through the tag service
+///
+/// Requests carrying an MQTT 5 response topic get a `WriteResponse` reply.
+pub struct WriteRequestListener {
+    tag_service: Arc<dyn TagService>,
+    subscriber: Arc<dyn MqttSubscriber>,
+    publisher: Arc<dyn MqttPublisher>,
+    writable_prefixes: Vec<String>,
+    mapping: TopicMapping,
+}
+
+impl WriteRequestListener {
+    /// Creates a new WriteRequestListener
+    pub fn new(
+        tag_service: Arc<dyn TagService>,
+        subscriber: Arc<dyn MqttSubscriber>,
+        publisher: Arc<dyn MqttPublisher>,
+    ) -> Self {
+        Self {
+            tag_service,
+            subscriber,
+            publisher,
+            writable_prefixes: Vec::new(),
+            mapping: TopicMapping::default(),
+        }
+    }
+
+    /// Only accepts writes to tags under these path prefixes (all tags when empty)
+    pub fn with_writable_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        self.writable_prefixes = prefixes;
+        self
+    }
+
+    /// Listens on the write request topics of the given mapping
+    pub fn with_topic_mapping(mut self, mapping: TopicMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    /// Subscribes to write requests and processes them in a background task
+    pub async fn start(&self) -> Result<JoinHandle<()>, UnsError> {
+        let mut requests = self.subscriber.subscribe_stream(&self.mapping.write_filter()).await?;
+
+        let tag_service = self.tag_service.clone();
+        let publisher = self.publisher.clone();
+        let writable_prefixes = self.writable_prefixes.clone();
+        let mapping = self.mapping.clone();
+        Ok(tokio::spawn(async move {
+            while let Some(message) = requests.recv().await {
+                let result = Self::handle_message(tag_service.as_ref(), &mapping, &writable_prefixes, &message).await;
+                if let Err(e) = &result {
+                    warn!("Rejected write request on {}: {}", message.topic, e);
+                }
+
+                if let Some(reply_to) = Self::reply_to(&mapping, &message) {
+                    let response = WriteResponse::from_result(&result);
+                    if let Err(e) = publisher.publish_write_response(&reply_to, &response).await {
+                        error!("Failed to reply on {}: {}", reply_to.topic, e);
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Applies a single received write request
+    pub async fn handle_message(
+        tag_service: &dyn TagService,
+        mapping: &TopicMapping,
+        writable_prefixes: &[String],
+        message: &IncomingMessage,
+    ) -> Result<(), UnsError> {
+        let request = WriteRequest::from_mqtt(mapping, &message.topic, &message.payload)?;
+        info!("Write request received: {} = {}", request.path, request.value);
+
+        let writable = writable_prefixes.is_empty()
+            || writable_prefixes.iter().any(|prefix| Tag::path_starts_with(&request.path, prefix));
+        if !writable {
+            return Err(UnsError::PermissionDenied(format!("Tag is not writable: {}", request.path)));
+        }
+
+        tag_service.update_and_publish_tag(&request.path, request.value).await
+    }
+
+    /// Returns where to reply to a message, if the requester asked for a reply
+    ///
+    /// Replies are only sent below `<prefix>/reply/`, so a request cannot make
+    /// the instance publish on tag topics.
+    fn reply_to(mapping: &TopicMapping, message: &IncomingMessage) -> Option<ReplyTo> {
+        let topic = message.properties.response_topic.as_ref()?;
+
+        let reply_prefix = mapping.reply_prefix();
+        if !topic.starts_with(&reply_prefix) {
+            warn!("Ignoring response topic outside {}: {}", reply_prefix, topic);
+            return None;
+        }
+
+        Some(ReplyTo {
+            topic: topic.clone(),
+            correlation_data: message.properties.correlation_data.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::tag_service::MockTagService;
+    use crate::domain::WriteStatus;
+    use crate::infrastructure::mqtt::publisher::MockMqttPublisher;
+    use crate::infrastructure::mqtt::subscriber::MockMqttSubscriber;
+    use crate::infrastructure::mqtt::{MessageProperties, MessageStream};
+    use mockall::predicate::*;
+
+    #[tokio::test]
+    async fn test_handle_write_request() {
+        // Create mock tag service
+        let mut mock_service = MockTagService::new();
+
+        // Set up expectations
+        mock_service
+            .expect_update_and_publish_tag()
+            .with(
+                eq("US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE"),
+                eq("50.2".to_string())
+            )
+            .times(1)
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        // Create mock subscriber delivering one valid and one invalid request
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .with(eq("tags/set/#"))
+            .times(1)
+            .returning(|_| {
+                Box::pin(async {
+                    Ok(MessageStream::from_messages(vec![
+                        IncomingMessage::new("tags/set/US.TX.AUSTIN", b"not json"),
+                        IncomingMessage::new(
+                            "tags/set/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1.PRESSURE",
+                            br#"{"value":"50.2"}"#,
+                        ),
+                    ]))
+                })
+            });
+
+        // Neither request asked for a reply
+        let mock_publisher = MockMqttPublisher::new();
+
+        let listener = WriteRequestListener::new(
+            Arc::new(mock_service),
+            Arc::new(mock_subscriber),
+            Arc::new(mock_publisher),
+        );
+
+        // The task ends once the stream is exhausted
+        let task = listener.start().await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replies_with_outcome() {
+        // Create mock tag service that does not know the tag
+        let mut mock_service = MockTagService::new();
+        mock_service
+            .expect_update_and_publish_tag()
+            .times(1)
+            .returning(|path, _| {
+                let message = format!("Tag not found: {}", path);
+                Box::pin(async move { Err(UnsError::NotFound(message)) })
+            });
+
+        // One request asks for a reply, the other names a tag topic as response topic
+        let mut request = IncomingMessage::new("tags/set/US.TX", br#"{"value":"1"}"#);
+        request.properties = MessageProperties {
+            response_topic: Some("tags/reply/abc".to_string()),
+            correlation_data: Some(b"abc".to_vec()),
+            ..MessageProperties::default()
+        };
+        let mut hostile = IncomingMessage::new("tags/set/US", b"not json");
+        hostile.properties.response_topic = Some("tags/US.TX".to_string());
+
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .times(1)
+            .return_once(move |_| Box::pin(async move { Ok(MessageStream::from_messages(vec![request, hostile])) }));
+
+        // Only the first request gets a reply
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_publish_write_response()
+            .withf(|reply_to, response| {
+                reply_to.topic == "tags/reply/abc"
+                    && reply_to.correlation_data.as_deref() == Some(b"abc".as_slice())
+                    && response.status == WriteStatus::NotFound
+            })
+            .times(1)
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let listener = WriteRequestListener::new(
+            Arc::new(mock_service),
+            Arc::new(mock_subscriber),
+            Arc::new(mock_publisher),
+        );
+
+        let task = listener.start().await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejects_bad_payloads() {
+        // No update is expected
+        let mock_service = MockTagService::new();
+
+        let invalid = IncomingMessage::new("tags/set/US.TX.AUSTIN", b"not json");
+        let result = WriteRequestListener::handle_message(&mock_service, &TopicMapping::default(), &[], &invalid).await;
+        assert!(matches!(result, Err(UnsError::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_writes_outside_writable_prefixes() {
+        // No update is expected
+        let mock_service = MockTagService::new();
+
+        let request = IncomingMessage::new("tags/set/US.CA.PUMP1", br#"{"value":"1"}"#);
+        let writable = vec!["US/TX".to_string()];
+        let result =
+            WriteRequestListener::handle_message(&mock_service, &TopicMapping::default(), &writable, &request).await;
+        assert!(matches!(result, Err(UnsError::PermissionDenied(_))));
+    }
+}
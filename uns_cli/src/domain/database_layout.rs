@@ -0,0 +1,142 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::domain::Tag;
+use crate::infrastructure::UnsError;
+
+/// How the tag database is split across topics
+///
+/// Written as `single`, `level:<n>` or `pages:<n>`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DatabaseLayout {
+    /// The whole database in one message on `<prefix>/database`
+    #[default]
+    Single,
+
+    /// One part per hierarchy prefix of the given number of path segments,
+    /// e.g. per site and area with 2
+    ByLevel(usize),
+
+    /// Parts of at most the given number of tags, in path order
+    Pages(usize),
+}
+
+impl DatabaseLayout {
+    /// Splits tags into parts, returned as (part id, tags) in part order
+    ///
+    /// Part ids are the hierarchy prefix (`US/TX`) for `ByLevel` and the page
+    /// number starting at 1 for `Pages`. `Single` yields one part with an
+    /// empty id.
+    pub fn partition(&self, tags: &HashMap<String, Tag>) -> Vec<(String, HashMap<String, Tag>)> {
+        match *self {
+            DatabaseLayout::Single => vec![(String::new(), tags.clone())],
+            DatabaseLayout::ByLevel(levels) => {
+                let mut parts: BTreeMap<String, HashMap<String, Tag>> = BTreeMap::new();
+                for (path, tag) in tags {
+                    // Keep at least the leaf out of the part id, unless the path has a single segment
+                    let segments: Vec<&str> = path.split('/').collect();
+                    let depth = levels.min(segments.len() - 1).max(1);
+                    let id = segments[..depth].join("/");
+                    parts.entry(id).or_default().insert(path.clone(), tag.clone());
+                }
+                parts.into_iter().collect()
+            }
+            DatabaseLayout::Pages(size) => {
+                let mut paths: Vec<&String> = tags.keys().collect();
+                paths.sort();
+                paths
+                    .chunks(size)
+                    .enumerate()
+                    .map(|(page, chunk)| {
+                        let part = chunk.iter().map(|path| ((*path).clone(), tags[*path].clone())).collect();
+                        ((page + 1).to_string(), part)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl fmt::Display for DatabaseLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseLayout::Single => write!(f, "single"),
+            DatabaseLayout::ByLevel(levels) => write!(f, "level:{}", levels),
+            DatabaseLayout::Pages(size) => write!(f, "pages:{}", size),
+        }
+    }
+}
+
+impl FromStr for DatabaseLayout {
+    type Err = UnsError;
+
+    fn from_str(layout: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            UnsError::Validation(format!(
+                "Invalid database layout '{}': expected single, level:<n> or pages:<n> with n > 0",
+                layout
+            ))
+        };
+
+        if layout == "single" {
+            return Ok(DatabaseLayout::Single);
+        }
+
+        let (kind, count) = layout.split_once(':').ok_or_else(invalid)?;
+        let count: usize = count.parse().ok().filter(|count| *count > 0).ok_or_else(invalid)?;
+        match kind {
+            "level" => Ok(DatabaseLayout::ByLevel(count)),
+            "pages" => Ok(DatabaseLayout::Pages(count)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(paths: &[&str]) -> HashMap<String, Tag> {
+        paths
+            .iter()
+            .map(|path| (path.to_string(), Tag::new(path.to_string(), "n".to_string(), "d".to_string(), "1".to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("single".parse::<DatabaseLayout>().unwrap(), DatabaseLayout::Single);
+        assert_eq!("level:2".parse::<DatabaseLayout>().unwrap(), DatabaseLayout::ByLevel(2));
+        assert_eq!("pages:500".parse::<DatabaseLayout>().unwrap(), DatabaseLayout::Pages(500));
+        assert_eq!(DatabaseLayout::Pages(500).to_string(), "pages:500");
+
+        assert!("pages:0".parse::<DatabaseLayout>().is_err());
+        assert!("level".parse::<DatabaseLayout>().is_err());
+        assert!("rows:5".parse::<DatabaseLayout>().is_err());
+    }
+
+    #[test]
+    fn test_partition_by_level() {
+        let tags = tags(&["US/TX/AUSTIN/PUMP1", "US/TX/DALLAS/PUMP2", "US/CA/PUMP3", "US/FLOW", "TOTAL"]);
+        let parts = DatabaseLayout::ByLevel(2).partition(&tags);
+
+        let ids: Vec<&str> = parts.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["TOTAL", "US", "US/CA", "US/TX"]);
+        assert_eq!(parts[3].1.len(), 2);
+        assert!(parts[1].1.contains_key("US/FLOW"));
+    }
+
+    #[test]
+    fn test_partition_pages() {
+        let tags = tags(&["C", "A", "B", "E", "D"]);
+        let parts = DatabaseLayout::Pages(2).partition(&tags);
+
+        let ids: Vec<&str> = parts.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        assert!(parts[0].1.contains_key("A") && parts[0].1.contains_key("B"));
+        assert!(parts[2].1.contains_key("E"));
+
+        assert_eq!(DatabaseLayout::Single.partition(&tags)[0].1.len(), 5);
+    }
+}
@@ -0,0 +1,107 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::infrastructure::UnsError;
+
+/// Topic prefix of the retained per-instance status topics
+pub const STATUS_PREFIX: &str = "uns/status/";
+
+/// Instance identifier used when none is configured
+pub const DEFAULT_INSTANCE_ID: &str = "uns_cli";
+
+/// Whether an instance is serving its tags
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceState {
+    /// The instance is connected and its retained tags are current
+    Online,
+
+    /// The instance is gone; its retained tags may be stale
+    Offline,
+}
+
+/// Birth/death message published on `uns/status/<instance id>`
+///
+/// The `offline` message doubles as the MQTT Last Will, so the broker
+/// publishes it when the instance disappears without disconnecting.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InstanceStatus {
+    /// Identifier of the instance
+    pub instance_id: String,
+
+    /// Current state of the instance
+    pub state: InstanceState,
+
+    /// Version of the running binary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    /// Number of tags the instance serves
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_count: Option<usize>,
+
+    /// When the instance started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+}
+
+impl InstanceStatus {
+    /// Creates the birth message of a running instance
+    pub fn online(instance_id: &str, tag_count: usize, started_at: DateTime<Utc>) -> Self {
+        Self {
+            instance_id: instance_id.to_string(),
+            state: InstanceState::Online,
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            tag_count: Some(tag_count),
+            started_at: Some(started_at),
+        }
+    }
+
+    /// Creates the death message of an instance
+    pub fn offline(instance_id: &str) -> Self {
+        Self {
+            instance_id: instance_id.to_string(),
+            state: InstanceState::Offline,
+            version: None,
+            tag_count: None,
+            started_at: None,
+        }
+    }
+
+    /// Returns the status topic of an instance
+    pub fn topic_for(instance_id: &str) -> String {
+        format!("{}{}", STATUS_PREFIX, instance_id)
+    }
+
+    /// Returns the topic this status is published on
+    pub fn to_mqtt_topic(&self) -> String {
+        Self::topic_for(&self.instance_id)
+    }
+
+    /// Encodes the status payload
+    pub fn to_payload(&self) -> Result<Vec<u8>, UnsError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_payloads() {
+        let offline = InstanceStatus::offline("gateway-1");
+        assert_eq!(offline.to_mqtt_topic(), "uns/status/gateway-1");
+        assert_eq!(
+            offline.to_payload().unwrap(),
+            br#"{"instance_id":"gateway-1","state":"offline"}"#.to_vec()
+        );
+
+        let online = InstanceStatus::online("gateway-1", 2, Utc::now());
+        let payload: serde_json::Value = serde_json::from_slice(&online.to_payload().unwrap()).unwrap();
+        assert_eq!(payload["state"], "online");
+        assert_eq!(payload["tag_count"], 2);
+        assert_eq!(payload["version"], env!("CARGO_PKG_VERSION"));
+        assert!(payload["started_at"].is_string());
+    }
+}
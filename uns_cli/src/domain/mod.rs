@@ -1,9 +1,23 @@
 // Domain module exports
+pub mod database_layout;
+pub mod instance_status;
+pub mod payload_codec;
+pub mod publish_policy;
 pub mod tag;
+pub mod tag_delta;
 pub mod tag_repository;
 pub mod tag_service;
+pub mod topic_mapping;
+pub mod write_request;
 
 // Re-export key types
+pub use database_layout::DatabaseLayout;
+pub use instance_status::{InstanceState, InstanceStatus};
+pub use payload_codec::PayloadCodec;
+pub use publish_policy::{PublishPolicies, PublishPolicy, PublishRule, QosLevel, TagSelector};
 pub use tag::Tag;
+pub use tag_delta::{DatabaseMode, TagDelta};
 pub use tag_repository::TagRepository;
 pub use tag_service::TagService;
+pub use topic_mapping::{TopicMapping, TopicStrategy};
+pub use write_request::{ReplyTo, WriteRequest, WriteResponse, WriteStatus};
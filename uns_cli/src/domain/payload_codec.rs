@@ -0,0 +1,124 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::infrastructure::UnsError;
+
+/// Encoding of tag, database and delta payloads
+///
+/// Written as `json`, `cbor` or `msgpack`. Payloads in any codec other than
+/// JSON carry their MQTT 5 content type, so consumers can tell them apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PayloadCodec {
+    /// JSON text, readable with any MQTT tool
+    #[default]
+    Json,
+
+    /// CBOR (RFC 8949)
+    Cbor,
+
+    /// MessagePack, with structs encoded as maps
+    MessagePack,
+}
+
+impl PayloadCodec {
+    /// Returns the MQTT 5 content type advertising the codec
+    pub fn content_type(self) -> &'static str {
+        match self {
+            PayloadCodec::Json => "application/json",
+            PayloadCodec::Cbor => "application/cbor",
+            PayloadCodec::MessagePack => "application/vnd.msgpack",
+        }
+    }
+
+    /// Returns the codec a content type advertises; a missing content type means JSON
+    pub fn from_content_type(content_type: Option<&str>) -> Option<Self> {
+        match content_type {
+            None => Some(PayloadCodec::Json),
+            Some(content_type) => [PayloadCodec::Json, PayloadCodec::Cbor, PayloadCodec::MessagePack]
+                .into_iter()
+                .find(|codec| codec.content_type() == content_type),
+        }
+    }
+
+    /// Encodes a payload
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, UnsError> {
+        match self {
+            PayloadCodec::Json => Ok(serde_json::to_vec(value)?),
+            PayloadCodec::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(value, &mut payload).map_err(|e| UnsError::Serialization(e.to_string()))?;
+                Ok(payload)
+            }
+            PayloadCodec::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|e| UnsError::Serialization(e.to_string()))
+            }
+        }
+    }
+
+    /// Decodes a payload
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, UnsError> {
+        let invalid = |e: String| UnsError::Validation(format!("Invalid {} payload: {}", self, e));
+        match self {
+            PayloadCodec::Json => serde_json::from_slice(payload).map_err(|e| invalid(e.to_string())),
+            PayloadCodec::Cbor => ciborium::from_reader(payload).map_err(|e| invalid(e.to_string())),
+            PayloadCodec::MessagePack => rmp_serde::from_slice(payload).map_err(|e| invalid(e.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for PayloadCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadCodec::Json => write!(f, "json"),
+            PayloadCodec::Cbor => write!(f, "cbor"),
+            PayloadCodec::MessagePack => write!(f, "msgpack"),
+        }
+    }
+}
+
+impl FromStr for PayloadCodec {
+    type Err = UnsError;
+
+    fn from_str(codec: &str) -> Result<Self, Self::Err> {
+        match codec {
+            "json" => Ok(PayloadCodec::Json),
+            "cbor" => Ok(PayloadCodec::Cbor),
+            "msgpack" => Ok(PayloadCodec::MessagePack),
+            _ => Err(UnsError::Validation(format!(
+                "Invalid codec '{}': expected json, cbor or msgpack",
+                codec
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::Tag;
+
+    #[test]
+    fn test_round_trip() {
+        let tag = Tag::new("US/TX/PUMP1".to_string(), "Pump".to_string(), "d".to_string(), "45.7".to_string())
+            .with_class("pressure");
+
+        for codec in [PayloadCodec::Json, PayloadCodec::Cbor, PayloadCodec::MessagePack] {
+            let payload = codec.encode(&tag).unwrap();
+            assert_eq!(codec.decode::<Tag>(&payload).unwrap(), tag, "{}", codec);
+            assert_eq!(PayloadCodec::from_content_type(Some(codec.content_type())), Some(codec));
+            assert_eq!(codec.to_string().parse::<PayloadCodec>().unwrap(), codec);
+        }
+
+        // The binary codecs are what makes them worth it on cellular links
+        let json = PayloadCodec::Json.encode(&tag).unwrap();
+        assert!(PayloadCodec::Cbor.encode(&tag).unwrap().len() < json.len());
+        assert!(PayloadCodec::MessagePack.encode(&tag).unwrap().len() < json.len());
+
+        assert!(PayloadCodec::Cbor.decode::<Tag>(&json).is_err());
+        assert_eq!(PayloadCodec::from_content_type(None), Some(PayloadCodec::Json));
+        assert_eq!(PayloadCodec::from_content_type(Some("text/plain")), None);
+        assert!("protobuf".parse::<PayloadCodec>().is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::str::FromStr;
+
+use crate::domain::{PayloadCodec, Tag};
+use crate::infrastructure::mqtt::subscriber::topic_matches;
+use crate::infrastructure::UnsError;
+
+/// MQTT delivery guarantee
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum QosLevel {
+    /// QoS 0: fire and forget
+    AtMostOnce,
+
+    /// QoS 1: acknowledged, may be duplicated
+    #[default]
+    AtLeastOnce,
+
+    /// QoS 2: delivered exactly once
+    ExactlyOnce,
+}
+
+impl QosLevel {
+    /// Returns the level for 0, 1 or 2
+    pub fn from_level(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(QosLevel::AtMostOnce),
+            1 => Some(QosLevel::AtLeastOnce),
+            2 => Some(QosLevel::ExactlyOnce),
+            _ => None,
+        }
+    }
+
+    /// Returns 0, 1 or 2
+    pub fn level(self) -> u8 {
+        match self {
+            QosLevel::AtMostOnce => 0,
+            QosLevel::AtLeastOnce => 1,
+            QosLevel::ExactlyOnce => 2,
+        }
+    }
+}
+
+/// QoS and retain flag of a published message
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PublishPolicy {
+    /// Delivery guarantee
+    pub qos: QosLevel,
+
+    /// Whether the broker keeps the message for new subscribers
+    pub retain: bool,
+}
+
+impl Default for PublishPolicy {
+    /// Tag state: QoS 1, retained
+    fn default() -> Self {
+        Self {
+            qos: QosLevel::AtLeastOnce,
+            retain: true,
+        }
+    }
+}
+
+impl PublishPolicy {
+    /// Creates a policy
+    pub fn new(qos: QosLevel, retain: bool) -> Self {
+        Self { qos, retain }
+    }
+
+    /// QoS 1 without retain, for commands and replies
+    pub fn not_retained() -> Self {
+        Self::new(QosLevel::AtLeastOnce, false)
+    }
+}
+
+/// Tags a publish rule applies to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagSelector {
+    /// Tags whose path matches a filter, with MQTT `+` and `#` wildcards over path segments
+    Path(String),
+
+    /// Tags of a class
+    Class(String),
+}
+
+impl TagSelector {
+    /// Returns true if the tag is selected
+    pub fn matches(&self, tag: &Tag) -> bool {
+        match self {
+            TagSelector::Path(filter) => topic_matches(filter, &tag.path),
+            TagSelector::Class(class) => tag.class.as_deref() == Some(class.as_str()),
+        }
+    }
+}
+
+/// Overrides the QoS, retain flag and/or codec for selected tags
+///
+/// Written as `<selector>=<settings>`, where the selector is a path filter
+/// such as `US/TX/#` or `+/+/VIBRATION`, or `class:<name>`, and the settings
+/// are a comma-separated list of `qos0`, `qos1`, `qos2`, `retain`,
+/// `no-retain`, `json`, `cbor` and `msgpack`, e.g. `class:vibration=qos0,no-retain`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublishRule {
+    /// Tags the rule applies to
+    pub selector: TagSelector,
+
+    /// QoS to use instead of the default
+    pub qos: Option<QosLevel>,
+
+    /// Retain flag to use instead of the default
+    pub retain: Option<bool>,
+
+    /// Payload codec to use instead of the default
+    pub codec: Option<PayloadCodec>,
+}
+
+impl FromStr for PublishRule {
+    type Err = UnsError;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| UnsError::Validation(format!("Invalid publish rule '{}': {}", rule, reason));
+
+        let (selector, settings) = rule
+            .rsplit_once('=')
+            .ok_or_else(|| invalid("expected <selector>=<settings>"))?;
+
+        let selector = match selector.strip_prefix("class:") {
+            Some("") => return Err(invalid("empty class name")),
+            Some(class) => TagSelector::Class(class.to_string()),
+            None => {
+                let levels: Vec<&str> = selector.split('/').collect();
+                let valid = levels.iter().enumerate().all(|(i, level)| match *level {
+                    "" => false,
+                    "#" => i == levels.len() - 1,
+                    "+" => true,
+                    _ => !level.contains(['+', '#']),
+                });
+                if !valid {
+                    return Err(invalid("path filters need non-empty segments, with '+' or a final '#' as wildcards"));
+                }
+                TagSelector::Path(selector.to_string())
+            }
+        };
+
+        let mut parsed = Self {
+            selector,
+            qos: None,
+            retain: None,
+            codec: None,
+        };
+        for setting in settings.split(',').map(str::trim) {
+            match setting {
+                "retain" => parsed.retain = Some(true),
+                "no-retain" => parsed.retain = Some(false),
+                "json" | "cbor" | "msgpack" => parsed.codec = Some(setting.parse()?),
+                _ => {
+                    let qos = setting
+                        .strip_prefix("qos")
+                        .and_then(|level| level.parse().ok())
+                        .and_then(QosLevel::from_level)
+                        .ok_or_else(|| invalid(&format!("unknown setting '{}'", setting)))?;
+                    parsed.qos = Some(qos);
+                }
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Publish policy of every tag: a default and rules that override it
+///
+/// Rules are checked in order and the first matching rule wins. Settings a
+/// rule leaves out come from the default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PublishPolicies {
+    /// Policy of tags no rule matches
+    pub default: PublishPolicy,
+
+    /// Codec of tags no rule matches, and of the database and deltas
+    pub codec: PayloadCodec,
+
+    /// Overrides for selected tags
+    pub rules: Vec<PublishRule>,
+}
+
+impl PublishPolicies {
+    /// Creates the policies from a default and rules, encoding payloads as JSON
+    pub fn new(default: PublishPolicy, rules: Vec<PublishRule>) -> Self {
+        Self {
+            default,
+            codec: PayloadCodec::default(),
+            rules,
+        }
+    }
+
+    /// Encodes payloads with the given codec unless a rule says otherwise
+    pub fn with_codec(mut self, codec: PayloadCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Returns the codec a tag is published with
+    pub fn codec_for(&self, tag: &Tag) -> PayloadCodec {
+        self.rules
+            .iter()
+            .find(|rule| rule.selector.matches(tag))
+            .and_then(|rule| rule.codec)
+            .unwrap_or(self.codec)
+    }
+
+    /// Returns the policy a tag is published with
+    pub fn policy_for(&self, tag: &Tag) -> PublishPolicy {
+        match self.rules.iter().find(|rule| rule.selector.matches(tag)) {
+            Some(rule) => PublishPolicy {
+                qos: rule.qos.unwrap_or(self.default.qos),
+                retain: rule.retain.unwrap_or(self.default.retain),
+            },
+            None => self.default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(path: &str) -> Tag {
+        Tag::new(path.to_string(), "n".to_string(), "d".to_string(), "1".to_string())
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rule: PublishRule = "class:vibration=qos0,no-retain".parse().unwrap();
+        assert_eq!(rule.selector, TagSelector::Class("vibration".to_string()));
+        assert_eq!(rule.qos, Some(QosLevel::AtMostOnce));
+        assert_eq!(rule.retain, Some(false));
+
+        let rule: PublishRule = "US/+/AUSTIN/#=qos2".parse().unwrap();
+        assert_eq!(rule.selector, TagSelector::Path("US/+/AUSTIN/#".to_string()));
+        assert_eq!(rule.qos, Some(QosLevel::ExactlyOnce));
+        assert_eq!(rule.retain, None);
+
+        let rule: PublishRule = "class:vibration=msgpack".parse().unwrap();
+        assert_eq!(rule.codec, Some(PayloadCodec::MessagePack));
+        assert_eq!(rule.qos, None);
+
+        assert!("US/TX".parse::<PublishRule>().is_err());
+        assert!("US/#/TX=qos0".parse::<PublishRule>().is_err());
+        assert!("US/TX=qos3".parse::<PublishRule>().is_err());
+        assert!("class:=qos0".parse::<PublishRule>().is_err());
+    }
+
+    #[test]
+    fn test_policy_for() {
+        let policies = PublishPolicies::new(
+            PublishPolicy::default(),
+            vec![
+                "US/TX/PUMP1/SETPOINT=qos2,retain".parse().unwrap(),
+                "class:vibration=qos0,no-retain".parse().unwrap(),
+                "US/CA/#=qos0".parse().unwrap(),
+            ],
+        );
+
+        assert_eq!(
+            policies.policy_for(&tag("US/TX/PUMP1/SETPOINT")),
+            PublishPolicy::new(QosLevel::ExactlyOnce, true)
+        );
+        assert_eq!(
+            policies.policy_for(&tag("US/TX/PUMP1/VIB").with_class("vibration")),
+            PublishPolicy::new(QosLevel::AtMostOnce, false)
+        );
+        assert_eq!(
+            policies.policy_for(&tag("US/CA/PUMP1/FLOW")),
+            PublishPolicy::new(QosLevel::AtMostOnce, true)
+        );
+        assert_eq!(policies.policy_for(&tag("US/TX/PUMP1/FLOW")), PublishPolicy::default());
+    }
+
+    #[test]
+    fn test_codec_for() {
+        let policies = PublishPolicies::new(
+            PublishPolicy::default(),
+            vec!["US/TX/#=qos0".parse().unwrap(), "class:vibration=cbor".parse().unwrap()],
+        )
+        .with_codec(PayloadCodec::MessagePack);
+
+        // The first matching rule wins even when it does not set a codec
+        assert_eq!(policies.codec_for(&tag("US/TX/PUMP1/VIB").with_class("vibration")), PayloadCodec::MessagePack);
+        assert_eq!(policies.codec_for(&tag("US/CA/PUMP1/VIB").with_class("vibration")), PayloadCodec::Cbor);
+        assert_eq!(policies.codec_for(&tag("US/CA/PUMP1/FLOW")), PayloadCodec::MessagePack);
+    }
+}
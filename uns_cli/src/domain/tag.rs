@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
+use crate::domain::TopicMapping;
+
 /// Represents a tag in the UNS system
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Tag {
@@ -16,6 +18,10 @@ pub struct Tag {
     /// Current value of the tag as a string
     pub value: String,
     
+    /// Optional class grouping similar tags, e.g. "vibration" or "setpoint"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    
     // Potential future fields (commented out for now)
     // pub quality: String,
     // #[serde(with = "chrono::serde::ts_seconds")]
@@ -36,9 +42,16 @@ impl Tag {
             name,
             description,
             value,
+            class: None,
         }
     }
     
+    /// Assigns the tag to a class
+    pub fn with_class(mut self, class: &str) -> Self {
+        self.class = Some(class.to_string());
+        self
+    }
+    
     /// Updates the value of the tag
     pub fn update_value(&mut self, new_value: String) -> String {
         let old_value = self.value.clone();
@@ -46,9 +59,45 @@ impl Tag {
         old_value
     }
     
-    /// Converts the tag path to an MQTT topic format (replacing '/' with '.')
-    pub fn to_mqtt_topic(&self) -> String {
-        format!("tags/{}", self.path.replace("/", "."))
+    /// Checks that a path is a well-formed tag hierarchy
+    ///
+    /// A valid path has at least one segment, no empty segments (so no leading,
+    /// trailing or doubled '/') and no MQTT wildcard or NUL characters.
+    pub fn validate_path(path: &str) -> Result<(), String> {
+        if path.is_empty() {
+            return Err("path must not be empty".to_string());
+        }
+
+        if path.split('/').any(|segment| segment.is_empty()) {
+            return Err(format!("path '{}' contains an empty segment", path));
+        }
+
+        if let Some(c) = path.chars().find(|c| matches!(c, '+' | '#' | '\0')) {
+            return Err(format!("path '{}' contains the reserved character '{}'", path, c.escape_default()));
+        }
+
+        Ok(())
+    }
+
+    /// Returns true when `path` equals `prefix` or lies below it in the hierarchy
+    ///
+    /// Matching is done on whole segments, so "US/TX" covers "US/TX/AUSTIN"
+    /// but not "US/TXX". An empty prefix covers every path.
+    pub fn path_starts_with(path: &str, prefix: &str) -> bool {
+        let prefix = prefix.trim_end_matches('/');
+        if prefix.is_empty() {
+            return true;
+        }
+        
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+    
+    /// Returns the MQTT topic of the tag under the given mapping
+    pub fn to_mqtt_topic(&self, mapping: &TopicMapping) -> String {
+        mapping.tag_topic(&self.path)
     }
 }
 
@@ -96,6 +145,27 @@ mod tests {
         assert_eq!(tag.value, "50.2");
     }
     
+    #[test]
+    fn test_validate_path() {
+        assert!(Tag::validate_path("US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE").is_ok());
+        assert!(Tag::validate_path("").is_err());
+        assert!(Tag::validate_path("/US/TX").is_err());
+        assert!(Tag::validate_path("US//TX").is_err());
+        assert!(Tag::validate_path("US/TX/").is_err());
+        assert!(Tag::validate_path("US/+/TX").is_err());
+        assert!(Tag::validate_path("US/TX/#").is_err());
+    }
+
+    #[test]
+    fn test_path_starts_with() {
+        assert!(Tag::path_starts_with("US/TX/AUSTIN", "US/TX"));
+        assert!(Tag::path_starts_with("US/TX/AUSTIN", "US/TX/"));
+        assert!(Tag::path_starts_with("US/TX", "US/TX"));
+        assert!(Tag::path_starts_with("US/TX", ""));
+        assert!(!Tag::path_starts_with("US/TXX", "US/TX"));
+        assert!(!Tag::path_starts_with("US", "US/TX"));
+    }
+
     #[test]
     fn test_to_mqtt_topic() {
         let tag = Tag::new(
@@ -106,7 +176,7 @@ mod tests {
         );
         
         assert_eq!(
-            tag.to_mqtt_topic(),
+            tag.to_mqtt_topic(&TopicMapping::default()),
             "tags/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1.PRESSURE"
         );
     }
@@ -0,0 +1,74 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::infrastructure::UnsError;
+
+/// How the database topic follows tag updates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DatabaseMode {
+    /// The full database is published again after every update
+    #[default]
+    Full,
+
+    /// Updates are published as deltas; the full database only periodically
+    /// and on request
+    Delta,
+}
+
+/// Tag values changed since the previous delta, published on `<prefix>/database/delta`
+///
+/// Deltas are numbered consecutively, and a database snapshot published in
+/// delta mode carries the `seq` of the last delta it includes. A consumer
+/// applies the deltas following its snapshot and asks for a new snapshot on
+/// `<prefix>/database/get` when it sees a gap in the numbering.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TagDelta {
+    /// Sequence number, one more than that of the previous delta
+    pub seq: u64,
+
+    /// When the change was made
+    pub timestamp: DateTime<Utc>,
+
+    /// New values keyed by tag path
+    pub changes: BTreeMap<String, String>,
+}
+
+impl TagDelta {
+    /// Creates a delta changing a single tag
+    pub fn single(seq: u64, path: &str, value: &str) -> Self {
+        Self {
+            seq,
+            timestamp: Utc::now(),
+            changes: BTreeMap::from([(path.to_string(), value.to_string())]),
+        }
+    }
+
+    /// Encodes the delta payload
+    pub fn to_payload(&self) -> Result<Vec<u8>, UnsError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Decodes a delta payload
+    pub fn from_payload(payload: &[u8]) -> Result<Self, UnsError> {
+        serde_json::from_slice(payload).map_err(|e| UnsError::Validation(format!("Invalid delta payload: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_round_trip() {
+        let delta = TagDelta::single(42, "US/TX/PUMP1/PRESSURE", "50.2");
+
+        let payload = delta.to_payload().unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["seq"], 42);
+        assert_eq!(json["changes"]["US/TX/PUMP1/PRESSURE"], "50.2");
+
+        assert_eq!(TagDelta::from_payload(&payload).unwrap(), delta);
+        assert!(TagDelta::from_payload(b"{}").is_err());
+    }
+}
@@ -18,6 +18,16 @@ pub trait TagRepository: Send + Sync {
     /// Updates a tag's value
     async fn update_tag(&self, path: &str, value: String) -> Result<Option<Tag>, UnsError>;
     
+    /// Inserts a tag, replacing any tag at the same path
+    async fn put_tag(&self, tag: Tag) -> Result<(), UnsError>;
+    
     /// Gets all tags
     async fn get_all_tags(&self) -> Result<HashMap<String, Tag>, UnsError>;
+    
+    /// Gets all tags at or below a path prefix (e.g. "US/TX/AUSTIN")
+    async fn get_tags_by_prefix(&self, prefix: &str) -> Result<HashMap<String, Tag>, UnsError> {
+        let mut tags = self.get_all_tags().await?;
+        tags.retain(|path, _| Tag::path_starts_with(path, prefix));
+        Ok(tags)
+    }
 }
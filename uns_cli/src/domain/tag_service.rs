@@ -2,6 +2,9 @@ use async_trait::async_trait;
 use crate::domain::Tag;
 use crate::infrastructure::UnsError;
 use std::collections::HashMap;
+use tokio::sync::watch;
+
+use crate::infrastructure::mqtt::ConnectionState;
 
 /// Service interface for tag operations
 #[async_trait]
@@ -18,4 +21,16 @@ pub trait TagService: Send + Sync {
     
     /// Gets a tag by its path
     async fn get_tag(&self, path: &str) -> Result<Option<Tag>, UnsError>;
+    
+    /// Publishes a full snapshot of the tag database
+    async fn publish_database(&self) -> Result<(), UnsError>;
+    
+    /// Publishes the current state of every tag and the full database again
+    async fn republish_tags(&self) -> Result<(), UnsError>;
+    
+    /// Publishes every tag again flagged as stale, before the instance goes away
+    async fn mark_tags_stale(&self) -> Result<(), UnsError>;
+    
+    /// Returns a receiver tracking the state of the broker connection
+    fn connection_state(&self) -> watch::Receiver<ConnectionState>;
 }
@@ -0,0 +1,279 @@
+use crate::domain::Tag;
+use crate::infrastructure::UnsError;
+
+/// Namespace used when none is configured
+pub const DEFAULT_TOPIC_PREFIX: &str = "tags";
+
+/// Topic levels below the namespace used for control messages rather than tags
+const RESERVED_LEVELS: [&str; 3] = ["set", "reply", "database"];
+
+/// How a tag path is laid out below the namespace
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TopicStrategy {
+    /// One topic level per path segment: `tags/US/TX/PUMP1`
+    Native,
+
+    /// A single topic level with '.' between segments: `tags/US.TX.PUMP1`
+    #[default]
+    Dotted,
+}
+
+/// Maps tag paths to MQTT topics and back
+///
+/// Tags are published below `<prefix>/`, write requests below `<prefix>/set/`,
+/// replies below `<prefix>/reply/` and the database on `<prefix>/database`,
+/// with its deltas and snapshot requests below it.
+///
+/// The mapping is lossless: '%' is escaped as `%25` and, in the dotted
+/// strategy, a '.' inside a segment as `%2E`. A first level that would
+/// collide with a control level gets its first character escaped as well.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicMapping {
+    strategy: TopicStrategy,
+    prefix: String,
+}
+
+impl Default for TopicMapping {
+    fn default() -> Self {
+        Self {
+            strategy: TopicStrategy::default(),
+            prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+        }
+    }
+}
+
+impl TopicMapping {
+    /// Creates a mapping below a custom namespace such as `acme/uns`
+    pub fn new(strategy: TopicStrategy, prefix: &str) -> Result<Self, UnsError> {
+        let valid = !prefix.is_empty()
+            && !prefix.split('/').any(|level| level.is_empty())
+            && !prefix.contains(['+', '#', '\0']);
+        if !valid {
+            return Err(UnsError::Validation(format!(
+                "Topic prefix must be one or more non-empty levels without wildcards: '{}'",
+                prefix
+            )));
+        }
+
+        Ok(Self {
+            strategy,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    /// Returns the layout of tag paths
+    pub fn strategy(&self) -> TopicStrategy {
+        self.strategy
+    }
+
+    /// Returns the namespace of all topics
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns the topic of a tag
+    pub fn tag_topic(&self, path: &str) -> String {
+        format!("{}/{}", self.prefix, self.encode(path))
+    }
+
+    /// Returns the path of a tag topic, or `None` for any other topic
+    pub fn tag_path(&self, topic: &str) -> Option<String> {
+        let rest = topic.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        let first_level = rest.split('/').next()?;
+        if RESERVED_LEVELS.contains(&first_level) {
+            return None;
+        }
+
+        self.decode(rest)
+    }
+
+    /// Returns the subscription filter covering every tag
+    pub fn tag_filter(&self) -> String {
+        format!("{}/#", self.prefix)
+    }
+
+    /// Returns the topic of the full tag database
+    pub fn database_topic(&self) -> String {
+        format!("{}/database", self.prefix)
+    }
+
+    /// Returns the topic of database deltas
+    pub fn delta_topic(&self) -> String {
+        format!("{}/database/delta", self.prefix)
+    }
+
+    /// Returns the topic listing the parts of a split database
+    pub fn database_index_topic(&self) -> String {
+        format!("{}/database/index", self.prefix)
+    }
+
+    /// Returns the topic of one part of a split database, encoded like a tag path
+    pub fn database_part_topic(&self, part_id: &str) -> String {
+        format!("{}/database/part/{}", self.prefix, self.encode(part_id))
+    }
+
+    /// Returns the topic consumers publish to when they need a full database snapshot
+    pub fn database_request_topic(&self) -> String {
+        format!("{}/database/get", self.prefix)
+    }
+
+    /// Returns the topic of a write request for a tag
+    pub fn write_topic(&self, path: &str) -> String {
+        format!("{}/set/{}", self.prefix, self.encode(path))
+    }
+
+    /// Returns the path of a write request topic, or `None` for any other topic
+    pub fn write_path(&self, topic: &str) -> Option<String> {
+        let rest = topic.strip_prefix(&self.prefix)?.strip_prefix("/set/")?;
+        self.decode(rest)
+    }
+
+    /// Returns the subscription filter covering every write request
+    pub fn write_filter(&self) -> String {
+        format!("{}/set/#", self.prefix)
+    }
+
+    /// Returns the topic prefix replies to write requests are published under
+    pub fn reply_prefix(&self) -> String {
+        format!("{}/reply/", self.prefix)
+    }
+
+    /// Encodes a path as the topic levels below the namespace
+    fn encode(&self, path: &str) -> String {
+        let (separator, escape_dot) = match self.strategy {
+            TopicStrategy::Native => ("/", false),
+            TopicStrategy::Dotted => (".", true),
+        };
+
+        let mut encoded = path
+            .split('/')
+            .map(|segment| {
+                let mut level = String::with_capacity(segment.len());
+                for c in segment.chars() {
+                    match c {
+                        '%' => level.push_str("%25"),
+                        '.' if escape_dot => level.push_str("%2E"),
+                        _ => level.push(c),
+                    }
+                }
+                level
+            })
+            .collect::<Vec<_>>()
+            .join(separator);
+
+        // Keep tags off the control topics
+        let first_level = encoded.split('/').next().unwrap_or_default();
+        if RESERVED_LEVELS.contains(&first_level) {
+            let first = encoded.remove(0);
+            encoded.insert_str(0, &format!("%{:02X}", first as u32));
+        }
+
+        encoded
+    }
+
+    /// Decodes the topic levels below the namespace into a valid path
+    fn decode(&self, levels: &str) -> Option<String> {
+        let segments: Vec<&str> = match self.strategy {
+            TopicStrategy::Native => levels.split('/').collect(),
+            TopicStrategy::Dotted if levels.contains('/') => return None,
+            TopicStrategy::Dotted => levels.split('.').collect(),
+        };
+
+        let path = segments
+            .into_iter()
+            .map(percent_decode)
+            .collect::<Option<Vec<_>>>()?
+            .join("/");
+
+        Tag::validate_path(&path).ok()?;
+        Some(path)
+    }
+}
+
+/// Replaces `%XX` escapes with the bytes they stand for
+fn percent_decode(level: &str) -> Option<String> {
+    let bytes = level.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = level.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE";
+
+    #[test]
+    fn test_dotted_mapping() {
+        let mapping = TopicMapping::default();
+        assert_eq!(mapping.tag_topic(PATH), "tags/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1.PRESSURE");
+        assert_eq!(mapping.write_topic(PATH), "tags/set/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1.PRESSURE");
+        assert_eq!(mapping.tag_path(&mapping.tag_topic(PATH)).as_deref(), Some(PATH));
+
+        // Dots inside a segment no longer look like separators
+        let path = "US/TX/PUMP1.5/FLOW%";
+        assert_eq!(mapping.tag_topic(path), "tags/US.TX.PUMP1%2E5.FLOW%25");
+        assert_eq!(mapping.tag_path(&mapping.tag_topic(path)).as_deref(), Some(path));
+        assert_eq!(mapping.write_path(&mapping.write_topic(path)).as_deref(), Some(path));
+
+        assert_eq!(mapping.tag_path("tags/US/TX"), None);
+        assert_eq!(mapping.tag_path("tags/US..TX"), None);
+        assert_eq!(mapping.tag_path("tags/database"), None);
+    }
+
+    #[test]
+    fn test_native_mapping() {
+        let mapping = TopicMapping::new(TopicStrategy::Native, "acme/uns").unwrap();
+        assert_eq!(mapping.tag_topic(PATH), format!("acme/uns/{}", PATH));
+        assert_eq!(mapping.tag_path(&format!("acme/uns/{}", PATH)).as_deref(), Some(PATH));
+        assert_eq!(mapping.write_filter(), "acme/uns/set/#");
+        assert_eq!(mapping.database_topic(), "acme/uns/database");
+        assert_eq!(mapping.delta_topic(), "acme/uns/database/delta");
+        assert_eq!(mapping.database_part_topic("US/TX"), "acme/uns/database/part/US/TX");
+        assert_eq!(TopicMapping::default().database_part_topic("US/TX"), "tags/database/part/US.TX");
+        assert_eq!(mapping.tag_path("acme/uns/database/get"), None);
+        assert_eq!(mapping.reply_prefix(), "acme/uns/reply/");
+
+        let path = "US/TX/PUMP1.5";
+        assert_eq!(mapping.tag_topic(path), "acme/uns/US/TX/PUMP1.5");
+        assert_eq!(mapping.write_path(&mapping.write_topic(path)).as_deref(), Some(path));
+
+        assert_eq!(mapping.tag_path("tags/US/TX"), None);
+        assert_eq!(mapping.tag_path("acme/uns/set/US/TX"), None);
+    }
+
+    #[test]
+    fn test_reserved_first_levels() {
+        for mapping in [
+            TopicMapping::default(),
+            TopicMapping::new(TopicStrategy::Native, "tags").unwrap(),
+        ] {
+            for path in ["set/US", "reply", "database"] {
+                let topic = mapping.tag_topic(path);
+                assert!(!topic.starts_with("tags/set/") && !topic.starts_with("tags/reply"));
+                assert_ne!(topic, mapping.database_topic());
+                assert_eq!(mapping.tag_path(&topic).as_deref(), Some(path));
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_prefixes() {
+        assert!(TopicMapping::new(TopicStrategy::Native, "").is_err());
+        assert!(TopicMapping::new(TopicStrategy::Native, "acme/").is_err());
+        assert!(TopicMapping::new(TopicStrategy::Native, "acme/+").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::TopicMapping;
+use crate::infrastructure::UnsError;
+
+/// Payload of a write request message
+#[derive(Serialize, Deserialize)]
+struct WriteRequestPayload {
+    value: String,
+}
+
+/// A request to change a tag value, sent to the running instance over MQTT
+///
+/// The request for a tag is published on `<prefix>/set/<topic>`, where
+/// `<topic>` is the tag's own topic without the prefix (see `TopicMapping`),
+/// with a JSON payload of the form `{"value": "50.2"}`. A requester that wants to know the outcome
+/// sets the MQTT 5 response topic and correlation data, carried in `reply_to`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteRequest {
+    /// Path of the tag to update
+    pub path: String,
+
+    /// New value for the tag
+    pub value: String,
+
+    /// Where the running instance should send its reply, if anywhere
+    pub reply_to: Option<ReplyTo>,
+}
+
+impl WriteRequest {
+    /// Creates a new write request
+    pub fn new(path: String, value: String) -> Self {
+        Self {
+            path,
+            value,
+            reply_to: None,
+        }
+    }
+
+    /// Asks for a reply on the given topic
+    pub fn with_reply_to(mut self, reply_to: ReplyTo) -> Self {
+        self.reply_to = Some(reply_to);
+        self
+    }
+
+    /// Returns the topic this request is published on
+    pub fn to_mqtt_topic(&self, mapping: &TopicMapping) -> String {
+        mapping.write_topic(&self.path)
+    }
+
+    /// Encodes the request payload
+    pub fn to_payload(&self) -> Result<Vec<u8>, UnsError> {
+        let payload = WriteRequestPayload {
+            value: self.value.clone(),
+        };
+        Ok(serde_json::to_vec(&payload)?)
+    }
+
+    /// Decodes a write request from a received message
+    pub fn from_mqtt(mapping: &TopicMapping, topic: &str, payload: &[u8]) -> Result<Self, UnsError> {
+        let path = mapping
+            .write_path(topic)
+            .ok_or_else(|| UnsError::Validation(format!("Not a valid write request topic: {}", topic)))?;
+
+        let payload: WriteRequestPayload = serde_json::from_slice(payload).map_err(|e| {
+            UnsError::Validation(format!("Invalid write request payload on {}: {}", topic, e))
+        })?;
+
+        Ok(Self::new(path, payload.value))
+    }
+}
+
+/// Response topic and correlation data of a request expecting a reply
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplyTo {
+    /// Topic the reply is published on
+    pub topic: String,
+
+    /// Data echoed in the reply so the requester can match it
+    pub correlation_data: Option<Vec<u8>>,
+}
+
+impl ReplyTo {
+    /// Creates a reply topic and correlation data no other requester uses
+    pub fn unique(mapping: &TopicMapping) -> Self {
+        let id: [u8; 16] = rand::thread_rng().gen();
+        let hex: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+
+        Self {
+            topic: format!("{}{}", mapping.reply_prefix(), hex),
+            correlation_data: Some(id.to_vec()),
+        }
+    }
+}
+
+/// Outcome of a write request
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteStatus {
+    /// The tag was updated and published
+    Ok,
+
+    /// No tag exists at the requested path
+    NotFound,
+
+    /// The request was malformed
+    Invalid,
+
+    /// The tag may not be written by remote requests
+    PermissionDenied,
+
+    /// The update failed for another reason
+    Error,
+}
+
+/// Reply to a write request, published as `{"status": "not_found", "message": "..."}`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WriteResponse {
+    /// Outcome of the request
+    pub status: WriteStatus,
+
+    /// Details about a failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl WriteResponse {
+    /// Creates the reply describing the result of handling a request
+    pub fn from_result(result: &Result<(), UnsError>) -> Self {
+        let (status, message) = match result {
+            Ok(()) => (WriteStatus::Ok, None),
+            Err(UnsError::NotFound(msg)) => (WriteStatus::NotFound, Some(msg.clone())),
+            Err(UnsError::Validation(msg)) => (WriteStatus::Invalid, Some(msg.clone())),
+            Err(UnsError::PermissionDenied(msg)) => (WriteStatus::PermissionDenied, Some(msg.clone())),
+            Err(e) => (WriteStatus::Error, Some(e.to_string())),
+        };
+
+        Self { status, message }
+    }
+
+    /// Converts the reply back into the result the running instance saw
+    pub fn into_result(self) -> Result<(), UnsError> {
+        let message = self.message.unwrap_or_default();
+
+        match self.status {
+            WriteStatus::Ok => Ok(()),
+            WriteStatus::NotFound => Err(UnsError::NotFound(message)),
+            WriteStatus::Invalid => Err(UnsError::Validation(message)),
+            WriteStatus::PermissionDenied => Err(UnsError::PermissionDenied(message)),
+            WriteStatus::Error => Err(UnsError::Other(message)),
+        }
+    }
+
+    /// Encodes the reply payload
+    pub fn to_payload(&self) -> Result<Vec<u8>, UnsError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Decodes a received reply
+    pub fn from_payload(payload: &[u8]) -> Result<Self, UnsError> {
+        serde_json::from_slice(payload)
+            .map_err(|e| UnsError::Validation(format!("Invalid write response: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let request = WriteRequest::new(
+            "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE".to_string(),
+            "50.2".to_string(),
+        );
+
+        let mapping = TopicMapping::default();
+        let topic = request.to_mqtt_topic(&mapping);
+        assert_eq!(topic, "tags/set/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1.PRESSURE");
+
+        let payload = request.to_payload().unwrap();
+        assert_eq!(WriteRequest::from_mqtt(&mapping, &topic, &payload).unwrap(), request);
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let mapping = TopicMapping::default();
+        assert!(WriteRequest::from_mqtt(&mapping, "tags/US.TX", br#"{"value":"1"}"#).is_err());
+        assert!(WriteRequest::from_mqtt(&mapping, "tags/set/US..TX", br#"{"value":"1"}"#).is_err());
+        assert!(WriteRequest::from_mqtt(&mapping, "tags/set/US.TX", b"50.2").is_err());
+        assert!(WriteRequest::from_mqtt(&mapping, "tags/set/US.TX", br#"{"value":50.2}"#).is_err());
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let result = Err(UnsError::NotFound("Tag not found: A/B".to_string()));
+        let response = WriteResponse::from_result(&result);
+
+        let payload = response.to_payload().unwrap();
+        assert_eq!(payload, br#"{"status":"not_found","message":"Tag not found: A/B"}"#.to_vec());
+
+        let decoded = WriteResponse::from_payload(&payload).unwrap();
+        assert!(matches!(decoded.into_result(), Err(UnsError::NotFound(msg)) if msg == "Tag not found: A/B"));
+
+        let ok = WriteResponse::from_payload(br#"{"status":"ok"}"#).unwrap();
+        assert!(ok.into_result().is_ok());
+    }
+
+    #[test]
+    fn test_unique_reply_to() {
+        let mapping = TopicMapping::default();
+        let first = ReplyTo::unique(&mapping);
+        let second = ReplyTo::unique(&mapping);
+
+        assert!(first.topic.starts_with("tags/reply/"));
+        assert_ne!(first.topic, second.topic);
+        assert_ne!(first.correlation_data, second.correlation_data);
+    }
+}
@@ -15,7 +15,13 @@ pub enum UnsError {
     
     /// Error when a tag is not found
     NotFound(String),
-    
+
+    /// Error when input data fails validation
+    Validation(String),
+
+    /// Error when an operation is not allowed
+    PermissionDenied(String),
+
     /// Any other error
     Other(String),
 }
@@ -27,6 +33,8 @@ impl fmt::Display for UnsError {
             UnsError::Mqtt(msg) => write!(f, "MQTT error: {}", msg),
             UnsError::Serialization(msg) => write!(f, "Serialization error: {}", msg),
             UnsError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            UnsError::Validation(msg) => write!(f, "Validation error: {}", msg),
+            UnsError::PermissionDenied(msg) => write!(f, "Permission denied: {}", msg),
             UnsError::Other(msg) => write!(f, "Error: {}", msg),
         }
     }
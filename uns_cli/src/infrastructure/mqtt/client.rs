@@ -1,72 +1,393 @@
 use async_trait::async_trait;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use bytes::Bytes;
+use log::{error, info, warn};
+use rumqttc::v5::{
+    mqttbytes::{
+        v5::{ConnectProperties, LastWill, Packet, Publish, PublishProperties},
+        QoS,
+    },
+    AsyncClient, Event, EventLoop, MqttOptions,
+};
+use rumqttc::Transport;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio;
+use tokio::{
+    self,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch, Mutex as SendOrder,
+    },
+};
 
+use crate::domain::{PublishPolicy, QosLevel};
+use crate::infrastructure::mqtt::connection::{ConnectionOptions, ConnectionState, ReconnectPolicy, WillMessage};
+use crate::infrastructure::mqtt::delivery::DeliveryTracker;
+use crate::infrastructure::mqtt::subscriber::{
+    topic_matches, IncomingMessage, MessageProperties, MessageStream, MqttSubscriber,
+};
 use crate::infrastructure::UnsError;
 
+/// Number of received messages buffered per subscription stream
+const STREAM_CAPACITY: usize = 1024;
+
+/// A subscription stream registered with the event loop
+struct Route {
+    filter: String,
+    sender: mpsc::Sender<IncomingMessage>,
+}
+
 /// MQTT client interface
 #[async_trait]
 #[cfg_attr(test, mockall::automock)]
 pub trait MqttClient: Send + Sync {
-    /// Publishes a message to a topic
-    async fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) -> Result<(), UnsError>;
+    /// Publishes a message to a topic with the given QoS and retain flag
+    ///
+    /// Resolves once the broker acknowledged the message as its QoS requires.
+    async fn publish(&self, topic: &str, payload: Vec<u8>, policy: PublishPolicy) -> Result<(), UnsError>;
+    
+    /// Publishes a message carrying MQTT 5 properties, resolving once it is acknowledged
+    async fn publish_with_properties(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        policy: PublishPolicy,
+        properties: MessageProperties,
+    ) -> Result<(), UnsError>;
     
     /// Subscribes to a topic
     async fn subscribe(&self, topic: &str) -> Result<(), UnsError>;
+    
+    /// Returns a receiver tracking the state of the broker connection
+    fn connection_state(&self) -> watch::Receiver<ConnectionState>;
+    
+    /// Replaces the Last Will sent with the next connect
+    fn set_last_will(&self, will: WillMessage);
 }
 
-/// Implementation of MQTT client using rumqttc
+/// Implementation of MQTT client using the MQTT 5 client of rumqttc
 pub struct RumqttcClient {
     client: AsyncClient,
+    routes: Arc<Mutex<Vec<Route>>>,
+    filters: Arc<Mutex<Vec<String>>>,
+    state: watch::Receiver<ConnectionState>,
+    pending_will: Arc<Mutex<Option<WillMessage>>>,
+    deliveries: Arc<DeliveryTracker>,
+    send_order: SendOrder<()>,
+    delivery_timeout: Duration,
 }
 
 impl RumqttcClient {
-    /// Creates a new MQTT client
+    /// Creates a new MQTT client with default connection settings
     pub async fn new(client_id: &str, host: &str, port: u16) -> Result<Self, UnsError> {
-        let mut mqtt_options = MqttOptions::new(client_id, host, port);
-        mqtt_options.set_keep_alive(Duration::from_secs(5));
-
-        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+        Self::connect(ConnectionOptions::new(client_id, host, port)).await
+    }
+    
+    /// Creates a new MQTT client from connection options
+    pub async fn connect(options: ConnectionOptions) -> Result<Self, UnsError> {
+        let mqtt_options = Self::mqtt_options(&options)?;
+        let (client, eventloop) = AsyncClient::new(mqtt_options, 10);
+        let routes: Arc<Mutex<Vec<Route>>> = Arc::new(Mutex::new(Vec::new()));
+        let filters: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let (state_tx, state) = watch::channel(ConnectionState::Connecting);
+        let pending_will: Arc<Mutex<Option<WillMessage>>> = Arc::new(Mutex::new(None));
+        let deliveries = Arc::new(DeliveryTracker::new());
 
         // Spawn the event loop in a separate task
-        tokio::spawn(async move {
-            loop {
-                match eventloop.poll().await {
-                    Ok(_notification) => { /* Handle notifications if needed */ }
-                    Err(e) => {
-                        eprintln!("Error in MQTT event loop: {:?}", e);
-                        // Implement reconnection logic here if needed
-                        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::spawn(Self::run_event_loop(
+            eventloop,
+            client.clone(),
+            options.reconnect,
+            routes.clone(),
+            filters.clone(),
+            state_tx,
+            pending_will.clone(),
+            deliveries.clone(),
+        ));
+
+        Ok(Self {
+            client,
+            routes,
+            filters,
+            state,
+            pending_will,
+            deliveries,
+            send_order: SendOrder::new(()),
+            delivery_timeout: options.delivery_timeout,
+        })
+    }
+    
+    /// Converts connection options into their rumqttc form
+    fn mqtt_options(options: &ConnectionOptions) -> Result<MqttOptions, UnsError> {
+        let mut mqtt_options = MqttOptions::new(options.client_id.clone(), options.host.clone(), options.port);
+        mqtt_options.set_keep_alive(options.keep_alive);
+        
+        // A ws:// or wss:// URL in place of the host selects MQTT over WebSockets; the URL includes the port
+        match (options.host.split_once("://").map(|(scheme, _)| scheme), &options.tls) {
+            (None, None) => {}
+            (None, Some(tls)) => {
+                mqtt_options.set_transport(Transport::Tls(tls.to_tls_configuration()?));
+            }
+            (Some("ws"), None) => {
+                mqtt_options.set_transport(Transport::Ws);
+            }
+            (Some("ws"), Some(_)) => {
+                return Err(UnsError::Validation("Use a wss:// URL for WebSockets over TLS".to_string()));
+            }
+            (Some("wss"), tls) => {
+                let tls = tls.clone().unwrap_or_default();
+                mqtt_options.set_transport(Transport::Wss(tls.to_tls_configuration()?));
+            }
+            (Some(scheme), _) => {
+                return Err(UnsError::Validation(format!(
+                    "Unsupported broker URL scheme '{}', expected ws or wss",
+                    scheme
+                )));
+            }
+        }
+        
+        if let Some(credentials) = &options.credentials {
+            mqtt_options.set_credentials(credentials.username.clone(), credentials.password.clone());
+        }
+        
+        if let Some(will) = &options.last_will {
+            mqtt_options.set_last_will(Self::last_will(will));
+        }
+        
+        // Resume the session the broker kept, with its subscriptions and queued messages
+        if let Some(expiry) = options.session_expiry {
+            let mut properties = ConnectProperties::new();
+            properties.session_expiry_interval = Some(expiry.as_secs().min(u32::MAX as u64) as u32);
+            mqtt_options.set_clean_start(false);
+            mqtt_options.set_connect_properties(properties);
+        }
+        
+        Ok(mqtt_options)
+    }
+    
+    /// Converts a will message into its rumqttc form
+    fn last_will(will: &WillMessage) -> LastWill {
+        LastWill::new(will.topic.clone(), will.payload.clone(), QoS::AtLeastOnce, will.retain, None)
+    }
+
+    /// Converts a delivery guarantee into its rumqttc form
+    fn qos(qos: QosLevel) -> QoS {
+        match qos {
+            QosLevel::AtMostOnce => QoS::AtMostOnce,
+            QosLevel::AtLeastOnce => QoS::AtLeastOnce,
+            QosLevel::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+
+    /// Drives the connection, reconnecting with backoff when it is lost
+    ///
+    /// The broker forgets subscriptions of a clean session, so every active
+    /// filter is subscribed again once a reconnect is acknowledged.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_event_loop(
+        mut eventloop: EventLoop,
+        client: AsyncClient,
+        policy: ReconnectPolicy,
+        routes: Arc<Mutex<Vec<Route>>>,
+        filters: Arc<Mutex<Vec<String>>>,
+        state: watch::Sender<ConnectionState>,
+        pending_will: Arc<Mutex<Option<WillMessage>>>,
+        deliveries: Arc<DeliveryTracker>,
+    ) {
+        let mut attempt = 0;
+        
+        loop {
+            // Only read when connecting, so a replaced will applies from the next reconnect
+            if let Some(will) = pending_will.lock().unwrap().take() {
+                eventloop.options.set_last_will(Self::last_will(&will));
+            }
+            
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(ack))) => {
+                    if attempt > 0 {
+                        info!("Reconnected to MQTT broker after {} attempt(s)", attempt);
+                    }
+                    attempt = 0;
+                    
+                    if !ack.session_present {
+                        let filters = filters.lock().unwrap().clone();
+                        if !filters.is_empty() {
+                            // Requests go through the channel this task drains, so send them from another task
+                            let client = client.clone();
+                            tokio::spawn(async move {
+                                for filter in filters {
+                                    if let Err(e) = client.subscribe(filter.as_str(), QoS::AtLeastOnce).await {
+                                        error!("Failed to resubscribe to {}: {}", filter, e);
+                                    }
+                                }
+                            });
+                        }
                     }
+                    
+                    state.send_replace(ConnectionState::Connected);
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    Self::dispatch(&routes, publish);
+                }
+                Ok(event @ (Event::Outgoing(_) | Event::Incoming(Packet::PubAck(_) | Packet::PubRec(_) | Packet::PubComp(_)))) => {
+                    deliveries.track(&event);
+                }
+                Ok(_notification) => { /* Handle other notifications if needed */ }
+                Err(e) => {
+                    attempt += 1;
+                    
+                    if !policy.allows(attempt) {
+                        error!("Giving up on MQTT broker after {} failed attempts: {}", attempt - 1, e);
+                        deliveries.connection_lost(&[], &e.to_string());
+                        state.send_replace(ConnectionState::Failed(e.to_string()));
+                        break;
+                    }
+                    
+                    // Publishes the event loop does not send again will never be acknowledged
+                    deliveries.connection_lost(eventloop.pending.as_slice(), &e.to_string());
+                    
+                    let delay = policy.delay(attempt);
+                    warn!("MQTT connection error: {}. Reconnecting in {:?} (attempt {})", e, delay, attempt);
+                    state.send_replace(ConnectionState::Reconnecting { attempt, delay });
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+    
+    /// Hands a publish to the event loop and waits for the broker to acknowledge it
+    ///
+    /// Publishes are registered with the tracker in the order they reach the
+    /// event loop, which is how acknowledgments are matched to them. Cancelling
+    /// the returned future is safe: a publish that did not reach the event loop
+    /// leaves no registration behind.
+    async fn send_confirmed<F>(&self, publish: F) -> Result<(), UnsError>
+    where
+        F: Future<Output = Result<(), rumqttc::v5::ClientError>>,
+    {
+        let delivery = {
+            let _order = self.send_order.lock().await;
+            // Removed again when dropped early: the publish failed, or this future was cancelled
+            // while the request channel was full
+            let pending = self.deliveries.expect();
+            publish.await.map_err(|e| UnsError::Mqtt(e.to_string()))?;
+            pending.handed_over()
+        };
+        
+        delivery.confirmed(self.delivery_timeout).await
+    }
+    
+    /// Remembers a filter so it can be restored after a reconnect
+    fn track_filter(&self, filter: &str) {
+        let mut filters = self.filters.lock().unwrap();
+        if !filters.iter().any(|f| f == filter) {
+            filters.push(filter.to_string());
+        }
+    }
+    
+    /// Delivers a received publish to every stream whose filter matches
+    ///
+    /// Never blocks the event loop: a message is dropped for a stream whose
+    /// buffer is full, and streams that were dropped are removed.
+    fn dispatch(routes: &Mutex<Vec<Route>>, publish: Publish) {
+        let properties = publish.properties.unwrap_or_default();
+        let message = IncomingMessage {
+            topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+            payload: publish.payload.to_vec(),
+            retain: publish.retain,
+            qos: publish.qos,
+            properties: MessageProperties {
+                response_topic: properties.response_topic,
+                correlation_data: properties.correlation_data.map(|data| data.to_vec()),
+                content_type: properties.content_type,
+            },
+        };
+        
+        let mut routes = routes.lock().unwrap();
+        routes.retain(|route| {
+            if !topic_matches(&route.filter, &message.topic) {
+                return !route.sender.is_closed();
+            }
+            
+            match route.sender.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Subscriber for {} is lagging, message on {} dropped", route.filter, message.topic);
+                    true
                 }
+                Err(TrySendError::Closed(_)) => false,
             }
         });
-
-        Ok(Self { client })
     }
 }
 
 #[async_trait]
 impl MqttClient for RumqttcClient {
-    async fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) -> Result<(), UnsError> {
-        self.client
-            .publish(topic, QoS::AtLeastOnce, retain, payload)
+    async fn publish(&self, topic: &str, payload: Vec<u8>, policy: PublishPolicy) -> Result<(), UnsError> {
+        self.send_confirmed(self.client.publish(topic, Self::qos(policy.qos), policy.retain, payload)).await
+    }
+    
+    async fn publish_with_properties(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        policy: PublishPolicy,
+        properties: MessageProperties,
+    ) -> Result<(), UnsError> {
+        let properties = PublishProperties {
+            response_topic: properties.response_topic,
+            correlation_data: properties.correlation_data.map(Bytes::from),
+            content_type: properties.content_type,
+            ..PublishProperties::default()
+        };
+        
+        self.send_confirmed(self.client.publish_with_properties(topic, Self::qos(policy.qos), policy.retain, payload, properties))
             .await
-            .map_err(|e| UnsError::Mqtt(e.to_string()))
     }
 
     async fn subscribe(&self, topic: &str) -> Result<(), UnsError> {
+        self.track_filter(topic);
         self.client
             .subscribe(topic, QoS::AtLeastOnce)
             .await
             .map_err(|e| UnsError::Mqtt(e.to_string()))
     }
+    
+    fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+    
+    fn set_last_will(&self, will: WillMessage) {
+        *self.pending_will.lock().unwrap() = Some(will);
+    }
+}
+
+#[async_trait]
+impl MqttSubscriber for RumqttcClient {
+    async fn subscribe_stream(&self, filter: &str) -> Result<MessageStream, UnsError> {
+        // Register the route first so retained messages sent right after the
+        // subscription is acknowledged are not missed
+        let (sender, stream) = MessageStream::channel(STREAM_CAPACITY);
+        self.routes.lock().unwrap().push(Route {
+            filter: filter.to_string(),
+            sender,
+        });
+        
+        MqttClient::subscribe(self, filter).await?;
+        Ok(stream)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "embedded-broker")]
+    use crate::domain::QosLevel;
+    #[cfg(feature = "embedded-broker")]
+    use crate::infrastructure::mqtt::connection::ReconnectPolicy;
+    use crate::infrastructure::mqtt::connection::TlsOptions;
+    #[cfg(feature = "embedded-broker")]
+    use crate::infrastructure::mqtt::EmbeddedBroker;
     use mockall::predicate::*;
     use mockall::*;
 
@@ -75,8 +396,141 @@ mod tests {
         
         #[async_trait]
         impl MqttClient for MqttClient {
-            async fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) -> Result<(), UnsError>;
+            async fn publish(&self, topic: &str, payload: Vec<u8>, policy: PublishPolicy) -> Result<(), UnsError>;
+            async fn publish_with_properties(
+                &self,
+                topic: &str,
+                payload: Vec<u8>,
+                policy: PublishPolicy,
+                properties: MessageProperties,
+            ) -> Result<(), UnsError>;
             async fn subscribe(&self, topic: &str) -> Result<(), UnsError>;
+            fn connection_state(&self) -> watch::Receiver<ConnectionState>;
+            fn set_last_will(&self, will: WillMessage);
         }
     }
+    
+    #[tokio::test]
+    async fn test_dispatch_routes_by_filter() {
+        let (set_sender, mut set_stream) = MessageStream::channel(10);
+        let (all_sender, mut all_stream) = MessageStream::channel(10);
+        let (closed_sender, closed_stream) = MessageStream::channel(10);
+        drop(closed_stream);
+        
+        let routes = Mutex::new(vec![
+            Route { filter: "tags/set/#".to_string(), sender: set_sender },
+            Route { filter: "tags/#".to_string(), sender: all_sender },
+            Route { filter: "tags/#".to_string(), sender: closed_sender },
+        ]);
+        
+        let properties = PublishProperties {
+            response_topic: Some("tags/reply/abc".to_string()),
+            correlation_data: Some(Bytes::from_static(b"42")),
+            content_type: Some("application/cbor".to_string()),
+            ..PublishProperties::default()
+        };
+        let mut publish = Publish::new("tags/US.TX", QoS::AtMostOnce, b"1".to_vec(), Some(properties));
+        publish.retain = true;
+        RumqttcClient::dispatch(&routes, publish);
+        
+        // Only the matching stream receives the message, with its flags
+        let message = all_stream.recv().await.unwrap();
+        assert_eq!(message.topic, "tags/US.TX");
+        assert!(message.retain);
+        assert_eq!(message.qos, QoS::AtMostOnce);
+        assert_eq!(message.properties.response_topic.as_deref(), Some("tags/reply/abc"));
+        assert_eq!(message.properties.correlation_data, Some(b"42".to_vec()));
+        assert_eq!(message.properties.content_type.as_deref(), Some("application/cbor"));
+        assert!(set_stream.try_recv().is_none());
+        
+        // The dropped stream was removed
+        assert_eq!(routes.lock().unwrap().len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_publish_fails_without_acknowledgment() {
+        // Nothing listens on this port, so nothing is ever acknowledged
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut options = ConnectionOptions::new("test_unacknowledged", "127.0.0.1", port);
+        options.delivery_timeout = Duration::from_millis(200);
+        let client = RumqttcClient::connect(options).await.unwrap();
+        
+        let result = client.publish("tags/A", b"1".to_vec(), PublishPolicy::default()).await;
+        assert!(matches!(result, Err(UnsError::Mqtt(_))));
+    }
+    
+    #[cfg(feature = "embedded-broker")]
+    #[tokio::test]
+    async fn test_cancelled_publish_does_not_take_a_later_acknowledgment() {
+        // The broker starts later, so publishes pile up in the request channel first
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut options = ConnectionOptions::new("test_cancelled_publish", "127.0.0.1", address.port());
+        options.reconnect = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(200),
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+        };
+        options.delivery_timeout = Duration::from_secs(5);
+        let client = Arc::new(RumqttcClient::connect(options).await.unwrap());
+        let policy = PublishPolicy::new(QosLevel::AtLeastOnce, false);
+        
+        // Fill the request channel, then block one more publish on it
+        let mut queued = Vec::new();
+        for i in 0..11 {
+            let client = client.clone();
+            queued.push(tokio::spawn(async move {
+                client.publish(&format!("tags/queued{}", i), b"1".to_vec(), policy).await
+            }));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(client.deliveries.queued(), 11);
+        
+        // Cancelling the blocked publish removes its registration
+        let blocked = queued.pop().unwrap();
+        blocked.abort();
+        assert!(blocked.await.unwrap_err().is_cancelled());
+        assert_eq!(client.deliveries.queued(), 10);
+        
+        let _broker = EmbeddedBroker::start(address).await.unwrap();
+        for publish in queued {
+            assert!(publish.await.unwrap().is_ok());
+        }
+        
+        // The next publish is confirmed by its own PubAck
+        assert!(client.publish("tags/next", b"1".to_vec(), policy).await.is_ok());
+    }
+    
+    #[test]
+    fn test_persistent_session_options() {
+        let mut options = ConnectionOptions::new("uns_cli_run", "localhost", 1883);
+        let mqtt_options = RumqttcClient::mqtt_options(&options).unwrap();
+        assert!(mqtt_options.clean_start());
+        assert!(mqtt_options.connect_properties().is_none());
+        
+        options.session_expiry = Some(Duration::from_secs(300));
+        let mqtt_options = RumqttcClient::mqtt_options(&options).unwrap();
+        assert!(!mqtt_options.clean_start());
+        assert_eq!(mqtt_options.connect_properties().unwrap().session_expiry_interval, Some(300));
+    }
+    
+    #[test]
+    fn test_transport_from_url_scheme() {
+        let options = ConnectionOptions::new("uns_cli_run", "ws://plant.example.com:8000/mqtt", 1883);
+        let mqtt_options = RumqttcClient::mqtt_options(&options).unwrap();
+        assert!(matches!(mqtt_options.transport(), Transport::Ws));
+        assert_eq!(mqtt_options.broker_address().0, "ws://plant.example.com:8000/mqtt");
+        
+        let options = ConnectionOptions::new("uns_cli_run", "wss://plant.example.com/mqtt", 1883);
+        let mqtt_options = RumqttcClient::mqtt_options(&options).unwrap();
+        assert!(matches!(mqtt_options.transport(), Transport::Wss(_)));
+        
+        let mut options = ConnectionOptions::new("uns_cli_run", "ws://plant.example.com:8000/mqtt", 1883);
+        options.tls = Some(TlsOptions::default());
+        assert!(matches!(RumqttcClient::mqtt_options(&options), Err(UnsError::Validation(_))));
+        
+        let options = ConnectionOptions::new("uns_cli_run", "http://plant.example.com", 1883);
+        assert!(matches!(RumqttcClient::mqtt_options(&options), Err(UnsError::Validation(_))));
+    }
 }
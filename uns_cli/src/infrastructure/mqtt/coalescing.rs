@@ -0,0 +1,383 @@
+use async_trait::async_trait;
+use log::error;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Mutex as SendLock};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+
+use crate::domain::{InstanceStatus, ReplyTo, Tag, TagDelta, WriteRequest, WriteResponse};
+use crate::infrastructure::mqtt::publisher::TagDatabase;
+use crate::infrastructure::mqtt::{ConnectionState, MqttPublisher};
+use crate::infrastructure::UnsError;
+
+/// A tag value or full database held back until its window opens
+enum Update {
+    Tag(Tag),
+    Database(TagDatabase),
+}
+
+impl Update {
+    async fn send(&self, publisher: &dyn MqttPublisher) -> Result<(), UnsError> {
+        match self {
+            Update::Tag(tag) => publisher.publish_tag(tag).await,
+            Update::Database(data) => publisher.publish_database(data).await,
+        }
+    }
+}
+
+/// Rate limiting state of one stream of updates
+struct Window<T> {
+    /// When the next update may be published
+    opens: Instant,
+
+    /// Latest update received while the window was closed
+    pending: Option<T>,
+
+    /// Task publishing the pending update once the window opens
+    flush: Option<JoinHandle<()>>,
+}
+
+impl<T> Window<T> {
+    fn new(now: Instant) -> Self {
+        Self { opens: now, pending: None, flush: None }
+    }
+
+    /// Drops the pending update and its flush task
+    fn cancel(&mut self) {
+        self.pending = None;
+        if let Some(flush) = self.flush.take() {
+            flush.abort();
+        }
+    }
+}
+
+/// Windows of each tag path, and of the full database under `None`
+type Windows = Arc<Mutex<HashMap<Option<String>, Window<Update>>>>;
+
+/// Receives tag values that were never published because a newer value replaced them
+///
+/// Called while the publisher's state is locked, so it must not block.
+/// `TagHistoryFile::sink` records the values in a file.
+pub type SupersededSink = Arc<dyn Fn(&Tag) + Send + Sync>;
+
+/// Deltas merged while their window is closed
+struct Deltas {
+    /// Sequence number of the last delta published downstream
+    seq: u64,
+    window: Window<TagDelta>,
+}
+
+/// Publisher that sends at most one update per tag and window
+///
+/// An update arriving while the window of its tag is closed is held back and
+/// replaced by any newer one, so the latest value is published as soon as the
+/// window opens. Replaced values are discarded unless a sink is set with
+/// `with_superseded_sink`. A held back value whose publish fails is only logged;
+/// the broker catches up with the next update of the tag, or with a republish.
+/// The full database is limited the same way. Deltas are merged into one per window and renumbered, and
+/// delta-mode snapshots carry the renumbered `seq`, so consumers still see
+/// consecutive numbers.
+pub struct CoalescingPublisher {
+    inner: Arc<dyn MqttPublisher>,
+    window: Duration,
+    windows: Windows,
+    deltas: Arc<SendLock<Deltas>>,
+    superseded: Option<SupersededSink>,
+}
+
+impl CoalescingPublisher {
+    /// Wraps a publisher, allowing one update per tag every `window`
+    pub fn new(inner: Arc<dyn MqttPublisher>, window: Duration) -> Self {
+        Self {
+            inner,
+            window,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            deltas: Arc::new(SendLock::new(Deltas { seq: 0, window: Window::new(Instant::now()) })),
+            superseded: None,
+        }
+    }
+
+    /// Hands tag values replaced before they were published to `sink`, e.g. to keep them in a history
+    pub fn with_superseded_sink(mut self, sink: SupersededSink) -> Self {
+        self.superseded = Some(sink);
+        self
+    }
+
+    /// Passes a held back update that will not be published to the sink
+    fn supersede(&self, update: Option<Update>) {
+        if let (Some(sink), Some(Update::Tag(tag))) = (&self.superseded, update) {
+            sink(&tag);
+        }
+    }
+
+    /// Publishes an update now if its window is open, or holds it back
+    async fn submit(&self, key: Option<String>, update: Update) -> Result<(), UnsError> {
+        let now = Instant::now();
+        {
+            let mut windows = self.windows.lock().unwrap();
+            let window = windows.entry(key.clone()).or_insert_with(|| Window::new(now));
+
+            if window.pending.is_some() || now < window.opens {
+                match window.pending.replace(update) {
+                    Some(previous) => self.supersede(Some(previous)),
+                    None => window.flush = Some(self.spawn_flush(key, window.opens)),
+                }
+                return Ok(());
+            }
+            window.opens = now + self.window;
+        }
+
+        update.send(self.inner.as_ref()).await
+    }
+
+    /// Publishes the pending update of a key once its window opens
+    fn spawn_flush(&self, key: Option<String>, at: Instant) -> JoinHandle<()> {
+        let windows = self.windows.clone();
+        let inner = self.inner.clone();
+        let period = self.window;
+
+        tokio::spawn(async move {
+            sleep_until(at).await;
+
+            let update = {
+                let mut windows = windows.lock().unwrap();
+                let Some(window) = windows.get_mut(&key) else {
+                    return;
+                };
+                window.opens = Instant::now() + period;
+                window.flush = None;
+                window.pending.take()
+            };
+
+            if let Some(update) = update {
+                if let Err(e) = update.send(inner.as_ref()).await {
+                    error!("Error publishing coalesced update: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Publishes the merged pending delta once the delta window opens
+    fn spawn_delta_flush(&self, at: Instant) -> JoinHandle<()> {
+        let deltas = self.deltas.clone();
+        let inner = self.inner.clone();
+        let period = self.window;
+
+        tokio::spawn(async move {
+            sleep_until(at).await;
+
+            let mut deltas = deltas.lock().await;
+            deltas.window.opens = Instant::now() + period;
+            deltas.window.flush = None;
+
+            if let Some(mut delta) = deltas.window.pending.take() {
+                deltas.seq += 1;
+                delta.seq = deltas.seq;
+                if let Err(e) = inner.publish_delta(&delta).await {
+                    error!("Error publishing coalesced delta: {}", e);
+                }
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl MqttPublisher for CoalescingPublisher {
+    async fn publish_tag(&self, tag: &Tag) -> Result<(), UnsError> {
+        self.submit(Some(tag.path.clone()), Update::Tag(tag.clone())).await
+    }
+
+    async fn publish_tags(&self, tags: &[Tag]) -> Result<(), UnsError> {
+        // Whole-catalog publishes (startup, reconnect) are rare and must stay complete
+        self.inner.publish_tags(tags).await
+    }
+
+    async fn publish_database(&self, data: &TagDatabase) -> Result<(), UnsError> {
+        if data.seq.is_none() {
+            return self.submit(None, Update::Database(data.clone())).await;
+        }
+
+        // A delta-mode snapshot includes every delta handed over so far, merged or not
+        let mut deltas = self.deltas.lock().await;
+        deltas.window.cancel();
+
+        let snapshot = TagDatabase { tags: data.tags.clone(), seq: Some(deltas.seq) };
+        self.inner.publish_database(&snapshot).await
+    }
+
+    async fn publish_delta(&self, delta: &TagDelta) -> Result<(), UnsError> {
+        let now = Instant::now();
+        let mut deltas = self.deltas.lock().await;
+
+        if deltas.window.pending.is_none() && now >= deltas.window.opens {
+            deltas.window.opens = now + self.window;
+            deltas.seq += 1;
+
+            let renumbered = TagDelta { seq: deltas.seq, ..delta.clone() };
+            return self.inner.publish_delta(&renumbered).await;
+        }
+
+        match deltas.window.pending.as_mut() {
+            Some(pending) => {
+                pending.changes.extend(delta.changes.clone());
+                pending.timestamp = delta.timestamp;
+            }
+            None => {
+                let opens = deltas.window.opens;
+                deltas.window.pending = Some(delta.clone());
+                deltas.window.flush = Some(self.spawn_delta_flush(opens));
+            }
+        }
+        Ok(())
+    }
+
+    async fn publish_write_request(&self, request: &WriteRequest) -> Result<(), UnsError> {
+        self.inner.publish_write_request(request).await
+    }
+
+    async fn publish_write_response(&self, reply_to: &ReplyTo, response: &WriteResponse) -> Result<(), UnsError> {
+        self.inner.publish_write_response(reply_to, response).await
+    }
+
+    async fn publish_status(&self, status: &InstanceStatus) -> Result<(), UnsError> {
+        self.inner.publish_status(status).await
+    }
+
+    async fn publish_stale(&self, data: &TagDatabase) -> Result<(), UnsError> {
+        // Held back values would overwrite the stale flag; the stale data is the latest anyway
+        for window in self.windows.lock().unwrap().values_mut() {
+            self.supersede(window.pending.take());
+            window.cancel();
+        }
+        self.deltas.lock().await.window.cancel();
+
+        self.inner.publish_stale(data).await
+    }
+
+    fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.inner.connection_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::mqtt::publisher::MockMqttPublisher;
+    use std::collections::BTreeMap;
+
+    fn tag(path: &str, value: &str) -> Tag {
+        Tag::new(path.to_string(), "n".to_string(), "d".to_string(), value.to_string())
+    }
+
+    fn delta(seq: u64, changes: &[(&str, &str)]) -> TagDelta {
+        TagDelta {
+            seq,
+            timestamp: chrono::Utc::now(),
+            changes: changes.iter().map(|(path, value)| (path.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publishes_latest_value_per_window() {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let log = published.clone();
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher.expect_publish_tag().returning(move |tag| {
+            log.lock().unwrap().push(format!("{}={}", tag.path, tag.value));
+            Box::pin(async { Ok(()) })
+        });
+
+        let publisher = CoalescingPublisher::new(Arc::new(mock_publisher), Duration::from_millis(100));
+        for value in ["1", "2", "3", "4", "5"] {
+            publisher.publish_tag(&tag("A", value)).await.unwrap();
+        }
+        publisher.publish_tag(&tag("B", "x")).await.unwrap();
+
+        // The first value of each tag goes out at once
+        assert_eq!(*published.lock().unwrap(), vec!["A=1", "B=x"]);
+
+        // Only the latest of the held back values follows when the window opens
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*published.lock().unwrap(), vec!["A=1", "B=x", "A=5"]);
+    }
+
+    #[tokio::test]
+    async fn test_superseded_values_reach_the_sink() {
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher.expect_publish_tag().returning(|_| Box::pin(async { Ok(()) }));
+        mock_publisher.expect_publish_stale().times(1).returning(|_| Box::pin(async { Ok(()) }));
+
+        let superseded = Arc::new(Mutex::new(Vec::new()));
+        let history = superseded.clone();
+        let publisher = CoalescingPublisher::new(Arc::new(mock_publisher), Duration::from_millis(100))
+            .with_superseded_sink(Arc::new(move |tag: &Tag| history.lock().unwrap().push(tag.value.clone())));
+        for value in ["1", "2", "3", "4"] {
+            publisher.publish_tag(&tag("A", value)).await.unwrap();
+        }
+
+        // Published values and the one still held back are not superseded
+        assert_eq!(*superseded.lock().unwrap(), vec!["2", "3"]);
+
+        // The held back value is never published once the data goes stale
+        publisher.publish_stale(&TagDatabase::new(HashMap::new())).await.unwrap();
+        assert_eq!(*superseded.lock().unwrap(), vec!["2", "3", "4"]);
+    }
+
+    #[tokio::test]
+    async fn test_merges_and_renumbers_deltas() {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let log = published.clone();
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher.expect_publish_delta().returning(move |delta| {
+            log.lock().unwrap().push((delta.seq, delta.changes.clone()));
+            Box::pin(async { Ok(()) })
+        });
+        mock_publisher
+            .expect_publish_database()
+            .withf(|data| data.seq == Some(2) && data.tags.len() == 1)
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(()) }));
+
+        let publisher = CoalescingPublisher::new(Arc::new(mock_publisher), Duration::from_millis(200));
+        publisher.publish_delta(&delta(1, &[("A", "1")])).await.unwrap();
+        publisher.publish_delta(&delta(2, &[("A", "2")])).await.unwrap();
+        publisher.publish_delta(&delta(3, &[("B", "3")])).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let expected = vec![
+            (1, BTreeMap::from([("A".to_string(), "1".to_string())])),
+            (2, BTreeMap::from([("A".to_string(), "2".to_string()), ("B".to_string(), "3".to_string())])),
+        ];
+        assert_eq!(*published.lock().unwrap(), expected);
+
+        // A snapshot replaces the held back delta and carries the downstream numbering
+        publisher.publish_delta(&delta(4, &[("A", "4")])).await.unwrap();
+        let snapshot = TagDatabase { tags: HashMap::from([("A".to_string(), tag("A", "4"))]), seq: Some(4) };
+        publisher.publish_database(&snapshot).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(published.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_cancels_held_back_values() {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let log = published.clone();
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher.expect_publish_tag().returning(move |tag| {
+            log.lock().unwrap().push(tag.value.clone());
+            Box::pin(async { Ok(()) })
+        });
+        mock_publisher.expect_publish_stale().times(1).returning(|_| Box::pin(async { Ok(()) }));
+
+        let publisher = CoalescingPublisher::new(Arc::new(mock_publisher), Duration::from_millis(100));
+        publisher.publish_tag(&tag("A", "1")).await.unwrap();
+        publisher.publish_tag(&tag("A", "2")).await.unwrap();
+        publisher.publish_stale(&TagDatabase::new(HashMap::new())).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*published.lock().unwrap(), vec!["1"]);
+    }
+}
@@ -0,0 +1,379 @@
+use rand::Rng;
+use rumqttc::{Key, TlsConfiguration};
+use std::{fmt, fs, time::Duration};
+
+use crate::infrastructure::UnsError;
+
+/// State of the connection to the broker
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// The first connection attempt is in progress
+    Connecting,
+
+    /// The broker accepted the connection
+    Connected,
+
+    /// The connection was lost; the next attempt starts after `delay`
+    Reconnecting { attempt: u32, delay: Duration },
+
+    /// The client gave up after exhausting its reconnect attempts
+    Failed(String),
+}
+
+impl ConnectionState {
+    /// Returns true when messages can currently reach the broker
+    pub fn is_connected(&self) -> bool {
+        matches!(self, ConnectionState::Connected)
+    }
+}
+
+/// Exponential backoff with jitter between reconnect attempts
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnect attempt
+    pub initial_delay: Duration,
+
+    /// Upper bound for the delay between attempts
+    pub max_delay: Duration,
+
+    /// Factor applied to the delay after each failed attempt
+    pub multiplier: f64,
+
+    /// Random spread applied to each delay, as a fraction (0.2 = ±20%)
+    pub jitter: f64,
+
+    /// Give up after this many consecutive failed attempts (`None` = never)
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay before the given (1-based) reconnect attempt
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let capped = base.min(self.max_delay.as_secs_f64());
+
+        // Spread reconnects so many clients do not hit a restarted broker at once
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+        } else {
+            1.0
+        };
+
+        Duration::from_secs_f64(capped * factor)
+    }
+
+    /// Returns true when another attempt is allowed after `attempt` failures
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt <= max)
+    }
+}
+
+/// Settings used to connect to the broker
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    /// Client identifier presented to the broker
+    pub client_id: String,
+
+    /// Broker host name or address
+    pub host: String,
+
+    /// Broker port
+    pub port: u16,
+
+    /// Interval between keep-alive pings
+    pub keep_alive: Duration,
+
+    /// Backoff used after the connection is lost
+    pub reconnect: ReconnectPolicy,
+
+    /// Connect over TLS when set
+    pub tls: Option<TlsOptions>,
+
+    /// Username and password presented to the broker
+    pub credentials: Option<Credentials>,
+
+    /// Message the broker publishes if the connection is lost without a disconnect
+    pub last_will: Option<WillMessage>,
+
+    /// How long a publish waits for the broker to acknowledge it
+    pub delivery_timeout: Duration,
+
+    /// How long the broker keeps the session after a disconnect; `None` starts clean on every connect
+    pub session_expiry: Option<Duration>,
+}
+
+impl ConnectionOptions {
+    /// Creates options with default keep-alive and reconnect settings
+    pub fn new(client_id: &str, host: &str, port: u16) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            host: host.to_string(),
+            port,
+            keep_alive: Duration::from_secs(5),
+            reconnect: ReconnectPolicy::default(),
+            tls: None,
+            credentials: None,
+            last_will: None,
+            delivery_timeout: Duration::from_secs(10),
+            session_expiry: None,
+        }
+    }
+}
+
+/// Appends a random suffix to a client id, so several instances can connect side by side
+///
+/// A broker disconnects a client when another one connects with the same id.
+pub fn unique_client_id(base: &str) -> String {
+    format!("{}_{:08x}", base, rand::thread_rng().gen::<u32>())
+}
+
+/// Last Will message registered with the broker on every connect
+#[derive(Clone, Debug, PartialEq)]
+pub struct WillMessage {
+    /// Topic the will is published on
+    pub topic: String,
+
+    /// Will payload
+    pub payload: Vec<u8>,
+
+    /// Whether the broker retains the will
+    pub retain: bool,
+}
+
+/// TLS settings for the broker connection
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsOptions {
+    /// PEM file with the CA certificates trusted for the broker (platform roots when `None`)
+    pub ca_file: Option<String>,
+
+    /// PEM client certificate, for brokers that require mutual TLS
+    pub client_cert_file: Option<String>,
+
+    /// PEM private key matching `client_cert_file`
+    pub client_key_file: Option<String>,
+}
+
+impl TlsOptions {
+    /// Reads the PEM files and builds the rumqttc TLS configuration
+    pub fn to_tls_configuration(&self) -> Result<TlsConfiguration, UnsError> {
+        if self.ca_file.is_none() && self.client_cert_file.is_some() {
+            return Err(UnsError::Validation(
+                "A CA file is required when using a client certificate".to_string(),
+            ));
+        }
+
+        let client_auth = match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let cert = read_file(cert_file)?;
+                let key = read_file(key_file)?;
+
+                // rumqttc reads PKCS#1 keys as RSA and PKCS#8 keys as ECC
+                let key = if String::from_utf8_lossy(&key).contains("BEGIN RSA PRIVATE KEY") {
+                    Key::RSA(key)
+                } else {
+                    Key::ECC(key)
+                };
+                Some((cert, key))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(UnsError::Validation(
+                    "A client certificate and its key must be given together".to_string(),
+                ))
+            }
+        };
+
+        match &self.ca_file {
+            Some(ca_file) => Ok(TlsConfiguration::Simple {
+                ca: read_file(ca_file)?,
+                alpn: None,
+                client_auth,
+            }),
+            None => Ok(TlsConfiguration::default()),
+        }
+    }
+}
+
+/// Username and password for the broker
+///
+/// The password never comes from the command line, where it would show up in
+/// process listings and shell history; it is read from a file or from the
+/// environment instead.
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    /// Username presented to the broker
+    pub username: String,
+
+    /// Password presented to the broker
+    pub password: String,
+}
+
+impl Credentials {
+    /// Environment variable holding the username
+    pub const USERNAME_ENV: &'static str = "UNS_MQTT_USERNAME";
+
+    /// Environment variable holding the password
+    pub const PASSWORD_ENV: &'static str = "UNS_MQTT_PASSWORD";
+
+    /// Resolves credentials from the given username and password file, falling back to the environment
+    pub fn resolve(username: Option<String>, password_file: Option<&str>) -> Result<Option<Self>, UnsError> {
+        Self::resolve_with(username, password_file, |name| std::env::var(name).ok())
+    }
+
+    /// Same as `resolve`, with a custom environment lookup
+    pub fn resolve_with(
+        username: Option<String>,
+        password_file: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, UnsError> {
+        let username = username.or_else(|| env(Self::USERNAME_ENV));
+
+        let password = match password_file {
+            Some(file) => {
+                let contents = fs::read_to_string(file)
+                    .map_err(|e| UnsError::Other(format!("Failed to read password file {}: {}", file, e)))?;
+                // Files written with `echo` end with a newline that is not part of the password
+                Some(contents.trim_end_matches(['\r', '\n']).to_string())
+            }
+            None => env(Self::PASSWORD_ENV),
+        };
+
+        match (username, password) {
+            (Some(username), password) => Ok(Some(Self {
+                username,
+                password: password.unwrap_or_default(),
+            })),
+            (None, Some(_)) => Err(UnsError::Validation(
+                "A broker password was given without a username".to_string(),
+            )),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the password out of logs
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, UnsError> {
+    fs::read(path).map_err(|e| UnsError::Other(format!("Failed to read {}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_grows_exponentially_and_is_capped() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+        };
+
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(1000), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_delay_jitter_stays_in_range() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            jitter: 0.25,
+            ..ReconnectPolicy::default()
+        };
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(750) && delay <= Duration::from_millis(1250));
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(3),
+            ..ReconnectPolicy::default()
+        };
+
+        assert!(policy.allows(3));
+        assert!(!policy.allows(4));
+        assert!(ReconnectPolicy::default().allows(u32::MAX));
+    }
+
+    #[test]
+    fn test_unique_client_id() {
+        let first = unique_client_id("uns_cli_run");
+        assert!(first.starts_with("uns_cli_run_"));
+        assert_eq!(first.len(), "uns_cli_run_".len() + 8);
+        assert_ne!(first, unique_client_id("uns_cli_run"));
+    }
+
+    #[test]
+    fn test_credentials_from_file_and_env() {
+        let mut password_file = tempfile::NamedTempFile::new().unwrap();
+        use std::io::Write;
+        writeln!(password_file, "s3cret").unwrap();
+        let password_file = password_file.path().to_str().unwrap().to_string();
+
+        // The file wins over the environment and loses its trailing newline
+        let env = |name: &str| match name {
+            Credentials::USERNAME_ENV => Some("gateway".to_string()),
+            Credentials::PASSWORD_ENV => Some("from-env".to_string()),
+            _ => None,
+        };
+        let credentials = Credentials::resolve_with(None, Some(&password_file), env).unwrap().unwrap();
+        assert_eq!(credentials.username, "gateway");
+        assert_eq!(credentials.password, "s3cret");
+        assert!(!format!("{:?}", credentials).contains("s3cret"));
+
+        let credentials = Credentials::resolve_with(Some("operator".to_string()), None, env).unwrap().unwrap();
+        assert_eq!(credentials.username, "operator");
+        assert_eq!(credentials.password, "from-env");
+
+        assert!(Credentials::resolve_with(None, None, |_| None).unwrap().is_none());
+        assert!(Credentials::resolve_with(None, Some(&password_file), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_tls_client_certificate_requires_key_and_ca() {
+        let cert_only = TlsOptions {
+            ca_file: Some("ca.pem".to_string()),
+            client_cert_file: Some("client.pem".to_string()),
+            client_key_file: None,
+        };
+        assert!(matches!(cert_only.to_tls_configuration(), Err(UnsError::Validation(_))));
+
+        let no_ca = TlsOptions {
+            ca_file: None,
+            client_cert_file: Some("client.pem".to_string()),
+            client_key_file: Some("client.key".to_string()),
+        };
+        assert!(matches!(no_ca.to_tls_configuration(), Err(UnsError::Validation(_))));
+    }
+}
@@ -0,0 +1,296 @@
+use rumqttc::v5::{mqttbytes::v5::Packet, Event, Request};
+use rumqttc::Outgoing;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::infrastructure::UnsError;
+
+/// Sender half of a delivery, completed when the broker acknowledges the publish
+type Confirmation = oneshot::Sender<Result<(), UnsError>>;
+
+/// A publish whose acknowledgment is awaited
+pub struct Delivery {
+    receiver: oneshot::Receiver<Result<(), UnsError>>,
+}
+
+impl Delivery {
+    /// Waits until the broker acknowledged the publish, or the timeout elapses
+    ///
+    /// QoS 0 publishes are confirmed once they are written to the connection,
+    /// QoS 1 on PubAck and QoS 2 on PubComp.
+    pub async fn confirmed(self, timeout: Duration) -> Result<(), UnsError> {
+        match tokio::time::timeout(timeout, self.receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(UnsError::Mqtt("The MQTT event loop stopped before the publish was sent".to_string())),
+            Err(_) => Err(UnsError::Mqtt(format!("The broker did not acknowledge the publish within {:?}", timeout))),
+        }
+    }
+}
+
+/// A publish registered with the tracker but not handed to the event loop yet
+///
+/// Dropping it without calling `handed_over`, because the publish failed or the
+/// future sending it was cancelled, removes the registration again. Otherwise
+/// it would take the acknowledgment of the next publish.
+pub struct PendingDelivery<'a> {
+    tracker: &'a DeliveryTracker,
+    id: u64,
+    receiver: Option<oneshot::Receiver<Result<(), UnsError>>>,
+}
+
+impl PendingDelivery<'_> {
+    /// Marks the publish as handed to the event loop, returning its delivery
+    pub fn handed_over(mut self) -> Delivery {
+        let receiver = self.receiver.take().expect("a pending delivery is handed over once");
+        Delivery { receiver }
+    }
+}
+
+impl Drop for PendingDelivery<'_> {
+    fn drop(&mut self) {
+        if self.receiver.is_some() {
+            self.tracker.forget(self.id);
+        }
+    }
+}
+
+/// Publishes known to the tracker, from hand-over to acknowledgment
+#[derive(Default)]
+struct Pending {
+    /// Id given to the next registered publish
+    next_id: u64,
+
+    /// Publishes handed to the event loop but not sent yet, in hand-over order
+    queued: VecDeque<(u64, Confirmation)>,
+
+    /// Sent QoS 1 and 2 publishes waiting for PubAck or PubRec, by packet id
+    unacked: HashMap<u16, VecDeque<Confirmation>>,
+
+    /// QoS 2 publishes waiting for PubComp, by packet id
+    released: HashMap<u16, Confirmation>,
+}
+
+/// Matches publishes to the acknowledgments the broker sends for them
+///
+/// rumqttc does not return packet ids from `publish`, but the event loop
+/// handles requests in order and reports the packet id of each publish it
+/// sends. Publishes must therefore be registered with `expect` in the same
+/// order as they are handed to the client, and the returned registration kept
+/// until the client accepted the publish.
+#[derive(Default)]
+pub struct DeliveryTracker {
+    pending: Mutex<Pending>,
+}
+
+impl DeliveryTracker {
+    /// Creates a tracker without pending publishes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the next publish handed to the event loop
+    pub fn expect(&self) -> PendingDelivery<'_> {
+        let (sender, receiver) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        let id = pending.next_id;
+        pending.next_id += 1;
+        pending.queued.push_back((id, sender));
+
+        PendingDelivery { tracker: self, id, receiver: Some(receiver) }
+    }
+
+    /// Returns the number of registered publishes the event loop has not sent yet
+    pub fn queued(&self) -> usize {
+        self.pending.lock().unwrap().queued.len()
+    }
+
+    /// Forgets a registered publish that never reached the event loop
+    fn forget(&self, id: u64) {
+        self.pending.lock().unwrap().queued.retain(|(queued, _)| *queued != id);
+    }
+
+    /// Follows an event of the event loop
+    pub fn track(&self, event: &Event) {
+        let mut pending = self.pending.lock().unwrap();
+        match event {
+            Event::Outgoing(Outgoing::Publish(0)) => {
+                // QoS 0 has no acknowledgment; being sent is all there is
+                if let Some((_, confirmation)) = pending.queued.pop_front() {
+                    let _ = confirmation.send(Ok(()));
+                }
+            }
+            Event::Outgoing(Outgoing::Publish(pkid)) => {
+                // A publish sent again after a reconnect or a packet id collision is already known
+                if pending.unacked.get(pkid).is_some_and(|waiting| !waiting.is_empty()) {
+                    return;
+                }
+                if let Some((_, confirmation)) = pending.queued.pop_front() {
+                    pending.unacked.entry(*pkid).or_default().push_back(confirmation);
+                }
+            }
+            Event::Outgoing(Outgoing::AwaitAck(pkid)) => {
+                // The packet id is still in use; the publish is sent once it is acknowledged
+                if let Some((_, confirmation)) = pending.queued.pop_front() {
+                    pending.unacked.entry(*pkid).or_default().push_back(confirmation);
+                }
+            }
+            Event::Incoming(Packet::PubAck(ack)) => {
+                if let Some(confirmation) = pending.unacked.get_mut(&ack.pkid).and_then(VecDeque::pop_front) {
+                    let _ = confirmation.send(Ok(()));
+                }
+            }
+            Event::Incoming(Packet::PubRec(rec)) => {
+                if let Some(confirmation) = pending.unacked.get_mut(&rec.pkid).and_then(VecDeque::pop_front) {
+                    pending.released.insert(rec.pkid, confirmation);
+                }
+            }
+            Event::Incoming(Packet::PubComp(comp)) => {
+                if let Some(confirmation) = pending.released.remove(&comp.pkid) {
+                    let _ = confirmation.send(Ok(()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Fails the sent publishes the event loop will not send again after a connection error
+    ///
+    /// `replay` holds the requests the event loop resends once reconnected. A
+    /// publish the broker rejected is not among them, and nor is one still
+    /// waiting for its packet id.
+    pub fn connection_lost(&self, replay: &[Request], error: &str) {
+        let mut resent = HashSet::new();
+        let mut released = HashSet::new();
+        for request in replay {
+            match request {
+                Request::Publish(publish) => {
+                    resent.insert(publish.pkid);
+                }
+                Request::PubRel(rel) => {
+                    released.insert(rel.pkid);
+                }
+                _ => {}
+            }
+        }
+
+        let fail = |confirmation: Confirmation| {
+            let _ = confirmation.send(Err(UnsError::Mqtt(format!("Publish not acknowledged: {}", error))));
+        };
+
+        let mut pending = self.pending.lock().unwrap();
+        for (pkid, waiting) in pending.unacked.iter_mut() {
+            let keep = usize::from(resent.contains(pkid));
+            while waiting.len() > keep {
+                if let Some(confirmation) = waiting.pop_back() {
+                    fail(confirmation);
+                }
+            }
+        }
+        pending.unacked.retain(|_, waiting| !waiting.is_empty());
+
+        let lost: Vec<u16> = pending.released.keys().filter(|pkid| !released.contains(pkid)).copied().collect();
+        for pkid in lost {
+            if let Some(confirmation) = pending.released.remove(&pkid) {
+                fail(confirmation);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::v5::mqttbytes::{
+        v5::{PubAck, PubComp, PubRec, PubRel, Publish},
+        QoS,
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn sent(pkid: u16) -> Event {
+        Event::Outgoing(Outgoing::Publish(pkid))
+    }
+
+    fn publish(pkid: u16) -> Request {
+        let mut publish = Publish::new("tags/A", QoS::AtLeastOnce, b"1".to_vec(), None);
+        publish.pkid = pkid;
+        Request::Publish(publish)
+    }
+
+    #[tokio::test]
+    async fn test_confirms_on_acknowledgment() {
+        let tracker = DeliveryTracker::new();
+        let qos0 = tracker.expect().handed_over();
+        let qos1 = tracker.expect().handed_over();
+        let qos2 = tracker.expect().handed_over();
+
+        tracker.track(&sent(0));
+        tracker.track(&sent(1));
+        tracker.track(&sent(2));
+        assert!(qos0.confirmed(TIMEOUT).await.is_ok());
+
+        // QoS 2 is only confirmed once the exchange completes
+        tracker.track(&Event::Incoming(Packet::PubRec(PubRec::new(2, None))));
+        tracker.track(&Event::Incoming(Packet::PubAck(PubAck::new(1, None))));
+        assert!(qos1.confirmed(TIMEOUT).await.is_ok());
+
+        let mut qos2 = Box::pin(qos2.confirmed(TIMEOUT * 5));
+        assert!(futures::poll!(&mut qos2).is_pending());
+        tracker.track(&Event::Incoming(Packet::PubComp(PubComp::new(2, None))));
+        assert!(qos2.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_times_out_without_acknowledgment() {
+        let tracker = DeliveryTracker::new();
+        let delivery = tracker.expect().handed_over();
+        tracker.track(&sent(1));
+
+        assert!(matches!(delivery.confirmed(TIMEOUT).await, Err(UnsError::Mqtt(_))));
+    }
+
+    #[tokio::test]
+    async fn test_resent_publishes_keep_their_delivery() {
+        let tracker = DeliveryTracker::new();
+        let first = tracker.expect().handed_over();
+        let rejected = tracker.expect().handed_over();
+        let colliding = tracker.expect().handed_over();
+        tracker.track(&sent(1));
+        tracker.track(&sent(2));
+        tracker.track(&Event::Outgoing(Outgoing::AwaitAck(1)));
+
+        // Packet 1 is resent after the reconnect, packet 2 was rejected by the broker
+        tracker.connection_lost(&[publish(1), Request::PubRel(PubRel::new(7, None))], "PubAck failed");
+        assert!(matches!(rejected.confirmed(TIMEOUT).await, Err(UnsError::Mqtt(_))));
+        assert!(matches!(colliding.confirmed(TIMEOUT).await, Err(UnsError::Mqtt(_))));
+
+        // The resend is not taken for the next publish
+        let next = tracker.expect().handed_over();
+        tracker.track(&sent(1));
+        tracker.track(&sent(2));
+        tracker.track(&Event::Incoming(Packet::PubAck(PubAck::new(1, None))));
+        tracker.track(&Event::Incoming(Packet::PubAck(PubAck::new(2, None))));
+        assert!(first.confirmed(TIMEOUT).await.is_ok());
+        assert!(next.confirmed(TIMEOUT).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_registration_is_forgotten() {
+        let tracker = DeliveryTracker::new();
+        let first = tracker.expect().handed_over();
+        let cancelled = tracker.expect();
+        let last = tracker.expect().handed_over();
+
+        // A registration in the middle goes away without disturbing the others
+        drop(cancelled);
+        assert_eq!(tracker.queued(), 2);
+
+        tracker.track(&sent(0));
+        tracker.track(&sent(0));
+        assert!(first.confirmed(TIMEOUT).await.is_ok());
+        assert!(last.confirmed(TIMEOUT).await.is_ok());
+        assert_eq!(tracker.queued(), 0);
+    }
+}
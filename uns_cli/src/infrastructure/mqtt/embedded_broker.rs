@@ -0,0 +1,235 @@
+use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use log::{debug, error, info, warn};
+use rumqttd::{Broker, Config, ConnectionSettings, RouterConfig, ServerSettings};
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
+use ws_stream_tungstenite::WsStream;
+
+use crate::infrastructure::UnsError;
+
+/// Largest message the embedded broker accepts, enough for the database of a large catalog
+const MAX_PAYLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long to wait for the listener to come up
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An MQTT 5 broker running inside this process
+///
+/// Lets `run` work without a separate broker, and gives tests a broker of their
+/// own. The broker runs on its own threads until the process exits.
+pub struct EmbeddedBroker {
+    address: SocketAddr,
+}
+
+impl EmbeddedBroker {
+    /// Starts a broker listening on the given address and waits until it accepts connections
+    ///
+    /// Port 0 picks a free port; `address()` returns the one chosen.
+    pub async fn start(listen: SocketAddr) -> Result<Self, UnsError> {
+        let address = if listen.port() == 0 {
+            let listener = std::net::TcpListener::bind(listen)
+                .map_err(|e| UnsError::Mqtt(format!("No free port for the embedded broker: {}", e)))?;
+            listener.local_addr()?
+        } else {
+            listen
+        };
+
+        let config = Self::config(address);
+        std::thread::Builder::new()
+            .name("embedded-broker".to_string())
+            .spawn(move || {
+                if let Err(e) = Broker::new(config).start() {
+                    error!("Embedded broker stopped: {}", e);
+                }
+            })?;
+
+        let probe = Self::local_address(address);
+        let ready = tokio::time::timeout(STARTUP_TIMEOUT, async {
+            while tokio::net::TcpStream::connect(probe).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        if ready.is_err() {
+            return Err(UnsError::Mqtt(format!("Embedded broker did not start listening on {}", address)));
+        }
+
+        info!("Embedded MQTT broker listening on {}", address);
+        Ok(Self { address })
+    }
+
+    /// Also accepts MQTT over WebSocket connections on the given address
+    ///
+    /// Each connection is relayed to the MQTT 5 listener, so WebSocket clients
+    /// must speak MQTT 5 as well. Returns the address listened on, which differs
+    /// from `listen` when it asks for port 0.
+    pub async fn serve_websocket(&self, listen: SocketAddr) -> Result<SocketAddr, UnsError> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .map_err(|e| UnsError::Mqtt(format!("Failed to listen for WebSockets on {}: {}", listen, e)))?;
+        let address = listener.local_addr()?;
+
+        let broker = Self::local_address(self.address);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(Self::relay_websocket(stream, broker));
+                    }
+                    Err(e) => error!("Failed to accept a WebSocket connection: {}", e),
+                }
+            }
+        });
+
+        info!("Embedded MQTT broker listening for WebSockets on {}", address);
+        Ok(address)
+    }
+
+    /// Passes the MQTT packets of one WebSocket connection to and from the broker
+    async fn relay_websocket(stream: TcpStream, broker: SocketAddr) {
+        let websocket = match async_tungstenite::tokio::accept_hdr_async(stream, Self::select_subprotocol).await {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                warn!("WebSocket handshake failed: {}", e);
+                return;
+            }
+        };
+
+        let result = async {
+            let mut upstream = TcpStream::connect(broker).await?;
+            tokio::io::copy_bidirectional(&mut WsStream::new(websocket), &mut upstream).await
+        }
+        .await;
+        if let Err(e) = result {
+            debug!("WebSocket connection closed: {}", e);
+        }
+    }
+
+    /// Answers the handshake with the first subprotocol the client offers, as MQTT clients expect one
+    // The signature is the one tungstenite expects of a handshake callback
+    #[allow(clippy::result_large_err)]
+    fn select_subprotocol(request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        let offered = request
+            .headers()
+            .get("sec-websocket-protocol")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .and_then(|protocol| protocol.trim().parse().ok());
+        if let Some(protocol) = offered {
+            response.headers_mut().insert("sec-websocket-protocol", protocol);
+        }
+        Ok(response)
+    }
+
+    /// Returns the address the broker listens on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Returns the host clients of this process connect to
+    pub fn host(&self) -> String {
+        if self.address.ip().is_unspecified() {
+            "127.0.0.1".to_string()
+        } else {
+            self.address.ip().to_string()
+        }
+    }
+
+    /// Returns the port the broker listens on
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+
+    /// Address to connect to the listener at, rather than the wildcard address it may be bound to
+    fn local_address(mut address: SocketAddr) -> SocketAddr {
+        if address.ip().is_unspecified() {
+            address.set_ip([127, 0, 0, 1].into());
+        }
+        address
+    }
+
+    /// Broker settings: a single plain MQTT 5 listener without authentication
+    fn config(address: SocketAddr) -> Config {
+        let server = ServerSettings {
+            name: "embedded".to_string(),
+            listen: address,
+            tls: None,
+            next_connection_delay_ms: 1,
+            connections: ConnectionSettings {
+                connection_timeout_ms: 60000,
+                max_payload_size: MAX_PAYLOAD_SIZE,
+                max_inflight_count: 100,
+                auth: None,
+                external_auth: None,
+                dynamic_filters: true,
+            },
+        };
+
+        Config {
+            id: 0,
+            router: RouterConfig {
+                max_connections: 1000,
+                max_outgoing_packet_count: 200,
+                max_segment_size: 100 * 1024 * 1024,
+                max_segment_count: 10,
+                ..RouterConfig::default()
+            },
+            v5: Some(HashMap::from([("1".to_string(), server)])),
+            ..Config::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::PublishPolicy;
+    use crate::infrastructure::mqtt::client::{MqttClient, RumqttcClient};
+    use crate::infrastructure::mqtt::MqttSubscriber;
+
+    #[tokio::test]
+    async fn test_relays_messages_between_clients() {
+        let broker = EmbeddedBroker::start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        assert_ne!(broker.port(), 0);
+        assert_eq!(broker.host(), "127.0.0.1");
+
+        let subscriber = RumqttcClient::new("embedded_test_subscriber", &broker.host(), broker.port()).await.unwrap();
+        let mut messages = subscriber.subscribe_stream("tags/#").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let publisher = RumqttcClient::new("embedded_test_publisher", &broker.host(), broker.port()).await.unwrap();
+        publisher.publish("tags/A.B", b"1".to_vec(), PublishPolicy::not_retained()).await.unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), messages.recv()).await.unwrap().unwrap();
+        assert_eq!(message.topic, "tags/A.B");
+        assert_eq!(message.payload, b"1".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_listens_on_given_address() {
+        let free = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let broker = EmbeddedBroker::start(free).await.unwrap();
+        assert_eq!(broker.address(), free);
+        assert!(std::net::TcpStream::connect(free).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_relays_websocket_clients() {
+        let broker = EmbeddedBroker::start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let websocket = broker.serve_websocket("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let url = format!("ws://{}/mqtt", websocket);
+
+        let subscriber = RumqttcClient::new("embedded_test_ws_subscriber", &url, websocket.port()).await.unwrap();
+        let mut messages = subscriber.subscribe_stream("tags/#").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // A TCP client and a WebSocket client share the same broker
+        let publisher = RumqttcClient::new("embedded_test_tcp_publisher", &broker.host(), broker.port()).await.unwrap();
+        publisher.publish("tags/A.B", b"1".to_vec(), PublishPolicy::not_retained()).await.unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), messages.recv()).await.unwrap().unwrap();
+        assert_eq!(message.topic, "tags/A.B");
+        assert_eq!(message.payload, b"1".to_vec());
+    }
+}
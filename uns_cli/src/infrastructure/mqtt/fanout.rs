@@ -0,0 +1,597 @@
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::domain::{InstanceStatus, QosLevel, ReplyTo, Tag, TagDelta, WriteRequest, WriteResponse};
+use crate::infrastructure::mqtt::publisher::TagDatabase;
+use crate::infrastructure::mqtt::{ConnectionOptions, ConnectionState, Credentials, MqttPublisher, TlsOptions};
+use crate::infrastructure::UnsError;
+
+/// Pause before catching up again after a failed catch-up on a live connection
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An additional broker the tags are published to
+///
+/// Written as `<name>=<host>:<port>` followed by optional comma-separated
+/// settings: `prefix=<topic prefix>`, `qos=<0|1|2>`, `tls` and `ca=<PEM file>`
+/// (which implies `tls`), e.g. `central=plant.example.com:8883,prefix=acme/austin,tls`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrokerSpec {
+    /// Name used in logs and in the credential environment variables
+    pub name: String,
+
+    /// Broker host name or address
+    pub host: String,
+
+    /// Broker port
+    pub port: u16,
+
+    /// Topic prefix to use instead of the primary broker's
+    pub prefix: Option<String>,
+
+    /// Default QoS to use instead of the primary broker's
+    pub qos: Option<QosLevel>,
+
+    /// Connect over TLS when set
+    pub tls: Option<TlsOptions>,
+}
+
+impl BrokerSpec {
+    /// Returns the environment variables holding the username and password of this broker
+    ///
+    /// For a broker named `central` these are `UNS_MQTT_CENTRAL_USERNAME` and
+    /// `UNS_MQTT_CENTRAL_PASSWORD`.
+    pub fn credential_env(&self) -> (String, String) {
+        let name = self.name.to_uppercase().replace('-', "_");
+        (format!("UNS_MQTT_{}_USERNAME", name), format!("UNS_MQTT_{}_PASSWORD", name))
+    }
+
+    /// Returns the connection options of this broker, with credentials from the environment
+    pub fn connection_options(&self, client_id: &str) -> Result<ConnectionOptions, UnsError> {
+        let mut options = ConnectionOptions::new(client_id, &self.host, self.port);
+        options.tls = self.tls.clone();
+
+        let (username_env, password_env) = self.credential_env();
+        options.credentials = Credentials::resolve_with(None, None, |name| match name {
+            Credentials::USERNAME_ENV => std::env::var(&username_env).ok(),
+            Credentials::PASSWORD_ENV => std::env::var(&password_env).ok(),
+            _ => None,
+        })?;
+        Ok(options)
+    }
+}
+
+impl FromStr for BrokerSpec {
+    type Err = UnsError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| UnsError::Validation(format!("Invalid broker '{}': {}", spec, reason));
+
+        let mut settings = spec.split(',').map(str::trim);
+        let (name, address) = settings
+            .next()
+            .and_then(|endpoint| endpoint.split_once('='))
+            .ok_or_else(|| invalid("expected <name>=<host>:<port>"))?;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(invalid("names may only contain letters, digits, '_' and '-'"));
+        }
+        let (host, port) = address
+            .rsplit_once(':')
+            .filter(|(host, _)| !host.is_empty())
+            .ok_or_else(|| invalid("expected <name>=<host>:<port>"))?;
+        let port = port.parse().map_err(|_| invalid("invalid port"))?;
+
+        let mut parsed = Self {
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+            prefix: None,
+            qos: None,
+            tls: None,
+        };
+        for setting in settings {
+            match setting.split_once('=') {
+                None if setting == "tls" => {
+                    parsed.tls.get_or_insert_with(TlsOptions::default);
+                }
+                Some(("prefix", prefix)) => parsed.prefix = Some(prefix.to_string()),
+                Some(("qos", level)) => {
+                    let qos = level.parse().ok().and_then(QosLevel::from_level);
+                    parsed.qos = Some(qos.ok_or_else(|| invalid("qos must be 0, 1 or 2"))?);
+                }
+                Some(("ca", ca_file)) => {
+                    parsed.tls.get_or_insert_with(TlsOptions::default).ca_file = Some(ca_file.to_string());
+                }
+                _ => return Err(invalid(&format!("unknown setting '{}'", setting))),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Retained messages published while a broker was catching up
+#[derive(Default)]
+struct Missed {
+    tags: HashSet<String>,
+    database: bool,
+    status: bool,
+}
+
+impl Missed {
+    fn is_empty(&self) -> bool {
+        self.tags.is_empty() && !self.database && !self.status
+    }
+}
+
+/// Delivery state of one broker
+enum Health {
+    /// Not connected: publishes are skipped, and the whole retained state is sent on connect
+    Down,
+
+    /// Connected and sending the retained state; newer publishes are sent after it
+    CatchingUp(Missed),
+
+    /// Connected and up to date
+    Live,
+}
+
+/// What a publish carries, to know what a broker catching up must send again
+enum Item<'a> {
+    Tags(&'a [Tag]),
+    Database,
+    Status,
+}
+
+/// Latest retained state, sent to brokers that (re)connect
+#[derive(Default)]
+struct Retained {
+    tags: HashMap<String, Tag>,
+    database: Option<TagDatabase>,
+    status: Option<InstanceStatus>,
+
+    /// The tags and database were last published flagged as stale
+    stale: bool,
+}
+
+/// One broker fed by the fan-out publisher
+struct Broker {
+    name: String,
+    publisher: Arc<dyn MqttPublisher>,
+    health: Mutex<Health>,
+}
+
+impl Broker {
+    /// Returns true if a publish should go to this broker now
+    fn admits(&self, item: &Item) -> bool {
+        let mut health = self.health.lock().unwrap();
+        match &mut *health {
+            Health::Live => true,
+            Health::Down => false,
+            Health::CatchingUp(missed) => {
+                match item {
+                    Item::Tags(tags) => missed.tags.extend(tags.iter().map(|tag| tag.path.clone())),
+                    Item::Database => missed.database = true,
+                    Item::Status => missed.status = true,
+                }
+                false
+            }
+        }
+    }
+
+    fn set_health(&self, health: Health) {
+        *self.health.lock().unwrap() = health;
+    }
+
+    /// Runs a publish, giving up if the broker disconnects meanwhile
+    async fn send(&self, publish: impl Future<Output = Result<(), UnsError>>) -> Result<(), UnsError> {
+        let mut state = self.publisher.connection_state();
+        tokio::select! {
+            result = publish => result,
+            _ = state.wait_for(|state| !state.is_connected()) => {
+                Err(UnsError::Mqtt(format!("Broker {} disconnected", self.name)))
+            }
+        }
+    }
+}
+
+/// Publisher feeding several brokers at once
+///
+/// Each broker has its own publisher (and so its own topic prefix and QoS)
+/// and its own health. A broker that is not connected is skipped instead of
+/// holding up the others; once it connects, it is sent the latest retained
+/// state (tags, database and status), so it catches up without a full
+/// republish on every broker. Stale data is retained state too: a broker
+/// catching up after it was published is sent the stale tags and database.
+/// Deltas only reach the brokers connected at the time. Write requests and replies
+/// belong to the primary broker, the first one, whose connection state this
+/// publisher reports.
+pub struct FanOutPublisher {
+    brokers: Vec<Arc<Broker>>,
+    retained: Arc<Mutex<Retained>>,
+    watchers: Vec<JoinHandle<()>>,
+}
+
+impl FanOutPublisher {
+    /// Feeds the given named publishers, the first being the primary broker
+    pub fn new(publishers: Vec<(String, Arc<dyn MqttPublisher>)>) -> Result<Self, UnsError> {
+        if publishers.is_empty() {
+            return Err(UnsError::Validation("At least one broker is required".to_string()));
+        }
+
+        let retained = Arc::new(Mutex::new(Retained::default()));
+        let brokers: Vec<Arc<Broker>> = publishers
+            .into_iter()
+            .map(|(name, publisher)| Arc::new(Broker { name, publisher, health: Mutex::new(Health::Down) }))
+            .collect();
+        let watchers = brokers
+            .iter()
+            .map(|broker| Self::watch(broker.clone(), retained.clone()))
+            .collect();
+
+        Ok(Self { brokers, retained, watchers })
+    }
+
+    /// Tracks the health of a broker and brings it up to date after each connect
+    fn watch(broker: Arc<Broker>, retained: Arc<Mutex<Retained>>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut state = broker.publisher.connection_state();
+
+            loop {
+                let connected = match state.wait_for(|s| s.is_connected() || matches!(s, ConnectionState::Failed(_))).await {
+                    Ok(current) => current.is_connected(),
+                    Err(_) => false,
+                };
+                if !connected {
+                    break;
+                }
+
+                broker.set_health(Health::CatchingUp(Missed::default()));
+                match broker.send(Self::catch_up(&broker, &retained)).await {
+                    Ok(()) => {
+                        info!("Broker {} is up to date", broker.name);
+                        if state.wait_for(|s| !s.is_connected()).await.is_err() {
+                            break;
+                        }
+                        broker.set_health(Health::Down);
+                        warn!("Broker {} is unreachable; skipping it until it reconnects", broker.name);
+                    }
+                    Err(e) => {
+                        broker.set_health(Health::Down);
+                        error!("Error bringing broker {} up to date: {}", broker.name, e);
+                        tokio::time::sleep(RETRY_DELAY).await;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Sends the retained state, then whatever changed meanwhile, until nothing did
+    async fn catch_up(broker: &Broker, retained: &Mutex<Retained>) -> Result<(), UnsError> {
+        let (mut tags, mut database, mut status, mut stale) = {
+            let retained = retained.lock().unwrap();
+            (
+                retained.tags.values().cloned().collect::<Vec<_>>(),
+                retained.database.clone(),
+                retained.status.clone(),
+                retained.stale,
+            )
+        };
+
+        loop {
+            match &database {
+                // Stale data covers the tags and the database alike
+                Some(database) if stale => broker.publisher.publish_stale(database).await?,
+                _ => {
+                    if !tags.is_empty() {
+                        broker.publisher.publish_tags(&tags).await?;
+                    }
+                    if let Some(database) = &database {
+                        broker.publisher.publish_database(database).await?;
+                    }
+                }
+            }
+            if let Some(status) = &status {
+                broker.publisher.publish_status(status).await?;
+            }
+
+            // Checked under both locks, so a publish either sees the broker live or is recorded here
+            let retained = retained.lock().unwrap();
+            let mut health = broker.health.lock().unwrap();
+            let Health::CatchingUp(missed) = &mut *health else {
+                return Ok(());
+            };
+            if missed.is_empty() {
+                *health = Health::Live;
+                return Ok(());
+            }
+
+            let missed = std::mem::take(missed);
+            tags = missed.tags.iter().filter_map(|path| retained.tags.get(path).cloned()).collect();
+            database = missed.database.then(|| retained.database.clone()).flatten();
+            status = missed.status.then(|| retained.status.clone()).flatten();
+            stale = retained.stale;
+        }
+    }
+
+    /// Publishes to every broker that admits the item, all at once
+    ///
+    /// Fails only if no broker got the message and none will get it later.
+    async fn fan_out<'a, F, Fut>(&'a self, item: Item<'_>, publish: F) -> Result<(), UnsError>
+    where
+        F: Fn(&'a dyn MqttPublisher) -> Fut,
+        Fut: Future<Output = Result<(), UnsError>>,
+    {
+        let mut deferred = false;
+        let publish = &publish;
+        let mut sends = Vec::new();
+        for broker in &self.brokers {
+            if broker.admits(&item) {
+                sends.push(async move { (broker, broker.send(publish(broker.publisher.as_ref())).await) });
+            } else {
+                deferred = true;
+            }
+        }
+
+        let mut delivered = false;
+        let mut first_error = None;
+        for (broker, result) in join_all(sends).await {
+            match result {
+                Ok(()) => delivered = true,
+                Err(e) => {
+                    error!("Error publishing to broker {}: {}", broker.name, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) if !delivered && !deferred => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for FanOutPublisher {
+    fn drop(&mut self) {
+        for watcher in &self.watchers {
+            watcher.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl MqttPublisher for FanOutPublisher {
+    async fn publish_tag(&self, tag: &Tag) -> Result<(), UnsError> {
+        {
+            let mut retained = self.retained.lock().unwrap();
+            retained.tags.insert(tag.path.clone(), tag.clone());
+            retained.stale = false;
+        }
+        self.fan_out(Item::Tags(std::slice::from_ref(tag)), |publisher| publisher.publish_tag(tag)).await
+    }
+
+    async fn publish_tags(&self, tags: &[Tag]) -> Result<(), UnsError> {
+        {
+            let mut retained = self.retained.lock().unwrap();
+            retained.tags.extend(tags.iter().map(|tag| (tag.path.clone(), tag.clone())));
+            retained.stale = false;
+        }
+        self.fan_out(Item::Tags(tags), |publisher| publisher.publish_tags(tags)).await
+    }
+
+    async fn publish_database(&self, data: &TagDatabase) -> Result<(), UnsError> {
+        {
+            let mut retained = self.retained.lock().unwrap();
+            retained.database = Some(data.clone());
+            retained.stale = false;
+        }
+        self.fan_out(Item::Database, |publisher| publisher.publish_database(data)).await
+    }
+
+    async fn publish_delta(&self, delta: &TagDelta) -> Result<(), UnsError> {
+        // A broker catching up gets the database with the delta applied instead
+        {
+            let mut retained = self.retained.lock().unwrap();
+            if let Some(database) = retained.database.as_mut() {
+                for (path, value) in &delta.changes {
+                    if let Some(tag) = database.tags.get_mut(path) {
+                        tag.value = value.clone();
+                    }
+                }
+                database.seq = Some(delta.seq);
+            }
+        }
+        self.fan_out(Item::Database, |publisher| publisher.publish_delta(delta)).await
+    }
+
+    async fn publish_write_request(&self, request: &WriteRequest) -> Result<(), UnsError> {
+        self.brokers[0].publisher.publish_write_request(request).await
+    }
+
+    async fn publish_write_response(&self, reply_to: &ReplyTo, response: &WriteResponse) -> Result<(), UnsError> {
+        self.brokers[0].publisher.publish_write_response(reply_to, response).await
+    }
+
+    async fn publish_status(&self, status: &InstanceStatus) -> Result<(), UnsError> {
+        self.retained.lock().unwrap().status = Some(status.clone());
+        self.fan_out(Item::Status, |publisher| publisher.publish_status(status)).await
+    }
+
+    async fn publish_stale(&self, data: &TagDatabase) -> Result<(), UnsError> {
+        // Stale flags are retained like the values they mark, so brokers catching up get them too
+        {
+            let mut retained = self.retained.lock().unwrap();
+            retained.tags.extend(data.tags.values().map(|tag| (tag.path.clone(), tag.clone())));
+            retained.database = Some(data.clone());
+            retained.stale = true;
+        }
+        self.fan_out(Item::Database, |publisher| publisher.publish_stale(data)).await
+    }
+
+    fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.brokers[0].publisher.connection_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::mqtt::publisher::MockMqttPublisher;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    fn tag(path: &str, value: &str) -> Tag {
+        Tag::new(path.to_string(), "n".to_string(), "d".to_string(), value.to_string())
+    }
+
+    /// A publisher recording what it publishes, following the given connection state
+    fn recording_publisher(state: watch::Receiver<ConnectionState>, log: &Log) -> Arc<dyn MqttPublisher> {
+        let mut publisher = MockMqttPublisher::new();
+        publisher.expect_connection_state().returning(move || state.clone());
+
+        let tags = log.clone();
+        publisher.expect_publish_tag().returning(move |tag| {
+            tags.lock().unwrap().push(format!("{}={}", tag.path, tag.value));
+            Box::pin(async { Ok(()) })
+        });
+        let all_tags = log.clone();
+        publisher.expect_publish_tags().returning(move |published| {
+            let mut log = all_tags.lock().unwrap();
+            log.extend(published.iter().map(|tag| format!("{}={}", tag.path, tag.value)));
+            Box::pin(async { Ok(()) })
+        });
+        let database = log.clone();
+        publisher.expect_publish_database().returning(move |data| {
+            database.lock().unwrap().push(format!("database seq={:?}", data.seq));
+            Box::pin(async { Ok(()) })
+        });
+        let deltas = log.clone();
+        publisher.expect_publish_delta().returning(move |delta| {
+            deltas.lock().unwrap().push(format!("delta seq={}", delta.seq));
+            Box::pin(async { Ok(()) })
+        });
+        let stale = log.clone();
+        publisher.expect_publish_stale().returning(move |data| {
+            stale.lock().unwrap().push(format!("stale {} tags", data.tags.len()));
+            Box::pin(async { Ok(()) })
+        });
+        let requests = log.clone();
+        publisher.expect_publish_write_request().returning(move |request| {
+            requests.lock().unwrap().push(format!("set {}", request.path));
+            Box::pin(async { Ok(()) })
+        });
+        Arc::new(publisher)
+    }
+
+    #[test]
+    fn test_parse_broker_spec() {
+        let spec: BrokerSpec = "central=plant.example.com:8883,prefix=acme/austin,qos=2,ca=ca.pem".parse().unwrap();
+        assert_eq!(spec.name, "central");
+        assert_eq!(spec.host, "plant.example.com");
+        assert_eq!(spec.port, 8883);
+        assert_eq!(spec.prefix.as_deref(), Some("acme/austin"));
+        assert_eq!(spec.qos, Some(QosLevel::ExactlyOnce));
+        assert_eq!(spec.tls.as_ref().unwrap().ca_file.as_deref(), Some("ca.pem"));
+        assert_eq!(
+            spec.credential_env(),
+            ("UNS_MQTT_CENTRAL_USERNAME".to_string(), "UNS_MQTT_CENTRAL_PASSWORD".to_string())
+        );
+
+        let spec: BrokerSpec = "local-hivemq=hivemq:1883,tls".parse().unwrap();
+        assert_eq!(spec.tls, Some(TlsOptions::default()));
+        assert_eq!(spec.prefix, None);
+
+        assert!("hivemq:1883".parse::<BrokerSpec>().is_err());
+        assert!("central=plant.example.com".parse::<BrokerSpec>().is_err());
+        assert!("central=plant:1883,qos=3".parse::<BrokerSpec>().is_err());
+        assert!("cen tral=plant:1883".parse::<BrokerSpec>().is_err());
+        assert!("central=plant:1883,retain".parse::<BrokerSpec>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_broker_does_not_block_and_catches_up() {
+        let (_local_state, local_rx) = watch::channel(ConnectionState::Connected);
+        let (central_state, central_rx) = watch::channel(ConnectionState::Connecting);
+        let local_log = Log::default();
+        let central_log = Log::default();
+
+        let publisher = FanOutPublisher::new(vec![
+            ("local".to_string(), recording_publisher(local_rx, &local_log)),
+            ("central".to_string(), recording_publisher(central_rx, &central_log)),
+        ])
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        publisher.publish_tag(&tag("A", "1")).await.unwrap();
+        publisher.publish_tag(&tag("A", "2")).await.unwrap();
+        publisher.publish_database(&TagDatabase { tags: HashMap::from([("A".to_string(), tag("A", "2"))]), seq: Some(4) })
+            .await
+            .unwrap();
+        publisher.publish_delta(&TagDelta::single(5, "A", "3")).await.unwrap();
+
+        assert_eq!(*local_log.lock().unwrap(), vec!["A=1", "A=2", "database seq=Some(4)", "delta seq=5"]);
+        assert!(central_log.lock().unwrap().is_empty());
+
+        // Once connected, the central broker gets the latest state only, with the delta applied
+        central_state.send_replace(ConnectionState::Connected);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*central_log.lock().unwrap(), vec!["A=2", "database seq=Some(5)"]);
+
+        publisher.publish_tag(&tag("B", "1")).await.unwrap();
+        assert_eq!(central_log.lock().unwrap().last().unwrap(), "B=1");
+    }
+
+    #[tokio::test]
+    async fn test_broker_catching_up_gets_stale_data() {
+        let (_local_state, local_rx) = watch::channel(ConnectionState::Connected);
+        let (central_state, central_rx) = watch::channel(ConnectionState::Connecting);
+        let local_log = Log::default();
+        let central_log = Log::default();
+
+        let publisher = FanOutPublisher::new(vec![
+            ("local".to_string(), recording_publisher(local_rx, &local_log)),
+            ("central".to_string(), recording_publisher(central_rx, &central_log)),
+        ])
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let tags = HashMap::from([("A".to_string(), tag("A", "1"))]);
+        publisher.publish_database(&TagDatabase::new(tags.clone())).await.unwrap();
+        publisher.publish_stale(&TagDatabase::new(tags)).await.unwrap();
+        assert_eq!(*local_log.lock().unwrap(), vec!["database seq=None", "stale 1 tags"]);
+
+        // The stale flags replace the live state the broker missed
+        central_state.send_replace(ConnectionState::Connected);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*central_log.lock().unwrap(), vec!["stale 1 tags"]);
+    }
+
+    #[tokio::test]
+    async fn test_write_requests_go_to_the_primary_broker() {
+        let (_local_state, local_rx) = watch::channel(ConnectionState::Connected);
+        let (_central_state, central_rx) = watch::channel(ConnectionState::Connected);
+        let local_log = Log::default();
+        let central_log = Log::default();
+
+        let publisher = FanOutPublisher::new(vec![
+            ("local".to_string(), recording_publisher(local_rx, &local_log)),
+            ("central".to_string(), recording_publisher(central_rx, &central_log)),
+        ])
+        .unwrap();
+
+        let request = WriteRequest::new("US/TX".to_string(), "1".to_string());
+        publisher.publish_write_request(&request).await.unwrap();
+
+        assert_eq!(*local_log.lock().unwrap(), vec!["set US/TX"]);
+        assert!(central_log.lock().unwrap().is_empty());
+        assert!(FanOutPublisher::new(Vec::new()).is_err());
+    }
+}
@@ -1,7 +1,26 @@
 // MQTT module exports
 pub mod client;
+pub mod coalescing;
+pub mod connection;
+pub mod delivery;
+#[cfg(feature = "embedded-broker")]
+pub mod embedded_broker;
+pub mod fanout;
+pub mod outbox;
 pub mod publisher;
+pub mod sparkplug;
+pub mod subscriber;
 
 // Re-export key types
 pub use client::MqttClient;
+pub use coalescing::{CoalescingPublisher, SupersededSink};
+pub use connection::{
+    unique_client_id, ConnectionOptions, ConnectionState, Credentials, ReconnectPolicy, TlsOptions, WillMessage,
+};
+#[cfg(feature = "embedded-broker")]
+pub use embedded_broker::EmbeddedBroker;
+pub use delivery::{Delivery, DeliveryTracker, PendingDelivery};
+pub use fanout::{BrokerSpec, FanOutPublisher};
+pub use outbox::{Outbox, OutboxClient, OutboxLimits, OverflowPolicy};
 pub use publisher::MqttPublisher;
+pub use subscriber::{IncomingMessage, MessageProperties, MessageStream, MqttSubscriber};
@@ -0,0 +1,619 @@
+use async_trait::async_trait;
+use log::{error, warn};
+use redb::{Database, ReadableTable, TableDefinition};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Mutex as SendLock, Notify};
+use tokio::task::JoinHandle;
+
+use crate::domain::{PublishPolicy, QosLevel};
+use crate::infrastructure::mqtt::{ConnectionState, MessageProperties, MqttClient, WillMessage};
+use crate::infrastructure::UnsError;
+
+/// A queued message: topic, payload, QoS, retain, response topic, correlation data and content type
+type StoredMessage<'a> = (&'a str, &'a [u8], u8, bool, Option<&'a str>, Option<&'a [u8]>, Option<&'a str>);
+
+/// Queued messages keyed by sequence number
+const MESSAGES_TABLE: TableDefinition<u64, StoredMessage<'static>> = TableDefinition::new("messages");
+
+/// Sequence number of the newest queued message of each topic
+const LATEST_TABLE: TableDefinition<&str, u64> = TableDefinition::new("latest");
+
+/// Pause before the drain task retries after a failure on a live connection
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What happens when a message does not fit into a full outbox
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued messages
+    #[default]
+    DropOldest,
+
+    /// Replace the queued message of the same tag, so each tag keeps only its
+    /// latest value; the oldest messages are dropped only if that is not enough
+    LatestPerTag,
+}
+
+/// Size limits of the outbox
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutboxLimits {
+    /// Maximum number of queued messages
+    pub max_messages: usize,
+
+    /// Maximum total size of the queued topics and payloads
+    pub max_bytes: u64,
+
+    /// How room is made once a limit is reached
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for OutboxLimits {
+    fn default() -> Self {
+        Self {
+            max_messages: 10_000,
+            max_bytes: 16 * 1024 * 1024,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// A publish waiting in the outbox
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedMessage {
+    /// Topic to publish on
+    pub topic: String,
+
+    /// Message payload
+    pub payload: Vec<u8>,
+
+    /// QoS and retain flag
+    pub policy: PublishPolicy,
+
+    /// MQTT 5 properties, for messages sent with `publish_with_properties`
+    pub properties: Option<MessageProperties>,
+}
+
+impl QueuedMessage {
+    /// Returns the size counted against `OutboxLimits::max_bytes`
+    fn size(&self) -> u64 {
+        (self.topic.len() + self.payload.len()) as u64
+    }
+}
+
+/// Number and size of the queued messages
+#[derive(Clone, Copy, Debug, Default)]
+struct Totals {
+    count: usize,
+    bytes: u64,
+    next_seq: u64,
+}
+
+impl Totals {
+    /// Returns true if a message of `size` bytes does not fit next to the queued ones
+    fn overflows(&self, limits: &OutboxLimits, size: u64) -> bool {
+        self.count + 1 > limits.max_messages || self.bytes + size > limits.max_bytes
+    }
+}
+
+/// Bounded first-in first-out queue of publishes, stored with redb
+///
+/// Every change is committed in its own transaction, so queued messages
+/// survive a restart and are sent once the next run connects.
+pub struct Outbox {
+    db: Database,
+    limits: OutboxLimits,
+    totals: Mutex<Totals>,
+}
+
+impl Outbox {
+    /// Opens (or creates) an outbox at the given file path
+    pub fn open(path: &str, limits: OutboxLimits) -> Result<Self, UnsError> {
+        let db = Database::create(path)
+            .map_err(|e| UnsError::Repository(format!("Failed to open outbox {}: {}", path, e)))?;
+
+        let txn = db.begin_write().map_err(store_error)?;
+        let mut totals = Totals::default();
+        {
+            txn.open_table(LATEST_TABLE).map_err(store_error)?;
+            let messages = txn.open_table(MESSAGES_TABLE).map_err(store_error)?;
+
+            // Messages left over from the last run still count against the limits
+            for entry in messages.iter().map_err(store_error)? {
+                let (seq, value) = entry.map_err(store_error)?;
+                let (topic, payload, ..) = value.value();
+                totals.count += 1;
+                totals.bytes += (topic.len() + payload.len()) as u64;
+                totals.next_seq = seq.value() + 1;
+            }
+        }
+        txn.commit().map_err(store_error)?;
+
+        Ok(Self {
+            db,
+            limits,
+            totals: Mutex::new(totals),
+        })
+    }
+
+    /// Returns the number of queued messages
+    pub fn len(&self) -> usize {
+        self.totals.lock().unwrap().count
+    }
+
+    /// Returns true when no message is queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends a message, making room according to the overflow policy
+    ///
+    /// Returns the number of queued messages dropped to make room.
+    pub fn push(&self, message: &QueuedMessage) -> Result<usize, UnsError> {
+        let size = message.size();
+        if self.limits.max_messages == 0 || size > self.limits.max_bytes {
+            return Err(UnsError::Mqtt(format!(
+                "Message on {} does not fit into the outbox ({} bytes, limit {})",
+                message.topic, size, self.limits.max_bytes
+            )));
+        }
+
+        let mut totals = self.totals.lock().unwrap();
+        let mut updated = *totals;
+        let mut dropped = 0;
+
+        let txn = self.db.begin_write().map_err(store_error)?;
+        {
+            let mut messages = txn.open_table(MESSAGES_TABLE).map_err(store_error)?;
+            let mut latest = txn.open_table(LATEST_TABLE).map_err(store_error)?;
+
+            if self.limits.overflow == OverflowPolicy::LatestPerTag && updated.overflows(&self.limits, size) {
+                let previous = latest.get(message.topic.as_str()).map_err(store_error)?.map(|seq| seq.value());
+                if let Some(seq) = previous {
+                    if let Some(old) = messages.remove(seq).map_err(store_error)? {
+                        let (topic, payload, ..) = old.value();
+                        updated.count -= 1;
+                        updated.bytes -= (topic.len() + payload.len()) as u64;
+                        dropped += 1;
+                    }
+                }
+            }
+
+            while updated.overflows(&self.limits, size) {
+                let Some((seq, old)) = messages.pop_first().map_err(store_error)? else {
+                    break;
+                };
+                let (topic, payload, ..) = old.value();
+                updated.count -= 1;
+                updated.bytes -= (topic.len() + payload.len()) as u64;
+                dropped += 1;
+
+                if latest.get(topic).map_err(store_error)?.map(|s| s.value()) == Some(seq.value()) {
+                    latest.remove(topic).map_err(store_error)?;
+                }
+            }
+
+            let seq = updated.next_seq;
+            let properties = message.properties.as_ref();
+            messages
+                .insert(
+                    seq,
+                    (
+                        message.topic.as_str(),
+                        message.payload.as_slice(),
+                        message.policy.qos.level(),
+                        message.policy.retain,
+                        properties.and_then(|p| p.response_topic.as_deref()),
+                        properties.and_then(|p| p.correlation_data.as_deref()),
+                        properties.and_then(|p| p.content_type.as_deref()),
+                    ),
+                )
+                .map_err(store_error)?;
+            latest.insert(message.topic.as_str(), seq).map_err(store_error)?;
+
+            updated.count += 1;
+            updated.bytes += size;
+            updated.next_seq += 1;
+        }
+        txn.commit().map_err(store_error)?;
+
+        *totals = updated;
+        Ok(dropped)
+    }
+
+    /// Returns the oldest queued message with its sequence number
+    pub fn front(&self) -> Result<Option<(u64, QueuedMessage)>, UnsError> {
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let messages = txn.open_table(MESSAGES_TABLE).map_err(store_error)?;
+
+        let Some((seq, value)) = messages.first().map_err(store_error)? else {
+            return Ok(None);
+        };
+        let (topic, payload, qos, retain, response_topic, correlation_data, content_type) = value.value();
+
+        let stored = response_topic.is_some() || correlation_data.is_some() || content_type.is_some();
+        let properties = stored.then(|| MessageProperties {
+            response_topic: response_topic.map(str::to_string),
+            correlation_data: correlation_data.map(<[u8]>::to_vec),
+            content_type: content_type.map(str::to_string),
+        });
+        let message = QueuedMessage {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            policy: PublishPolicy::new(QosLevel::from_level(qos).unwrap_or_default(), retain),
+            properties,
+        };
+
+        Ok(Some((seq.value(), message)))
+    }
+
+    /// Removes a message once it has been sent
+    pub fn remove(&self, seq: u64) -> Result<(), UnsError> {
+        let mut totals = self.totals.lock().unwrap();
+        let mut updated = *totals;
+
+        let txn = self.db.begin_write().map_err(store_error)?;
+        {
+            let mut messages = txn.open_table(MESSAGES_TABLE).map_err(store_error)?;
+            let mut latest = txn.open_table(LATEST_TABLE).map_err(store_error)?;
+
+            let removed = messages.remove(seq).map_err(store_error)?;
+            if let Some(old) = removed {
+                let (topic, payload, ..) = old.value();
+                updated.count -= 1;
+                updated.bytes -= (topic.len() + payload.len()) as u64;
+
+                if latest.get(topic).map_err(store_error)?.map(|s| s.value()) == Some(seq) {
+                    latest.remove(topic).map_err(store_error)?;
+                }
+            }
+        }
+        txn.commit().map_err(store_error)?;
+
+        *totals = updated;
+        Ok(())
+    }
+}
+
+/// Outcome of handing a message to the broker connection
+enum Forwarded {
+    Sent,
+    Disconnected,
+    Failed(UnsError),
+}
+
+/// MQTT client that stores publishes in an outbox while the broker is unreachable
+///
+/// Messages go straight to the wrapped client while it is connected and the
+/// outbox is empty. Otherwise they are queued, and a background task sends
+/// them in order once the connection is back. New messages queue behind
+/// older ones until the outbox is drained, so the broker always receives
+/// them in publish order.
+pub struct OutboxClient {
+    inner: Arc<dyn MqttClient>,
+    outbox: Arc<Outbox>,
+    send_lock: Arc<SendLock<()>>,
+    wake: Arc<Notify>,
+    drainer: JoinHandle<()>,
+}
+
+impl OutboxClient {
+    /// Wraps a client and starts draining the outbox whenever it is connected
+    pub fn new(inner: Arc<dyn MqttClient>, outbox: Outbox) -> Self {
+        let outbox = Arc::new(outbox);
+        let send_lock = Arc::new(SendLock::new(()));
+        let wake = Arc::new(Notify::new());
+
+        let drainer = tokio::spawn(Self::drain(inner.clone(), outbox.clone(), send_lock.clone(), wake.clone()));
+
+        Self {
+            inner,
+            outbox,
+            send_lock,
+            wake,
+            drainer,
+        }
+    }
+
+    /// Returns the number of messages waiting for the broker
+    pub fn queued(&self) -> usize {
+        self.outbox.len()
+    }
+
+    /// Sends a message directly if possible, otherwise queues it
+    async fn send_or_queue(&self, message: QueuedMessage) -> Result<(), UnsError> {
+        let _guard = self.send_lock.lock().await;
+
+        if self.outbox.is_empty() && self.inner.connection_state().borrow().is_connected() {
+            match Self::forward(self.inner.as_ref(), &message).await {
+                Forwarded::Sent => return Ok(()),
+                Forwarded::Failed(e) => return Err(e),
+                Forwarded::Disconnected => {}
+            }
+        }
+
+        let dropped = self.outbox.push(&message)?;
+        if dropped > 0 {
+            warn!("Outbox full, dropped {} queued message(s)", dropped);
+        }
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Sends the queued messages in order while the client is connected
+    async fn drain(inner: Arc<dyn MqttClient>, outbox: Arc<Outbox>, send_lock: Arc<SendLock<()>>, wake: Arc<Notify>) {
+        let mut state = inner.connection_state();
+
+        loop {
+            let connected = state
+                .wait_for(|state| state.is_connected() || matches!(state, ConnectionState::Failed(_)))
+                .await
+                .map(|state| state.is_connected());
+            if !matches!(connected, Ok(true)) {
+                break;
+            }
+
+            let guard = send_lock.lock().await;
+            let front = match outbox.front() {
+                Ok(front) => front,
+                Err(e) => {
+                    error!("Failed to read the outbox: {}", e);
+                    drop(guard);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let Some((seq, message)) = front else {
+                drop(guard);
+                wake.notified().await;
+                continue;
+            };
+
+            match Self::forward(inner.as_ref(), &message).await {
+                Forwarded::Sent => {
+                    if let Err(e) = outbox.remove(seq) {
+                        error!("Failed to remove a sent message from the outbox: {}", e);
+                    }
+                }
+                Forwarded::Disconnected => {}
+                Forwarded::Failed(e) => {
+                    // The broker is reachable, so retrying would fail the same way
+                    warn!("Dropping queued message on {}: {}", message.topic, e);
+                    if let Err(e) = outbox.remove(seq) {
+                        error!("Failed to remove a message from the outbox: {}", e);
+                    }
+                    drop(guard);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Hands a message to the wrapped client, giving up when the connection drops
+    ///
+    /// A publish into a disconnected client waits until the connection is
+    /// back, so it is abandoned, and the message kept in the outbox, as soon
+    /// as the connection is lost.
+    async fn forward(inner: &dyn MqttClient, message: &QueuedMessage) -> Forwarded {
+        let mut watcher = inner.connection_state();
+        let send = async {
+            match &message.properties {
+                Some(properties) => {
+                    inner
+                        .publish_with_properties(&message.topic, message.payload.clone(), message.policy, properties.clone())
+                        .await
+                }
+                None => inner.publish(&message.topic, message.payload.clone(), message.policy).await,
+            }
+        };
+
+        tokio::select! {
+            biased;
+            result = send => match result {
+                Ok(()) => Forwarded::Sent,
+                Err(_) if !inner.connection_state().borrow().is_connected() => Forwarded::Disconnected,
+                Err(e) => Forwarded::Failed(e),
+            },
+            _ = watcher.wait_for(|state| !state.is_connected()) => Forwarded::Disconnected,
+        }
+    }
+}
+
+impl Drop for OutboxClient {
+    fn drop(&mut self) {
+        self.drainer.abort();
+    }
+}
+
+#[async_trait]
+impl MqttClient for OutboxClient {
+    async fn publish(&self, topic: &str, payload: Vec<u8>, policy: PublishPolicy) -> Result<(), UnsError> {
+        self.send_or_queue(QueuedMessage {
+            topic: topic.to_string(),
+            payload,
+            policy,
+            properties: None,
+        })
+        .await
+    }
+
+    async fn publish_with_properties(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        policy: PublishPolicy,
+        properties: MessageProperties,
+    ) -> Result<(), UnsError> {
+        self.send_or_queue(QueuedMessage {
+            topic: topic.to_string(),
+            payload,
+            policy,
+            properties: Some(properties),
+        })
+        .await
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<(), UnsError> {
+        self.inner.subscribe(topic).await
+    }
+
+    fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.inner.connection_state()
+    }
+
+    fn set_last_will(&self, will: WillMessage) {
+        self.inner.set_last_will(will)
+    }
+}
+
+/// Converts any redb error into a repository error
+fn store_error(error: impl Into<redb::Error>) -> UnsError {
+    UnsError::Repository(format!("Outbox error: {}", error.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::mqtt::client::MockMqttClient;
+    use tempfile::TempDir;
+
+    fn message(topic: &str, payload: &str) -> QueuedMessage {
+        QueuedMessage {
+            topic: topic.to_string(),
+            payload: payload.as_bytes().to_vec(),
+            policy: PublishPolicy::default(),
+            properties: None,
+        }
+    }
+
+    fn open(dir: &TempDir, max_messages: usize, overflow: OverflowPolicy) -> Outbox {
+        let limits = OutboxLimits {
+            max_messages,
+            overflow,
+            ..OutboxLimits::default()
+        };
+        Outbox::open(dir.path().join("outbox.redb").to_str().unwrap(), limits).unwrap()
+    }
+
+    // Removes and returns the payloads of every queued message
+    fn drain(outbox: &Outbox) -> Vec<String> {
+        let mut payloads = Vec::new();
+        while let Some((seq, message)) = outbox.front().unwrap() {
+            payloads.push(String::from_utf8(message.payload).unwrap());
+            outbox.remove(seq).unwrap();
+        }
+        payloads
+    }
+
+    #[test]
+    fn test_order_survives_reopen() {
+        let dir = TempDir::new().unwrap();
+        {
+            let outbox = open(&dir, 10, OverflowPolicy::DropOldest);
+            outbox.push(&message("tags/a", "1")).unwrap();
+
+            let mut reply = message("tags/reply/x", "2");
+            reply.policy = PublishPolicy::not_retained();
+            reply.properties = Some(MessageProperties {
+                response_topic: None,
+                correlation_data: Some(b"x".to_vec()),
+                content_type: Some("application/cbor".to_string()),
+            });
+            outbox.push(&reply).unwrap();
+        }
+
+        let outbox = open(&dir, 10, OverflowPolicy::DropOldest);
+        assert_eq!(outbox.len(), 2);
+
+        let (seq, first) = outbox.front().unwrap().unwrap();
+        assert_eq!(first, message("tags/a", "1"));
+        outbox.remove(seq).unwrap();
+
+        let (_, second) = outbox.front().unwrap().unwrap();
+        assert_eq!(second.policy, PublishPolicy::not_retained());
+        let properties = second.properties.unwrap();
+        assert_eq!(properties.correlation_data.as_deref(), Some(&b"x"[..]));
+        assert_eq!(properties.content_type.as_deref(), Some("application/cbor"));
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let dir = TempDir::new().unwrap();
+        let outbox = open(&dir, 2, OverflowPolicy::DropOldest);
+
+        assert_eq!(outbox.push(&message("tags/a", "1")).unwrap(), 0);
+        assert_eq!(outbox.push(&message("tags/b", "2")).unwrap(), 0);
+        assert_eq!(outbox.push(&message("tags/b", "3")).unwrap(), 1);
+
+        assert_eq!(drain(&outbox), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_latest_per_tag() {
+        let dir = TempDir::new().unwrap();
+        let outbox = open(&dir, 2, OverflowPolicy::LatestPerTag);
+
+        outbox.push(&message("tags/a", "1")).unwrap();
+        outbox.push(&message("tags/b", "2")).unwrap();
+
+        // A new value of a queued tag replaces it and keeps the other tag
+        assert_eq!(outbox.push(&message("tags/b", "3")).unwrap(), 1);
+        assert_eq!(outbox.len(), 2);
+
+        // A tag with nothing queued has to push out the oldest message
+        assert_eq!(outbox.push(&message("tags/c", "4")).unwrap(), 1);
+        assert_eq!(drain(&outbox), vec!["3", "4"]);
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let dir = TempDir::new().unwrap();
+        let limits = OutboxLimits {
+            max_bytes: 16,
+            ..OutboxLimits::default()
+        };
+        let outbox = Outbox::open(dir.path().join("outbox.redb").to_str().unwrap(), limits).unwrap();
+
+        outbox.push(&message("tags/a", "1")).unwrap();
+        outbox.push(&message("tags/b", "2")).unwrap();
+        assert_eq!(outbox.push(&message("tags/c", "3")).unwrap(), 1);
+        assert!(outbox.push(&message("tags/d", "too large to ever fit")).is_err());
+
+        assert_eq!(drain(&outbox), vec!["2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn test_queues_while_disconnected_and_drains_in_order() {
+        let dir = TempDir::new().unwrap();
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Connecting);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+
+        let mut mock_client = MockMqttClient::new();
+        mock_client.expect_connection_state().returning(move || state_rx.clone());
+        let recorder = sent.clone();
+        mock_client.expect_publish().returning(move |topic, payload, _| {
+            recorder.lock().unwrap().push(format!("{}={}", topic, String::from_utf8(payload).unwrap()));
+            Box::pin(async { Ok(()) })
+        });
+
+        let client = OutboxClient::new(Arc::new(mock_client), open(&dir, 10, OverflowPolicy::DropOldest));
+
+        // Nothing reaches the broker while it is unreachable
+        client.publish("tags/a", b"1".to_vec(), PublishPolicy::default()).await.unwrap();
+        client.publish("tags/b", b"2".to_vec(), PublishPolicy::default()).await.unwrap();
+        assert_eq!(client.queued(), 2);
+        assert!(sent.lock().unwrap().is_empty());
+
+        state_tx.send_replace(ConnectionState::Connected);
+        for _ in 0..50 {
+            if client.queued() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Once drained, messages go straight to the client again
+        client.publish("tags/a", b"3".to_vec(), PublishPolicy::default()).await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec!["tags/a=1", "tags/b=2", "tags/a=3"]);
+        assert_eq!(client.queued(), 0);
+    }
+}
@@ -1,15 +1,65 @@
 use async_trait::async_trait;
-use serde::Serialize;
-use std::{collections::HashMap, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::watch;
 
-use crate::domain::Tag;
+use crate::domain::{
+    DatabaseLayout, InstanceStatus, PayloadCodec, PublishPolicies, PublishPolicy, ReplyTo, Tag, TagDelta, TopicMapping,
+    WriteRequest, WriteResponse,
+};
 use crate::infrastructure::UnsError;
-use crate::infrastructure::mqtt::MqttClient;
+use crate::infrastructure::mqtt::{ConnectionState, MessageProperties, MqttClient, MqttSubscriber};
 
 /// Tag database structure for serialization
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct TagDatabase {
     pub tags: HashMap<String, Tag>,
+    
+    /// Sequence number of the last delta included, in delta mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl TagDatabase {
+    /// Creates a database snapshot without a delta sequence number
+    pub fn new(tags: HashMap<String, Tag>) -> Self {
+        Self { tags, seq: None }
+    }
+}
+
+/// A tag or database payload flagged as no longer kept up to date
+#[derive(Serialize)]
+struct Stale<'a, T: Serialize> {
+    #[serde(flatten)]
+    data: &'a T,
+    stale: bool,
+}
+
+impl<'a, T: Serialize> Stale<'a, T> {
+    fn new(data: &'a T) -> Self {
+        Self { data, stale: true }
+    }
+}
+
+/// Index of a database split into parts, published on `<prefix>/database/index`
+#[derive(Serialize, Deserialize)]
+struct DatabaseIndex {
+    layout: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    parts: Vec<DatabasePart>,
+}
+
+/// One entry of the database index
+#[derive(Serialize, Deserialize)]
+struct DatabasePart {
+    id: String,
+    topic: String,
+    tags: usize,
 }
 
 /// MQTT publisher interface
@@ -24,46 +74,263 @@ pub trait MqttPublisher: Send + Sync {
     
     /// Publishes the full tag database to a single topic
     async fn publish_database(&self, data: &TagDatabase) -> Result<(), UnsError>;
+    
+    /// Publishes the tag values changed since the previous delta
+    async fn publish_delta(&self, delta: &TagDelta) -> Result<(), UnsError>;
+    
+    /// Publishes a request asking the running instance to change a tag value
+    async fn publish_write_request(&self, request: &WriteRequest) -> Result<(), UnsError>;
+    
+    /// Publishes the reply to a write request
+    async fn publish_write_response(&self, reply_to: &ReplyTo, response: &WriteResponse) -> Result<(), UnsError>;
+    
+    /// Publishes the retained birth/death status of an instance
+    async fn publish_status(&self, status: &InstanceStatus) -> Result<(), UnsError>;
+    
+    /// Publishes every tag and the database again, flagged as stale
+    async fn publish_stale(&self, data: &TagDatabase) -> Result<(), UnsError>;
+    
+    /// Returns a receiver tracking the state of the broker connection
+    fn connection_state(&self) -> watch::Receiver<ConnectionState>;
 }
 
 /// Implementation of MQTT publisher
 pub struct MqttTagPublisher {
     client: Arc<dyn MqttClient>,
+    mapping: TopicMapping,
+    policies: PublishPolicies,
+    layout: DatabaseLayout,
+    /// Part topics holding a retained part, cleared once a database no longer has them
+    part_topics: Mutex<HashSet<String>>,
 }
 
 impl MqttTagPublisher {
-    /// Creates a new MQTT publisher
+    /// Creates a new MQTT publisher using the default dotted `tags/` topics
     pub fn new(client: Arc<dyn MqttClient>) -> Self {
-        Self { client }
+        Self {
+            client,
+            mapping: TopicMapping::default(),
+            policies: PublishPolicies::default(),
+            layout: DatabaseLayout::default(),
+            part_topics: Mutex::new(HashSet::new()),
+        }
+    }
+    
+    /// Publishes under the topics of the given mapping
+    pub fn with_topic_mapping(mut self, mapping: TopicMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+    
+    /// Publishes tags and the database with the given QoS and retain settings
+    pub fn with_publish_policies(mut self, policies: PublishPolicies) -> Self {
+        self.policies = policies;
+        self
+    }
+    
+    /// Splits the database across part topics listed by an index
+    pub fn with_database_layout(mut self, layout: DatabaseLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+    
+    /// Clears the database topics a previous run left behind under another layout
+    ///
+    /// Reads the retained index, waiting up to `settle_time` for it. With the
+    /// single layout the parts it lists are cleared together with the index.
+    /// Otherwise the whole database topic is cleared, and the listed parts are
+    /// remembered so the first database publish clears those it no longer has.
+    pub async fn clear_previous_layout(
+        &self,
+        subscriber: &dyn MqttSubscriber,
+        settle_time: Duration,
+    ) -> Result<(), UnsError> {
+        let index_topic = self.mapping.database_index_topic();
+        let mut messages = subscriber.subscribe_stream(&index_topic).await?;
+        let index = match tokio::time::timeout(settle_time, messages.recv()).await {
+            Ok(Some(message)) if message.retain && !message.payload.is_empty() => {
+                let content_type = message.properties.content_type.as_deref();
+                let codec = PayloadCodec::from_content_type(content_type).ok_or_else(|| {
+                    UnsError::Validation(format!("Unsupported content type: {}", content_type.unwrap_or_default()))
+                })?;
+                Some(codec.decode::<DatabaseIndex>(&message.payload)?)
+            }
+            _ => None,
+        };
+        
+        if self.layout == DatabaseLayout::Single {
+            if let Some(index) = index {
+                for part in &index.parts {
+                    self.clear(&part.topic).await?;
+                }
+                self.clear(&index_topic).await?;
+            }
+            return Ok(());
+        }
+        
+        self.clear(&self.mapping.database_topic()).await?;
+        if let Some(index) = index {
+            self.part_topics.lock().unwrap().extend(index.parts.into_iter().map(|part| part.topic));
+        }
+        Ok(())
+    }
+    
+    /// Clears a retained topic with an empty retained message
+    async fn clear(&self, topic: &str) -> Result<(), UnsError> {
+        let policy = PublishPolicy::new(self.policies.default.qos, true);
+        self.client.publish(topic, Vec::new(), policy).await
+    }
+    
+    /// Encodes a payload, flagged as stale if requested, and publishes it
+    ///
+    /// JSON payloads go out without properties as before; any other codec is
+    /// advertised in the MQTT 5 content type.
+    async fn publish_encoded<T: Serialize + Sync>(
+        &self,
+        topic: &str,
+        data: &T,
+        stale: bool,
+        policy: PublishPolicy,
+        codec: PayloadCodec,
+    ) -> Result<(), UnsError> {
+        let payload = if stale { codec.encode(&Stale::new(data))? } else { codec.encode(data)? };
+        if codec == PayloadCodec::Json {
+            return self.client.publish(topic, payload, policy).await;
+        }
+        
+        let properties = MessageProperties {
+            content_type: Some(codec.content_type().to_string()),
+            ..MessageProperties::default()
+        };
+        self.client.publish_with_properties(topic, payload, policy, properties).await
+    }
+    
+    /// Publishes the database on its topic, or as parts followed by their index
+    async fn publish_database_payload(&self, data: &TagDatabase, stale: bool) -> Result<(), UnsError> {
+        let (policy, codec) = (self.policies.default, self.policies.codec);
+        if self.layout == DatabaseLayout::Single {
+            return self.publish_encoded(&self.mapping.database_topic(), data, stale, policy, codec).await;
+        }
+        
+        let mut parts = Vec::new();
+        let mut current = HashSet::new();
+        for (id, tags) in self.layout.partition(&data.tags) {
+            let topic = self.mapping.database_part_topic(&id);
+            let count = tags.len();
+            let part = TagDatabase { tags, seq: data.seq };
+            self.publish_encoded(&topic, &part, stale, policy, codec).await?;
+            current.insert(topic.clone());
+            parts.push(DatabasePart { id, topic, tags: count });
+        }
+        
+        // The index goes last so consumers reading it find every part it lists
+        let index = DatabaseIndex {
+            layout: self.layout.to_string(),
+            seq: data.seq,
+            parts,
+        };
+        self.publish_encoded(&self.mapping.database_index_topic(), &index, stale, policy, codec).await?;
+        
+        // Parts that are gone, e.g. the last page after tags were removed, would otherwise stay retained
+        let vanished: Vec<String> = {
+            let mut part_topics = self.part_topics.lock().unwrap();
+            let vanished = part_topics.difference(&current).cloned().collect();
+            *part_topics = current;
+            vanished
+        };
+        for (i, topic) in vanished.iter().enumerate() {
+            if let Err(e) = self.clear(topic).await {
+                // Try again with the next database
+                self.part_topics.lock().unwrap().extend(vanished[i..].iter().cloned());
+                return Err(e);
+            }
+        }
+        Ok(())
     }
 }
 
 #[async_trait]
 impl MqttPublisher for MqttTagPublisher {
     async fn publish_tag(&self, tag: &Tag) -> Result<(), UnsError> {
-        let topic = tag.to_mqtt_topic();
-        let payload = serde_json::to_string(tag)?;
+        let topic = tag.to_mqtt_topic(&self.mapping);
         
-        self.client.publish(&topic, payload.into_bytes(), true).await
+        self.publish_encoded(&topic, tag, false, self.policies.policy_for(tag), self.policies.codec_for(tag)).await
     }
     
     async fn publish_tags(&self, tags: &[Tag]) -> Result<(), UnsError> {
-        for tag in tags {
-            self.publish_tag(tag).await?;
-        }
+        // Sent in order, but acknowledgments are awaited together rather than one round trip per tag
+        futures::future::try_join_all(tags.iter().map(|tag| self.publish_tag(tag))).await?;
         Ok(())
     }
     
     async fn publish_database(&self, data: &TagDatabase) -> Result<(), UnsError> {
-        let payload = serde_json::to_string(data)?;
-        self.client.publish("tags/database", payload.into_bytes(), true).await
+        self.publish_database_payload(data, false).await
+    }
+    
+    async fn publish_delta(&self, delta: &TagDelta) -> Result<(), UnsError> {
+        // Deltas only make sense on top of a snapshot, so late subscribers must not get one
+        let policy = PublishPolicy::new(self.policies.default.qos, false);
+        self.publish_encoded(&self.mapping.delta_topic(), delta, false, policy, self.policies.codec).await
+    }
+    
+    async fn publish_write_request(&self, request: &WriteRequest) -> Result<(), UnsError> {
+        // Write requests are commands, not state, so they are never retained
+        let payload = request.to_payload()?;
+        let topic = request.to_mqtt_topic(&self.mapping);
+        
+        match &request.reply_to {
+            Some(reply_to) => {
+                let properties = MessageProperties {
+                    response_topic: Some(reply_to.topic.clone()),
+                    correlation_data: reply_to.correlation_data.clone(),
+                    ..MessageProperties::default()
+                };
+                self.client
+                    .publish_with_properties(&topic, payload, PublishPolicy::not_retained(), properties)
+                    .await
+            }
+            None => self.client.publish(&topic, payload, PublishPolicy::not_retained()).await,
+        }
+    }
+    
+    async fn publish_write_response(&self, reply_to: &ReplyTo, response: &WriteResponse) -> Result<(), UnsError> {
+        let properties = MessageProperties {
+            response_topic: None,
+            correlation_data: reply_to.correlation_data.clone(),
+            ..MessageProperties::default()
+        };
+        
+        self.client
+            .publish_with_properties(&reply_to.topic, response.to_payload()?, PublishPolicy::not_retained(), properties)
+            .await
+    }
+    
+    async fn publish_status(&self, status: &InstanceStatus) -> Result<(), UnsError> {
+        // The status must stay retained whatever the tags use, so late subscribers see it
+        self.client.publish(&status.to_mqtt_topic(), status.to_payload()?, PublishPolicy::default()).await
+    }
+    
+    async fn publish_stale(&self, data: &TagDatabase) -> Result<(), UnsError> {
+        for tag in data.tags.values() {
+            let topic = tag.to_mqtt_topic(&self.mapping);
+            self.publish_encoded(&topic, tag, true, self.policies.policy_for(tag), self.policies.codec_for(tag)).await?;
+        }
+        
+        self.publish_database_payload(data, true).await
+    }
+    
+    fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.client.connection_state()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::domain::QosLevel;
     use crate::infrastructure::mqtt::client::MockMqttClient;
+    use crate::infrastructure::mqtt::subscriber::MockMqttSubscriber;
+    use crate::infrastructure::mqtt::{IncomingMessage, MessageStream};
     use mockall::predicate::*;
 
     #[tokio::test]
@@ -76,7 +343,7 @@ mod tests {
             .with(
                 eq("tags/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1.PRESSURE"),
                 always(),
-                eq(true)
+                eq(PublishPolicy::default())
             )
             .times(1)
             .returning(|_, _, _| Box::pin(async { Ok(()) }));
@@ -104,7 +371,7 @@ mod tests {
             .with(
                 eq("tags/database"),
                 always(),
-                eq(true)
+                eq(PublishPolicy::default())
             )
             .times(1)
             .returning(|_, _, _| Box::pin(async { Ok(()) }));
@@ -122,9 +389,400 @@ mod tests {
             ),
         );
         
-        let data = TagDatabase { tags };
+        let data = TagDatabase::new(tags);
         
         let result = publisher.publish_database(&data).await;
         assert!(result.is_ok());
     }
+    
+    #[tokio::test]
+    async fn test_publish_database_in_parts() {
+        let mut mock_client = MockMqttClient::new();
+        let mut sequence = mockall::Sequence::new();
+        
+        // Parts first, in order, then the index listing them
+        for (topic, path) in [("tags/database/part/1", "A/X"), ("tags/database/part/2", "B/Y")] {
+            mock_client
+                .expect_publish()
+                .withf(move |t, payload, policy| {
+                    let json: serde_json::Value = serde_json::from_slice(payload).unwrap();
+                    t == topic && policy.retain && json["tags"][path]["value"] == "1" && json["seq"] == 3
+                })
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        }
+        mock_client
+            .expect_publish()
+            .withf(|topic, payload, _| {
+                let json: serde_json::Value = serde_json::from_slice(payload).unwrap();
+                topic == "tags/database/index"
+                    && json["layout"] == "pages:1"
+                    && json["seq"] == 3
+                    && json["parts"][1]["topic"] == "tags/database/part/2"
+                    && json["parts"][1]["tags"] == 1
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client)).with_database_layout(DatabaseLayout::Pages(1));
+        
+        let tags = ["A/X", "B/Y"]
+            .iter()
+            .map(|path| (path.to_string(), Tag::new(path.to_string(), "n".to_string(), "d".to_string(), "1".to_string())))
+            .collect();
+        let data = TagDatabase { tags, seq: Some(3) };
+        
+        let result = publisher.publish_database(&data).await;
+        assert!(result.is_ok());
+    }
+    
+    fn pages(paths: &[&str]) -> TagDatabase {
+        let tags = paths
+            .iter()
+            .map(|path| (path.to_string(), Tag::new(path.to_string(), "n".to_string(), "d".to_string(), "1".to_string())))
+            .collect();
+        TagDatabase::new(tags)
+    }
+    
+    /// Records the topics published to, and whether each payload was empty
+    fn recording_client(log: &Arc<Mutex<Vec<(String, bool)>>>) -> MockMqttClient {
+        let mut mock_client = MockMqttClient::new();
+        let log = log.clone();
+        mock_client.expect_publish().returning(move |topic, payload, policy| {
+            assert!(policy.retain);
+            log.lock().unwrap().push((topic.to_string(), payload.is_empty()));
+            Box::pin(async { Ok(()) })
+        });
+        mock_client
+    }
+    
+    fn subscriber_with_index(payload: &'static [u8]) -> MockMqttSubscriber {
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .with(eq("tags/database/index"))
+            .times(1)
+            .returning(move |_| {
+                let mut index = IncomingMessage::new("tags/database/index", payload);
+                index.retain = true;
+                Box::pin(async move { Ok(MessageStream::from_messages(vec![index])) })
+            });
+        mock_subscriber
+    }
+    
+    #[tokio::test]
+    async fn test_vanished_parts_are_cleared() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let publisher =
+            MqttTagPublisher::new(Arc::new(recording_client(&log))).with_database_layout(DatabaseLayout::Pages(1));
+        
+        publisher.publish_database(&pages(&["A/X", "B/Y"])).await.unwrap();
+        log.lock().unwrap().clear();
+        
+        // The second page is cleared once the index no longer lists it
+        publisher.publish_database(&pages(&["A/X"])).await.unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                ("tags/database/part/1".to_string(), false),
+                ("tags/database/index".to_string(), false),
+                ("tags/database/part/2".to_string(), true),
+            ]
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_clears_parts_of_a_previous_layout() {
+        let index = br#"{"layout":"pages:1","parts":[
+            {"id":"1","topic":"tags/database/part/1","tags":1},
+            {"id":"2","topic":"tags/database/part/2","tags":1}]}"#;
+        
+        // Back to a single topic: the parts and the index go
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let publisher = MqttTagPublisher::new(Arc::new(recording_client(&log)));
+        publisher.clear_previous_layout(&subscriber_with_index(index), Duration::from_millis(50)).await.unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                ("tags/database/part/1".to_string(), true),
+                ("tags/database/part/2".to_string(), true),
+                ("tags/database/index".to_string(), true),
+            ]
+        );
+        
+        // Still split: the single topic goes, and so do the parts the first database does not have
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let publisher =
+            MqttTagPublisher::new(Arc::new(recording_client(&log))).with_database_layout(DatabaseLayout::Pages(1));
+        publisher.clear_previous_layout(&subscriber_with_index(index), Duration::from_millis(50)).await.unwrap();
+        publisher.publish_database(&pages(&["A/X"])).await.unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                ("tags/database".to_string(), true),
+                ("tags/database/part/1".to_string(), false),
+                ("tags/database/index".to_string(), false),
+                ("tags/database/part/2".to_string(), true),
+            ]
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_publish_delta_and_snapshot() {
+        let mut mock_client = MockMqttClient::new();
+        
+        // Deltas are not retained; the snapshot names the last delta it includes
+        mock_client
+            .expect_publish()
+            .withf(|topic, payload, policy| {
+                let json: serde_json::Value = serde_json::from_slice(payload).unwrap();
+                topic == "tags/database/delta" && !policy.retain && json["seq"] == 8 && json["changes"]["A/B"] == "2"
+            })
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        mock_client
+            .expect_publish()
+            .withf(|topic, payload, policy| {
+                let json: serde_json::Value = serde_json::from_slice(payload).unwrap();
+                topic == "tags/database" && policy.retain && json["seq"] == 8
+            })
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client));
+        
+        publisher.publish_delta(&TagDelta::single(8, "A/B", "2")).await.unwrap();
+        let mut snapshot = TagDatabase::new(HashMap::new());
+        snapshot.seq = Some(8);
+        publisher.publish_database(&snapshot).await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_publish_with_topic_mapping() {
+        let mut mock_client = MockMqttClient::new();
+        
+        // Every topic follows the native layout below the custom namespace
+        mock_client
+            .expect_publish()
+            .with(eq("acme/uns/US/TX/PUMP1.5"), always(), eq(PublishPolicy::default()))
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        mock_client
+            .expect_publish()
+            .with(eq("acme/uns/database"), always(), eq(PublishPolicy::default()))
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        mock_client
+            .expect_publish()
+            .with(eq("acme/uns/set/US/TX/PUMP1.5"), always(), eq(PublishPolicy::not_retained()))
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        
+        let mapping = TopicMapping::new(crate::domain::TopicStrategy::Native, "acme/uns").unwrap();
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client)).with_topic_mapping(mapping);
+        
+        let tag = Tag::new("US/TX/PUMP1.5".to_string(), "n".to_string(), "d".to_string(), "1".to_string());
+        publisher.publish_tag(&tag).await.unwrap();
+        publisher.publish_database(&TagDatabase::new(HashMap::new())).await.unwrap();
+        publisher
+            .publish_write_request(&WriteRequest::new(tag.path.clone(), "2".to_string()))
+            .await
+            .unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_publish_with_policies() {
+        let mut mock_client = MockMqttClient::new();
+        
+        // Vibration is fire-and-forget, everything else keeps the default
+        mock_client
+            .expect_publish()
+            .with(
+                eq("tags/US.TX.PUMP1.VIBRATION"),
+                always(),
+                eq(PublishPolicy::new(QosLevel::AtMostOnce, false))
+            )
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        mock_client
+            .expect_publish()
+            .with(
+                eq("tags/US.TX.PUMP1.SETPOINT"),
+                always(),
+                eq(PublishPolicy::new(QosLevel::ExactlyOnce, true))
+            )
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        
+        let policies = PublishPolicies::new(
+            PublishPolicy::new(QosLevel::ExactlyOnce, true),
+            vec!["class:vibration=qos0,no-retain".parse().unwrap()],
+        );
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client)).with_publish_policies(policies);
+        
+        let vibration = Tag::new("US/TX/PUMP1/VIBRATION".to_string(), "n".to_string(), "d".to_string(), "0.3".to_string())
+            .with_class("vibration");
+        let setpoint = Tag::new("US/TX/PUMP1/SETPOINT".to_string(), "n".to_string(), "d".to_string(), "50".to_string());
+        publisher.publish_tags(&[vibration, setpoint]).await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_publish_with_codec() {
+        let mut mock_client = MockMqttClient::new();
+        
+        // Non-JSON payloads advertise their codec; JSON ones stay as they were
+        mock_client
+            .expect_publish_with_properties()
+            .withf(|topic, payload, _, properties| {
+                let tag: Tag = PayloadCodec::Cbor.decode(payload).unwrap();
+                topic == "tags/US.TX.VIB"
+                    && tag.value == "0.3"
+                    && properties.content_type.as_deref() == Some("application/cbor")
+            })
+            .times(1)
+            .returning(|_, _, _, _| Box::pin(async { Ok(()) }));
+        mock_client
+            .expect_publish()
+            .withf(|topic, payload, _| {
+                topic == "tags/US.TX.FLOW" && serde_json::from_slice::<Tag>(payload).is_ok()
+            })
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        
+        let policies = PublishPolicies::new(PublishPolicy::default(), vec!["class:vibration=cbor".parse().unwrap()]);
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client)).with_publish_policies(policies);
+        
+        let vibration = Tag::new("US/TX/VIB".to_string(), "n".to_string(), "d".to_string(), "0.3".to_string())
+            .with_class("vibration");
+        let flow = Tag::new("US/TX/FLOW".to_string(), "n".to_string(), "d".to_string(), "12".to_string());
+        
+        assert!(publisher.publish_tag(&vibration).await.is_ok());
+        assert!(publisher.publish_tag(&flow).await.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_publish_write_request() {
+        let mut mock_client = MockMqttClient::new();
+        
+        // Set up expectations
+        mock_client
+            .expect_publish()
+            .with(
+                eq("tags/set/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1.PRESSURE"),
+                eq(br#"{"value":"50.2"}"#.to_vec()),
+                eq(PublishPolicy::not_retained())
+            )
+            .times(1)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client));
+        
+        let request = WriteRequest::new(
+            "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE".to_string(),
+            "50.2".to_string(),
+        );
+        
+        let result = publisher.publish_write_request(&request).await;
+        assert!(result.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_publish_stale() {
+        let mut mock_client = MockMqttClient::new();
+        
+        // Both the tag and the database keep their data and gain the flag
+        mock_client
+            .expect_publish()
+            .withf(|topic, payload, policy| {
+                let json: serde_json::Value = serde_json::from_slice(payload).unwrap();
+                policy.retain && json["stale"] == true && match topic {
+                    "tags/test.tag" => json["value"] == "test",
+                    "tags/database" => json["tags"]["test/tag"]["value"] == "test",
+                    _ => false,
+                }
+            })
+            .times(2)
+            .returning(|_, _, _| Box::pin(async { Ok(()) }));
+        
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client));
+        
+        let mut tags = HashMap::new();
+        tags.insert(
+            "test/tag".to_string(),
+            Tag::new(
+                "test/tag".to_string(),
+                "Test Tag".to_string(),
+                "A test tag".to_string(),
+                "test".to_string(),
+            ),
+        );
+        
+        let result = publisher.publish_stale(&TagDatabase::new(tags)).await;
+        assert!(result.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_publish_write_request_with_reply_to() {
+        let mut mock_client = MockMqttClient::new();
+        
+        // The response topic and correlation data travel as MQTT 5 properties
+        mock_client
+            .expect_publish_with_properties()
+            .with(
+                eq("tags/set/US.TX"),
+                eq(br#"{"value":"1"}"#.to_vec()),
+                eq(PublishPolicy::not_retained()),
+                eq(MessageProperties {
+                    response_topic: Some("tags/reply/abc".to_string()),
+                    correlation_data: Some(b"abc".to_vec()),
+                    ..MessageProperties::default()
+                })
+            )
+            .times(1)
+            .returning(|_, _, _, _| Box::pin(async { Ok(()) }));
+        
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client));
+        
+        let reply_to = ReplyTo {
+            topic: "tags/reply/abc".to_string(),
+            correlation_data: Some(b"abc".to_vec()),
+        };
+        let request = WriteRequest::new("US/TX".to_string(), "1".to_string()).with_reply_to(reply_to);
+        
+        let result = publisher.publish_write_request(&request).await;
+        assert!(result.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_publish_write_response() {
+        let mut mock_client = MockMqttClient::new();
+        
+        // Set up expectations
+        mock_client
+            .expect_publish_with_properties()
+            .with(
+                eq("tags/reply/abc"),
+                eq(br#"{"status":"ok"}"#.to_vec()),
+                eq(PublishPolicy::not_retained()),
+                eq(MessageProperties {
+                    response_topic: None,
+                    correlation_data: Some(b"abc".to_vec()),
+                    ..MessageProperties::default()
+                })
+            )
+            .times(1)
+            .returning(|_, _, _, _| Box::pin(async { Ok(()) }));
+        
+        let publisher = MqttTagPublisher::new(Arc::new(mock_client));
+        
+        let reply_to = ReplyTo {
+            topic: "tags/reply/abc".to_string(),
+            correlation_data: Some(b"abc".to_vec()),
+        };
+        
+        let result = publisher.publish_write_response(&reply_to, &WriteResponse::from_result(&Ok(()))).await;
+        assert!(result.is_ok());
+    }
 }
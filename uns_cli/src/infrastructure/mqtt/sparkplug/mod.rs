@@ -0,0 +1,9 @@
+// Sparkplug B module exports
+pub mod payload;
+pub mod publisher;
+pub mod topology;
+
+// Re-export key types
+pub use payload::{DataType, Metric, MetricValue, Payload};
+pub use publisher::SparkplugPublisher;
+pub use topology::{MessageType, SparkplugTopology};
@@ -0,0 +1,147 @@
+/// A Sparkplug B payload
+///
+/// Hand-written equivalent of the `Payload` message of `sparkplug_b.proto`, so
+/// no protoc is needed at build time. Only the fields this crate uses are
+/// declared; the others are skipped when decoding.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Payload {
+    /// Milliseconds since the Unix epoch
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+
+    /// Metrics carried by the message
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+
+    /// Sequence number, 0-255, absent in NDEATH
+    #[prost(uint64, optional, tag = "3")]
+    pub seq: Option<u64>,
+}
+
+/// A single metric of a payload
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Metric {
+    /// Metric name, required in births and optional once an alias is known
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+
+    /// Numeric alias announced in the birth
+    #[prost(uint64, optional, tag = "2")]
+    pub alias: Option<u64>,
+
+    /// Milliseconds since the Unix epoch
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+
+    /// One of the `DataType` codes
+    #[prost(uint32, optional, tag = "4")]
+    pub datatype: Option<u32>,
+
+    /// Metric value
+    #[prost(oneof = "MetricValue", tags = "10, 11, 12, 13, 14, 15, 16")]
+    pub value: Option<MetricValue>,
+}
+
+/// Value of a metric
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum MetricValue {
+    #[prost(uint32, tag = "10")]
+    IntValue(u32),
+
+    #[prost(uint64, tag = "11")]
+    LongValue(u64),
+
+    #[prost(float, tag = "12")]
+    FloatValue(f32),
+
+    #[prost(double, tag = "13")]
+    DoubleValue(f64),
+
+    #[prost(bool, tag = "14")]
+    BooleanValue(bool),
+
+    #[prost(string, tag = "15")]
+    StringValue(String),
+
+    #[prost(bytes = "vec", tag = "16")]
+    BytesValue(Vec<u8>),
+}
+
+/// Sparkplug B data types used for tag metrics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataType {
+    Int64 = 4,
+    UInt64 = 8,
+    Double = 10,
+    Boolean = 11,
+    String = 12,
+}
+
+impl DataType {
+    /// Picks the narrowest type able to hold a tag value
+    pub fn infer(value: &str) -> Self {
+        if value.parse::<bool>().is_ok() {
+            DataType::Boolean
+        } else if value.parse::<i64>().is_ok() {
+            DataType::Int64
+        } else if value.parse::<f64>().is_ok_and(f64::is_finite) {
+            DataType::Double
+        } else {
+            DataType::String
+        }
+    }
+
+    /// Returns the Sparkplug code of the type
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Encodes a tag value as this type, or `None` if it does not fit
+    pub fn encode(self, value: &str) -> Option<MetricValue> {
+        match self {
+            // Signed values travel as their two's complement in the unsigned field
+            DataType::Int64 => value.parse::<i64>().ok().map(|v| MetricValue::LongValue(v as u64)),
+            DataType::UInt64 => value.parse::<u64>().ok().map(MetricValue::LongValue),
+            DataType::Double => value.parse::<f64>().ok().map(MetricValue::DoubleValue),
+            DataType::Boolean => value.parse::<bool>().ok().map(MetricValue::BooleanValue),
+            DataType::String => Some(MetricValue::StringValue(value.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn test_infer_and_encode() {
+        assert_eq!(DataType::infer("true"), DataType::Boolean);
+        assert_eq!(DataType::infer("-42"), DataType::Int64);
+        assert_eq!(DataType::infer("50.2"), DataType::Double);
+        assert_eq!(DataType::infer("inf"), DataType::String);
+        assert_eq!(DataType::infer("RUNNING"), DataType::String);
+
+        assert_eq!(DataType::Int64.encode("-1"), Some(MetricValue::LongValue(u64::MAX)));
+        assert_eq!(DataType::Double.encode("7"), Some(MetricValue::DoubleValue(7.0)));
+        assert_eq!(DataType::Int64.encode("7.5"), None);
+    }
+
+    #[test]
+    fn test_payload_round_trip() {
+        let payload = Payload {
+            timestamp: Some(1_700_000_000_000),
+            metrics: vec![Metric {
+                name: Some("PUMP1/PRESSURE".to_string()),
+                alias: Some(3),
+                timestamp: None,
+                datatype: Some(DataType::Double.code()),
+                value: Some(MetricValue::DoubleValue(50.2)),
+            }],
+            seq: Some(0),
+        };
+
+        let decoded = Payload::decode(payload.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, payload);
+    }
+}
@@ -0,0 +1,538 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{error, info};
+use prost::Message;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::domain::{InstanceState, InstanceStatus, PublishPolicy, ReplyTo, Tag, TagDelta, WriteRequest, WriteResponse};
+use crate::infrastructure::mqtt::publisher::{MqttTagPublisher, TagDatabase};
+use crate::infrastructure::mqtt::sparkplug::payload::{DataType, Metric, MetricValue, Payload};
+use crate::infrastructure::mqtt::sparkplug::topology::{MessageType, SparkplugTopology};
+use crate::infrastructure::mqtt::{ConnectionState, MqttClient, MqttPublisher, MqttSubscriber, WillMessage};
+use crate::infrastructure::UnsError;
+
+/// Node metric carrying the birth/death sequence number
+const BD_SEQ_METRIC: &str = "bdSeq";
+
+/// Node metric a host application sets to request a new birth
+const REBIRTH_METRIC: &str = "Node Control/Rebirth";
+
+/// A tag as announced to host applications
+struct MetricState {
+    alias: u64,
+    datatype: DataType,
+    value: String,
+}
+
+/// Sequence numbers and announced metrics of the edge node
+#[derive(Default)]
+struct Session {
+    bd_seq: u64,
+    seq: u64,
+    born: bool,
+    next_alias: u64,
+
+    /// Metrics by device id (empty for node metrics) and metric name
+    devices: BTreeMap<String, BTreeMap<String, MetricState>>,
+}
+
+impl Session {
+    /// Returns the sequence number of the next message, wrapping after 255
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        seq
+    }
+}
+
+/// The edge node shared by the publisher and its session task
+struct EdgeNode {
+    client: Arc<dyn MqttClient>,
+    topology: SparkplugTopology,
+    session: Mutex<Session>,
+}
+
+impl EdgeNode {
+    /// Records tag values and publishes births for new metrics and data for changed ones
+    async fn update(&self, tags: &[Tag]) -> Result<(), UnsError> {
+        let mut guard = self.session.lock().await;
+        let session = &mut *guard;
+        let timestamp = now_millis();
+        let mut rebirths = BTreeSet::new();
+        let mut changes: BTreeMap<String, Vec<Metric>> = BTreeMap::new();
+
+        for tag in tags {
+            let (device_id, name) = self.topology.metric_address(&tag.path);
+            let metrics = session.devices.entry(device_id.clone()).or_default();
+
+            let Some(metric) = metrics.get_mut(&name) else {
+                metrics.insert(
+                    name,
+                    MetricState {
+                        alias: session.next_alias,
+                        datatype: DataType::infer(&tag.value),
+                        value: tag.value.clone(),
+                    },
+                );
+                session.next_alias += 1;
+                rebirths.insert(device_id);
+                continue;
+            };
+
+            if metric.value == tag.value {
+                continue;
+            }
+            metric.value = tag.value.clone();
+
+            match metric.datatype.encode(&tag.value) {
+                Some(value) => changes.entry(device_id).or_default().push(Metric {
+                    alias: Some(metric.alias),
+                    timestamp: Some(timestamp),
+                    datatype: Some(metric.datatype.code()),
+                    value: Some(value),
+                    ..Metric::default()
+                }),
+                None => {
+                    // The announced type cannot hold the value, so announce it again
+                    metric.datatype = DataType::infer(&tag.value);
+                    rebirths.insert(device_id);
+                }
+            }
+        }
+
+        // Node metrics can only be announced by a new NBIRTH
+        if !session.born || rebirths.contains("") {
+            if self.client.connection_state().borrow().is_connected() {
+                return self.birth(session).await;
+            }
+            return Ok(());
+        }
+
+        for device_id in &rebirths {
+            changes.remove(device_id);
+            self.device_birth(session, device_id, timestamp).await?;
+        }
+
+        for (device_id, metrics) in changes {
+            let topic = if device_id.is_empty() {
+                self.topology.node_topic(MessageType::NData)
+            } else {
+                self.topology.device_topic(MessageType::DData, &device_id)
+            };
+            self.publish(session, &topic, timestamp, metrics).await?;
+        }
+        Ok(())
+    }
+
+    /// Announces the node and every device with their current values
+    async fn birth(&self, session: &mut Session) -> Result<(), UnsError> {
+        let timestamp = now_millis();
+        session.seq = 0;
+
+        let mut metrics = vec![
+            Metric {
+                name: Some(BD_SEQ_METRIC.to_string()),
+                datatype: Some(DataType::UInt64.code()),
+                value: Some(MetricValue::LongValue(session.bd_seq)),
+                ..Metric::default()
+            },
+            Metric {
+                name: Some(REBIRTH_METRIC.to_string()),
+                datatype: Some(DataType::Boolean.code()),
+                value: Some(MetricValue::BooleanValue(false)),
+                ..Metric::default()
+            },
+        ];
+        if let Some(node_metrics) = session.devices.get("") {
+            metrics.extend(birth_metrics(node_metrics, timestamp));
+        }
+        self.publish(session, &self.topology.node_topic(MessageType::NBirth), timestamp, metrics)
+            .await?;
+
+        let device_ids: Vec<String> = session.devices.keys().filter(|id| !id.is_empty()).cloned().collect();
+        for device_id in device_ids {
+            self.device_birth(session, &device_id, timestamp).await?;
+        }
+
+        session.born = true;
+        Ok(())
+    }
+
+    /// Announces one device with all its metrics
+    async fn device_birth(&self, session: &mut Session, device_id: &str, timestamp: u64) -> Result<(), UnsError> {
+        let metrics = birth_metrics(&session.devices[device_id], timestamp);
+        let topic = self.topology.device_topic(MessageType::DBirth, device_id);
+        self.publish(session, &topic, timestamp, metrics).await
+    }
+
+    /// Publishes a payload with the next sequence number
+    async fn publish(&self, session: &mut Session, topic: &str, timestamp: u64, metrics: Vec<Metric>) -> Result<(), UnsError> {
+        let payload = Payload {
+            timestamp: Some(timestamp),
+            metrics,
+            seq: Some(session.next_seq()),
+        };
+
+        // Sparkplug messages are never retained
+        self.client.publish(topic, payload.encode_to_vec(), PublishPolicy::not_retained()).await
+    }
+
+    /// Publishes the node death before a graceful disconnect
+    async fn death(&self) -> Result<(), UnsError> {
+        let mut session = self.session.lock().await;
+        session.born = false;
+
+        let death = death_certificate(&self.topology, session.bd_seq);
+        self.client.publish(&death.topic, death.payload, PublishPolicy::not_retained()).await
+    }
+
+    /// Starts a new session after the connection was lost
+    ///
+    /// The next NBIRTH must carry the bdSeq of the Last Will registered by the
+    /// reconnect, so both move on together.
+    async fn connection_lost(&self) {
+        let mut session = self.session.lock().await;
+        if session.born {
+            session.born = false;
+            session.bd_seq = (session.bd_seq + 1) % 256;
+            self.client.set_last_will(death_certificate(&self.topology, session.bd_seq));
+        }
+    }
+}
+
+/// Publishes tags as a Sparkplug B edge node
+///
+/// Every connection starts with an NBIRTH followed by a DBIRTH per device,
+/// which define each metric with its alias and data type. Value changes are
+/// then sent as DDATA (NDATA for node metrics) carrying only the alias. New
+/// metrics, or values that no longer fit the announced type, trigger a new
+/// birth. The NDEATH is registered as Last Will.
+///
+/// Write requests and replies are not tag state and keep using the JSON topics.
+pub struct SparkplugPublisher {
+    node: Arc<EdgeNode>,
+    json: MqttTagPublisher,
+}
+
+impl SparkplugPublisher {
+    /// Creates a new Sparkplug publisher
+    pub fn new(client: Arc<dyn MqttClient>, topology: SparkplugTopology) -> Self {
+        Self {
+            json: MqttTagPublisher::new(client.clone()),
+            node: Arc::new(EdgeNode {
+                client,
+                topology,
+                session: Mutex::new(Session::default()),
+            }),
+        }
+    }
+
+    /// Returns the NDEATH to register as Last Will of the first connection
+    pub fn will_message(topology: &SparkplugTopology) -> WillMessage {
+        death_certificate(topology, 0)
+    }
+
+    /// Follows the connection and rebirth requests in a background task
+    ///
+    /// Sends the births once connected, prepares the next bdSeq when the
+    /// connection is lost and answers `Node Control/Rebirth` commands.
+    pub async fn start(&self, subscriber: Arc<dyn MqttSubscriber>) -> Result<JoinHandle<()>, UnsError> {
+        let mut commands = subscriber
+            .subscribe_stream(&self.node.topology.node_topic(MessageType::NCmd))
+            .await?;
+        let mut state = self.node.client.connection_state();
+        let node = self.node.clone();
+
+        Ok(tokio::spawn(async move {
+            loop {
+                let current = state.borrow_and_update().clone();
+                match current {
+                    ConnectionState::Connected => {
+                        let mut session = node.session.lock().await;
+                        if !session.born {
+                            if let Err(e) = node.birth(&mut session).await {
+                                error!("Error publishing Sparkplug birth: {}", e);
+                            }
+                        }
+                    }
+                    ConnectionState::Failed(_) => break,
+                    _ => node.connection_lost().await,
+                }
+
+                // Answer rebirth requests until the connection state changes
+                loop {
+                    tokio::select! {
+                        changed = state.changed() => {
+                            if changed.is_err() {
+                                return;
+                            }
+                            break;
+                        }
+                        Some(message) = commands.recv() => {
+                            if is_rebirth_request(&message.payload) {
+                                info!("Rebirth requested on {}", message.topic);
+                                let mut session = node.session.lock().await;
+                                if let Err(e) = node.birth(&mut session).await {
+                                    error!("Error publishing Sparkplug birth: {}", e);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }))
+    }
+}
+
+#[async_trait]
+impl MqttPublisher for SparkplugPublisher {
+    async fn publish_tag(&self, tag: &Tag) -> Result<(), UnsError> {
+        self.node.update(std::slice::from_ref(tag)).await
+    }
+
+    async fn publish_tags(&self, tags: &[Tag]) -> Result<(), UnsError> {
+        self.node.update(tags).await
+    }
+
+    async fn publish_database(&self, _data: &TagDatabase) -> Result<(), UnsError> {
+        // The births already describe every metric
+        Ok(())
+    }
+
+    async fn publish_delta(&self, _delta: &TagDelta) -> Result<(), UnsError> {
+        // Data messages already carry every change
+        Ok(())
+    }
+
+    async fn publish_write_request(&self, request: &WriteRequest) -> Result<(), UnsError> {
+        self.json.publish_write_request(request).await
+    }
+
+    async fn publish_write_response(&self, reply_to: &ReplyTo, response: &WriteResponse) -> Result<(), UnsError> {
+        self.json.publish_write_response(reply_to, response).await
+    }
+
+    async fn publish_status(&self, status: &InstanceStatus) -> Result<(), UnsError> {
+        // NBIRTH and NDEATH are the Sparkplug status messages
+        match status.state {
+            InstanceState::Online => Ok(()),
+            InstanceState::Offline => self.node.death().await,
+        }
+    }
+
+    async fn publish_stale(&self, _data: &TagDatabase) -> Result<(), UnsError> {
+        // Host applications mark every metric stale on NDEATH
+        Ok(())
+    }
+
+    fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.node.client.connection_state()
+    }
+}
+
+/// Returns the current time in milliseconds since the Unix epoch
+fn now_millis() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+/// Describes metrics with name, alias, type and current value
+fn birth_metrics(metrics: &BTreeMap<String, MetricState>, timestamp: u64) -> Vec<Metric> {
+    metrics
+        .iter()
+        .map(|(name, metric)| Metric {
+            name: Some(name.clone()),
+            alias: Some(metric.alias),
+            timestamp: Some(timestamp),
+            datatype: Some(metric.datatype.code()),
+            value: metric.datatype.encode(&metric.value),
+        })
+        .collect()
+}
+
+/// Builds the NDEATH of a session
+fn death_certificate(topology: &SparkplugTopology, bd_seq: u64) -> WillMessage {
+    let payload = Payload {
+        timestamp: Some(now_millis()),
+        metrics: vec![Metric {
+            name: Some(BD_SEQ_METRIC.to_string()),
+            datatype: Some(DataType::UInt64.code()),
+            value: Some(MetricValue::LongValue(bd_seq)),
+            ..Metric::default()
+        }],
+        seq: None,
+    };
+
+    WillMessage {
+        topic: topology.node_topic(MessageType::NDeath),
+        payload: payload.encode_to_vec(),
+        retain: false,
+    }
+}
+
+/// Returns true for an NCMD setting `Node Control/Rebirth`
+fn is_rebirth_request(payload: &[u8]) -> bool {
+    Payload::decode(payload).is_ok_and(|payload| {
+        payload.metrics.iter().any(|metric| {
+            metric.name.as_deref() == Some(REBIRTH_METRIC) && metric.value == Some(MetricValue::BooleanValue(true))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::mqtt::client::MockMqttClient;
+    use std::sync::Mutex as StdMutex;
+
+    type Sent = Arc<StdMutex<Vec<(String, Payload)>>>;
+
+    // Creates a mock client in the given state that records every publish
+    fn recording_client(state: ConnectionState) -> (MockMqttClient, Sent) {
+        let sent: Sent = Arc::new(StdMutex::new(Vec::new()));
+
+        let mut mock_client = MockMqttClient::new();
+        mock_client
+            .expect_connection_state()
+            .returning(move || watch::channel(state.clone()).1);
+        let recorder = sent.clone();
+        mock_client
+            .expect_publish()
+            .withf(|_, _, policy| !policy.retain)
+            .returning(move |topic, payload, _| {
+                let payload = Payload::decode(payload.as_slice()).unwrap();
+                recorder.lock().unwrap().push((topic.to_string(), payload));
+                Box::pin(async { Ok(()) })
+            });
+
+        (mock_client, sent)
+    }
+
+    fn tag(path: &str, value: &str) -> Tag {
+        Tag::new(path.to_string(), "name".to_string(), "description".to_string(), value.to_string())
+    }
+
+    fn topics(sent: &Sent) -> Vec<String> {
+        sent.lock().unwrap().drain(..).map(|(topic, _)| topic).collect()
+    }
+
+    #[tokio::test]
+    async fn test_births_then_data() {
+        let (mock_client, sent) = recording_client(ConnectionState::Connected);
+        let publisher = SparkplugPublisher::new(Arc::new(mock_client), SparkplugTopology::new("UNS", "gw"));
+
+        publisher
+            .publish_tags(&[tag("US/TX/PUMP1/PRESSURE", "50.2"), tag("US/TX/PUMP2/RUNNING", "true")])
+            .await
+            .unwrap();
+
+        // NBIRTH, then one DBIRTH per device, numbered from 0
+        {
+            let sent = sent.lock().unwrap();
+            let seqs: Vec<_> = sent.iter().map(|(_, payload)| payload.seq).collect();
+            assert_eq!(seqs, vec![Some(0), Some(1), Some(2)]);
+
+            let (topic, nbirth) = &sent[0];
+            assert_eq!(topic, "spBv1.0/UNS/NBIRTH/gw");
+            assert_eq!(nbirth.metrics[0].name.as_deref(), Some("bdSeq"));
+            assert_eq!(nbirth.metrics[0].value, Some(MetricValue::LongValue(0)));
+
+            let (topic, dbirth) = &sent[1];
+            assert_eq!(topic, "spBv1.0/UNS/DBIRTH/gw/US.TX.PUMP1");
+            assert_eq!(dbirth.metrics[0].name.as_deref(), Some("PRESSURE"));
+            assert_eq!(dbirth.metrics[0].datatype, Some(DataType::Double.code()));
+            assert_eq!(dbirth.metrics[0].value, Some(MetricValue::DoubleValue(50.2)));
+        }
+        let alias = sent.lock().unwrap()[1].1.metrics[0].alias;
+        sent.lock().unwrap().clear();
+
+        // A changed value is sent by alias only; an unchanged one not at all
+        publisher.publish_tag(&tag("US/TX/PUMP1/PRESSURE", "51")).await.unwrap();
+        publisher.publish_tag(&tag("US/TX/PUMP2/RUNNING", "true")).await.unwrap();
+        {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            let (topic, ddata) = &sent[0];
+            assert_eq!(topic, "spBv1.0/UNS/DDATA/gw/US.TX.PUMP1");
+            assert_eq!(ddata.seq, Some(3));
+            assert_eq!(ddata.metrics[0].name, None);
+            assert_eq!(ddata.metrics[0].alias, alias);
+            assert_eq!(ddata.metrics[0].value, Some(MetricValue::DoubleValue(51.0)));
+        }
+        sent.lock().unwrap().clear();
+
+        // New metrics and values of another type need a new DBIRTH
+        publisher.publish_tag(&tag("US/TX/PUMP1/FLOW", "3")).await.unwrap();
+        publisher.publish_tag(&tag("US/TX/PUMP2/RUNNING", "FAULT")).await.unwrap();
+        assert_eq!(
+            topics(&sent),
+            vec!["spBv1.0/UNS/DBIRTH/gw/US.TX.PUMP1", "spBv1.0/UNS/DBIRTH/gw/US.TX.PUMP2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_connection() {
+        let (mock_client, sent) = recording_client(ConnectionState::Connecting);
+        let publisher = SparkplugPublisher::new(Arc::new(mock_client), SparkplugTopology::new("UNS", "gw"));
+
+        publisher.publish_tags(&[tag("US/TX/PUMP1/PRESSURE", "50.2")]).await.unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_death_and_next_session() {
+        let (mut mock_client, sent) = recording_client(ConnectionState::Connected);
+
+        // The reconnect registers the NDEATH of the next session
+        mock_client
+            .expect_set_last_will()
+            .withf(|will| {
+                let payload = Payload::decode(will.payload.as_slice()).unwrap();
+                will.topic == "spBv1.0/UNS/NDEATH/gw" && payload.metrics[0].value == Some(MetricValue::LongValue(1))
+            })
+            .times(1)
+            .return_const(());
+
+        let publisher = SparkplugPublisher::new(Arc::new(mock_client), SparkplugTopology::new("UNS", "gw"));
+        publisher.publish_tags(&[tag("PRESSURE", "1")]).await.unwrap();
+        assert_eq!(topics(&sent), vec!["spBv1.0/UNS/NBIRTH/gw"]);
+
+        publisher.node.connection_lost().await;
+        publisher.node.connection_lost().await;
+
+        // The births of the new session carry the new bdSeq
+        let mut session = publisher.node.session.lock().await;
+        publisher.node.birth(&mut session).await.unwrap();
+        drop(session);
+        publisher.publish_status(&InstanceStatus::offline("gw")).await.unwrap();
+
+        let sent = sent.lock().unwrap();
+        let (_, nbirth) = &sent[0];
+        assert_eq!(nbirth.seq, Some(0));
+        assert_eq!(nbirth.metrics[0].value, Some(MetricValue::LongValue(1)));
+        assert_eq!(nbirth.metrics[2].name.as_deref(), Some("PRESSURE"));
+
+        let (topic, ndeath) = &sent[1];
+        assert_eq!(topic, "spBv1.0/UNS/NDEATH/gw");
+        assert_eq!(ndeath.seq, None);
+        assert_eq!(ndeath.metrics[0].value, Some(MetricValue::LongValue(1)));
+    }
+
+    #[test]
+    fn test_rebirth_request() {
+        let request = Payload {
+            timestamp: None,
+            metrics: vec![Metric {
+                name: Some(REBIRTH_METRIC.to_string()),
+                value: Some(MetricValue::BooleanValue(true)),
+                ..Metric::default()
+            }],
+            seq: None,
+        };
+        assert!(is_rebirth_request(&request.encode_to_vec()));
+        assert!(!is_rebirth_request(&Payload::default().encode_to_vec()));
+        assert!(!is_rebirth_request(b"\xff\xff"));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::infrastructure::UnsError;
+
+/// Topic namespace of Sparkplug B
+pub const NAMESPACE: &str = "spBv1.0";
+
+/// Sparkplug message types sent or received by an edge node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    NBirth,
+    NDeath,
+    NData,
+    NCmd,
+    DBirth,
+    DData,
+}
+
+impl MessageType {
+    /// Returns the message type as it appears in topics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageType::NBirth => "NBIRTH",
+            MessageType::NDeath => "NDEATH",
+            MessageType::NData => "NDATA",
+            MessageType::NCmd => "NCMD",
+            MessageType::DBirth => "DBIRTH",
+            MessageType::DData => "DDATA",
+        }
+    }
+}
+
+/// Maps tag paths onto the Sparkplug group / edge node / device hierarchy
+///
+/// One instance is one edge node, because the node's NDEATH is the Last Will
+/// of its connection. The leading `device_levels` path segments name the
+/// device, joined with '.', and the remaining segments form the metric name.
+/// Without `device_levels`, all segments but the last name the device. Tags
+/// with no segment left for the device become metrics of the node itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SparkplugTopology {
+    /// Sparkplug group id
+    pub group_id: String,
+
+    /// Sparkplug edge node id of this instance
+    pub edge_node_id: String,
+
+    /// Number of leading path segments forming the device id
+    pub device_levels: Option<usize>,
+}
+
+impl SparkplugTopology {
+    /// Creates a topology placing each tag's parent path in its own device
+    pub fn new(group_id: &str, edge_node_id: &str) -> Self {
+        Self {
+            group_id: group_id.to_string(),
+            edge_node_id: edge_node_id.to_string(),
+            device_levels: None,
+        }
+    }
+
+    /// Uses a fixed number of leading path segments as device id
+    pub fn with_device_levels(mut self, levels: usize) -> Self {
+        self.device_levels = Some(levels);
+        self
+    }
+
+    /// Checks that the ids are usable as Sparkplug topic levels
+    pub fn validate(&self) -> Result<(), UnsError> {
+        for (kind, id) in [("group id", &self.group_id), ("edge node id", &self.edge_node_id)] {
+            if id.is_empty() || id.contains(['/', '+', '#']) {
+                return Err(UnsError::Validation(format!(
+                    "Sparkplug {} must be non-empty and must not contain '/', '+' or '#': '{}'",
+                    kind, id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the topic of a node-level message
+    pub fn node_topic(&self, message_type: MessageType) -> String {
+        format!("{}/{}/{}/{}", NAMESPACE, self.group_id, message_type.as_str(), self.edge_node_id)
+    }
+
+    /// Returns the topic of a device-level message
+    pub fn device_topic(&self, message_type: MessageType, device_id: &str) -> String {
+        format!("{}/{}", self.node_topic(message_type), device_id)
+    }
+
+    /// Splits a tag path into its device id and metric name
+    ///
+    /// An empty device id means the metric belongs to the node.
+    pub fn metric_address(&self, path: &str) -> (String, String) {
+        let segments: Vec<&str> = path.split('/').collect();
+        let levels = self
+            .device_levels
+            .unwrap_or(segments.len() - 1)
+            .min(segments.len() - 1);
+
+        (segments[..levels].join("."), segments[levels..].join("/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_address() {
+        let path = "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE";
+
+        let topology = SparkplugTopology::new("UNS", "gateway-1");
+        assert_eq!(
+            topology.metric_address(path),
+            ("US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1".to_string(), "PRESSURE".to_string())
+        );
+        assert_eq!(topology.metric_address("PRESSURE"), (String::new(), "PRESSURE".to_string()));
+
+        let topology = topology.with_device_levels(3);
+        assert_eq!(
+            topology.metric_address(path),
+            ("US.TX.AUSTIN".to_string(), "AREA1/LINE1/MACHINE1/PUMP1/PRESSURE".to_string())
+        );
+        assert_eq!(topology.metric_address("US/PRESSURE"), ("US".to_string(), "PRESSURE".to_string()));
+
+        assert_eq!(topology.node_topic(MessageType::NBirth), "spBv1.0/UNS/NBIRTH/gateway-1");
+        assert_eq!(topology.device_topic(MessageType::DData, "US.TX.AUSTIN"), "spBv1.0/UNS/DDATA/gateway-1/US.TX.AUSTIN");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(SparkplugTopology::new("UNS", "gateway-1").validate().is_ok());
+        assert!(SparkplugTopology::new("", "gateway-1").validate().is_err());
+        assert!(SparkplugTopology::new("UNS", "site/gateway").validate().is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use async_trait::async_trait;
+use rumqttc::v5::mqttbytes::QoS;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+
+use crate::infrastructure::UnsError;
+
+/// A message received from the broker
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncomingMessage {
+    /// Topic the message was published on
+    pub topic: String,
+
+    /// Raw message payload
+    pub payload: Vec<u8>,
+
+    /// True when the broker delivered a retained message
+    pub retain: bool,
+
+    /// Quality of service the message was delivered with
+    pub qos: QoS,
+
+    /// MQTT 5 properties sent along with the message
+    pub properties: MessageProperties,
+}
+
+/// MQTT 5 publish properties used by this application
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageProperties {
+    /// Topic the receiver should publish its reply to
+    pub response_topic: Option<String>,
+
+    /// Opaque data the reply echoes so the requester can match it
+    pub correlation_data: Option<Vec<u8>>,
+
+    /// Encoding of the payload, e.g. `application/cbor`
+    pub content_type: Option<String>,
+}
+
+impl IncomingMessage {
+    /// Creates a non-retained QoS 1 message, mostly useful in tests
+    pub fn new(topic: &str, payload: &[u8]) -> Self {
+        Self {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            properties: MessageProperties::default(),
+        }
+    }
+}
+
+/// Stream of messages matching one subscription filter
+///
+/// Implements `tokio_stream::Stream`; `recv` is a shortcut for callers that
+/// do not want to import `StreamExt`. The stream ends when the client shuts
+/// down.
+pub struct MessageStream {
+    receiver: mpsc::Receiver<IncomingMessage>,
+}
+
+impl MessageStream {
+    /// Creates a connected sender/stream pair
+    ///
+    /// Client implementations keep the sender; tests can use it to feed
+    /// messages to the code under test.
+    pub fn channel(capacity: usize) -> (mpsc::Sender<IncomingMessage>, Self) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (sender, Self { receiver })
+    }
+
+    /// Creates a stream that yields the given messages and then ends
+    pub fn from_messages(messages: Vec<IncomingMessage>) -> Self {
+        let (sender, stream) = Self::channel(messages.len().max(1));
+        for message in messages {
+            // Cannot fail: the channel is large enough and the receiver is alive
+            let _ = sender.try_send(message);
+        }
+        stream
+    }
+
+    /// Receives the next message
+    pub async fn recv(&mut self) -> Option<IncomingMessage> {
+        self.receiver.recv().await
+    }
+
+    /// Returns the next message if one is already buffered
+    pub fn try_recv(&mut self) -> Option<IncomingMessage> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Stream for MessageStream {
+    type Item = IncomingMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// MQTT subscriber interface
+#[async_trait]
+#[cfg_attr(test, mockall::automock)]
+pub trait MqttSubscriber: Send + Sync {
+    /// Subscribes to a topic filter (wildcards allowed) and returns the matching messages
+    async fn subscribe_stream(&self, filter: &str) -> Result<MessageStream, UnsError>;
+}
+
+/// Checks whether a topic matches a subscription filter with `+` and `#` wildcards
+///
+/// Follows the MQTT rules: `#` also matches the parent level, and wildcards
+/// at the first level never match topics starting with `$`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("tags/#", "tags/US.TX"));
+        assert!(topic_matches("tags/#", "tags"));
+        assert!(topic_matches("tags/+/PUMP1", "tags/US/PUMP1"));
+        assert!(topic_matches("tags/set/US.TX", "tags/set/US.TX"));
+        assert!(!topic_matches("tags/+", "tags/US/PUMP1"));
+        assert!(!topic_matches("tags/set/#", "tags/US.TX"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+    }
+
+    #[tokio::test]
+    async fn test_message_stream() {
+        let mut stream = MessageStream::from_messages(vec![
+            IncomingMessage::new("tags/a", b"1"),
+            IncomingMessage::new("tags/b", b"2"),
+        ]);
+
+        assert_eq!(stream.next().await.unwrap().topic, "tags/a");
+        assert_eq!(stream.recv().await.unwrap().payload, b"2".to_vec());
+        assert!(stream.next().await.is_none());
+    }
+}
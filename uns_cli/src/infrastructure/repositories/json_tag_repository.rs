@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use log::debug;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
@@ -8,6 +9,7 @@ use std::{
 };
 
 use crate::domain::{Tag, TagRepository};
+use crate::infrastructure::repositories::TagFileValidator;
 use crate::infrastructure::UnsError;
 
 /// Data structure for serializing/deserializing tags
@@ -37,6 +39,12 @@ impl JsonTagRepository {
     }
 }
 
+impl Default for JsonTagRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait]
 impl TagRepository for JsonTagRepository {
     async fn load_tags(&self, source: &str) -> Result<HashMap<String, Tag>, UnsError> {
@@ -49,6 +57,15 @@ impl TagRepository for JsonTagRepository {
         let contents = fs::read_to_string(source)
             .map_err(|e| UnsError::Repository(format!("Failed to read file {}: {}", source, e)))?;
         
+        // Validate the file so mistakes are reported with their position
+        let report = TagFileValidator::validate_str(&contents);
+        if !report.is_valid() {
+            return Err(UnsError::Validation(format!(
+                "Invalid tag file:\n{}",
+                report.render(source)
+            )));
+        }
+        
         // Parse the JSON
         let data: TagData = serde_json::from_str(&contents)
             .map_err(|e| UnsError::Serialization(format!("Failed to parse JSON: {}", e)))?;
@@ -89,7 +106,7 @@ impl TagRepository for JsonTagRepository {
         
         if let Some(tag) = tags_map.get_mut(path) {
             // Log the change before updating
-            println!("Updating tag: {} from '{}' to '{}'", path, tag.value, value);
+            debug!("Updating tag: {} from '{}' to '{}'", path, tag.value, value);
             
             // Update the value
             tag.value = value;
@@ -101,6 +118,12 @@ impl TagRepository for JsonTagRepository {
         }
     }
     
+    async fn put_tag(&self, tag: Tag) -> Result<(), UnsError> {
+        let mut tags_map = self.tags.write().unwrap();
+        tags_map.insert(tag.path.clone(), tag);
+        Ok(())
+    }
+    
     async fn get_all_tags(&self) -> Result<HashMap<String, Tag>, UnsError> {
         let tags_map = self.tags.read().unwrap();
         Ok(tags_map.clone())
@@ -142,6 +165,33 @@ mod tests {
         assert_eq!(tag.value, "45.7");
     }
     
+    #[tokio::test]
+    async fn test_load_tags_rejects_key_path_mismatch() {
+        // Create a temporary file whose key differs from the embedded path
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let test_data = r#"{
+            "tags": {
+                "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE": {
+                    "path": "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESURE",
+                    "name": "Pump 1 Pressure",
+                    "description": "Pressure sensor for Pump 1",
+                    "value": "45.7"
+                }
+            }
+        }"#;
+        use std::io::Write;
+        write!(temp_file, "{}", test_data).unwrap();
+        
+        // Loading must fail instead of accepting the file silently
+        let repo = JsonTagRepository::new();
+        let result = repo.load_tags(temp_file.path().to_str().unwrap()).await;
+        
+        match result {
+            Err(UnsError::Validation(msg)) => assert!(msg.contains(":3:17: tag key")),
+            other => panic!("Expected validation error, got {:?}", other),
+        }
+    }
+    
     #[tokio::test]
     async fn test_update_tag() {
         // Create a repository with a test tag
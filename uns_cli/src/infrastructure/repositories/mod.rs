@@ -1,5 +1,11 @@
 // Repository implementations module exports
 pub mod json_tag_repository;
+pub mod redb_tag_repository;
+pub mod tag_file_validator;
+pub mod tag_history_file;
 
 // Re-export key types
 pub use json_tag_repository::JsonTagRepository;
+pub use redb_tag_repository::RedbTagRepository;
+pub use tag_file_validator::TagFileValidator;
+pub use tag_history_file::TagHistoryFile;
@@ -0,0 +1,389 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use crate::domain::{Tag, TagRepository};
+use crate::infrastructure::repositories::JsonTagRepository;
+use crate::infrastructure::UnsError;
+
+/// Tags keyed by path, stored as JSON-encoded `StoredTag` records
+const TAGS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("tags");
+
+/// Store-level metadata (schema version, migration source, ...)
+const METADATA_TABLE: TableDefinition<&str, &str> = TableDefinition::new("metadata");
+
+/// Current layout version of the store
+const SCHEMA_VERSION: &str = "1";
+
+/// A tag together with its per-tag metadata
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct StoredTag {
+    tag: Tag,
+    updated_at: DateTime<Utc>,
+}
+
+impl StoredTag {
+    fn new(tag: Tag) -> Self {
+        Self {
+            tag,
+            updated_at: Utc::now(),
+        }
+    }
+}
+
+/// Embedded key-value store implementation of TagRepository backed by redb
+///
+/// Every write runs in its own ACID transaction, so a power loss leaves the
+/// store at the last committed state. The database file is fixed when the
+/// store is opened; the `source` and `destination` arguments of the trait
+/// only matter for the initial migration from a JSON tag file.
+pub struct RedbTagRepository {
+    db: Arc<Database>,
+}
+
+impl RedbTagRepository {
+    /// Opens (or creates) a store at the given file path
+    pub fn open(path: &str) -> Result<Self, UnsError> {
+        let db = Database::create(path)
+            .map_err(|e| UnsError::Repository(format!("Failed to open store {}: {}", path, e)))?;
+
+        // Make sure both tables exist so read transactions can always open them
+        let txn = db.begin_write().map_err(store_error)?;
+        {
+            txn.open_table(TAGS_TABLE).map_err(store_error)?;
+            let mut metadata = txn.open_table(METADATA_TABLE).map_err(store_error)?;
+            if metadata.get("schema_version").map_err(store_error)?.is_none() {
+                metadata.insert("schema_version", SCHEMA_VERSION).map_err(store_error)?;
+            }
+        }
+        txn.commit().map_err(store_error)?;
+
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Gets a store-level metadata value
+    pub fn metadata(&self, key: &str) -> Result<Option<String>, UnsError> {
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(METADATA_TABLE).map_err(store_error)?;
+        let value = table.get(key).map_err(store_error)?;
+        Ok(value.map(|v| v.value().to_string()))
+    }
+
+    /// Gets the time a tag was last written
+    pub fn updated_at(&self, path: &str) -> Result<Option<DateTime<Utc>>, UnsError> {
+        Ok(self.get_stored(path)?.map(|stored| stored.updated_at))
+    }
+
+    /// Returns true when the store holds no tags
+    fn is_empty(&self) -> Result<bool, UnsError> {
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(TAGS_TABLE).map_err(store_error)?;
+        table.is_empty().map_err(store_error)
+    }
+
+    fn get_stored(&self, path: &str) -> Result<Option<StoredTag>, UnsError> {
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(TAGS_TABLE).map_err(store_error)?;
+        let value = table.get(path).map_err(store_error)?;
+        value.map(|v| decode(v.value())).transpose()
+    }
+
+    /// Imports a JSON tag file into the store in a single transaction
+    async fn migrate_from_json(&self, source: &str) -> Result<HashMap<String, Tag>, UnsError> {
+        info!("Migrating tags from {} into the embedded store...", source);
+
+        let tags = JsonTagRepository::new().load_tags(source).await?;
+        let migrated_at = Utc::now().to_rfc3339();
+
+        let txn = self.db.begin_write().map_err(store_error)?;
+        {
+            let mut table = txn.open_table(TAGS_TABLE).map_err(store_error)?;
+            for (path, tag) in &tags {
+                let bytes = encode(&StoredTag::new(tag.clone()))?;
+                table.insert(path.as_str(), bytes.as_slice()).map_err(store_error)?;
+            }
+
+            let mut metadata = txn.open_table(METADATA_TABLE).map_err(store_error)?;
+            metadata.insert("migrated_from", source).map_err(store_error)?;
+            metadata.insert("migrated_at", migrated_at.as_str()).map_err(store_error)?;
+        }
+        txn.commit().map_err(store_error)?;
+
+        info!("Migrated {} tags.", tags.len());
+        Ok(tags)
+    }
+}
+
+#[async_trait]
+impl TagRepository for RedbTagRepository {
+    async fn load_tags(&self, source: &str) -> Result<HashMap<String, Tag>, UnsError> {
+        // The store is the source of truth once it holds tags; the JSON
+        // file is only read on first start
+        if self.is_empty()? && Path::new(source).exists() {
+            return self.migrate_from_json(source).await;
+        }
+
+        self.get_all_tags().await
+    }
+
+    async fn save_tags(&self, tags: &HashMap<String, Tag>, _destination: &str) -> Result<(), UnsError> {
+        let txn = self.db.begin_write().map_err(store_error)?;
+        {
+            let mut table = txn.open_table(TAGS_TABLE).map_err(store_error)?;
+            table.retain(|path, _| tags.contains_key(path)).map_err(store_error)?;
+
+            for (path, tag) in tags {
+                write_tag(&mut table, path, tag)?;
+            }
+        }
+        txn.commit().map_err(store_error)?;
+
+        Ok(())
+    }
+
+    async fn get_tag(&self, path: &str) -> Result<Option<Tag>, UnsError> {
+        Ok(self.get_stored(path)?.map(|stored| stored.tag))
+    }
+
+    async fn update_tag(&self, path: &str, value: String) -> Result<Option<Tag>, UnsError> {
+        let txn = self.db.begin_write().map_err(store_error)?;
+        let updated = {
+            let mut table = txn.open_table(TAGS_TABLE).map_err(store_error)?;
+            let existing = table.get(path).map_err(store_error)?.map(|v| decode(v.value()));
+
+            let mut stored = match existing {
+                Some(stored) => stored?,
+                None => return Err(UnsError::NotFound(format!("Tag not found: {}", path))),
+            };
+
+            // Log the change before updating
+            debug!("Updating tag: {} from '{}' to '{}'", path, stored.tag.value, value);
+
+            stored.tag.value = value;
+            stored.updated_at = Utc::now();
+
+            let bytes = encode(&stored)?;
+            table.insert(path, bytes.as_slice()).map_err(store_error)?;
+            stored.tag
+        };
+        txn.commit().map_err(store_error)?;
+
+        Ok(Some(updated))
+    }
+
+    async fn put_tag(&self, tag: Tag) -> Result<(), UnsError> {
+        let txn = self.db.begin_write().map_err(store_error)?;
+        {
+            let mut table = txn.open_table(TAGS_TABLE).map_err(store_error)?;
+            write_tag(&mut table, &tag.path, &tag)?;
+        }
+        txn.commit().map_err(store_error)?;
+
+        Ok(())
+    }
+
+    async fn get_all_tags(&self) -> Result<HashMap<String, Tag>, UnsError> {
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(TAGS_TABLE).map_err(store_error)?;
+
+        let mut tags = HashMap::new();
+        for entry in table.iter().map_err(store_error)? {
+            let (path, value) = entry.map_err(store_error)?;
+            tags.insert(path.value().to_string(), decode(value.value())?.tag);
+        }
+
+        Ok(tags)
+    }
+
+    async fn get_tags_by_prefix(&self, prefix: &str) -> Result<HashMap<String, Tag>, UnsError> {
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(TAGS_TABLE).map_err(store_error)?;
+
+        // A trailing '/' names the same subtree, and "/" alone every tag
+        let prefix = prefix.trim_end_matches('/');
+
+        // Keys are sorted, so all matches form one contiguous range
+        let mut tags = HashMap::new();
+        for entry in table.range(prefix..).map_err(store_error)? {
+            let (path, value) = entry.map_err(store_error)?;
+            let path = path.value();
+            if !path.starts_with(prefix) {
+                break;
+            }
+            if Tag::path_starts_with(path, prefix) {
+                tags.insert(path.to_string(), decode(value.value())?.tag);
+            }
+        }
+
+        Ok(tags)
+    }
+}
+
+/// Writes a tag, keeping its `updated_at` when the stored tag is unchanged
+fn write_tag(table: &mut redb::Table<&str, &[u8]>, path: &str, tag: &Tag) -> Result<(), UnsError> {
+    let existing = table.get(path).map_err(store_error)?.map(|v| decode(v.value())).transpose()?;
+    if existing.is_some_and(|stored| stored.tag == *tag) {
+        return Ok(());
+    }
+
+    let bytes = encode(&StoredTag::new(tag.clone()))?;
+    table.insert(path, bytes.as_slice()).map_err(store_error)?;
+    Ok(())
+}
+
+/// Converts any redb error into a repository error
+fn store_error(error: impl Into<redb::Error>) -> UnsError {
+    UnsError::Repository(format!("Store error: {}", error.into()))
+}
+
+fn encode(stored: &StoredTag) -> Result<Vec<u8>, UnsError> {
+    serde_json::to_vec(stored)
+        .map_err(|e| UnsError::Serialization(format!("Failed to encode tag: {}", e)))
+}
+
+fn decode(bytes: &[u8]) -> Result<StoredTag, UnsError> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| UnsError::Serialization(format!("Failed to decode tag: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::{NamedTempFile, TempDir};
+
+    fn test_tag(path: &str, value: &str) -> Tag {
+        Tag::new(
+            path.to_string(),
+            "Test Tag".to_string(),
+            "A test tag".to_string(),
+            value.to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_migrates_from_json_on_first_start() {
+        // Create a JSON tag file to migrate from
+        let mut json_file = NamedTempFile::new().unwrap();
+        let test_data = r#"{
+            "tags": {
+                "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE": {
+                    "path": "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE",
+                    "name": "Pump 1 Pressure",
+                    "description": "Pressure sensor for Pump 1",
+                    "value": "45.7"
+                }
+            }
+        }"#;
+        use std::io::Write;
+        write!(json_file, "{}", test_data).unwrap();
+        let source = json_file.path().to_str().unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let db_path = dir.path().join("tags.redb");
+        let db_path = db_path.to_str().unwrap();
+
+        // First start migrates the JSON file
+        {
+            let repo = RedbTagRepository::open(db_path).unwrap();
+            let tags = repo.load_tags(source).await.unwrap();
+            assert_eq!(tags.len(), 1);
+            assert_eq!(repo.metadata("migrated_from").unwrap().as_deref(), Some(source));
+
+            repo.update_tag("US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE", "50.2".to_string())
+                .await
+                .unwrap();
+        }
+
+        // Later starts keep the stored state instead of re-reading the file
+        let repo = RedbTagRepository::open(db_path).unwrap();
+        let tags = repo.load_tags(source).await.unwrap();
+        let tag = tags.get("US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE").unwrap();
+        assert_eq!(tag.value, "50.2");
+        assert!(repo.updated_at(&tag.path).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_update_missing_tag() {
+        let dir = TempDir::new().unwrap();
+        let repo = RedbTagRepository::open(dir.path().join("tags.redb").to_str().unwrap()).unwrap();
+
+        let result = repo.update_tag("A/B", "1".to_string()).await;
+
+        assert!(matches!(result, Err(UnsError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_put_tag() {
+        let dir = TempDir::new().unwrap();
+        let repo = RedbTagRepository::open(dir.path().join("tags.redb").to_str().unwrap()).unwrap();
+
+        repo.put_tag(test_tag("A/B", "1")).await.unwrap();
+        repo.put_tag(test_tag("A/B", "2").with_class("setpoint")).await.unwrap();
+
+        let tag = repo.get_tag("A/B").await.unwrap().unwrap();
+        assert_eq!(tag.value, "2");
+        assert_eq!(tag.class.as_deref(), Some("setpoint"));
+        assert!(repo.updated_at("A/B").unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_saving_an_unchanged_tag_keeps_its_timestamp() {
+        let dir = TempDir::new().unwrap();
+        let repo = RedbTagRepository::open(dir.path().join("tags.redb").to_str().unwrap()).unwrap();
+
+        let tags = HashMap::from([("A/B".to_string(), test_tag("A/B", "1"))]);
+        repo.save_tags(&tags, "").await.unwrap();
+        let saved_at = repo.updated_at("A/B").unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        repo.save_tags(&tags, "").await.unwrap();
+        repo.put_tag(test_tag("A/B", "1")).await.unwrap();
+        assert_eq!(repo.updated_at("A/B").unwrap(), saved_at);
+
+        // A changed value moves the timestamp
+        repo.put_tag(test_tag("A/B", "2")).await.unwrap();
+        assert!(repo.updated_at("A/B").unwrap() > saved_at);
+    }
+
+    #[tokio::test]
+    async fn test_prefix_iteration() {
+        let dir = TempDir::new().unwrap();
+        let repo = RedbTagRepository::open(dir.path().join("tags.redb").to_str().unwrap()).unwrap();
+
+        let mut tags = HashMap::new();
+        for path in ["US/TX/AUSTIN/PUMP1", "US/TX/AUSTIN/PUMP2", "US/TXX/PUMP3", "US/CA/PUMP4"] {
+            tags.insert(path.to_string(), test_tag(path, "1"));
+        }
+        repo.save_tags(&tags, "").await.unwrap();
+
+        let texas = repo.get_tags_by_prefix("US/TX").await.unwrap();
+        assert_eq!(texas.len(), 2);
+        assert!(texas.contains_key("US/TX/AUSTIN/PUMP1"));
+        assert!(texas.contains_key("US/TX/AUSTIN/PUMP2"));
+
+        // Prefixes match as the default implementation does
+        let mut tags = HashMap::new();
+        for path in ["US/TX", "US/TX/AUSTIN/PUMP1", "US/TXX/PUMP3", "US/CA/PUMP4"] {
+            tags.insert(path.to_string(), test_tag(path, "1"));
+        }
+        repo.save_tags(&tags, "").await.unwrap();
+
+        // A trailing '/' still includes the tag named by the prefix itself
+        let texas = repo.get_tags_by_prefix("US/TX/").await.unwrap();
+        assert_eq!(texas.len(), 2);
+        assert!(texas.contains_key("US/TX"));
+        assert!(texas.contains_key("US/TX/AUSTIN/PUMP1"));
+
+        // "/" is the root of the hierarchy
+        assert_eq!(repo.get_tags_by_prefix("/").await.unwrap().len(), 4);
+        assert_eq!(repo.get_tags_by_prefix("").await.unwrap().len(), 4);
+
+        // Saving a smaller set replaces the previous contents
+        tags.remove("US/CA/PUMP4");
+        repo.save_tags(&tags, "").await.unwrap();
+        assert_eq!(repo.get_all_tags().await.unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,463 @@
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use serde_json::{json, Value};
+use std::{collections::HashMap, fmt, fs};
+
+use crate::domain::Tag;
+use crate::infrastructure::UnsError;
+
+/// Fields every tag entry must provide
+const REQUIRED_FIELDS: [&str; 4] = ["path", "name", "description", "value"];
+
+/// Kind of problem found in a tag file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// The file is not valid JSON or does not have the expected shape
+    Syntax,
+
+    /// A map key differs from the embedded `Tag.path`
+    KeyPathMismatch,
+
+    /// The same path is defined more than once
+    DuplicatePath,
+
+    /// A required field is absent
+    MissingField,
+
+    /// A field is present but its value is not acceptable
+    InvalidValue,
+}
+
+/// A single validation finding with its position in the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// Kind of problem
+    pub kind: DiagnosticKind,
+
+    /// 1-based line number, when the position is known
+    pub line: Option<usize>,
+
+    /// 1-based column number, when the position is known
+    pub column: Option<usize>,
+
+    /// Human-readable description of the problem
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}: {}", line, column, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Result of validating a tag file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidationReport {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ValidationReport {
+    /// Returns true when no problems were found
+    pub fn is_valid(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    /// Renders the diagnostics one per line, prefixed with the source name
+    pub fn render(&self, source: &str) -> String {
+        self.diagnostics
+            .iter()
+            .map(|d| format!("{}:{}", source, d))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Validator for JSON tag files
+pub struct TagFileValidator;
+
+impl TagFileValidator {
+    /// Validates the contents of a tag file
+    pub fn validate_str(contents: &str) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        // Parse while keeping duplicate keys, so they can be reported
+        let file: RawTagFile = match serde_json::from_str(contents) {
+            Ok(file) => file,
+            Err(e) => {
+                report.diagnostics.push(Diagnostic {
+                    kind: DiagnosticKind::Syntax,
+                    line: Some(e.line()),
+                    column: Some(e.column()),
+                    message: e.to_string(),
+                });
+                return report;
+            }
+        };
+
+        let entries = match file.tags {
+            Some(entries) => entries,
+            None => {
+                report.diagnostics.push(Diagnostic {
+                    kind: DiagnosticKind::MissingField,
+                    line: Some(1),
+                    column: Some(1),
+                    message: "missing required top-level field `tags`".to_string(),
+                });
+                return report;
+            }
+        };
+
+        let mut key_occurrences: HashMap<&str, usize> = HashMap::new();
+        let mut embedded_paths: HashMap<String, String> = HashMap::new();
+
+        for (key, value) in &entries {
+            // Locate this entry in the source, counting earlier entries with the same key
+            let occurrence = key_occurrences.entry(key.as_str()).or_insert(0);
+            let position = locate_key(contents, key, *occurrence);
+            *occurrence += 1;
+
+            let mut push = |kind: DiagnosticKind, message: String| {
+                report.diagnostics.push(Diagnostic {
+                    kind,
+                    line: position.map(|(line, _)| line),
+                    column: position.map(|(_, column)| column),
+                    message,
+                });
+            };
+
+            if *occurrence > 1 {
+                push(DiagnosticKind::DuplicatePath, format!("duplicate tag key '{}'", key));
+                continue;
+            }
+
+            if let Err(e) = Tag::validate_path(key) {
+                push(DiagnosticKind::InvalidValue, format!("invalid tag key: {}", e));
+            }
+
+            let fields = match value.as_object() {
+                Some(fields) => fields,
+                None => {
+                    push(
+                        DiagnosticKind::InvalidValue,
+                        format!("tag '{}' must be an object, found {}", key, type_name(value)),
+                    );
+                    continue;
+                }
+            };
+
+            for field in REQUIRED_FIELDS {
+                match fields.get(field) {
+                    None => push(
+                        DiagnosticKind::MissingField,
+                        format!("tag '{}' is missing required field `{}`", key, field),
+                    ),
+                    Some(Value::String(_)) => {}
+                    Some(other) => push(
+                        DiagnosticKind::InvalidValue,
+                        format!("field `{}` of tag '{}' must be a string, found {}", field, key, type_name(other)),
+                    ),
+                }
+            }
+
+            match fields.get("class") {
+                None | Some(Value::String(_)) => {}
+                Some(other) => push(
+                    DiagnosticKind::InvalidValue,
+                    format!("field `class` of tag '{}' must be a string, found {}", key, type_name(other)),
+                ),
+            }
+
+            if let Some(Value::String(path)) = fields.get("path") {
+                if path != key {
+                    push(
+                        DiagnosticKind::KeyPathMismatch,
+                        format!("tag key '{}' does not match its path '{}'", key, path),
+                    );
+                }
+
+                if let Some(first_key) = embedded_paths.get(path) {
+                    push(
+                        DiagnosticKind::DuplicatePath,
+                        format!("path '{}' of tag '{}' is already used by tag '{}'", path, key, first_key),
+                    );
+                } else {
+                    embedded_paths.insert(path.clone(), key.clone());
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Reads and validates a tag file
+    pub fn validate_file(source: &str) -> Result<ValidationReport, UnsError> {
+        let contents = fs::read_to_string(source)
+            .map_err(|e| UnsError::Repository(format!("Failed to read file {}: {}", source, e)))?;
+
+        Ok(Self::validate_str(&contents))
+    }
+
+    /// Returns a JSON Schema (draft-07) describing the tag file format
+    ///
+    /// Editors can use it for completion and inline errors. The key/path
+    /// equality rule cannot be expressed in JSON Schema and is only checked
+    /// by `validate_str`.
+    pub fn json_schema() -> Value {
+        let path_pattern = "^[^/+#\\u0000]+(/[^/+#\\u0000]+)*$";
+
+        json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "title": "UNS tag file",
+            "type": "object",
+            "required": ["tags"],
+            "properties": {
+                "tags": {
+                    "description": "Tags keyed by their hierarchical path",
+                    "type": "object",
+                    "propertyNames": { "pattern": path_pattern },
+                    "additionalProperties": { "$ref": "#/definitions/tag" }
+                }
+            },
+            "definitions": {
+                "tag": {
+                    "type": "object",
+                    "required": REQUIRED_FIELDS,
+                    "properties": {
+                        "path": {
+                            "description": "Hierarchical path of the tag; must equal the map key",
+                            "type": "string",
+                            "pattern": path_pattern
+                        },
+                        "name": {
+                            "description": "Human-readable name of the tag",
+                            "type": "string"
+                        },
+                        "description": {
+                            "description": "Description of what the tag represents",
+                            "type": "string"
+                        },
+                        "value": {
+                            "description": "Current value of the tag as a string",
+                            "type": "string"
+                        },
+                        "class": {
+                            "description": "Optional class grouping similar tags, used by publish rules",
+                            "type": "string"
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Top-level tag file, with the tag map kept as an ordered list of entries
+struct RawTagFile {
+    tags: Option<Vec<(String, Value)>>,
+}
+
+impl<'de> Deserialize<'de> for RawTagFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FileVisitor;
+
+        impl<'de> Visitor<'de> for FileVisitor {
+            type Value = RawTagFile;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object with a `tags` field")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RawTagFile, A::Error> {
+                let mut tags = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == "tags" {
+                        if tags.is_some() {
+                            return Err(de::Error::duplicate_field("tags"));
+                        }
+                        tags = Some(map.next_value::<TagEntries>()?.0);
+                    } else {
+                        map.next_value::<de::IgnoredAny>()?;
+                    }
+                }
+                Ok(RawTagFile { tags })
+            }
+        }
+
+        deserializer.deserialize_map(FileVisitor)
+    }
+}
+
+/// Tag map entries in file order, duplicates included
+struct TagEntries(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for TagEntries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = TagEntries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object mapping tag paths to tags")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<TagEntries, A::Error> {
+                let mut entries = Vec::new();
+                while let Some((key, value)) = map.next_entry::<String, Value>()? {
+                    entries.push((key, value));
+                }
+                Ok(TagEntries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+/// Finds the line and column of the nth occurrence of `key` used as an object key
+fn locate_key(contents: &str, key: &str, occurrence: usize) -> Option<(usize, usize)> {
+    let needle = serde_json::to_string(key).ok()?;
+    let mut seen = 0;
+    let mut offset = 0;
+
+    while let Some(found) = contents[offset..].find(&needle) {
+        let start = offset + found;
+        offset = start + needle.len();
+
+        // Only count strings followed by ':', i.e. keys rather than values
+        if contents[offset..].trim_start().starts_with(':') {
+            if seen == occurrence {
+                let before = &contents[..start];
+                let line = before.matches('\n').count() + 1;
+                let column = before.rfind('\n').map_or(start, |nl| start - nl - 1) + 1;
+                return Some((line, column));
+            }
+            seen += 1;
+        }
+    }
+
+    None
+}
+
+/// Returns a JSON type name for error messages
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_file() {
+        let contents = r#"{
+            "tags": {
+                "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE": {
+                    "path": "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE",
+                    "name": "Pump 1 Pressure",
+                    "description": "Pressure sensor for Pump 1",
+                    "value": "45.7"
+                }
+            }
+        }"#;
+
+        let report = TagFileValidator::validate_str(contents);
+        assert!(report.is_valid(), "{:?}", report);
+    }
+
+    #[test]
+    fn test_syntax_error_position() {
+        let contents = "{\n  \"tags\": {\n    \"A/B\": {,\n  }\n}";
+
+        let report = TagFileValidator::validate_str(contents);
+
+        assert_eq!(report.diagnostics.len(), 1);
+        let diagnostic = &report.diagnostics[0];
+        assert_eq!(diagnostic.kind, DiagnosticKind::Syntax);
+        assert_eq!(diagnostic.line, Some(3));
+        assert_eq!(diagnostic.column, Some(13));
+    }
+
+    #[test]
+    fn test_key_path_mismatch() {
+        let contents = r#"{
+  "tags": {
+    "US/TX/PUMP1/PRESSURE": {
+      "path": "US/TX/PUMP1/PRESURE",
+      "name": "Pump 1 Pressure",
+      "description": "Pressure sensor for Pump 1",
+      "value": "45.7"
+    }
+  }
+}"#;
+
+        let report = TagFileValidator::validate_str(contents);
+
+        assert_eq!(report.diagnostics.len(), 1);
+        let diagnostic = &report.diagnostics[0];
+        assert_eq!(diagnostic.kind, DiagnosticKind::KeyPathMismatch);
+        assert_eq!(diagnostic.line, Some(3));
+        assert_eq!(diagnostic.column, Some(5));
+    }
+
+    #[test]
+    fn test_duplicate_paths() {
+        let contents = r#"{
+  "tags": {
+    "A/B": { "path": "A/B", "name": "n", "description": "d", "value": "1" },
+    "A/B": { "path": "A/B", "name": "n", "description": "d", "value": "2" }
+  }
+}"#;
+
+        let report = TagFileValidator::validate_str(contents);
+
+        assert_eq!(report.diagnostics.len(), 1);
+        assert_eq!(report.diagnostics[0].kind, DiagnosticKind::DuplicatePath);
+        assert_eq!(report.diagnostics[0].line, Some(4));
+    }
+
+    #[test]
+    fn test_missing_and_invalid_fields() {
+        let contents = r#"{
+  "tags": {
+    "A/B": { "path": "A/B", "description": "d", "value": 1 },
+    "A/+": { "path": "A/+", "name": "n", "description": "d", "value": "1", "class": 5 }
+  }
+}"#;
+
+        let report = TagFileValidator::validate_str(contents);
+        let kinds: Vec<DiagnosticKind> = report.diagnostics.iter().map(|d| d.kind).collect();
+
+        assert!(kinds.contains(&DiagnosticKind::MissingField));
+        assert_eq!(kinds.iter().filter(|k| **k == DiagnosticKind::InvalidValue).count(), 3);
+        assert!(report.render("tags.json").starts_with("tags.json:3:5: "));
+    }
+
+    #[test]
+    fn test_missing_tags_field() {
+        let report = TagFileValidator::validate_str("{}");
+
+        assert_eq!(report.diagnostics.len(), 1);
+        assert_eq!(report.diagnostics[0].kind, DiagnosticKind::MissingField);
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = TagFileValidator::json_schema();
+
+        assert_eq!(schema["required"], json!(["tags"]));
+        assert_eq!(
+            schema["definitions"]["tag"]["required"],
+            json!(["path", "name", "description", "value"])
+        );
+    }
+}
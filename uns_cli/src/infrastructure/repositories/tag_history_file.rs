@@ -0,0 +1,129 @@
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::domain::Tag;
+use crate::infrastructure::mqtt::SupersededSink;
+use crate::infrastructure::UnsError;
+
+/// One line of the history file
+#[derive(Serialize, Debug)]
+struct HistoryEntry {
+    path: String,
+    value: String,
+    recorded_at: DateTime<Utc>,
+}
+
+/// Append-only file of tag values, one JSON object per line
+///
+/// Values are handed to a writer thread, so recording one never blocks the
+/// caller. Lines are flushed as soon as the thread has caught up; entries still
+/// queued when the process is killed are lost.
+pub struct TagHistoryFile {
+    sender: Sender<HistoryEntry>,
+    writer: JoinHandle<()>,
+}
+
+impl TagHistoryFile {
+    /// Opens (or creates) a history file, appending to what it already holds
+    pub fn open(path: &str) -> Result<Self, UnsError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| UnsError::Repository(format!("Failed to open history file {}: {}", path, e)))?;
+
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_string();
+        let writer = thread::spawn(move || write_entries(file, receiver, &path));
+
+        Ok(Self { sender, writer })
+    }
+
+    /// Returns a sink recording every tag value it receives
+    pub fn sink(&self) -> SupersededSink {
+        let sender = self.sender.clone();
+        Arc::new(move |tag: &Tag| record(&sender, tag))
+    }
+
+    /// Waits until every value recorded so far is written
+    ///
+    /// Sinks handed out by `sink` keep the writer running, so they must be dropped first.
+    pub fn close(self) {
+        drop(self.sender);
+        if self.writer.join().is_err() {
+            error!("History writer panicked");
+        }
+    }
+}
+
+fn record(sender: &Sender<HistoryEntry>, tag: &Tag) {
+    let entry = HistoryEntry {
+        path: tag.path.clone(),
+        value: tag.value.clone(),
+        recorded_at: Utc::now(),
+    };
+    // The writer only stops once every sender is gone
+    let _ = sender.send(entry);
+}
+
+/// Appends entries until every sender is dropped, flushing whenever the queue runs empty
+fn write_entries(file: File, receiver: Receiver<HistoryEntry>, path: &str) {
+    let mut out = BufWriter::new(file);
+    while let Ok(entry) = receiver.recv() {
+        for entry in std::iter::once(entry).chain(receiver.try_iter()) {
+            let written = serde_json::to_writer(&mut out, &entry)
+                .map_err(|e| e.to_string())
+                .and_then(|_| out.write_all(b"\n").map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                error!("Failed to write {} to history file {}: {}", entry.path, path, e);
+            }
+        }
+        if let Err(e) = out.flush() {
+            error!("Failed to flush history file {}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    fn test_tag(path: &str, value: &str) -> Tag {
+        Tag::new(path.to_string(), "n".to_string(), "d".to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_appends_one_line_per_value() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "{\"path\":\"A/B\",\"value\":\"0\",\"recorded_at\":\"2024-01-01T00:00:00Z\"}\n").unwrap();
+
+        let history = TagHistoryFile::open(path).unwrap();
+        let sink = history.sink();
+        sink(&test_tag("A/B", "1"));
+        sink(&test_tag("A/C", "2"));
+        drop(sink);
+        history.close();
+
+        // Earlier lines are kept
+        let lines: Vec<Value> = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["path"], "A/B");
+        assert_eq!(lines[1]["value"], "1");
+        assert_eq!(lines[2]["path"], "A/C");
+        assert!(lines[2]["recorded_at"].is_string());
+    }
+}
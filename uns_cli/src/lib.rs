@@ -11,8 +11,8 @@ pub mod presentation;
 pub use domain::{Tag, TagRepository, TagService};
 pub use application::TagServiceImpl;
 pub use infrastructure::{
-    mqtt::{MqttClient, MqttPublisher},
-    repositories::JsonTagRepository,
+    mqtt::{MqttClient, MqttPublisher, MqttSubscriber},
+    repositories::{JsonTagRepository, RedbTagRepository},
     UnsError,
 };
 pub use presentation::Cli;
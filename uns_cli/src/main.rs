@@ -1,58 +1,240 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
+#[cfg(feature = "embedded-broker")]
+use uns_cli::infrastructure::mqtt::EmbeddedBroker;
 use uns_cli::{
-    application::TagServiceImpl,
+    application::{retained_purger::DEFAULT_SETTLE_TIME, TagServiceImpl},
     domain::TagService,
     infrastructure::{
-        mqtt::{client::RumqttcClient, publisher::MqttTagPublisher, MqttClient, MqttPublisher},
-        repositories::JsonTagRepository,
+        mqtt::{
+            client::RumqttcClient, publisher::MqttTagPublisher, sparkplug::SparkplugPublisher, CoalescingPublisher,
+            FanOutPublisher, MqttClient, MqttPublisher, MqttSubscriber, Outbox, OutboxClient, WillMessage,
+        },
+        repositories::{JsonTagRepository, RedbTagRepository, TagHistoryFile},
         UnsError,
     },
-    presentation::cli::CliHandler,
+    domain::{InstanceStatus, TagRepository, TopicMapping},
+    presentation::cli::{Cli, CliHandler, Commands, PublishFormat, RepositoryBackend},
 };
 
+/// Clears database topics a run with another layout left retained; failing only leaves them behind
+async fn clear_previous_layout(publisher: &MqttTagPublisher, subscriber: &dyn MqttSubscriber) {
+    if let Err(e) = publisher.clear_previous_layout(subscriber, DEFAULT_SETTLE_TIME).await {
+        log::warn!("Failed to clear the database topics of a previous layout: {}", e);
+    }
+}
+
+/// Starts the in-process broker, returning the host and port to connect to
+#[cfg(feature = "embedded-broker")]
+async fn start_embedded_broker(listen: SocketAddr, ws_listen: Option<SocketAddr>) -> Result<(String, u16), UnsError> {
+    let broker = EmbeddedBroker::start(listen).await?;
+    if let Some(ws_listen) = ws_listen {
+        broker.serve_websocket(ws_listen).await?;
+    }
+    Ok((broker.host(), broker.port()))
+}
+
+/// Reports that this build has no in-process broker
+#[cfg(not(feature = "embedded-broker"))]
+async fn start_embedded_broker(_listen: SocketAddr, _ws_listen: Option<SocketAddr>) -> Result<(String, u16), UnsError> {
+    Err(UnsError::Validation(
+        "--embedded-broker needs a build with the embedded-broker feature".to_string(),
+    ))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), UnsError> {
-    // Parse command-line arguments
-    let cli = clap::Command::new("uns_cli")
-        .version("0.1")
-        .author("Your Name")
-        .about("UNS CLI")
-        .subcommand_required(true)
-        .get_matches();
+    // Parse command-line arguments once, filling the rest from the configuration file
+    let (cli, config_file) = Cli::parse_layered()?;
+    
+    // Only this crate logs; the embedded broker would otherwise report every packet
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Off)
+        .filter_module("uns_cli", cli.output.log_level.filter())
+        .format_target(false)
+        .init();
+    if let Some(path) = &config_file {
+        log::info!("Using configuration file {}", path.display());
+    }
+    
+    // Options only `run` has
+    let run = match &cli.command {
+        Commands::Run(run) => Some(run),
+        _ => None,
+    };
+    
+    // Commands run next to each other, so each gets its own client id unless one is given
+    let command_name = match &cli.command {
+        Commands::Run(_) => "run",
+        Commands::Update { .. } => "update",
+        Commands::Mirror { .. } => "mirror",
+        Commands::Purge { .. } => "purge",
+        Commands::Validate { .. } => "validate",
+    };
+    let mut connection_options = cli.broker.connection_options(&format!("uns_cli_{}", command_name))?;
+    
+    // Start the in-process broker and connect to it instead of --mqtt-host
+    if let Some(listen) = run.and_then(|run| run.embedded.listen_address()) {
+        // It accepts any client, so there is nothing to secure the local connection with
+        if cli.broker.security.tls_options().is_some() || cli.broker.security.mqtt_username.is_some() {
+            return Err(UnsError::Validation(
+                "--embedded-broker cannot be used with TLS or username options".to_string(),
+            ));
+        }
+        let ws_listen = run.and_then(|run| run.embedded.embedded_broker_ws_listen);
+        let (host, port) = start_embedded_broker(listen, ws_listen).await?;
+        connection_options.host = host;
+        connection_options.port = port;
+    }
     
-    // Get MQTT connection parameters
-    let mqtt_host = match cli.subcommand() {
-        Some(("run", args)) => args.get_one::<String>("mqtt-host").unwrap_or(&"hivemq".to_string()).clone(),
-        Some(("update", args)) => args.get_one::<String>("mqtt-host").unwrap_or(&"localhost".to_string()).clone(),
-        _ => "localhost".to_string(),
+    // Get the mapping between tag paths and topics
+    let topic_mapping = cli.broker.topics.mapping()?;
+    
+    // Get the QoS and retain settings of the tag topics
+    let publish_policies = run.map(|run| run.delivery.policies()).unwrap_or_default();
+    
+    // Sparkplug B replaces the JSON tag topics of a running instance
+    let sparkplug_topology = match run {
+        Some(run) if run.format == PublishFormat::SparkplugB => Some(run.sparkplug.topology(&run.instance_id)?),
+        _ => None,
     };
     
-    let mqtt_port = match cli.subcommand() {
-        Some(("run", args)) => *args.get_one::<u16>("mqtt-port").unwrap_or(&1883),
-        Some(("update", args)) => *args.get_one::<u16>("mqtt-port").unwrap_or(&1883),
-        _ => 1883,
+    // Brokers fed alongside the primary one
+    let extra_brokers = run.map(|run| run.brokers.extra_brokers.clone()).unwrap_or_default();
+    if !extra_brokers.is_empty() && sparkplug_topology.is_some() {
+        return Err(UnsError::Validation(
+            "--extra-broker cannot be used with --format sparkplug-b".to_string(),
+        ));
+    }
+    
+    // Queue publishes on disk while the broker is unreachable
+    let outbox = match run {
+        Some(run) => match run.outbox.outbox_file.as_deref() {
+            // Replayed Sparkplug messages would carry sequence numbers of an old session
+            Some(_) if sparkplug_topology.is_some() => {
+                return Err(UnsError::Validation(
+                    "--outbox-file cannot be used with --format sparkplug-b".to_string(),
+                ));
+            }
+            // The fan-out skips unreachable brokers, so nothing would ever be queued
+            Some(_) if !extra_brokers.is_empty() => {
+                return Err(UnsError::Validation(
+                    "--outbox-file cannot be used with --extra-broker".to_string(),
+                ));
+            }
+            Some(path) => Some(Outbox::open(path, run.outbox.limits())?),
+            None => None,
+        },
+        None => None,
     };
     
+    // The broker marks a running instance offline if it disappears without disconnecting
+    if let Some(run) = run {
+        connection_options.last_will = Some(match &sparkplug_topology {
+            Some(topology) => SparkplugPublisher::will_message(topology),
+            None => WillMessage {
+                topic: InstanceStatus::topic_for(&run.instance_id),
+                payload: InstanceStatus::offline(&run.instance_id).to_payload()?,
+                retain: true,
+            },
+        });
+    }
+    
+    // Get how the database topic follows tag updates and how it is split
+    let (database_mode, database_layout) =
+        run.map(|run| (run.database.mode(), run.database.database_layout)).unwrap_or_default();
+    
     // Create the MQTT client
-    let mqtt_client = RumqttcClient::new("uns_cli_publisher", &mqtt_host, mqtt_port).await?;
-    let mqtt_client: Arc<dyn MqttClient> = Arc::new(mqtt_client);
+    let mqtt_client = Arc::new(RumqttcClient::connect(connection_options.clone()).await?);
+    let mqtt_subscriber: Arc<dyn MqttSubscriber> = mqtt_client.clone();
+    let mqtt_client: Arc<dyn MqttClient> = match outbox {
+        Some(outbox) => Arc::new(OutboxClient::new(mqtt_client, outbox)),
+        None => mqtt_client,
+    };
+    let raw_client = mqtt_client.clone();
     
     // Create the MQTT publisher
-    let mqtt_publisher: Arc<dyn MqttPublisher> = Arc::new(MqttTagPublisher::new(mqtt_client));
+    let mqtt_publisher: Arc<dyn MqttPublisher> = match sparkplug_topology {
+        Some(topology) => {
+            let publisher = SparkplugPublisher::new(mqtt_client, topology);
+            publisher.start(mqtt_subscriber.clone()).await?;
+            Arc::new(publisher)
+        }
+        None => {
+            let publisher = MqttTagPublisher::new(mqtt_client)
+                .with_topic_mapping(topic_mapping.clone())
+                .with_publish_policies(publish_policies.clone())
+                .with_database_layout(database_layout);
+            if run.is_some() {
+                clear_previous_layout(&publisher, mqtt_subscriber.as_ref()).await;
+            }
+            Arc::new(publisher)
+        }
+    };
+    
+    // Fan out to the extra brokers, each with its own prefix and QoS
+    let mqtt_publisher: Arc<dyn MqttPublisher> = if extra_brokers.is_empty() {
+        mqtt_publisher
+    } else {
+        let mut publishers = vec![("primary".to_string(), mqtt_publisher)];
+        for broker in &extra_brokers {
+            let mut options = broker.connection_options(&connection_options.client_id)?;
+            options.last_will = connection_options.last_will.clone();
+            options.delivery_timeout = connection_options.delivery_timeout;
+            options.session_expiry = connection_options.session_expiry;
+            let client = Arc::new(RumqttcClient::connect(options).await?);
+            let subscriber = client.clone();
+            
+            let mapping = match &broker.prefix {
+                Some(prefix) => TopicMapping::new(topic_mapping.strategy(), prefix)?,
+                None => topic_mapping.clone(),
+            };
+            let mut policies = publish_policies.clone();
+            if let Some(qos) = broker.qos {
+                policies.default.qos = qos;
+            }
+            
+            let publisher = MqttTagPublisher::new(client)
+                .with_topic_mapping(mapping)
+                .with_publish_policies(policies)
+                .with_database_layout(database_layout);
+            clear_previous_layout(&publisher, subscriber.as_ref()).await;
+            publishers.push((broker.name.clone(), Arc::new(publisher) as Arc<dyn MqttPublisher>));
+        }
+        Arc::new(FanOutPublisher::new(publishers)?)
+    };
     
-    // Create the tag repository
-    let tag_repository = JsonTagRepository::new();
+    // Publish at most the latest value per tag and window
+    let mqtt_publisher: Arc<dyn MqttPublisher> = match run.and_then(|run| run.rate_limit.window().map(|w| (run, w))) {
+        Some((run, window)) => {
+            let mut coalescing = CoalescingPublisher::new(mqtt_publisher, window);
+            // Keep the values that are replaced before they are published
+            if let Some(path) = &run.rate_limit.tag_history {
+                coalescing = coalescing.with_superseded_sink(TagHistoryFile::open(path)?.sink());
+            }
+            Arc::new(coalescing)
+        }
+        None => mqtt_publisher,
+    };
+    
+    // Create the tag repository; update and validate never open the store, so they can run next to `run`
+    let tag_repository: Arc<dyn TagRepository> = match (&cli.command, cli.repository.backend) {
+        (Commands::Run(_) | Commands::Mirror { .. } | Commands::Purge { .. }, RepositoryBackend::Redb) => {
+            Arc::new(RedbTagRepository::open(&cli.repository.store_file)?)
+        }
+        _ => Arc::new(JsonTagRepository::new()),
+    };
     
     // Create the tag service
-    let tag_service: Arc<dyn TagService> = Arc::new(TagServiceImpl::new(
-        Arc::new(tag_repository),
-        mqtt_publisher,
-    ));
+    let tag_service: Arc<dyn TagService> = Arc::new(
+        TagServiceImpl::new(tag_repository.clone(), mqtt_publisher.clone()).with_database_mode(database_mode),
+    );
     
     // Create the CLI handler
-    let cli_handler = CliHandler::new(tag_service);
+    let cli_handler = CliHandler::new(tag_service, mqtt_subscriber, mqtt_publisher)
+        .with_repository(tag_repository)
+        .with_client(raw_client);
     
-    // Run the CLI
-    cli_handler.run().await
+    // Run the command
+    cli_handler.run(cli).await
 }
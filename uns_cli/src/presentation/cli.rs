@@ -1,34 +1,578 @@
-use clap::{Parser, Subcommand};
-use std::sync::Arc;
+use clap::builder::BoolishValueParser;
+use clap::{ArgMatches, Args, CommandFactory as _, FromArgMatches, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use crate::application::commands::{CommandFactory, CommandHandler};
-use crate::domain::TagService;
+use crate::domain::instance_status::DEFAULT_INSTANCE_ID;
+use crate::domain::topic_mapping::DEFAULT_TOPIC_PREFIX;
+use crate::domain::{
+    DatabaseLayout, DatabaseMode, PayloadCodec, PublishPolicies, PublishPolicy, PublishRule, QosLevel, TagRepository,
+    TagService, TopicMapping, TopicStrategy,
+};
+use crate::infrastructure::mqtt::sparkplug::SparkplugTopology;
+use crate::infrastructure::mqtt::{
+    unique_client_id, BrokerSpec, ConnectionOptions, Credentials, MqttClient, MqttPublisher, MqttSubscriber,
+    OutboxLimits, OverflowPolicy, TlsOptions,
+};
 use crate::infrastructure::UnsError;
+use crate::presentation::config::ConfigFile;
 
 /// UNS CLI command-line interface
 #[derive(Parser, Debug)]
 #[clap(author = "Your Name", version = "0.1", about = "UNS CLI", long_about = None)]
 pub struct Cli {
+    /// TOML configuration file (default: the first of uns_cli.toml, ~/.config/uns_cli/config.toml and /etc/uns_cli/config.toml)
+    #[clap(long, value_parser, env = "UNS_CONFIG", global = true)]
+    pub config: Option<String>,
+    
+    #[clap(flatten)]
+    pub broker: BrokerArgs,
+    
+    #[clap(flatten)]
+    pub repository: RepositoryArgs,
+    
+    #[clap(flatten)]
+    pub output: OutputArgs,
+    
     #[clap(subcommand)]
-    command: Commands,
+    pub command: Commands,
+}
+
+impl Cli {
+    /// Parses the command line, filling the options it leaves unset from the configuration file
+    ///
+    /// Returns the options and the path of the configuration file used, if any.
+    pub fn parse_layered() -> Result<(Self, Option<PathBuf>), UnsError> {
+        Self::from_matches_layered(&Self::command().get_matches())
+    }
+    
+    /// Builds the options from parsed arguments and the configuration file they point to
+    ///
+    /// Flags and environment variables take precedence over the file, which takes precedence over
+    /// the built-in defaults.
+    pub fn from_matches_layered(matches: &ArgMatches) -> Result<(Self, Option<PathBuf>), UnsError> {
+        let mut cli = Self::from_arg_matches(matches).map_err(|e| UnsError::Validation(e.to_string()))?;
+        
+        let path = ConfigFile::locate(cli.config.as_deref())?;
+        if let Some(path) = &path {
+            ConfigFile::load(path)?.apply(&mut cli, matches);
+        }
+        cli.check()?;
+        Ok((cli, path))
+    }
+    
+    /// Checks the options that depend on each other, wherever they were set
+    pub fn check(&self) -> Result<(), UnsError> {
+        let session = &self.broker.session;
+        // A random client id would never find its session again
+        if session.persistent_session && session.client_id.is_none() {
+            return Err(UnsError::Validation("--persistent-session needs a --client-id".to_string()));
+        }
+        
+        let security = &self.broker.security;
+        if security.mqtt_client_cert.is_some() != security.mqtt_client_key.is_some() {
+            return Err(UnsError::Validation(
+                "--mqtt-client-cert and --mqtt-client-key must be given together".to_string(),
+            ));
+        }
+        
+        if self.broker.confirmation.delivery_timeout == 0 {
+            return Err(UnsError::Validation("--delivery-timeout must be at least 1 second".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Storage backend for the tag repository
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RepositoryBackend {
+    /// In-memory tags loaded from the JSON tag file
+    Json,
+    
+    /// Embedded redb store, migrated from the JSON tag file on first start
+    Redb,
+}
+
+/// How tags are encoded on the broker
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PublishFormat {
+    /// Retained JSON messages under tags/
+    Json,
+    
+    /// Sparkplug B births and data messages under spBv1.0/
+    SparkplugB,
+}
+
+/// Layout of tag paths in MQTT topics
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TopicLayout {
+    /// One topic level per path segment: tags/US/TX/PUMP1
+    Native,
+    
+    /// A single topic level with '.' between segments: tags/US.TX.PUMP1
+    Dotted,
+}
+
+/// Topic mapping options, which must match between `run` and `update`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct TopicArgs {
+    /// How tag paths are laid out in topics
+    #[clap(long, value_enum, default_value = "dotted", global = true, env = "UNS_TOPIC_STRATEGY")]
+    pub topic_strategy: TopicLayout,
+    
+    /// Namespace of all tag topics, e.g. acme/uns
+    #[clap(long, value_parser, default_value = DEFAULT_TOPIC_PREFIX, global = true, env = "UNS_TOPIC_PREFIX")]
+    pub topic_prefix: String,
+}
+
+impl TopicArgs {
+    /// Returns the mapping between tag paths and topics
+    pub fn mapping(&self) -> Result<TopicMapping, UnsError> {
+        let strategy = match self.topic_strategy {
+            TopicLayout::Native => TopicStrategy::Native,
+            TopicLayout::Dotted => TopicStrategy::Dotted,
+        };
+        TopicMapping::new(strategy, &self.topic_prefix)
+    }
+}
+
+/// QoS, retain and codec settings of published tags
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct PublishPolicyArgs {
+    /// QoS of tag and database messages
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=2), default_value_t = 1)]
+    pub qos: u8,
+    
+    /// Publish tags without the retain flag
+    #[clap(long)]
+    pub no_retain: bool,
+    
+    /// Encoding of tag, database and delta payloads: json, cbor or msgpack
+    #[clap(long, value_parser, default_value_t = PayloadCodec::Json)]
+    pub codec: PayloadCodec,
+    
+    /// Override for selected tags, e.g. class:vibration=qos0,no-retain or US/TX/#=qos2,cbor (repeatable; first match wins)
+    #[clap(long = "publish-rule", value_parser)]
+    pub publish_rules: Vec<PublishRule>,
+}
+
+impl PublishPolicyArgs {
+    /// Returns the QoS, retain and codec settings of every tag
+    pub fn policies(&self) -> PublishPolicies {
+        let qos = QosLevel::from_level(self.qos).unwrap_or_default();
+        PublishPolicies::new(PublishPolicy::new(qos, !self.no_retain), self.publish_rules.clone()).with_codec(self.codec)
+    }
+}
+
+/// Limit on how often each tag is published
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct RateLimitArgs {
+    /// Maximum publishes per second and tag; faster updates publish only the latest value (0 = unlimited)
+    #[clap(long, value_parser, default_value_t = 0)]
+    pub max_tag_rate: u32,
+    
+    /// File the held back values that were never published are appended to, one JSON line each
+    #[clap(long, value_parser, requires = "max_tag_rate")]
+    pub tag_history: Option<String>,
+}
+
+impl RateLimitArgs {
+    /// Returns the minimum time between two publishes of a tag, if limited
+    pub fn window(&self) -> Option<Duration> {
+        (self.max_tag_rate > 0).then(|| Duration::from_secs(1) / self.max_tag_rate)
+    }
+}
+
+/// How the database topic follows tag updates
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatabaseUpdates {
+    /// Publish the whole database after every update
+    Full,
+    
+    /// Publish numbered deltas, and the whole database periodically and on request
+    Delta,
+}
+
+/// Options for publishing the tag database
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct DatabaseArgs {
+    /// How the database topic follows tag updates
+    #[clap(long, value_enum, default_value = "full")]
+    pub database_updates: DatabaseUpdates,
+    
+    /// Seconds between full database snapshots in delta mode (0 = only on request)
+    #[clap(long, value_parser, default_value_t = 60)]
+    pub database_interval: u64,
+    
+    /// How the database is split across topics: single, level:<n> or pages:<n>
+    #[clap(long, value_parser, default_value_t = DatabaseLayout::Single)]
+    pub database_layout: DatabaseLayout,
+}
+
+impl DatabaseArgs {
+    /// Returns how the database topic follows tag updates
+    pub fn mode(&self) -> DatabaseMode {
+        match self.database_updates {
+            DatabaseUpdates::Full => DatabaseMode::Full,
+            DatabaseUpdates::Delta => DatabaseMode::Delta,
+        }
+    }
+    
+    /// Returns the time between periodic snapshots, if any
+    pub fn snapshot_interval(&self) -> Option<Duration> {
+        (self.database_interval > 0).then(|| Duration::from_secs(self.database_interval))
+    }
+}
+
+/// How a full outbox makes room for new messages
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutboxOverflow {
+    /// Drop the oldest queued messages
+    DropOldest,
+    
+    /// Replace the queued message of the same tag, dropping the oldest only if needed
+    LatestPerTag,
+}
+
+/// Store-and-forward options for publishes made while the broker is unreachable
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct OutboxArgs {
+    /// Queue publishes in this file while disconnected and send them after reconnecting
+    #[clap(long, value_parser, env = "UNS_OUTBOX_FILE")]
+    pub outbox_file: Option<String>,
+    
+    /// Maximum number of queued messages
+    #[clap(long, value_parser, default_value_t = OutboxLimits::default().max_messages, env = "UNS_OUTBOX_MAX_MESSAGES")]
+    pub outbox_max_messages: usize,
+    
+    /// Maximum total size of the queued messages in bytes
+    #[clap(long, value_parser, default_value_t = OutboxLimits::default().max_bytes, env = "UNS_OUTBOX_MAX_BYTES")]
+    pub outbox_max_bytes: u64,
+    
+    /// How a full outbox makes room for new messages
+    #[clap(long, value_enum, default_value = "drop-oldest", env = "UNS_OUTBOX_OVERFLOW")]
+    pub outbox_overflow: OutboxOverflow,
+}
+
+impl OutboxArgs {
+    /// Returns the size limits of the outbox
+    pub fn limits(&self) -> OutboxLimits {
+        OutboxLimits {
+            max_messages: self.outbox_max_messages,
+            max_bytes: self.outbox_max_bytes,
+            overflow: match self.outbox_overflow {
+                OutboxOverflow::DropOldest => OverflowPolicy::DropOldest,
+                OutboxOverflow::LatestPerTag => OverflowPolicy::LatestPerTag,
+            },
+        }
+    }
+}
+
+/// Options of the Sparkplug B publishing mode
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct SparkplugArgs {
+    /// Sparkplug group id
+    #[clap(long, value_parser, default_value = "UNS")]
+    pub sparkplug_group_id: String,
+    
+    /// Sparkplug edge node id (defaults to the instance id)
+    #[clap(long, value_parser)]
+    pub sparkplug_edge_node_id: Option<String>,
+    
+    /// Number of leading path segments forming the device id (default: all but the last)
+    #[clap(long, value_parser)]
+    pub sparkplug_device_levels: Option<usize>,
+}
+
+impl SparkplugArgs {
+    /// Returns the mapping of tag paths onto this edge node
+    pub fn topology(&self, instance_id: &str) -> Result<SparkplugTopology, UnsError> {
+        let edge_node_id = self.sparkplug_edge_node_id.as_deref().unwrap_or(instance_id);
+        let mut topology = SparkplugTopology::new(&self.sparkplug_group_id, edge_node_id);
+        topology.device_levels = self.sparkplug_device_levels;
+        
+        topology.validate()?;
+        Ok(topology)
+    }
+}
+
+/// TLS and authentication options for the broker connection
+#[derive(Args, Debug, Clone, Default, PartialEq)]
+pub struct BrokerSecurityArgs {
+    /// Connect to the broker over TLS
+    #[clap(long, value_parser = BoolishValueParser::new(), global = true, env = "UNS_MQTT_TLS")]
+    pub mqtt_tls: bool,
+    
+    /// PEM file with the CA certificates trusted for the broker (implies --mqtt-tls)
+    #[clap(long, value_parser, global = true, env = "UNS_MQTT_CA_FILE")]
+    pub mqtt_ca_file: Option<String>,
+    
+    /// PEM client certificate for brokers requiring mutual TLS
+    #[clap(long, value_parser, requires = "mqtt_client_key", global = true, env = "UNS_MQTT_CLIENT_CERT")]
+    pub mqtt_client_cert: Option<String>,
+    
+    /// PEM private key for the client certificate
+    #[clap(long, value_parser, requires = "mqtt_client_cert", global = true, env = "UNS_MQTT_CLIENT_KEY")]
+    pub mqtt_client_key: Option<String>,
+    
+    /// Broker username
+    #[clap(long, value_parser, global = true, env = Credentials::USERNAME_ENV)]
+    pub mqtt_username: Option<String>,
+    
+    /// File holding the broker password (defaults to UNS_MQTT_PASSWORD)
+    #[clap(long, value_parser, global = true, env = "UNS_MQTT_PASSWORD_FILE")]
+    pub mqtt_password_file: Option<String>,
+}
+
+impl BrokerSecurityArgs {
+    /// Returns the TLS settings, or `None` for a plain TCP connection
+    pub fn tls_options(&self) -> Option<TlsOptions> {
+        let enabled = self.mqtt_tls || self.mqtt_ca_file.is_some() || self.mqtt_client_cert.is_some();
+        
+        enabled.then(|| TlsOptions {
+            ca_file: self.mqtt_ca_file.clone(),
+            client_cert_file: self.mqtt_client_cert.clone(),
+            client_key_file: self.mqtt_client_key.clone(),
+        })
+    }
+    
+    /// Resolves the broker credentials from the flags and the environment
+    pub fn credentials(&self) -> Result<Option<Credentials>, UnsError> {
+        Credentials::resolve(self.mqtt_username.clone(), self.mqtt_password_file.as_deref())
+    }
+}
+
+/// How long publishes wait for the broker to confirm them
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct ConfirmationArgs {
+    /// Seconds to wait for the broker to acknowledge a publish before reporting it as failed
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 10, global = true, env = "UNS_DELIVERY_TIMEOUT")]
+    pub delivery_timeout: u64,
+}
+
+impl ConfirmationArgs {
+    /// Returns the time a publish waits for its acknowledgment
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.delivery_timeout)
+    }
+}
+
+/// Client identity and session options for the broker connection
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct SessionArgs {
+    /// Client id presented to the broker (defaults to uns_cli_<command> with a random suffix)
+    #[clap(long, value_parser, global = true, env = "UNS_CLIENT_ID")]
+    pub client_id: Option<String>,
+    
+    /// Keep subscriptions and queued QoS 1 and 2 messages on the broker across disconnects
+    #[clap(long, value_parser = BoolishValueParser::new(), global = true, env = "UNS_PERSISTENT_SESSION")]
+    pub persistent_session: bool,
+    
+    /// Seconds the broker keeps a persistent session after a disconnect
+    #[clap(long, value_parser, default_value_t = 300, global = true, env = "UNS_SESSION_EXPIRY")]
+    pub session_expiry: u64,
+}
+
+impl SessionArgs {
+    /// Returns the given client id, or a unique one derived from `base`
+    pub fn client_id(&self, base: &str) -> String {
+        self.client_id.clone().unwrap_or_else(|| unique_client_id(base))
+    }
+    
+    /// Returns how long the broker keeps the session, or `None` for a clean session
+    pub fn session_expiry(&self) -> Option<Duration> {
+        self.persistent_session.then(|| Duration::from_secs(self.session_expiry))
+    }
+}
+
+/// Broker connection options, shared by every command
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct BrokerArgs {
+    /// Broker host name, or a ws:// or wss:// URL for MQTT over WebSockets
+    #[clap(long, value_parser, default_value = "localhost", global = true, env = "UNS_MQTT_HOST")]
+    pub mqtt_host: String,
+    
+    /// Broker port
+    #[clap(long, value_parser, default_value_t = 1883, global = true, env = "UNS_MQTT_PORT")]
+    pub mqtt_port: u16,
+    
+    #[clap(flatten)]
+    pub security: BrokerSecurityArgs,
+    
+    #[clap(flatten)]
+    pub session: SessionArgs,
+    
+    #[clap(flatten)]
+    pub confirmation: ConfirmationArgs,
+    
+    #[clap(flatten)]
+    pub topics: TopicArgs,
+}
+
+impl BrokerArgs {
+    /// Returns the settings to connect with, using a client id derived from `client_id_base` unless one is given
+    pub fn connection_options(&self, client_id_base: &str) -> Result<ConnectionOptions, UnsError> {
+        let mut options = ConnectionOptions::new(&self.session.client_id(client_id_base), &self.mqtt_host, self.mqtt_port);
+        options.tls = self.security.tls_options();
+        options.credentials = self.security.credentials()?;
+        options.delivery_timeout = self.confirmation.timeout();
+        options.session_expiry = self.session.session_expiry();
+        Ok(options)
+    }
+}
+
+/// Tag file and store options, shared by every command
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct RepositoryArgs {
+    /// Tag file loaded by run, compared against by purge and checked by validate
+    #[clap(long, value_parser, default_value = "tags.json", global = true, env = "UNS_TAGS_FILE")]
+    pub tags_file: String,
+    
+    /// Where tags are kept
+    #[clap(long, value_enum, default_value = "json", global = true, env = "UNS_BACKEND")]
+    pub backend: RepositoryBackend,
+    
+    /// Store file used by the redb backend
+    #[clap(long, value_parser, default_value = "tags.redb", global = true, env = "UNS_STORE_FILE")]
+    pub store_file: String,
+}
+
+/// Severity of the messages written to the log
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogLevel {
+    /// Only errors
+    Error,
+    
+    /// Errors and warnings
+    Warn,
+    
+    /// Errors, warnings and progress messages
+    Info,
+    
+    /// Everything, including individual messages
+    Debug,
+}
+
+impl LogLevel {
+    /// Returns the matching filter of the log facade
+    pub fn filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+        }
+    }
+}
+
+/// Logging options, shared by every command
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct OutputArgs {
+    /// Least severe messages written to the log
+    #[clap(long, value_enum, default_value = "info", global = true, env = "UNS_LOG_LEVEL")]
+    pub log_level: LogLevel,
+}
+
+/// What to do with retained tag topics whose tag is no longer loaded
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StalePurge {
+    /// Only list them
+    Report,
+    
+    /// List them, then clear them with empty retained messages
+    Apply,
+}
+
+/// Brokers published to alongside the primary one
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct ExtraBrokerArgs {
+    /// Additional broker, e.g. central=plant.example.com:8883,prefix=acme/austin,qos=1,tls (repeatable)
+    #[clap(long = "extra-broker", value_parser)]
+    pub extra_brokers: Vec<BrokerSpec>,
+}
+
+/// Options of the in-process broker
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct EmbeddedBrokerArgs {
+    /// Start an MQTT 5 broker inside this process and connect to it instead of --mqtt-host
+    #[clap(long)]
+    pub embedded_broker: bool,
+    
+    /// Address the embedded broker listens on; use 0.0.0.0:<port> to accept other hosts
+    #[clap(long, value_parser, default_value = "127.0.0.1:1883")]
+    pub embedded_broker_listen: SocketAddr,
+    
+    /// Also accept MQTT over WebSockets on this address, e.g. 127.0.0.1:8000
+    #[clap(long, value_parser, requires = "embedded_broker")]
+    pub embedded_broker_ws_listen: Option<SocketAddr>,
+}
+
+impl EmbeddedBrokerArgs {
+    /// Returns the address to start the embedded broker on, if it is enabled
+    pub fn listen_address(&self) -> Option<SocketAddr> {
+        self.embedded_broker.then_some(self.embedded_broker_listen)
+    }
+}
+
+/// Options of the `run` command
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct RunArgs {
+    #[clap(flatten)]
+    pub embedded: EmbeddedBrokerArgs,
+    
+    #[clap(flatten)]
+    pub brokers: ExtraBrokerArgs,
+    
+    #[clap(flatten)]
+    pub delivery: PublishPolicyArgs,
+    
+    #[clap(flatten)]
+    pub rate_limit: RateLimitArgs,
+    
+    #[clap(flatten)]
+    pub outbox: OutboxArgs,
+    
+    #[clap(flatten)]
+    pub database: DatabaseArgs,
+    
+    /// Publish all tags again after reconnecting, in case the broker lost its retained messages
+    #[clap(long)]
+    pub republish_on_reconnect: bool,
+    
+    /// Only accept write requests for tags under this path prefix (repeatable; default: all tags)
+    #[clap(long = "writable-prefix", value_parser)]
+    pub writable_prefixes: Vec<String>,
+    
+    /// Identifier announced on the status topic uns/status/<instance id>
+    #[clap(long, value_parser, default_value = DEFAULT_INSTANCE_ID)]
+    pub instance_id: String,
+    
+    /// How tags are encoded on the broker
+    #[clap(long, value_enum, default_value = "json")]
+    pub format: PublishFormat,
+    
+    #[clap(flatten)]
+    pub sparkplug: SparkplugArgs,
+    
+    /// After loading, look for retained tag topics of tags that are no longer in the tag file
+    #[clap(long, value_enum)]
+    pub purge_stale: Option<StalePurge>,
 }
 
 /// CLI commands
 #[derive(Subcommand, Debug)]
+// Parsed once at startup, so the size of `Run` does not matter
+#[allow(clippy::large_enum_variant)]
 pub enum Commands {
-    /// Loads tags from a JSON file and keeps running
-    Run {
-        #[clap(long, value_parser, default_value = "tags.json")]
-        tags_file: String,
-        
-        #[clap(long, value_parser, default_value = "hivemq")]
-        mqtt_host: String,
-        
-        #[clap(long, value_parser, default_value_t = 1883)]
-        mqtt_port: u16,
-    },
+    /// Loads tags from the tag file and keeps running
+    Run(RunArgs),
     
-    /// Updates a tag value (for testing, requires a running instance)
+    /// Asks the running instance to update a tag value
     Update {
         #[clap(value_parser)]
         path: String,
@@ -36,11 +580,50 @@ pub enum Commands {
         #[clap(value_parser)]
         value: String,
         
-        #[clap(long, value_parser, default_value = "localhost")]
-        mqtt_host: String,
+        /// Seconds to wait for the running instance to confirm the write
+        #[clap(long, value_parser, default_value_t = 5)]
+        timeout: u64,
+    },
+    
+    /// Mirrors the tags published on a broker into a local replica, kept in the redb store with --backend redb
+    Mirror {
+        /// Write the replica as a tag file whenever it changes
+        #[clap(long, value_parser)]
+        output: Option<String>,
+        
+        /// Write the mirrored tag paths, one per line, whenever the replica changes
+        #[clap(long, value_parser)]
+        paths_output: Option<String>,
+        
+        /// Only write tags under this path prefix to the output files
+        #[clap(long, value_parser)]
+        prefix: Option<String>,
+        
+        /// Write the output files and exit once the replica is complete, instead of running until interrupted
+        #[clap(long)]
+        once: bool,
+        
+        /// Seconds without new messages after which --once considers the replica complete
+        #[clap(long, value_parser, default_value_t = 2)]
+        settle_time: u64,
+    },
+    
+    /// Finds retained tag topics of tags no longer in the tag file, and clears them with --apply
+    Purge {
+        /// Clear the stale topics; without it they are only reported
+        #[clap(long)]
+        apply: bool,
         
-        #[clap(long, value_parser, default_value_t = 1883)]
-        mqtt_port: u16,
+        /// Seconds without new retained messages after which all are considered received
+        #[clap(long, value_parser, default_value_t = 2)]
+        settle_time: u64,
+    },
+    
+    /// Checks the tag file for errors, or prints its JSON Schema
+    Validate {
+        /// Print the JSON Schema for tag files instead of validating
+        #[clap(long)]
+        schema: bool,
     },
 }
 
@@ -51,23 +634,77 @@ pub struct CliHandler {
 
 impl CliHandler {
     /// Creates a new CLI handler
-    pub fn new(tag_service: Arc<dyn TagService>) -> Self {
+    pub fn new(
+        tag_service: Arc<dyn TagService>,
+        subscriber: Arc<dyn MqttSubscriber>,
+        publisher: Arc<dyn MqttPublisher>,
+    ) -> Self {
         Self {
-            command_factory: CommandFactory::new(tag_service),
+            command_factory: CommandFactory::new(tag_service, subscriber, publisher),
         }
     }
     
-    /// Runs the CLI
-    pub async fn run(&self) -> Result<(), UnsError> {
-        let cli = Cli::parse();
+    /// Sets the repository behind the tag service, used by the mirror and purge commands
+    pub fn with_repository(mut self, repository: Arc<dyn TagRepository>) -> Self {
+        self.command_factory = self.command_factory.with_repository(repository);
+        self
+    }
+    
+    /// Sets the client used for snapshot requests and clearing retained topics
+    pub fn with_client(mut self, client: Arc<dyn MqttClient>) -> Self {
+        self.command_factory = self.command_factory.with_client(client);
+        self
+    }
+    
+    /// Runs the parsed command
+    pub async fn run(&self, cli: Cli) -> Result<(), UnsError> {
+        let tags_file = cli.repository.tags_file;
+        let mapping = cli.broker.topics.mapping()?;
         
         match cli.command {
-            Commands::Run { tags_file, .. } => {
-                let command = self.command_factory.create_run_command(tags_file);
+            Commands::Run(run) => {
+                let mut command = self
+                    .command_factory
+                    .create_run_command(tags_file)
+                    .with_republish_on_reconnect(run.republish_on_reconnect)
+                    .with_writable_prefixes(run.writable_prefixes)
+                    .with_instance_id(run.instance_id)
+                    .with_topic_mapping(mapping.clone())
+                    .with_database_mode(run.database.mode(), run.database.snapshot_interval());
+                if let Some(purge) = run.purge_stale {
+                    let purger = self.command_factory.create_purger(mapping)?;
+                    command = command.with_stale_purge(purger, purge == StalePurge::Apply);
+                }
+                command.execute().await
+            }
+            Commands::Update { path, value, timeout } => {
+                let command = self
+                    .command_factory
+                    .create_update_command(path, value)
+                    .with_reply_timeout(Duration::from_secs(timeout))
+                    .with_topic_mapping(mapping);
+                command.execute().await
+            }
+            Commands::Mirror { output, paths_output, prefix, once, settle_time } => {
+                let command = self
+                    .command_factory
+                    .create_mirror_command(mapping)?
+                    .with_output(output)
+                    .with_paths_output(paths_output)
+                    .with_prefix(prefix)
+                    .with_settle_time(once.then(|| Duration::from_secs(settle_time)));
+                command.execute().await
+            }
+            Commands::Purge { apply, settle_time } => {
+                let purger = self
+                    .command_factory
+                    .create_purger(mapping)?
+                    .with_settle_time(Duration::from_secs(settle_time));
+                let command = self.command_factory.create_purge_command(tags_file, purger)?.with_apply(apply);
                 command.execute().await
             }
-            Commands::Update { path, value, .. } => {
-                let command = self.command_factory.create_update_command(path, value);
+            Commands::Validate { schema } => {
+                let command = self.command_factory.create_validate_command(tags_file, schema);
                 command.execute().await
             }
         }
@@ -78,19 +715,346 @@ impl CliHandler {
 mod tests {
     use super::*;
     use crate::domain::tag_service::MockTagService;
+    use crate::domain::WriteRequest;
+    use crate::infrastructure::mqtt::subscriber::MockMqttSubscriber;
+    use crate::infrastructure::mqtt::{IncomingMessage, MessageStream};
+    use crate::infrastructure::mqtt::publisher::MockMqttPublisher;
+    use crate::infrastructure::mqtt::ConnectionState;
+    use tokio::sync::watch;
     use mockall::predicate::*;
     
     #[test]
     fn test_cli_parsing_run() {
-        let args = vec!["uns_cli", "run", "--tags-file", "test.json"];
+        let args = vec![
+            "uns_cli",
+            "run",
+            "--tags-file",
+            "test.json",
+            "--writable-prefix",
+            "US/TX",
+            "--writable-prefix",
+            "US/CA",
+            "--instance-id",
+            "gateway-1",
+        ];
+        let cli = Cli::parse_from(args);
+        
+        assert_eq!(cli.repository.tags_file, "test.json");
+        match cli.command {
+            Commands::Run(RunArgs { writable_prefixes, instance_id, .. }) => {
+                assert_eq!(writable_prefixes, vec!["US/TX", "US/CA"]);
+                assert_eq!(instance_id, "gateway-1");
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+    
+    #[test]
+    fn test_cli_parsing_run_backend() {
+        let args = vec!["uns_cli", "run", "--backend", "redb", "--store-file", "/data/tags.redb"];
+        let cli = Cli::parse_from(args);
+        
+        assert_eq!(cli.repository.backend, RepositoryBackend::Redb);
+        assert_eq!(cli.repository.store_file, "/data/tags.redb");
+        match cli.command {
+            Commands::Run(RunArgs { republish_on_reconnect, writable_prefixes, instance_id, .. }) => {
+                assert!(!republish_on_reconnect);
+                assert!(writable_prefixes.is_empty());
+                assert_eq!(instance_id, "uns_cli");
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+    
+    #[test]
+    fn test_cli_parsing_topics() {
+        let args = vec!["uns_cli", "update", "A/B", "1", "--topic-strategy", "native", "--topic-prefix", "acme/uns"];
+        let cli = Cli::parse_from(args);
+        let mapping = cli.broker.topics.mapping().unwrap();
+        assert_eq!(mapping, TopicMapping::new(TopicStrategy::Native, "acme/uns").unwrap());
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "run"]);
+        assert_eq!(cli.broker.topics.mapping().unwrap(), TopicMapping::default());
+    }
+    
+    #[test]
+    fn test_cli_parsing_publish_policies() {
+        let args = vec![
+            "uns_cli",
+            "run",
+            "--qos",
+            "2",
+            "--publish-rule",
+            "class:vibration=qos0,no-retain",
+            "--publish-rule",
+            "US/+/PUMP1/#=qos1",
+        ];
+        let cli = Cli::parse_from(args);
+        
+        match cli.command {
+            Commands::Run(RunArgs { delivery, .. }) => {
+                let policies = delivery.policies();
+                assert_eq!(policies.default, PublishPolicy::new(QosLevel::ExactlyOnce, true));
+                assert_eq!(policies.rules.len(), 2);
+                assert_eq!(policies.rules[0], "class:vibration=qos0,no-retain".parse().unwrap());
+            }
+            _ => panic!("Expected Run command"),
+        }
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "run", "--no-retain", "--codec", "msgpack"]);
+        match cli.command {
+            Commands::Run(RunArgs { delivery, .. }) => {
+                assert_eq!(delivery.policies().default, PublishPolicy::not_retained());
+                assert_eq!(delivery.policies().codec, PayloadCodec::MessagePack);
+            }
+            _ => panic!("Expected Run command"),
+        }
+        
+        assert!(Cli::try_parse_from(vec!["uns_cli", "run", "--qos", "3"]).is_err());
+        assert!(Cli::try_parse_from(vec!["uns_cli", "run", "--publish-rule", "US/TX"]).is_err());
+    }
+    
+    #[test]
+    fn test_cli_parsing_rate_limit() {
+        let cli = Cli::parse_from(vec!["uns_cli", "run", "--max-tag-rate", "10"]);
+        match cli.command {
+            Commands::Run(RunArgs { rate_limit, .. }) => assert_eq!(rate_limit.window(), Some(Duration::from_millis(100))),
+            _ => panic!("Expected Run command"),
+        }
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "run"]);
+        match cli.command {
+            Commands::Run(RunArgs { rate_limit, .. }) => assert_eq!(rate_limit.window(), None),
+            _ => panic!("Expected Run command"),
+        }
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "run", "--max-tag-rate", "10", "--tag-history", "history.jsonl"]);
+        match cli.command {
+            Commands::Run(RunArgs { rate_limit, .. }) => assert_eq!(rate_limit.tag_history.as_deref(), Some("history.jsonl")),
+            _ => panic!("Expected Run command"),
+        }
+        
+        // Without a limit nothing is held back, so there is no history to keep
+        assert!(Cli::try_parse_from(vec!["uns_cli", "run", "--tag-history", "history.jsonl"]).is_err());
+    }
+    
+    #[test]
+    fn test_cli_parsing_outbox() {
+        let args = vec![
+            "uns_cli",
+            "run",
+            "--outbox-file",
+            "/var/lib/uns_cli/outbox.redb",
+            "--outbox-max-messages",
+            "500",
+            "--outbox-overflow",
+            "latest-per-tag",
+        ];
         let cli = Cli::parse_from(args);
         
         match cli.command {
-            Commands::Run { tags_file, .. } => {
-                assert_eq!(tags_file, "test.json");
+            Commands::Run(RunArgs { outbox, .. }) => {
+                assert_eq!(outbox.outbox_file.as_deref(), Some("/var/lib/uns_cli/outbox.redb"));
+                
+                let limits = outbox.limits();
+                assert_eq!(limits.max_messages, 500);
+                assert_eq!(limits.max_bytes, OutboxLimits::default().max_bytes);
+                assert_eq!(limits.overflow, OverflowPolicy::LatestPerTag);
             }
             _ => panic!("Expected Run command"),
         }
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "run"]);
+        match cli.command {
+            Commands::Run(RunArgs { outbox, .. }) => assert_eq!(outbox.outbox_file, None),
+            _ => panic!("Expected Run command"),
+        }
+    }
+    
+    #[test]
+    fn test_cli_parsing_database() {
+        let cli = Cli::parse_from(vec![
+            "uns_cli", "run", "--database-updates", "delta", "--database-interval", "0", "--database-layout", "level:2",
+        ]);
+        match cli.command {
+            Commands::Run(RunArgs { database, .. }) => {
+                assert_eq!(database.mode(), DatabaseMode::Delta);
+                assert_eq!(database.snapshot_interval(), None);
+                assert_eq!(database.database_layout, DatabaseLayout::ByLevel(2));
+            }
+            _ => panic!("Expected Run command"),
+        }
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "run"]);
+        match cli.command {
+            Commands::Run(RunArgs { database, .. }) => {
+                assert_eq!(database.mode(), DatabaseMode::Full);
+                assert_eq!(database.snapshot_interval(), Some(Duration::from_secs(60)));
+                assert_eq!(database.database_layout, DatabaseLayout::Single);
+            }
+            _ => panic!("Expected Run command"),
+        }
+        
+        assert!(Cli::try_parse_from(vec!["uns_cli", "run", "--database-layout", "pages:0"]).is_err());
+    }
+    
+    #[test]
+    fn test_cli_parsing_sparkplug() {
+        let args = vec![
+            "uns_cli",
+            "run",
+            "--instance-id",
+            "gateway-1",
+            "--format",
+            "sparkplug-b",
+            "--sparkplug-device-levels",
+            "3",
+        ];
+        let cli = Cli::parse_from(args);
+        
+        match cli.command {
+            Commands::Run(RunArgs { format, sparkplug, instance_id, .. }) => {
+                assert_eq!(format, PublishFormat::SparkplugB);
+                
+                let topology = sparkplug.topology(&instance_id).unwrap();
+                assert_eq!(topology, SparkplugTopology::new("UNS", "gateway-1").with_device_levels(3));
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+    
+    #[test]
+    fn test_cli_parsing_extra_brokers() {
+        let args = vec![
+            "uns_cli",
+            "run",
+            "--extra-broker",
+            "central=plant.example.com:8883,prefix=acme/austin,tls",
+            "--extra-broker",
+            "backup=10.0.0.5:1883,qos=0",
+        ];
+        match Cli::parse_from(args).command {
+            Commands::Run(RunArgs { brokers, .. }) => {
+                assert_eq!(brokers.extra_brokers.len(), 2);
+                assert_eq!(brokers.extra_brokers[0].prefix.as_deref(), Some("acme/austin"));
+                assert_eq!(brokers.extra_brokers[1].qos, Some(QosLevel::AtMostOnce));
+            }
+            _ => panic!("Expected Run command"),
+        }
+        
+        assert!(Cli::try_parse_from(vec!["uns_cli", "run", "--extra-broker", "plant.example.com:8883"]).is_err());
+    }
+    
+    #[test]
+    fn test_cli_parsing_session() {
+        let session = Cli::parse_from(vec!["uns_cli", "update", "A/B", "1"]).broker.session;
+        assert!(session.client_id("uns_cli_update").starts_with("uns_cli_update_"));
+        assert_eq!(session.session_expiry(), None);
+        
+        let args = vec!["uns_cli", "run", "--client-id", "austin-gateway", "--persistent-session", "--session-expiry", "60"];
+        let session = Cli::parse_from(args).broker.session;
+        assert_eq!(session.client_id("uns_cli_run"), "austin-gateway");
+        assert_eq!(session.session_expiry(), Some(Duration::from_secs(60)));
+        
+        // A random client id would never find its session again
+        let cli = Cli::parse_from(vec!["uns_cli", "mirror", "--persistent-session"]);
+        assert!(cli.check().is_err());
+    }
+    
+    #[test]
+    fn test_cli_parsing_delivery_timeout() {
+        let confirmation = Cli::parse_from(vec!["uns_cli", "update", "A/B", "1"]).broker.confirmation;
+        assert_eq!(confirmation.timeout(), Duration::from_secs(10));
+        
+        let confirmation = Cli::parse_from(vec!["uns_cli", "run", "--delivery-timeout", "3"]).broker.confirmation;
+        assert_eq!(confirmation.timeout(), Duration::from_secs(3));
+        
+        // Waiting zero seconds could never succeed
+        assert!(Cli::try_parse_from(vec!["uns_cli", "run", "--delivery-timeout", "0"]).is_err());
+    }
+    
+    #[test]
+    fn test_cli_parsing_embedded_broker() {
+        match Cli::parse_from(vec!["uns_cli", "run"]).command {
+            Commands::Run(RunArgs { embedded, .. }) => {
+                assert!(!embedded.embedded_broker);
+                assert_eq!(embedded.listen_address(), None);
+            }
+            _ => panic!("Expected Run command"),
+        }
+        
+        let args = vec!["uns_cli", "run", "--embedded-broker", "--embedded-broker-listen", "0.0.0.0:11883"];
+        match Cli::parse_from(args).command {
+            Commands::Run(RunArgs { embedded, .. }) => {
+                assert_eq!(embedded.listen_address(), Some("0.0.0.0:11883".parse().unwrap()));
+                assert_eq!(embedded.embedded_broker_ws_listen, None);
+            }
+            _ => panic!("Expected Run command"),
+        }
+        
+        let args = vec!["uns_cli", "run", "--embedded-broker", "--embedded-broker-ws-listen", "127.0.0.1:8000"];
+        match Cli::parse_from(args).command {
+            Commands::Run(RunArgs { embedded, .. }) => {
+                assert_eq!(embedded.embedded_broker_ws_listen, Some("127.0.0.1:8000".parse().unwrap()));
+            }
+            _ => panic!("Expected Run command"),
+        }
+        assert!(Cli::try_parse_from(vec!["uns_cli", "run", "--embedded-broker-ws-listen", "127.0.0.1:8000"]).is_err());
+    }
+        
+    #[test]
+    fn test_cli_parsing_broker_security() {
+        let args = vec![
+            "uns_cli",
+            "run",
+            "--mqtt-ca-file",
+            "/etc/uns/ca.pem",
+            "--mqtt-client-cert",
+            "/etc/uns/client.pem",
+            "--mqtt-client-key",
+            "/etc/uns/client.key",
+            "--mqtt-username",
+            "gateway",
+        ];
+        let security = Cli::parse_from(args).broker.security;
+        let tls = security.tls_options().unwrap();
+        assert_eq!(tls.ca_file.as_deref(), Some("/etc/uns/ca.pem"));
+        assert_eq!(tls.client_key_file.as_deref(), Some("/etc/uns/client.key"));
+        assert_eq!(security.mqtt_username.as_deref(), Some("gateway"));
+        
+        // A client certificate without its key is rejected
+        let args = vec!["uns_cli", "update", "A/B", "1", "--mqtt-client-cert", "/etc/uns/client.pem"];
+        assert!(Cli::try_parse_from(args).is_err());
+        
+        // Plain TCP by default
+        let cli = Cli::parse_from(vec!["uns_cli", "update", "A/B", "1"]);
+        assert!(cli.broker.security.tls_options().is_none());
+    }
+    
+    #[test]
+    fn test_cli_parsing_global_options() {
+        // Shared options are accepted before and after the command
+        let before = Cli::parse_from(vec!["uns_cli", "--mqtt-host", "broker.example.com", "--mqtt-port", "8883", "mirror"]);
+        let after = Cli::parse_from(vec!["uns_cli", "mirror", "--mqtt-host", "broker.example.com", "--mqtt-port", "8883"]);
+        assert_eq!(before.broker, after.broker);
+        
+        // The connection is made with them
+        let options = after.broker.connection_options("uns_cli_mirror").unwrap();
+        assert_eq!(options.host, "broker.example.com");
+        assert_eq!(options.port, 8883);
+        assert!(options.client_id.starts_with("uns_cli_mirror_"));
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "validate"]);
+        assert_eq!(cli.broker.mqtt_host, "localhost");
+        assert_eq!(cli.repository, RepositoryArgs {
+            tags_file: "tags.json".to_string(),
+            backend: RepositoryBackend::Json,
+            store_file: "tags.redb".to_string(),
+        });
+        assert_eq!(cli.output.log_level, LogLevel::Info);
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "purge", "--log-level", "debug"]);
+        assert_eq!(cli.output.log_level.filter(), log::LevelFilter::Debug);
     }
     
     #[test]
@@ -104,14 +1068,81 @@ mod tests {
         let cli = Cli::parse_from(args);
         
         match cli.command {
-            Commands::Update { path, value, .. } => {
+            Commands::Update { path, value, timeout } => {
                 assert_eq!(path, "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE");
                 assert_eq!(value, "50.2");
+                assert_eq!(timeout, 5);
             }
             _ => panic!("Expected Update command"),
         }
     }
     
+    #[test]
+    fn test_cli_parsing_validate() {
+        let args = vec!["uns_cli", "validate", "--tags-file", "test.json", "--schema"];
+        let cli = Cli::parse_from(args);
+        
+        assert_eq!(cli.repository.tags_file, "test.json");
+        match cli.command {
+            Commands::Validate { schema } => {
+                assert!(schema);
+            }
+            _ => panic!("Expected Validate command"),
+        }
+    }
+    
+    #[test]
+    fn test_cli_parsing_mirror() {
+        let args = vec![
+            "uns_cli",
+            "mirror",
+            "--mqtt-host",
+            "hivemq",
+            "--output",
+            "tags_database.json",
+            "--prefix",
+            "US/TX",
+            "--once",
+        ];
+        let cli = Cli::parse_from(args);
+        
+        assert_eq!(cli.broker.mqtt_host, "hivemq");
+        assert_eq!(cli.repository.backend, RepositoryBackend::Json);
+        match cli.command {
+            Commands::Mirror { output, paths_output, prefix, once, settle_time } => {
+                assert_eq!(output.as_deref(), Some("tags_database.json"));
+                assert_eq!(paths_output, None);
+                assert_eq!(prefix.as_deref(), Some("US/TX"));
+                assert!(once);
+                assert_eq!(settle_time, 2);
+            }
+            _ => panic!("Expected Mirror command"),
+        }
+    }
+    
+    #[test]
+    fn test_cli_parsing_purge() {
+        let cli = Cli::parse_from(vec!["uns_cli", "purge", "--tags-file", "test.json", "--settle-time", "5"]);
+        assert_eq!(cli.repository.tags_file, "test.json");
+        assert_eq!(cli.broker.mqtt_host, "localhost");
+        match cli.command {
+            Commands::Purge { apply, settle_time } => {
+                assert!(!apply);
+                assert_eq!(settle_time, 5);
+            }
+            _ => panic!("Expected Purge command"),
+        }
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "purge", "--apply"]);
+        assert!(matches!(cli.command, Commands::Purge { apply: true, .. }));
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "run", "--purge-stale", "apply"]);
+        assert!(matches!(cli.command, Commands::Run(RunArgs { purge_stale: Some(StalePurge::Apply), .. })));
+        
+        let cli = Cli::parse_from(vec!["uns_cli", "run"]);
+        assert!(matches!(cli.command, Commands::Run(RunArgs { purge_stale: None, .. })));
+    }
+    
     #[tokio::test]
     async fn test_cli_handler_run() {
         // Create mock tag service
@@ -124,8 +1155,27 @@ mod tests {
             .times(1)
             .returning(|_| Box::pin(async { Ok(()) }));
         
+        // Create mock subscriber for the write request listener
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .with(eq("tags/set/#"))
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(MessageStream::from_messages(Vec::new())) }));
+        
+        // Create mock publisher for the status announcer
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_connection_state()
+            .times(1)
+            .returning(|| watch::channel(ConnectionState::Connecting).1);
+        
         // Create the CLI handler
-        let handler = CliHandler::new(Arc::new(mock_service));
+        let handler = CliHandler::new(
+            Arc::new(mock_service),
+            Arc::new(mock_subscriber),
+            Arc::new(mock_publisher),
+        );
         
         // We can't easily test the actual CLI parsing, but we can test the command execution
         let command = handler.command_factory.create_run_command_test_mode("test.json".to_string());
@@ -137,21 +1187,34 @@ mod tests {
     
     #[tokio::test]
     async fn test_cli_handler_update() {
-        // Create mock tag service
-        let mut mock_service = MockTagService::new();
+        // Create mock subscriber for the reply
+        let (reply_sender, replies) = MessageStream::channel(10);
+        let mut mock_subscriber = MockMqttSubscriber::new();
+        mock_subscriber
+            .expect_subscribe_stream()
+            .times(1)
+            .return_once(move |_| Box::pin(async move { Ok(replies) }));
         
-        // Set up expectations
-        mock_service
-            .expect_update_and_publish_tag()
-            .with(
-                eq("US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE"),
-                eq("50.2".to_string())
-            )
+        // Create mock publisher; the running instance confirms the write
+        let mut mock_publisher = MockMqttPublisher::new();
+        mock_publisher
+            .expect_publish_write_request()
+            .withf(|request| request.path == "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE")
             .times(1)
-            .returning(|_, _| Box::pin(async { Ok(()) }));
+            .returning(move |request: &WriteRequest| {
+                let reply_to = request.reply_to.clone().unwrap();
+                let mut reply = IncomingMessage::new(&reply_to.topic, br#"{"status":"ok"}"#);
+                reply.properties.correlation_data = reply_to.correlation_data;
+                reply_sender.try_send(reply).unwrap();
+                Box::pin(async { Ok(()) })
+            });
         
         // Create the CLI handler
-        let handler = CliHandler::new(Arc::new(mock_service));
+        let handler = CliHandler::new(
+            Arc::new(MockTagService::new()),
+            Arc::new(mock_subscriber),
+            Arc::new(mock_publisher),
+        );
         
         // We can't easily test the actual CLI parsing, but we can test the command execution
         let command = handler.command_factory.create_update_command(
@@ -0,0 +1,328 @@
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::infrastructure::mqtt::Credentials;
+use crate::infrastructure::UnsError;
+use crate::presentation::cli::{Cli, Commands, LogLevel, OutboxOverflow, RepositoryBackend, TopicLayout};
+
+/// Settings read from a TOML configuration file
+///
+/// Every setting mirrors a command-line option; the option and its `UNS_*` environment variable
+/// take precedence over the file.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub broker: BrokerConfig,
+    pub tls: TlsConfig,
+    pub topics: TopicConfig,
+    pub repository: RepositoryConfig,
+    pub persistence: PersistenceConfig,
+    pub logging: LoggingConfig,
+}
+
+/// `[broker]`: connection, session and authentication settings
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub client_id: Option<String>,
+    pub persistent_session: Option<bool>,
+    pub session_expiry: Option<u64>,
+    pub delivery_timeout: Option<u64>,
+    pub username: Option<String>,
+    pub password_file: Option<String>,
+}
+
+/// `[tls]`: TLS settings of the broker connection
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: Option<bool>,
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+/// `[topics]`: mapping between tag paths and topics
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopicConfig {
+    pub strategy: Option<TopicLayout>,
+    pub prefix: Option<String>,
+}
+
+/// `[repository]`: tag file and store
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepositoryConfig {
+    pub tags_file: Option<String>,
+    pub backend: Option<RepositoryBackend>,
+    pub store_file: Option<String>,
+}
+
+/// `[persistence]`: store-and-forward outbox of `run`
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    pub outbox_file: Option<String>,
+    pub outbox_max_messages: Option<usize>,
+    pub outbox_max_bytes: Option<u64>,
+    pub outbox_overflow: Option<OutboxOverflow>,
+}
+
+/// `[logging]`: log output
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: Option<LogLevel>,
+}
+
+impl ConfigFile {
+    /// Name of the configuration file looked for in the working directory
+    pub const LOCAL_FILE: &'static str = "uns_cli.toml";
+
+    /// System-wide configuration file
+    pub const SYSTEM_FILE: &'static str = "/etc/uns_cli/config.toml";
+
+    /// Returns the places searched for a configuration file when none is given, in order
+    pub fn search_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(Self::LOCAL_FILE)];
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        if let Some(config_home) = config_home {
+            paths.push(config_home.join("uns_cli").join("config.toml"));
+        }
+        paths.push(PathBuf::from(Self::SYSTEM_FILE));
+        paths
+    }
+
+    /// Returns the configuration file to use: the given one, which must exist, or the first one found
+    pub fn locate(explicit: Option<&str>) -> Result<Option<PathBuf>, UnsError> {
+        match explicit {
+            Some(path) if Path::new(path).is_file() => Ok(Some(PathBuf::from(path))),
+            Some(path) => Err(UnsError::Validation(format!("Configuration file {} not found", path))),
+            None => Ok(Self::search_paths().into_iter().find(|path| path.is_file())),
+        }
+    }
+
+    /// Reads a configuration file
+    pub fn load(path: &Path) -> Result<Self, UnsError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| UnsError::Other(format!("Failed to read {}: {}", path.display(), e)))?;
+        Self::parse(&contents).map_err(|e| match e {
+            UnsError::Validation(msg) => UnsError::Validation(format!("{}: {}", path.display(), msg)),
+            e => e,
+        })
+    }
+
+    /// Parses the contents of a configuration file
+    pub fn parse(contents: &str) -> Result<Self, UnsError> {
+        toml::from_str(contents).map_err(|e| UnsError::Validation(e.message().to_string()))
+    }
+
+    /// Fills the options of `cli` that were neither given as flags nor in the environment
+    pub fn apply(&self, cli: &mut Cli, matches: &ArgMatches) {
+        let broker = &mut cli.broker;
+        set(&mut broker.mqtt_host, &self.broker.host, matches, "mqtt_host");
+        set(&mut broker.mqtt_port, &self.broker.port, matches, "mqtt_port");
+        set_some(&mut broker.session.client_id, &self.broker.client_id, matches, "client_id");
+        set(&mut broker.session.persistent_session, &self.broker.persistent_session, matches, "persistent_session");
+        set(&mut broker.session.session_expiry, &self.broker.session_expiry, matches, "session_expiry");
+        set(&mut broker.confirmation.delivery_timeout, &self.broker.delivery_timeout, matches, "delivery_timeout");
+        set_some(&mut broker.security.mqtt_username, &self.broker.username, matches, "mqtt_username");
+        // A password in the environment replaces the one in a file named here
+        if std::env::var_os(Credentials::PASSWORD_ENV).is_none() {
+            set_some(&mut broker.security.mqtt_password_file, &self.broker.password_file, matches, "mqtt_password_file");
+        }
+
+        set(&mut broker.security.mqtt_tls, &self.tls.enabled, matches, "mqtt_tls");
+        set_some(&mut broker.security.mqtt_ca_file, &self.tls.ca_file, matches, "mqtt_ca_file");
+        set_some(&mut broker.security.mqtt_client_cert, &self.tls.client_cert, matches, "mqtt_client_cert");
+        set_some(&mut broker.security.mqtt_client_key, &self.tls.client_key, matches, "mqtt_client_key");
+
+        set(&mut broker.topics.topic_strategy, &self.topics.strategy, matches, "topic_strategy");
+        set(&mut broker.topics.topic_prefix, &self.topics.prefix, matches, "topic_prefix");
+
+        let repository = &mut cli.repository;
+        set(&mut repository.tags_file, &self.repository.tags_file, matches, "tags_file");
+        set(&mut repository.backend, &self.repository.backend, matches, "backend");
+        set(&mut repository.store_file, &self.repository.store_file, matches, "store_file");
+
+        set(&mut cli.output.log_level, &self.logging.level, matches, "log_level");
+
+        // Only `run` keeps an outbox
+        if let (Commands::Run(run), Some(run_matches)) = (&mut cli.command, matches.subcommand_matches("run")) {
+            let outbox = &mut run.outbox;
+            set_some(&mut outbox.outbox_file, &self.persistence.outbox_file, run_matches, "outbox_file");
+            set(&mut outbox.outbox_max_messages, &self.persistence.outbox_max_messages, run_matches, "outbox_max_messages");
+            set(&mut outbox.outbox_max_bytes, &self.persistence.outbox_max_bytes, run_matches, "outbox_max_bytes");
+            set(&mut outbox.outbox_overflow, &self.persistence.outbox_overflow, run_matches, "outbox_overflow");
+        }
+    }
+}
+
+/// Returns whether an option was given as a flag or in the environment
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+}
+
+/// Replaces an option with the value from the file, unless it was given
+fn set<T: Clone>(option: &mut T, value: &Option<T>, matches: &ArgMatches, id: &str) {
+    if let Some(value) = value {
+        if !given(matches, id) {
+            *option = value.clone();
+        }
+    }
+}
+
+/// Same as `set`, for options without a default
+fn set_some<T: Clone>(option: &mut Option<T>, value: &Option<T>, matches: &ArgMatches, id: &str) {
+    if value.is_some() && !given(matches, id) {
+        *option = value.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+    use std::io::Write;
+
+    const CONFIG: &str = r#"
+        [broker]
+        host = "hivemq"
+        port = 8883
+        client_id = "austin-gateway"
+        persistent_session = true
+
+        [tls]
+        ca_file = "/etc/uns_cli/ca.pem"
+
+        [topics]
+        strategy = "native"
+        prefix = "acme/uns"
+
+        [repository]
+        tags_file = "/etc/uns_cli/tags.json"
+        backend = "redb"
+
+        [persistence]
+        outbox_file = "/var/lib/uns_cli/outbox"
+        outbox_overflow = "latest-per-tag"
+
+        [logging]
+        level = "debug"
+    "#;
+
+    fn layered(config: &ConfigFile, args: &[&str]) -> Result<Cli, UnsError> {
+        let matches = Cli::command().try_get_matches_from(args).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        config.apply(&mut cli, &matches);
+        cli.check()?;
+        Ok(cli)
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+        assert_eq!(config.broker.host.as_deref(), Some("hivemq"));
+        assert_eq!(config.broker.port, Some(8883));
+        assert_eq!(config.topics.strategy, Some(TopicLayout::Native));
+        assert_eq!(config.repository.backend, Some(RepositoryBackend::Redb));
+        assert_eq!(config.persistence.outbox_overflow, Some(OutboxOverflow::LatestPerTag));
+        assert_eq!(config.logging.level, Some(LogLevel::Debug));
+
+        // An empty file changes nothing
+        assert_eq!(ConfigFile::parse("").unwrap(), ConfigFile::default());
+    }
+
+    #[test]
+    fn test_parse_config_rejects_unknown_settings() {
+        // Misspelled settings would otherwise be ignored silently
+        let err = ConfigFile::parse("[broker]\nhots = \"hivemq\"\n").unwrap_err();
+        assert!(err.to_string().contains("hots"), "{}", err);
+
+        assert!(ConfigFile::parse("[topics]\nstrategy = \"flat\"\n").is_err());
+    }
+
+    #[test]
+    fn test_config_fills_unset_options() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+        let cli = layered(&config, &["uns_cli", "run"]).unwrap();
+
+        assert_eq!(cli.broker.mqtt_host, "hivemq");
+        assert_eq!(cli.broker.mqtt_port, 8883);
+        assert_eq!(cli.broker.session.client_id.as_deref(), Some("austin-gateway"));
+        assert!(cli.broker.session.persistent_session);
+        assert!(cli.broker.security.tls_options().is_some());
+        assert_eq!(cli.broker.topics.topic_prefix, "acme/uns");
+        assert_eq!(cli.repository.tags_file, "/etc/uns_cli/tags.json");
+        assert_eq!(cli.repository.backend, RepositoryBackend::Redb);
+        assert_eq!(cli.repository.store_file, "tags.redb");
+        assert_eq!(cli.output.log_level, LogLevel::Debug);
+        match cli.command {
+            Commands::Run(run) => {
+                assert_eq!(run.outbox.outbox_file.as_deref(), Some("/var/lib/uns_cli/outbox"));
+                assert_eq!(run.outbox.outbox_overflow, OutboxOverflow::LatestPerTag);
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_flags_take_precedence_over_config() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+        let cli = layered(&config, &[
+            "uns_cli", "--mqtt-port", "1883", "run", "--topic-strategy", "dotted", "--outbox-file", "outbox",
+        ]).unwrap();
+
+        // Given flags win, before and after the command name
+        assert_eq!(cli.broker.mqtt_port, 1883);
+        assert_eq!(cli.broker.topics.topic_strategy, TopicLayout::Dotted);
+        assert_eq!(cli.broker.mqtt_host, "hivemq");
+        match cli.command {
+            Commands::Run(run) => assert_eq!(run.outbox.outbox_file.as_deref(), Some("outbox")),
+            _ => panic!("Expected Run command"),
+        }
+
+        // A flag equal to the built-in default still wins over the file
+        let cli = layered(&config, &["uns_cli", "validate", "--log-level", "info"]).unwrap();
+        assert_eq!(cli.output.log_level, LogLevel::Info);
+    }
+
+    #[test]
+    fn test_config_is_checked_after_merging() {
+        let config = ConfigFile::parse("[broker]\npersistent_session = true\n").unwrap();
+        assert!(layered(&config, &["uns_cli", "run"]).is_err());
+        assert!(layered(&config, &["uns_cli", "run", "--client-id", "gateway"]).is_ok());
+
+        let config = ConfigFile::parse("[tls]\nclient_cert = \"client.pem\"\n").unwrap();
+        assert!(layered(&config, &["uns_cli", "run"]).is_err());
+
+        let config = ConfigFile::parse("[broker]\ndelivery_timeout = 0\n").unwrap();
+        assert!(layered(&config, &["uns_cli", "run"]).is_err());
+    }
+
+    #[test]
+    fn test_locate_config() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "[broker]\nhost = \"hivemq\"").unwrap();
+        let path = file.path().to_str().unwrap();
+
+        assert_eq!(ConfigFile::locate(Some(path)).unwrap(), Some(file.path().to_path_buf()));
+        assert_eq!(ConfigFile::load(file.path()).unwrap().broker.host.as_deref(), Some("hivemq"));
+
+        // A file asked for by name must exist
+        assert!(ConfigFile::locate(Some("/nonexistent/uns_cli.toml")).is_err());
+
+        let matches = Cli::command().try_get_matches_from(["uns_cli", "--config", path, "validate"]).unwrap();
+        let (cli, used) = Cli::from_matches_layered(&matches).unwrap();
+        assert_eq!(used, Some(file.path().to_path_buf()));
+        assert_eq!(cli.broker.mqtt_host, "hivemq");
+    }
+}
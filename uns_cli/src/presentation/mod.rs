@@ -1,5 +1,7 @@
 // Presentation module exports
 pub mod cli;
+pub mod config;
 
 // Re-export key types
 pub use cli::Cli;
+pub use config::ConfigFile;
@@ -1,21 +1,50 @@
 use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::sync::OnceCell;
 
-use rumqttc::{AsyncClient, MqttOptions, QoS};
-use serde_json;
-use tokio;
+use prost::Message;
+use rumqttc::v5::{
+    mqttbytes::{v5::Packet, QoS},
+    AsyncClient, Event, EventLoop, MqttOptions,
+};
 
 use uns_cli::{
-    application::TagServiceImpl,
-    domain::{Tag, TagService},
+    application::{
+        commands::{CommandHandler, UpdateCommandHandler},
+        StatusAnnouncer, TagServiceImpl, WriteRequestListener,
+    },
+    domain::{InstanceState, InstanceStatus, PublishPolicy, QosLevel, Tag, TagService},
     infrastructure::{
-        mqtt::{client::RumqttcClient, publisher::MqttTagPublisher, MqttClient, MqttPublisher},
+        mqtt::{
+            client::RumqttcClient,
+            publisher::MqttTagPublisher,
+            sparkplug::{MetricValue, Payload, SparkplugPublisher, SparkplugTopology},
+            EmbeddedBroker, MessageStream, MqttClient, MqttPublisher, MqttSubscriber,
+        },
         repositories::JsonTagRepository,
+        UnsError,
     },
 };
 
+// Broker shared by all tests, started on a free port
+static BROKER: OnceCell<EmbeddedBroker> = OnceCell::const_new();
+
+// Helper function to start the test broker once
+async fn broker() -> &'static EmbeddedBroker {
+    BROKER
+        .get_or_init(|| async { EmbeddedBroker::start("127.0.0.1:0".parse().unwrap()).await.unwrap() })
+        .await
+}
+
+// Helper function to connect a client of the application to the test broker
+async fn connect_client(client_id: &str) -> RumqttcClient {
+    let broker = broker().await;
+    RumqttcClient::new(client_id, &broker.host(), broker.port()).await.unwrap()
+}
+
 // Helper function to create a test MQTT client
-async fn create_test_mqtt_client(client_id: &str) -> (AsyncClient, rumqttc::EventLoop) {
-    let mut mqtt_options = MqttOptions::new(client_id, "localhost", 1883);
+async fn create_test_mqtt_client(client_id: &str) -> (AsyncClient, EventLoop) {
+    let broker = broker().await;
+    let mut mqtt_options = MqttOptions::new(client_id, broker.host(), broker.port());
     mqtt_options.set_keep_alive(Duration::from_secs(5));
     AsyncClient::new(mqtt_options, 10)
 }
@@ -23,7 +52,7 @@ async fn create_test_mqtt_client(client_id: &str) -> (AsyncClient, rumqttc::Even
 #[tokio::test]
 async fn test_end_to_end_tag_loading() {
     // Create the components
-    let mqtt_client = RumqttcClient::new("test_client", "localhost", 1883).await.unwrap();
+    let mqtt_client = connect_client("test_client").await;
     let mqtt_client: Arc<dyn MqttClient> = Arc::new(mqtt_client);
     
     let mqtt_publisher: Arc<dyn MqttPublisher> = Arc::new(MqttTagPublisher::new(mqtt_client));
@@ -47,7 +76,7 @@ async fn test_end_to_end_tag_loading() {
     // Spawn a task to process MQTT events
     tokio::spawn(async move {
         while let Ok(notification) = eventloop.poll().await {
-            if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) = notification {
+            if let Event::Incoming(Packet::Publish(publish)) = notification {
                 let _ = tx.send(publish).await;
             }
         }
@@ -65,19 +94,17 @@ async fn test_end_to_end_tag_loading() {
     
     // Wait for a reasonable time to receive all messages
     for _ in 0..10 {
-        if let Ok(publish) = tokio::time::timeout(
+        if let Ok(Some(publish)) = tokio::time::timeout(
             Duration::from_secs(1), 
             rx.recv()
         ).await {
-            if let Some(publish) = publish {
-                // Extract tag path from topic
-                let topic = publish.topic.clone();
-                if topic == "tags/database" {
-                    database_received = true;
-                } else if topic.starts_with("tags/") {
-                    let tag_path = topic.replace("tags/", "").replace(".", "/");
-                    received_tags.insert(tag_path);
-                }
+            // Extract tag path from topic
+            let topic = String::from_utf8_lossy(&publish.topic).to_string();
+            if topic == "tags/database" {
+                database_received = true;
+            } else if topic.starts_with("tags/") {
+                let tag_path = topic.replace("tags/", "").replace(".", "/");
+                received_tags.insert(tag_path);
             }
         }
     }
@@ -91,7 +118,7 @@ async fn test_end_to_end_tag_loading() {
 #[tokio::test]
 async fn test_end_to_end_tag_update() {
     // Create the components
-    let mqtt_client = RumqttcClient::new("test_client_update", "localhost", 1883).await.unwrap();
+    let mqtt_client = connect_client("test_client_update").await;
     let mqtt_client: Arc<dyn MqttClient> = Arc::new(mqtt_client);
     
     let mqtt_publisher: Arc<dyn MqttPublisher> = Arc::new(MqttTagPublisher::new(mqtt_client));
@@ -120,7 +147,7 @@ async fn test_end_to_end_tag_update() {
     // Spawn a task to process MQTT events
     tokio::spawn(async move {
         while let Ok(notification) = eventloop.poll().await {
-            if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) = notification {
+            if let Event::Incoming(Packet::Publish(publish)) = notification {
                 let _ = tx.send(publish).await;
             }
         }
@@ -183,3 +210,227 @@ async fn test_end_to_end_tag_update() {
     assert!(updated_value_received, "Tag update was not propagated through MQTT");
     assert!(database_updated, "Tag update was not reflected in the database publication");
 }
+
+#[tokio::test]
+async fn test_subscribe_stream_receives_publishes() {
+    let client = connect_client("test_client_stream").await;
+    
+    // Retain a message before subscribing
+    client.publish("tags/stream_test/retained", b"old".to_vec(), PublishPolicy::default()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    
+    let mut stream = client.subscribe_stream("tags/stream_test/#").await.unwrap();
+    
+    // The retained message is delivered first, flagged as retained
+    let retained = tokio::time::timeout(Duration::from_secs(5), stream.recv())
+        .await
+        .expect("No retained message received")
+        .unwrap();
+    assert_eq!(retained.topic, "tags/stream_test/retained");
+    assert_eq!(retained.payload, b"old".to_vec());
+    assert!(retained.retain);
+    
+    // Live messages follow, and messages outside the filter are not delivered
+    client.publish("tags/other", b"ignored".to_vec(), PublishPolicy::not_retained()).await.unwrap();
+    client.publish("tags/stream_test/live", b"new".to_vec(), PublishPolicy::not_retained()).await.unwrap();
+    
+    let live = tokio::time::timeout(Duration::from_secs(5), stream.recv())
+        .await
+        .expect("No live message received")
+        .unwrap();
+    assert_eq!(live.topic, "tags/stream_test/live");
+    assert!(!live.retain);
+    
+    // Clear the retained message
+    client.publish("tags/stream_test/retained", Vec::new(), PublishPolicy::default()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_publish_waits_for_acknowledgment() {
+    let client = connect_client("test_client_confirmed").await;
+    
+    // Each publish only returns once the broker confirmed it, at every QoS
+    for qos in [QosLevel::AtMostOnce, QosLevel::AtLeastOnce, QosLevel::ExactlyOnce] {
+        client.publish("confirm_test/value", b"1".to_vec(), PublishPolicy::new(qos, false)).await.unwrap();
+    }
+    
+    // Many publishes in flight at once are each matched to their own acknowledgment
+    let publishes = (0..300).map(|i| client.publish("confirm_test/burst", i.to_string().into_bytes(), PublishPolicy::not_retained()));
+    futures::future::try_join_all(publishes).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_websocket_transport() {
+    let websocket = broker().await.serve_websocket("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let url = format!("ws://{}/mqtt", websocket);
+    let client = RumqttcClient::new("test_client_websocket", &url, websocket.port()).await.unwrap();
+    
+    let mut stream = client.subscribe_stream("websocket_test/#").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    
+    // Publishes are confirmed over WebSockets just as over TCP
+    for qos in [QosLevel::AtMostOnce, QosLevel::AtLeastOnce, QosLevel::ExactlyOnce] {
+        client.publish("websocket_test/value", b"1".to_vec(), PublishPolicy::new(qos, false)).await.unwrap();
+    }
+    
+    for _ in 0..3 {
+        let message = tokio::time::timeout(Duration::from_secs(5), stream.recv())
+            .await
+            .expect("No message received over WebSockets")
+            .unwrap();
+        assert_eq!(message.topic, "websocket_test/value");
+    }
+}
+
+#[tokio::test]
+async fn test_acknowledged_write_request() {
+    // The running instance: loads tags and listens for write requests
+    let runner = Arc::new(connect_client("test_runner_ack").await);
+    let runner_client: Arc<dyn MqttClient> = runner.clone();
+    let runner_publisher: Arc<dyn MqttPublisher> = Arc::new(MqttTagPublisher::new(runner_client));
+    
+    let tag_service: Arc<dyn TagService> = Arc::new(TagServiceImpl::new(
+        Arc::new(JsonTagRepository::new()),
+        runner_publisher.clone(),
+    ));
+    tag_service.load_and_publish_tags("tests/test_tags.json").await.unwrap();
+    
+    let listener = WriteRequestListener::new(tag_service.clone(), runner.clone(), runner_publisher)
+        .with_writable_prefixes(vec!["US/TX".to_string()]);
+    let listener_task = listener.start().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    
+    // The update command, in its own connection
+    let requester = Arc::new(connect_client("test_requester_ack").await);
+    let requester_client: Arc<dyn MqttClient> = requester.clone();
+    let requester_publisher: Arc<dyn MqttPublisher> = Arc::new(MqttTagPublisher::new(requester_client));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    
+    let update = |path: &str| {
+        UpdateCommandHandler::new(requester_publisher.clone(), requester.clone(), path.to_string(), "61.5".to_string())
+    };
+    
+    // A successful write is confirmed and applied
+    let tag_path = "US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE";
+    update(tag_path).execute().await.unwrap();
+    assert_eq!(tag_service.get_tag(tag_path).await.unwrap().unwrap().value, "61.5");
+    
+    // Failures come back with their reason
+    let result = update("US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP9/PRESSURE").execute().await;
+    assert!(matches!(result, Err(UnsError::NotFound(_))));
+    
+    let result = update("US/CA/PUMP1/PRESSURE").execute().await;
+    assert!(matches!(result, Err(UnsError::PermissionDenied(_))));
+    
+    listener_task.abort();
+}
+
+#[tokio::test]
+async fn test_instance_status() {
+    // The running instance announces itself once connected
+    let runner = Arc::new(connect_client("test_runner_status").await);
+    let runner_client: Arc<dyn MqttClient> = runner.clone();
+    let runner_publisher: Arc<dyn MqttPublisher> = Arc::new(MqttTagPublisher::new(runner_client));
+    
+    let tag_service: Arc<dyn TagService> = Arc::new(TagServiceImpl::new(
+        Arc::new(JsonTagRepository::new()),
+        runner_publisher.clone(),
+    ));
+    tag_service.load_and_publish_tags("tests/test_tags.json").await.unwrap();
+    
+    let announcer = StatusAnnouncer::new(tag_service.clone(), runner_publisher, "test-status".to_string());
+    let status_task = announcer.start();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    
+    // A late subscriber gets the retained status
+    let status_topic = InstanceStatus::topic_for("test-status");
+    let mut statuses = runner.subscribe_stream(&status_topic).await.unwrap();
+    let message = tokio::time::timeout(Duration::from_secs(2), statuses.recv()).await.unwrap().unwrap();
+    let status: InstanceStatus = serde_json::from_slice(&message.payload).unwrap();
+    assert_eq!(status.state, InstanceState::Online);
+    assert_eq!(status.tag_count, Some(tag_service.get_all_tags().await.unwrap().len()));
+    
+    // A graceful shutdown replaces it with offline
+    status_task.abort();
+    announcer.announce_offline().await.unwrap();
+    let message = tokio::time::timeout(Duration::from_secs(2), statuses.recv()).await.unwrap().unwrap();
+    let status: InstanceStatus = serde_json::from_slice(&message.payload).unwrap();
+    assert_eq!(status.state, InstanceState::Offline);
+}
+
+// Helper function to receive the next Sparkplug message
+async fn next_sparkplug(messages: &mut MessageStream) -> (String, Payload) {
+    let message = tokio::time::timeout(Duration::from_secs(2), messages.recv()).await.unwrap().unwrap();
+    (message.topic, Payload::decode(message.payload.as_slice()).unwrap())
+}
+
+#[tokio::test]
+async fn test_sparkplug_publishing() {
+    // A host application listening to the Sparkplug namespace
+    let host = connect_client("test_sparkplug_host").await;
+    let mut messages = host.subscribe_stream("spBv1.0/IT/#").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    
+    // The edge node
+    let topology = SparkplugTopology::new("IT", "test-edge");
+    let node = Arc::new(connect_client("test_sparkplug_edge").await);
+    let node_client: Arc<dyn MqttClient> = node.clone();
+    let publisher = SparkplugPublisher::new(node_client, topology);
+    publisher.start(node.clone()).await.unwrap();
+    let publisher: Arc<dyn MqttPublisher> = Arc::new(publisher);
+    
+    let tag_service: Arc<dyn TagService> = Arc::new(TagServiceImpl::new(
+        Arc::new(JsonTagRepository::new()),
+        publisher,
+    ));
+    tag_service.load_and_publish_tags("tests/test_tags.json").await.unwrap();
+    
+    // The node is born first, then its devices
+    let (topic, nbirth) = next_sparkplug(&mut messages).await;
+    assert_eq!(topic, "spBv1.0/IT/NBIRTH/test-edge");
+    assert_eq!(nbirth.seq, Some(0));
+    
+    let device_count = {
+        let tags = tag_service.get_all_tags().await.unwrap();
+        let mut parents: Vec<_> = tags.keys().map(|path| path.rsplit_once('/').unwrap().0.to_string()).collect();
+        parents.sort();
+        parents.dedup();
+        parents.len()
+    };
+    let mut alias = None;
+    for seq in 1..=device_count as u64 {
+        let (topic, dbirth) = next_sparkplug(&mut messages).await;
+        assert!(topic.starts_with("spBv1.0/IT/DBIRTH/test-edge/"));
+        assert_eq!(dbirth.seq, Some(seq));
+        if topic.ends_with("/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1") {
+            alias = dbirth.metrics.iter().find(|m| m.name.as_deref() == Some("PRESSURE")).unwrap().alias;
+        }
+    }
+    
+    // Changes are sent by alias
+    tag_service
+        .update_and_publish_tag("US/TX/AUSTIN/AREA1/LINE1/MACHINE1/PUMP1/PRESSURE", "61.5".to_string())
+        .await
+        .unwrap();
+    let (topic, ddata) = next_sparkplug(&mut messages).await;
+    assert_eq!(topic, "spBv1.0/IT/DDATA/test-edge/US.TX.AUSTIN.AREA1.LINE1.MACHINE1.PUMP1");
+    assert_eq!(ddata.metrics[0].alias, alias);
+    assert_eq!(ddata.metrics[0].value, Some(MetricValue::DoubleValue(61.5)));
+    
+    // A rebirth request starts over with NBIRTH
+    let request = Payload {
+        timestamp: None,
+        metrics: vec![uns_cli::infrastructure::mqtt::sparkplug::Metric {
+            name: Some("Node Control/Rebirth".to_string()),
+            value: Some(MetricValue::BooleanValue(true)),
+            ..Default::default()
+        }],
+        seq: None,
+    };
+    host.publish("spBv1.0/IT/NCMD/test-edge", request.encode_to_vec(), PublishPolicy::not_retained()).await.unwrap();
+    let (topic, _) = next_sparkplug(&mut messages).await;
+    assert_eq!(topic, "spBv1.0/IT/NCMD/test-edge");
+    let (topic, nbirth) = next_sparkplug(&mut messages).await;
+    assert_eq!(topic, "spBv1.0/IT/NBIRTH/test-edge");
+    assert_eq!(nbirth.seq, Some(0));
+}
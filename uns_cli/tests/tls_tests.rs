@@ -0,0 +1,140 @@
+use std::{net::TcpListener, path::PathBuf, time::Duration};
+
+use uns_cli::{
+    domain::PublishPolicy,
+    infrastructure::{
+        mqtt::{client::RumqttcClient, ConnectionOptions, ConnectionState, Credentials, MqttClient, MqttSubscriber, TlsOptions},
+        UnsError,
+    },
+};
+
+// Returns the path of a file in tests/fixtures/tls
+fn fixture(name: &str) -> String {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "tls", name].iter().collect();
+    path.to_str().unwrap().to_string()
+}
+
+// Starts a broker requiring client certificates and username/password, returning its port
+fn start_tls_broker() -> u16 {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let config: rumqttd::Config = serde_json::from_value(serde_json::json!({
+        "id": 0,
+        "router": {
+            "max_connections": 10,
+            "max_outgoing_packet_count": 200,
+            "max_segment_size": 104857600,
+            "max_segment_count": 10
+        },
+        "v5": {
+            "1": {
+                "name": "tls",
+                "listen": format!("127.0.0.1:{}", port),
+                "next_connection_delay_ms": 1,
+                "tls": {
+                    "capath": fixture("ca.cert.pem"),
+                    "certpath": fixture("server.cert.pem"),
+                    "keypath": fixture("server.key.pem")
+                },
+                "connections": {
+                    "connection_timeout_ms": 60000,
+                    "max_payload_size": 20480,
+                    "max_inflight_count": 100,
+                    "auth": { "gateway": "s3cret" },
+                    "dynamic_filters": true
+                }
+            }
+        }
+    }))
+    .unwrap();
+
+    std::thread::spawn(move || {
+        rumqttd::Broker::new(config).start().unwrap();
+    });
+
+    // Wait for the listener to come up
+    for _ in 0..50 {
+        if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    port
+}
+
+fn tls_options() -> TlsOptions {
+    TlsOptions {
+        ca_file: Some(fixture("ca.cert.pem")),
+        client_cert_file: Some(fixture("client.cert.pem")),
+        client_key_file: Some(fixture("client.key.pem")),
+    }
+}
+
+fn credentials(password: &str) -> Option<Credentials> {
+    Some(Credentials {
+        username: "gateway".to_string(),
+        password: password.to_string(),
+    })
+}
+
+// Waits until the client either connects or starts retrying
+async fn wait_for_outcome(client: &RumqttcClient) -> ConnectionState {
+    let mut state = client.connection_state();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let current = state.borrow_and_update().clone();
+            if !matches!(current, ConnectionState::Connecting) {
+                return current;
+            }
+            state.changed().await.unwrap();
+        }
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_tls_with_client_certificate_and_credentials() {
+    let port = start_tls_broker();
+
+    let mut options = ConnectionOptions::new("tls_test_client", "localhost", port);
+    options.tls = Some(tls_options());
+    options.credentials = credentials("s3cret");
+
+    let client = RumqttcClient::connect(options).await.unwrap();
+    assert_eq!(wait_for_outcome(&client).await, ConnectionState::Connected);
+
+    // Messages round-trip over the encrypted connection
+    let mut stream = client.subscribe_stream("test/tls/#").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    client.publish("test/tls/value", b"42".to_vec(), PublishPolicy::not_retained()).await.unwrap();
+
+    let message = tokio::time::timeout(Duration::from_secs(5), stream.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(message.payload, b"42".to_vec());
+}
+
+#[tokio::test]
+async fn test_tls_rejects_wrong_password() {
+    let port = start_tls_broker();
+
+    let mut options = ConnectionOptions::new("tls_test_bad_password", "localhost", port);
+    options.tls = Some(tls_options());
+    options.credentials = credentials("wrong");
+
+    let client = RumqttcClient::connect(options).await.unwrap();
+    assert!(matches!(wait_for_outcome(&client).await, ConnectionState::Reconnecting { .. }));
+}
+
+#[tokio::test]
+async fn test_tls_missing_ca_file() {
+    let mut options = ConnectionOptions::new("tls_test_missing_ca", "localhost", 8883);
+    options.tls = Some(TlsOptions {
+        ca_file: Some(fixture("missing.pem")),
+        ..TlsOptions::default()
+    });
+
+    assert!(matches!(RumqttcClient::connect(options).await, Err(UnsError::Other(_))));
+}